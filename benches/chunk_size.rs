@@ -0,0 +1,78 @@
+/*
+ampack, to unpack and pack Aml burning images: chunk size benchmark
+Copyright (C) 2024-present Guoxin "7Ji" Pu
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as
+published by the Free Software Foundation, either version 3 of the
+License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::{fs::File, io::{BufWriter, Write}};
+
+use ampack::{crc32::Crc32Hasher, payload::SIZE_CHUNK_DEFAULT, retry, sha1sum::Sha1sumHasher};
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+const SIZE: usize = 0x4000000;
+
+const SIZES_CHUNK: [usize; 7] = 
+    [0x1000, 0x10000, 0x40000, SIZE_CHUNK_DEFAULT, 0x400000, 0x1000000, 0x4000000];
+
+/// The chunk size is global and only settable once, so these follow the loops
+/// of payloads and the image writer with each chunk size instead: writing 
+/// chunks through a buffer of the same size while calculating the CRC32, and
+/// reading chunks back from the file (likely page cache) into a reused buffer
+/// while calculating the sha1sum
+fn chunk_size(c: &mut Criterion) {
+    let data: Vec<u8> = (0..SIZE as u32).map(|id|(id * 131) as u8).collect();
+    let path = std::env::temp_dir().join(format!("ampack-bench.{}", 
+        std::process::id()));
+    let mut group = c.benchmark_group("chunk_size");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(SIZE as u64));
+    for size_chunk in SIZES_CHUNK {
+        group.bench_with_input(BenchmarkId::new("write", size_chunk), 
+            &size_chunk, |b, size_chunk| b.iter(|| 
+        {
+            let mut writer = BufWriter::with_capacity(*size_chunk, 
+                File::create(&path).unwrap());
+            let mut hasher = Crc32Hasher::new();
+            for chunk in data.chunks(*size_chunk) {
+                hasher.update(chunk);
+                retry::write_all(&mut writer, black_box(chunk))
+                    .unwrap_or_else(|e|panic!("{}", e.error));
+            }
+            writer.flush().unwrap();
+            hasher.value
+        }));
+        group.bench_with_input(BenchmarkId::new("hash", size_chunk), 
+            &size_chunk, |b, size_chunk| b.iter(|| 
+        {
+            let mut file = File::open(&path).unwrap();
+            let mut buffer = vec![0; *size_chunk];
+            let mut hasher = Sha1sumHasher::new();
+            let mut done = 0;
+            while done < SIZE {
+                let buffer = &mut buffer[0..(SIZE - done).min(*size_chunk)];
+                retry::read_exact(&mut file, buffer)
+                    .unwrap_or_else(|e|panic!("{}", e.error));
+                hasher.update(black_box(buffer));
+                done += buffer.len()
+            }
+            hasher.finalize()
+        }));
+    }
+    group.finish();
+    let _ = std::fs::remove_file(&path);
+}
+
+criterion_group!(benches, chunk_size);
+criterion_main!(benches);
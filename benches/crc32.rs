@@ -0,0 +1,39 @@
+/*
+ampack, to unpack and pack Aml burning images: crc32 benchmark
+Copyright (C) 2024-present Guoxin "7Ji" Pu
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as
+published by the Free Software Foundation, either version 3 of the
+License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use ampack::crc32::Crc32Hasher;
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+
+/// Throughput of the slicing-by-8 CRC32 over buffers from a partial slice to
+/// the default chunk size
+fn update(c: &mut Criterion) {
+    let data: Vec<u8> = (0..0x100000_u32).map(|id|(id * 131) as u8).collect();
+    let mut group = c.benchmark_group("crc32");
+    for size in [7, 0x1000, 0x100000] {
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_function(format!("update {}", size), |b| b.iter(|| {
+            let mut hasher = Crc32Hasher::new();
+            hasher.update(black_box(&data[..size]));
+            hasher.value
+        }));
+    }
+    group.finish()
+}
+
+criterion_group!(benches, update);
+criterion_main!(benches);
@@ -0,0 +1,43 @@
+/*
+ampack, to unpack and pack Aml burning images: sha1sum benchmark
+Copyright (C) 2024-present Guoxin "7Ji" Pu
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as
+published by the Free Software Foundation, either version 3 of the
+License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use ampack::{payload::Payload, progress::progress_bar_with_template_optional, sha1sum::Sha1sum};
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+
+const SIZE: usize = 0x1000000;
+
+/// Throughput of sha1sums over in-memory data, compare a build with the 
+/// default `sha1-asm` against one with `--features sha1-soft`
+fn from_data(c: &mut Criterion) {
+    let data: Vec<u8> = (0..SIZE as u32).map(|id|(id * 131) as u8).collect();
+    let mut group = c.benchmark_group("sha1sum");
+    group.throughput(Throughput::Bytes(SIZE as u64));
+    group.bench_function("from_data", |b| b.iter(||
+        Sha1sum::from_data(black_box(&data))));
+    let payload = Payload::Owned(data);
+    group.bench_function("try_from_payload_with_bar", |b| b.iter(|| {
+        let bar = progress_bar_with_template_optional(
+            false, SIZE as u64, "Hashing => {bar}").unwrap();
+        Sha1sum::try_from_payload_with_bar(black_box(&payload), "bench", &bar)
+            .unwrap()
+    }));
+    group.finish()
+}
+
+criterion_group!(benches, from_data);
+criterion_main!(benches);
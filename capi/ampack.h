@@ -0,0 +1,131 @@
+/*
+ * C API of ampack, built as a cdylib with
+ *   cargo rustc --release --lib --features capi --crate-type cdylib
+ *
+ * Every call returning an AmpackStatus returns AMPACK_STATUS_OK (0) on
+ * success and non-zero on failure, like the exit code of the command, with
+ * the message of the failure from ampack_last_error().
+ *
+ * Ownership: an AmpackImage is created by ampack_image_open() and owned by
+ * the caller until passed to ampack_image_free(). Strings handed out (item
+ * names, error messages) are owned by the library, never freed by the
+ * caller, and only valid as documented on each function. Buffers passed in
+ * are owned by the caller and never kept after a call returns.
+ */
+
+#ifndef AMPACK_H
+#define AMPACK_H
+
+/* Generated by cbindgen from src/capi.rs, do not edit */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+// Result of a call, 0 for success and non-zero for failures, like the exit
+// code of the command, with the kind of failure told apart for callers
+typedef enum AmpackStatus {
+  AMPACK_STATUS_OK = 0,
+  // Any failure not of the kinds below
+  AMPACK_STATUS_ERROR = 1,
+  // A null pointer, a path not in UTF-8, or an index out of range
+  AMPACK_STATUS_INVALID_ARGUMENT = 2,
+  // The image could not be read
+  AMPACK_STATUS_IO = 3,
+  // The image is malformed
+  AMPACK_STATUS_INVALID_IMAGE = 4,
+  // The image was read, but items or the CRC32 do not match
+  AMPACK_STATUS_MISMATCH = 5,
+} AmpackStatus;
+
+// An image opened from a file, with its payloads read only when asked
+typedef struct AmpackImage AmpackImage;
+
+// Metadata of an item, `name` is owned by the image and valid until it's
+// freed
+typedef struct AmpackItemInfo {
+  // `[stem].[extension]`, e.g. `boot.PARTITION`
+  const char *name;
+  uint64_t size;
+  // Offset of the payload in the image file
+  uint64_t offset;
+  // File type recorded in the item info, 0 for normal, 0xfe for sparse
+  uint32_t file_type;
+} AmpackItemInfo;
+
+// Receives the progress of verification: `position` of `length` bytes of
+// `stage` are done, with the `user_data` passed alongside it. `stage` is
+// only valid during the call.
+typedef void (*AmpackProgressCallback)(const char *stage,
+                                       uint64_t position,
+                                       uint64_t length,
+                                       void *user_data);
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// The message of the last failed call on this thread, empty if it
+// succeeded. Owned by the library and valid until the next call on this
+// thread.
+const char *ampack_last_error(void);
+
+// Open the image file at `path` and store a new image owned by the caller
+// into `out`, to be freed with `ampack_image_free`
+//
+// # Safety
+// `path` must be a NUL-terminated string and `out` must be valid for writes
+enum AmpackStatus ampack_image_open(const char *path, struct AmpackImage **out);
+
+// Free an image opened by `ampack_image_open`, doing nothing on null
+//
+// # Safety
+// `image` must be null or from `ampack_image_open` and not freed yet
+void ampack_image_free(struct AmpackImage *image);
+
+// Count of items in the image, VERIFY ones excluded, 0 for null
+//
+// # Safety
+// `image` must be null or a valid image
+size_t ampack_image_item_count(const struct AmpackImage *image);
+
+// Store the metadata of the item at `index` into `out`
+//
+// # Safety
+// `image` must be a valid image and `out` must be valid for writes
+enum AmpackStatus ampack_image_item_info(const struct AmpackImage *image,
+                                         size_t index,
+                                         struct AmpackItemInfo *out);
+
+// Read up to `len` bytes of the item at `index` from `offset` in it into
+// `buffer`, and store the count of bytes read into `read`, which is less
+// than `len` only at the end of the item
+//
+// # Safety
+// `image` must be a valid image, `buffer` must be valid for `len` bytes of
+// writes, and `read` must be valid for writes
+enum AmpackStatus ampack_image_read_item(const struct AmpackImage *image,
+                                         size_t index,
+                                         uint64_t offset,
+                                         uint8_t *buffer,
+                                         size_t len,
+                                         size_t *read);
+
+// Verify the image against the file it was opened from, calling `callback`
+// with `user_data` as it goes if it's not null. Returns `Mismatch` if any
+// item or the CRC32 does not match. Verifications run one at a time.
+//
+// # Safety
+// `image` must be a valid image, and `callback` must be safe to call with
+// `user_data` until this returns
+enum AmpackStatus ampack_image_verify(const struct AmpackImage *image,
+                                      AmpackProgressCallback callback,
+                                      void *user_data);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* AMPACK_H */
@@ -0,0 +1,98 @@
+/*
+ampack, to unpack and pack Aml burning images: C API smoke test
+Copyright (C) 2024-present Guoxin "7Ji" Pu
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as
+published by the Free Software Foundation, either version 3 of the
+License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+#include <stdio.h>
+#include <string.h>
+
+#include "ampack.h"
+
+#define CHECK(call) do { \
+    AmpackStatus status = (call); \
+    if (status != AMPACK_STATUS_OK) { \
+        fprintf(stderr, "%s failed with %d: %s\n", #call, status, \
+            ampack_last_error()); \
+        return 1; \
+    } \
+} while (0)
+
+static void progress(const char *stage, uint64_t position, uint64_t length,
+    void *user_data)
+{
+    (void)stage;
+    (void)length;
+    *(uint64_t *)user_data = position;
+}
+
+int main(int argc, char *argv[]) {
+    if (argc != 2) {
+        fprintf(stderr, "Usage: %s [image]\n", argv[0]);
+        return 1;
+    }
+    AmpackImage *image = NULL;
+    if (ampack_image_open("/nonexistent/image", &image) != AMPACK_STATUS_IO ||
+        image != NULL || ampack_last_error()[0] == '\0') 
+    {
+        fprintf(stderr, "Opening a missing image did not fail with IO\n");
+        return 1;
+    }
+    CHECK(ampack_image_open(argv[1], &image));
+    size_t count = ampack_image_item_count(image);
+    if (count == 0) {
+        fprintf(stderr, "No items in image\n");
+        return 1;
+    }
+    for (size_t i = 0; i < count; ++i) {
+        AmpackItemInfo info;
+        CHECK(ampack_image_item_info(image, i, &info));
+        /* Read the item in small pieces, the last one is short */
+        uint8_t buffer[4096];
+        uint64_t total = 0;
+        size_t read;
+        do {
+            CHECK(ampack_image_read_item(image, i, total, buffer, 
+                sizeof buffer, &read));
+            total += read;
+        } while (read == sizeof buffer);
+        if (total != info.size) {
+            fprintf(stderr, "Read 0x%llx bytes of item '%s', expected 0x%llx\n",
+                (unsigned long long)total, info.name, 
+                (unsigned long long)info.size);
+            return 1;
+        }
+        printf("%s: 0x%llx bytes at 0x%llx, file type %u\n", info.name,
+            (unsigned long long)info.size, (unsigned long long)info.offset,
+            info.file_type);
+    }
+    AmpackItemInfo info;
+    if (ampack_image_item_info(image, count, &info) != 
+        AMPACK_STATUS_INVALID_ARGUMENT) 
+    {
+        fprintf(stderr, "Item out of range did not fail\n");
+        return 1;
+    }
+    uint64_t position = 0;
+    CHECK(ampack_image_verify(image, progress, &position));
+    if (position == 0) {
+        fprintf(stderr, "Progress callback was not called\n");
+        return 1;
+    }
+    ampack_image_free(image);
+    ampack_image_free(NULL);
+    printf("C API OK\n");
+    return 0;
+}
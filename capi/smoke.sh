@@ -0,0 +1,28 @@
+#!/bin/bash
+# Build the cdylib and run the C smoke test against an image, e.g.
+#   capi/smoke.sh path/to/update.img
+# or without an image, against one packed from a few generated items
+set -euo pipefail
+cd "$(dirname "$0")/.."
+cargo rustc --release --lib --features capi --crate-type cdylib
+target=$(cargo metadata --format-version 1 --no-deps | 
+    sed -n 's/.*"target_directory":"\([^"]*\)".*/\1/p')
+out=$(mktemp -d)
+trap 'rm -rf "${out}"' EXIT
+if [[ $# -gt 0 ]]; then
+    image="$1"
+else
+    cargo build --release --bin ampack
+    mkdir "${out}/in"
+    head -c 70000 /dev/urandom > "${out}/in/DDR.USB"
+    head -c 300000 /dev/urandom > "${out}/in/UBOOT.USB"
+    printf '[common]\nerase=1\n' > "${out}/in/aml_sdc_burn.ini"
+    head -c 5000 /dev/urandom > "${out}/in/meson1.dtb"
+    printf 'Platform:0x0811\n' > "${out}/in/platform.conf"
+    head -c 100000 /dev/urandom > "${out}/in/boot.PARTITION"
+    image="${out}/update.img"
+    "${target}/release/ampack" pack "${out}/in" "${image}" > /dev/null
+fi
+cc -std=c99 -Wall -Wextra -Werror -Icapi capi/smoke.c \
+    -L"${target}/release" -lampack -o "${out}/smoke"
+LD_LIBRARY_PATH="${target}/release" "${out}/smoke" "${image}"
@@ -0,0 +1,320 @@
+/*
+ampack, to unpack and pack Aml burning images: archive module
+Copyright (C) 2024-present Guoxin "7Ji" Pu
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as
+published by the Free Software Foundation, either version 3 of the
+License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Images inside zip archives, found and extracted without unpacking the
+//! whole archive, and tar archives written from items.
+
+use std::{fmt::Display, fs::File, io::{Read, Seek, SeekFrom}, path::Path};
+#[cfg(feature = "tar")]
+use std::io::Write;
+
+use crate::{Error, Result};
+
+#[derive(Debug)]
+pub enum ArchiveError {
+    NoImageMember,
+    MultipleImageMembers {
+        count: usize
+    },
+    MissingMember {
+        name: String
+    },
+}
+
+impl From<ArchiveError> for Error {
+    fn from(value: ArchiveError) -> Self {
+        Self::ArchiveError(value)
+    }
+}
+
+impl Display for ArchiveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Archive Error: ")?;
+        match self {
+            ArchiveError::NoImageMember =>
+                write!(f, "No Image Member"),
+            ArchiveError::MultipleImageMembers { count } =>
+                write!(f, "{} Image Members", count),
+            ArchiveError::MissingMember { name } =>
+                write!(f, "Missing Member '{}'", name),
+        }
+    }
+}
+
+impl std::error::Error for ArchiveError {}
+
+/// A region of a file read as a file of its own, e.g. an image stored
+/// uncompressed in an archive
+pub struct FileRegion {
+    file: File,
+    offset: u64,
+    size: u64,
+    position: u64,
+}
+
+impl FileRegion {
+    pub fn open<P: AsRef<Path>>(path: P, offset: u64, size: u64) -> Result<Self> {
+        Ok(Self { file: File::open(path)?, offset, size, position: 0 })
+    }
+}
+
+impl Read for FileRegion {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let left = self.size.saturating_sub(self.position);
+        let len = (buf.len() as u64).min(left) as usize;
+        if len == 0 {
+            return Ok(0)
+        }
+        self.file.seek(SeekFrom::Start(self.offset + self.position))?;
+        let size = self.file.read(&mut buf[..len])?;
+        self.position += size as u64;
+        Ok(size)
+    }
+}
+
+impl Seek for FileRegion {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.size.checked_add_signed(offset),
+            SeekFrom::Current(offset) =>
+                self.position.checked_add_signed(offset),
+        };
+        match position {
+            Some(position) => {
+                self.position = position;
+                Ok(position)
+            },
+            None => Err(std::io::Error::new(std::io::ErrorKind::InvalidInput,
+                "seek to a negative position")),
+        }
+    }
+}
+
+/// Magic of the local file header at the start of a zip archive
+pub const ZIP_MAGIC: &[u8; 4] = b"PK\x03\x04";
+
+/// Whether the file at `path` is a zip archive, by its magic
+#[cfg(feature = "zip")]
+pub fn is_zip<P: AsRef<Path>>(path: P) -> Result<bool> {
+    let mut magic = [0; 4];
+    match File::open(path)?.read_exact(&mut magic) {
+        Ok(()) => Ok(&magic == ZIP_MAGIC),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// A member of a zip archive holding an image
+#[cfg(feature = "zip")]
+pub struct ZipMember {
+    pub name: String,
+    /// Uncompressed size
+    pub size: u64,
+    /// Offset of the data in the archive if it's stored uncompressed, so it
+    /// could be read in place as a `FileRegion`
+    pub offset: Option<u64>,
+    index: usize,
+}
+
+#[cfg(feature = "zip")]
+fn open_zip(path: &Path) -> Result<zip::ZipArchive<std::io::BufReader<File>>> {
+    Ok(zip::ZipArchive::new(std::io::BufReader::new(File::open(path)?))?)
+}
+
+/// Find the member named `name` in the zip archive at `path`, or the only
+/// member named as `*.img` if None
+#[cfg(feature = "zip")]
+pub fn find_zip_member<P: AsRef<Path>>(path: P, name: Option<&str>)
+    -> Result<ZipMember>
+{
+    let path = path.as_ref();
+    let mut archive = open_zip(path)?;
+    let mut found = Vec::new();
+    for index in 0..archive.len() {
+        let member = archive.by_index_raw(index)?;
+        let wanted = match name {
+            Some(name) => member.name() == name,
+            None => member.is_file() &&
+                member.name().to_lowercase().ends_with(".img"),
+        };
+        if wanted {
+            found.push(ZipMember {
+                name: member.name().into(),
+                size: member.size(),
+                offset: (member.compression() ==
+                    zip::CompressionMethod::Stored)
+                    .then(|| member.data_start()),
+                index,
+            })
+        }
+    }
+    if found.len() > 1 && name.is_none() {
+        eprintln!("Zip archive '{}' has {} members named as *.img, pick one \
+            with --member:{}", path.display(), found.len(), found.iter()
+                .map(|member|format!("\n  - {}", member.name))
+                .collect::<String>());
+        return Err(ArchiveError::MultipleImageMembers {
+            count: found.len() }.into())
+    }
+    match (found.into_iter().next(), name) {
+        (Some(member), _) => Ok(member),
+        (None, Some(name)) => {
+            eprintln!("Zip archive '{}' has no member '{}'", path.display(),
+                name);
+            Err(ArchiveError::MissingMember { name: name.into() }.into())
+        },
+        (None, None) => {
+            eprintln!("Zip archive '{}' has no member named as *.img, pick one \
+                with --member", path.display());
+            Err(ArchiveError::NoImageMember.into())
+        },
+    }
+}
+
+/// Decompress `member` of the zip archive at `path` into a new file at `to`
+#[cfg(feature = "zip")]
+pub fn extract_zip_member<P1, P2>(path: P1, member: &ZipMember, to: P2)
+    -> Result<()>
+where
+    P1: AsRef<Path>,
+    P2: AsRef<Path>
+{
+    let mut archive = open_zip(path.as_ref())?;
+    let mut reader = archive.by_index(member.index)?;
+    let mut file = File::options().write(true).create_new(true)
+        .open(to.as_ref())?;
+    std::io::copy(&mut reader, &mut file)?;
+    Ok(())
+}
+
+/// Size of tar headers, and the granularity entries are padded to
+#[cfg(feature = "tar")]
+const TAR_BLOCK: usize = 512;
+
+/// Writer of a tar archive streaming entries of known sizes, with fixed
+/// metadata (mtime 0, uid/gid 0, mode 0644) so the same entries always give
+/// the same archive. Data of an entry is written into it between
+/// `start_entry` and `finish_entry`.
+#[cfg(feature = "tar")]
+pub struct TarWriter<W: Write> {
+    writer: W,
+    /// Size of the current entry, and how much of it was written
+    size: u64,
+    written: u64,
+    /// Total bytes written into `writer`
+    len: u64,
+}
+
+#[cfg(feature = "tar")]
+impl<W: Write> TarWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer, size: 0, written: 0, len: 0 }
+    }
+
+    fn write_raw(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.writer.write_all(data)?;
+        self.len += data.len() as u64;
+        Ok(())
+    }
+
+    fn write_padding(&mut self, size: u64) -> std::io::Result<()> {
+        let remainder = (size % TAR_BLOCK as u64) as usize;
+        if remainder != 0 {
+            self.write_raw(&[0; TAR_BLOCK][remainder..])?
+        }
+        Ok(())
+    }
+
+    fn write_header(&mut self, name: &[u8], size: u64, 
+                    entry_type: tar::EntryType) 
+        -> std::io::Result<()> 
+    {
+        let mut header = tar::Header::new_gnu();
+        let field = &mut header.as_old_mut().name;
+        let len = name.len().min(field.len());
+        field[..len].copy_from_slice(&name[..len]);
+        header.set_entry_type(entry_type);
+        header.set_size(size);
+        header.set_mode(0o644);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mtime(0);
+        header.set_cksum();
+        self.write_raw(header.as_bytes())
+    }
+
+    /// Start a regular file entry named `name` of `size` bytes, names not 
+    /// fitting in the header are carried by a GNU long name entry before it
+    pub fn start_entry(&mut self, name: &str, size: u64) -> Result<()> {
+        if self.written != self.size {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput,
+                "tar entry started before the last one finished").into())
+        }
+        let name = name.as_bytes();
+        if name.len() > tar::Header::new_gnu().as_old().name.len() {
+            let mut long_name = name.to_vec();
+            long_name.push(0);
+            self.write_header(b"././@LongLink", long_name.len() as u64, 
+                tar::EntryType::GNULongName)?;
+            self.write_raw(&long_name)?;
+            self.write_padding(long_name.len() as u64)?;
+        }
+        self.write_header(name, size, tar::EntryType::Regular)?;
+        self.size = size;
+        self.written = 0;
+        Ok(())
+    }
+
+    /// Finish the current entry, which must have been written fully
+    pub fn finish_entry(&mut self) -> Result<()> {
+        if self.written != self.size {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData,
+                format!("tar entry of 0x{:x} bytes written with 0x{:x} bytes",
+                    self.size, self.written)).into())
+        }
+        self.write_padding(self.size)?;
+        Ok(())
+    }
+
+    /// Write the end of the archive, and return the inner writer and the 
+    /// size of the archive
+    pub fn finish(mut self) -> Result<(W, u64)> {
+        self.write_raw(&[0; TAR_BLOCK * 2])?;
+        Ok((self.writer, self.len))
+    }
+}
+
+#[cfg(feature = "tar")]
+impl<W: Write> Write for TarWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let left = self.size - self.written;
+        if buf.len() as u64 > left {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput,
+                "data beyond the size of the tar entry"))
+        }
+        let size = self.writer.write(buf)?;
+        self.written += size as u64;
+        self.len += size as u64;
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
@@ -0,0 +1,69 @@
+/*
+ampack, to unpack and pack Aml burning images: async adapters module
+Copyright (C) 2024-present Guoxin "7Ji" Pu
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as
+published by the Free Software Foundation, either version 3 of the
+License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// The sync API is the only implementation, these only move it onto the 
+// blocking pool of the runtime, with async readers bridged to sync ones
+
+use std::sync::Arc;
+
+use tokio::io::{AsyncRead, AsyncSeek};
+use tokio_util::io::SyncIoBridge;
+
+use crate::{checksum::DigestAlgo, image::{Image, ReadFileOptions, VerifyReport}, Result};
+
+/// Run `work` on the blocking pool of the current runtime
+async fn spawn_blocking<T, F>(work: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T> + Send + 'static
+{
+    tokio::task::spawn_blocking(work).await
+        .map_err(std::io::Error::other)?
+}
+
+impl Image {
+    /// Read an image from an async reader without blocking the runtime, see
+    /// `try_read_from`. Must be called within a tokio runtime. The payloads
+    /// stay backed by the reader, so they could only be consumed off the 
+    /// runtime threads, e.g. by `verify_from_async`, or in `spawn_blocking`.
+    pub async fn try_read_from_async<R>(reader: R, options: &ReadFileOptions) 
+        -> Result<Self> 
+    where
+        R: AsyncRead + AsyncSeek + Unpin + Send + 'static
+    {
+        let reader = SyncIoBridge::new(reader);
+        let options = options.clone();
+        spawn_blocking(move || Self::try_read_from(reader, &options)).await
+    }
+
+    /// Verify the image against an async reader of it without blocking the
+    /// runtime, hashing on the blocking pool, see `verify_from`. Must be 
+    /// called within a tokio runtime.
+    pub async fn verify_from_async<R>(
+        self: Arc<Self>, reader: R, with_crc32: bool, 
+        digest: Option<DigestAlgo>, fail_fast: bool
+    ) 
+        -> Result<VerifyReport> 
+    where
+        R: AsyncRead + AsyncSeek + Unpin + Send + 'static
+    {
+        let reader = SyncIoBridge::new(reader);
+        spawn_blocking(move || 
+            self.verify_from(reader, with_crc32, digest, fail_fast)).await
+    }
+}
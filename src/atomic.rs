@@ -0,0 +1,148 @@
+/*
+ampack, to unpack and pack Aml burning images: atomic output module
+Copyright (C) 2024-present Guoxin "7Ji" Pu
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as
+published by the Free Software Foundation, either version 3 of the
+License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Output files written atomically through temporary files, with the
+//! dirs and space they need checked first.
+
+use std::{cell::Cell, fs::{create_dir_all, remove_file, rename, File}, path::{Path, PathBuf}, time::{Duration, Instant}};
+
+use crate::Result;
+
+/// The temporary file `path` would be written as in atomic mode, in the same
+/// dir so the final rename would not cross filesystems
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".tmp.{}", std::process::id()));
+    path.with_file_name(name)
+}
+
+/// Create the missing parent dirs of `path`, if any
+pub fn create_parent_dir(path: &Path) -> Result<()> {
+    let parent = match path.parent() {
+        Some(parent) if ! parent.as_os_str().is_empty() => parent,
+        _ => return Ok(()),
+    };
+    if let Err(e) = create_dir_all(parent) {
+        eprintln!("Failed to create parent dir '{}' for '{}': {}", 
+            parent.display(), path.display(), e);
+        return Err(e.into())
+    }
+    Ok(())
+}
+
+/// Space available to unprivileged users on the filesystem `path` is on, 
+/// None if unknown on this platform
+#[cfg(unix)]
+pub fn available_space(path: &Path) -> Result<Option<u64>> {
+    use std::os::unix::ffi::OsStrExt;
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error().into())
+    }
+    Ok(Some(stat.f_bavail as u64 * stat.f_frsize as u64))
+}
+
+#[cfg(not(unix))]
+pub fn available_space(_path: &Path) -> Result<Option<u64>> {
+    Ok(None)
+}
+
+/// Create `path` for writing, reporting the path if that fails
+fn create_file(path: &Path) -> Result<File> {
+    File::create(path).map_err(|e| {
+        eprintln!("Failed to create file '{}': {}", path.display(), e);
+        e.into()
+    })
+}
+
+/// How `write_file` writes the file
+#[derive(Default)]
+pub struct WriteFileOptions {
+    /// Write via a temporary file renamed to the final path on success
+    pub atomic: bool,
+    /// Flush the file and its parent dir to the storage before returning
+    pub fsync: bool,
+}
+
+/// Flush the entry of `path` in its parent dir to the storage
+fn sync_parent_dir(path: &Path) -> Result<()> {
+    #[cfg(unix)]
+    {
+        let dir = match path.parent() {
+            Some(parent) if ! parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        File::open(dir)?.sync_all()?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+/// Create the file at `path` (and its missing parent dirs) and fill it with 
+/// `write`. In atomic mode the content is written into a temporary file which 
+/// is synced and then renamed to `path` only on success, and removed on 
+/// failure, so an existing file at `path` is never left truncated. With fsync
+/// the file and its parent dir are also synced before returning, so the
+/// content is on the storage and not only in page cache.
+pub fn write_file<P, F>(path: P, options: &WriteFileOptions, write: F) 
+    -> Result<()>
+where
+    P: AsRef<Path>,
+    F: FnOnce(&mut File) -> Result<()>
+{
+    let path = path.as_ref();
+    create_parent_dir(path)?;
+    let time_sync = Cell::new(Duration::ZERO);
+    let sync = |sync: &dyn Fn() -> Result<()>| -> Result<()> {
+        let time_start = Instant::now();
+        sync()?;
+        time_sync.set(time_sync.get() + time_start.elapsed());
+        Ok(())
+    };
+    let result = if options.atomic {
+        let path_temp = temp_path(path);
+        let result = create_file(&path_temp)
+            .and_then(|mut file| {
+                write(&mut file)?;
+                sync(&|| Ok(file.sync_all()?))
+            }).and_then(|_| Ok(rename(&path_temp, path)?));
+        if result.is_err() && path_temp.exists() {
+            if let Err(e) = remove_file(&path_temp) {
+                eprintln!("Failed to remove temporary file '{}': {}", 
+                    path_temp.display(), e)
+            }
+        }
+        result
+    } else {
+        create_file(path).and_then(|mut file| {
+            write(&mut file)?;
+            if options.fsync {
+                sync(&|| Ok(file.sync_all()?))?
+            }
+            Ok(())
+        })
+    };
+    if result.is_ok() && options.fsync {
+        sync(&|| sync_parent_dir(path))?;
+        println!("Synced '{}' to storage in {:.2}s", 
+            path.display(), time_sync.get().as_secs_f64())
+    }
+    result
+}
@@ -0,0 +1,283 @@
+/*
+ampack, to unpack and pack Aml burning images: item hook module
+Copyright (C) 2024-present Guoxin "7Ji" Pu
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as
+published by the Free Software Foundation, either version 3 of the
+License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::{ffi::{OsStr, OsString}, io::Write, path::Path, process::Command};
+
+use ampack::{image::Image, manifest::Manifest, output, payload::{Payload, SourceFile, SpillDir}, Error, Result};
+
+use crate::logger;
+
+/// A command run on items, split from a template into words like a shell
+/// would, with `{placeholder}`s in them substituted for each item. It's run
+/// directly, not through a shell, so substituted paths never need quoting.
+pub(crate) struct Hook {
+    template: String,
+    words: Vec<String>,
+}
+
+impl Hook {
+    /// Split `template` into words on whitespaces, with '...' and "..."
+    /// quoting whitespaces and \ escaping the next character outside '...'
+    pub(crate) fn parse(template: &str) -> Result<Self> {
+        let mut words = Vec::new();
+        let mut word = None;
+        let mut quote = None;
+        let mut chars = template.chars();
+        while let Some(c) = chars.next() {
+            match (quote, c) {
+                (Some(q), c) if c == q => quote = None,
+                (Some('\''), c) => word.get_or_insert_with(String::new).push(c),
+                (_, '\\') => match chars.next() {
+                    Some(c) => word.get_or_insert_with(String::new).push(c),
+                    None => break,
+                },
+                (None, '\'' | '"') => {
+                    word.get_or_insert_with(String::new);
+                    quote = Some(c)
+                },
+                (None, c) if c.is_whitespace() =>
+                    if let Some(word) = word.take() {
+                        words.push(word)
+                    },
+                (_, c) => word.get_or_insert_with(String::new).push(c),
+            }
+        }
+        if let Some(word) = word {
+            words.push(word)
+        }
+        if quote.is_some() || words.is_empty() {
+            eprintln!("Invalid hook command '{}': {}", template,
+                if words.is_empty() { "empty" } else { "unterminated quote" });
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput,
+                "invalid hook command").into())
+        }
+        Ok(Self { template: template.into(), words })
+    }
+
+    /// Run the command for item `name`, with each `{key}` in its words
+    /// replaced by the value in `values`. Its stdout and stderr are captured
+    /// into the log file, or passed on to stderr without one, to keep stdout
+    /// for results and images written there. Fails if it could not be started
+    /// or does not exit successfully.
+    pub(crate) fn run(&self, name: &str, values: &[(&str, &OsStr)])
+        -> Result<()>
+    {
+        let words: Vec<OsString> = self.words.iter().map(|word|
+            substitute(word, values)).collect();
+        log::info!("Running hook '{}' on item '{}': {:?}",
+            self.template, name, words);
+        let output = match Command::new(&words[0]).args(&words[1..]).output() {
+            Ok(output) => output,
+            Err(e) => {
+                eprintln!("Failed to run hook '{}' on item '{}': {}",
+                    self.template, name, e);
+                return Err(Error::HookFailed(name.into(), 
+                    format!("failed to start: {}", e)))
+            },
+        };
+        for (stream, bytes) in [("stdout", &output.stdout), 
+                                ("stderr", &output.stderr)] 
+        {
+            for line in String::from_utf8_lossy(bytes).lines() {
+                log::info!("Hook on item '{}' {}: {}", name, stream, line)
+            }
+            if ! logger::logging_to_file() && ! bytes.is_empty() {
+                ampack::progress::suspend(||
+                    std::io::stderr().lock().write_all(bytes))?
+            }
+        }
+        let status = output.status;
+        if status.success() {
+            Ok(())
+        } else {
+            eprintln!("Hook '{}' on item '{}' failed with {}",
+                self.template, name, status);
+            Err(Error::HookFailed(name.into(), status.to_string()))
+        }
+    }
+}
+
+impl std::fmt::Display for Hook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.template)
+    }
+}
+
+/// Run a hook on the file of each item before it's packed, and pack the file
+/// it wrote instead: the hook recorded for the item in `manifest` if any and
+/// `manifest_hooks`, or otherwise `hook` if the item name matches one of 
+/// `globs`. Hooks in `manifest` are only listed without `manifest_hooks`, as
+/// the manifest comes with the input dir and could be from anyone. Hooks are
+/// run one after another in the order of items, in-memory payloads (e.g. 
+/// normalized text) are spilled into `spill` for them first, and their 
+/// outputs are written there. Returns the names of hooked items.
+pub(crate) fn run_pack_hooks(image: &mut Image, hook: Option<&Hook>, 
+                             globs: &[String], manifest: Option<&Manifest>,
+                             manifest_hooks: bool, spill: &mut SpillDir) 
+    -> Result<Vec<String>> 
+{
+    let recorded: Vec<_> = manifest.map(|manifest|
+        manifest.items.iter().filter_map(|item|
+            item.hook.as_ref().map(|hook|(item.name.as_str(), hook.as_str())))
+        .collect()).unwrap_or_default();
+    let manifest_hooks = if recorded.is_empty() {
+        Vec::new()
+    } else if manifest_hooks {
+        for (name, hook) in recorded.iter() {
+            println!("Hook '{}' recorded in manifest for item '{}'", hook, name)
+        }
+        recorded.into_iter().map(|(name, hook)|Ok((name, Hook::parse(hook)?)))
+            .collect::<Result<Vec<_>>>()?
+    } else {
+        warning!(ManifestHooks, "ignoring hooks recorded in manifest \
+            for {} items, check them and pass --manifest-hooks to run them:", 
+            recorded.len());
+        for (name, hook) in recorded.iter() {
+            eprintln!("  {}: {}", name, hook)
+        }
+        Vec::new()
+    };
+    image.try_replace_payloads(|name, data| {
+        let hook = match manifest_hooks.iter().find(|(item, _)|*item == name) {
+            Some((_, hook)) => hook,
+            None => match hook {
+                Some(hook) if glob_match_any(globs, name) =>
+                    hook,
+                _ => return Ok(None),
+            },
+        };
+        let path_in = match data.source_file() {
+            Some((path, _)) => path.to_path_buf(),
+            None => spill.try_spill(data)?,
+        };
+        let path_out = spill.try_new_path(&format!("-{}", name))?;
+        println!("Running hook on item '{}'", name);
+        hook.run(name, &[("in", path_in.as_os_str()), 
+            ("out", path_out.as_os_str()), ("name", OsStr::new(name))])?;
+        let size = match path_out.metadata() {
+            Ok(metadata) => metadata.len(),
+            Err(e) => {
+                eprintln!("Hook '{}' on item '{}' did not write its output \
+                    '{}': {}", hook, name, path_out.display(), e);
+                return Err(Error::HookFailed(name.into(), 
+                    "no output written".into()))
+            },
+        };
+        println!("Hook on item '{}' gave 0x{:x} bytes to pack, instead of \
+            0x{:x} bytes", name, size, data.len());
+        Ok(Some(Payload::Region { source: SourceFile::open(&path_out)?, 
+            offset: 0, size }))
+    })
+}
+
+/// Run a hook on the file of each item just unpacked into `out_dir`, except
+/// those in `skip` (e.g. ones that failed to verify). Hooks are run one after
+/// another in the order of items, and a failing one does not stop the rest.
+/// Returns the items the hook failed on.
+pub(crate) fn run_unpack_hooks(image: &Image, hook: &Hook, out_dir: &Path,
+                               skip: &[String]) 
+    -> Vec<(String, Error)> 
+{
+    let mut failures = Vec::new();
+    for (item, file_name) in image.items().iter().zip(image.file_names()) {
+        let name = item.name();
+        if skip.contains(&name) {
+            continue
+        }
+        println!("Running hook on item '{}'", name);
+        let path = out_dir.join(&file_name);
+        if let Err(e) = hook.run(&name, &[("file", path.as_os_str()), 
+            ("name", OsStr::new(&name))]) 
+        {
+            failures.push((name, e))
+        }
+    }
+    if failures.is_empty() {
+        println!("Hook succeeded on all items")
+    } else {
+        eprintln!("Hook '{}' failed on {} items:", hook, failures.len());
+        for (_, e) in failures.iter() {
+            eprintln!("  {}", e)
+        }
+    }
+    output::record("hook_failures", &failures.iter().map(|(name, e)| 
+        serde_json::json!({"name": name, "error": e.to_string()}))
+        .collect::<Vec<_>>());
+    failures
+}
+
+/// Whether `name` matches any of `globs`, see `glob_match`
+pub(crate) fn glob_match_any(globs: &[String], name: &str) -> bool {
+    globs.iter().any(|glob|glob_match(glob, name))
+}
+
+/// Replace each `{key}` in `word` with its value in `values`, leaving other
+/// braces untouched
+fn substitute(word: &str, values: &[(&str, &OsStr)]) -> OsString {
+    let mut result = OsString::new();
+    let mut rest = word;
+    while let Some(start) = rest.find('{') {
+        result.push(&rest[..start]);
+        rest = &rest[start..];
+        match values.iter().find(|(key, _)|
+            rest[1..].starts_with(key) && rest[1 + key.len()..].starts_with('}'))
+        {
+            Some((key, value)) => {
+                result.push(value);
+                rest = &rest[key.len() + 2..]
+            },
+            None => {
+                result.push("{");
+                rest = &rest[1..]
+            },
+        }
+    }
+    result.push(rest);
+    result
+}
+
+/// Whether `name` matches the glob `pattern`, where * matches any run of
+/// characters and ? a single one, case-sensitively
+pub(crate) fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    // Where to resume after the last *, in the pattern and in the name
+    let mut star = None;
+    let (mut p, mut n) = (0, 0);
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1
+            },
+            Some(c) if *c == '?' || *c == name[n] => {
+                p += 1;
+                n += 1
+            },
+            _ => match star {
+                Some((p_star, n_star)) => {
+                    p = p_star + 1;
+                    n = n_star + 1;
+                    star = Some((p_star, n_star + 1))
+                },
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
@@ -0,0 +1,408 @@
+/*
+ampack, to unpack and pack Aml burning images: input image module
+Copyright (C) 2024-present Guoxin "7Ji" Pu
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as
+published by the Free Software Foundation, either version 3 of the
+License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::{fs::File, io::BufReader, path::{Path, PathBuf}, sync::OnceLock};
+
+use ampack::{archive::FileRegion, checksum::DigestAlgo, crc32::Crc32Hasher, error::Context, identify::PayloadKind, image::{self, Image, ImageHeader, ImageRead, RawItemInfo, ReadFileOptions, VerifyReport}, payload::ReadSeek, Error, ErrorContext, Result};
+
+use crate::stdio;
+
+/// Where the image of an input is read from
+enum Source {
+    File(PathBuf),
+    /// A region of a file, e.g. an image stored uncompressed in a zip archive
+    #[cfg_attr(not(feature = "zip"), allow(dead_code))]
+    Region {
+        path: PathBuf,
+        offset: u64,
+        size: u64,
+    },
+    /// A file on an http(s) server serving ranges, read in place
+    #[cfg(feature = "http")]
+    Remote(ampack::http::RemoteFile),
+}
+
+/// What an input is opened for
+#[derive(PartialEq, Eq)]
+enum Reading {
+    /// The image, in whole
+    Image,
+    /// Only the table and the heads of items of the image
+    Table,
+    /// The file itself as-is, whatever it is
+    Raw,
+}
+
+/// An input image as given on the command line: a file, `-` for stdin, a
+/// compressed image, or a zip archive with the image as a member
+pub(crate) struct Input {
+    /// Shown in messages, the path as given, or `[archive]:[member]`
+    name: PathBuf,
+    source: Source,
+    /// Temporary files removed when dropped, i.e. stdin spooled, images
+    /// decompressed or members extracted
+    temporaries: Vec<PathBuf>,
+    /// The format the image was decompressed from, to tell a corrupt image
+    /// from a corrupt stream
+    decompressed: Option<String>,
+    /// File name of the image itself, e.g. to look up its digest in a 
+    /// checksum file: the basename as given, without the suffix of the
+    /// compression if decompressed, or of the member if in an archive
+    image_name: String,
+}
+
+static TEMP_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// Set the dir of temporary files, only the first call takes effect
+pub(crate) fn set_temp_dir(dir: Option<PathBuf>) {
+    if let Some(dir) = dir {
+        let _ = TEMP_DIR.set(dir);
+    }
+}
+
+/// The dir of temporary files, the system temporary dir by default
+pub(crate) fn temp_dir() -> PathBuf {
+    match TEMP_DIR.get() {
+        Some(dir) => dir.clone(),
+        None => std::env::temp_dir(),
+    }
+}
+
+/// The last component of a path or URL, either separator taken
+fn base_name(path: &str) -> &str {
+    path.rsplit(['/', '\\']).next().unwrap_or(path)
+}
+
+/// A path in the temporary dir for a temporary file of this run
+fn temporary_path(label: &str) -> PathBuf {
+    temp_dir().join(format!("ampack-{}-{}.img", label, std::process::id()))
+}
+
+impl Input {
+    /// An input image file as-is, never spooled or looked into
+    pub(crate) fn file(path: &Path) -> Self {
+        Self { name: path.into(), source: Source::File(path.into()),
+            temporaries: Vec::new(), decompressed: None, 
+            image_name: base_name(&path.to_string_lossy()).into() }
+    }
+
+    /// Open `in_file` as given on the command line, with `member` picking the
+    /// member of a zip archive
+    pub(crate) fn open(in_file: &str, member: Option<&str>) -> Result<Self> {
+        Self::open_for(in_file, member, Reading::Image)
+    }
+
+    /// Open `in_file` as `open` does, but only to read the table and the heads
+    /// of items, so a remote image is not downloaded whole if the server
+    /// serves ranges of it
+    pub(crate) fn open_table(in_file: &str, member: Option<&str>) 
+        -> Result<Self> 
+    {
+        Self::open_for(in_file, member, Reading::Table)
+    }
+
+    /// Open `in_file` as a file to be read as-is, never decompressed nor 
+    /// looked into as an archive, e.g. a partition file to hash
+    pub(crate) fn open_raw(in_file: &str) -> Result<Self> {
+        Self::open_for(in_file, None, Reading::Raw)
+    }
+
+    #[cfg_attr(not(feature = "zip"), allow(unused_variables))]
+    #[cfg_attr(not(feature = "http"), allow(unused_variables))]
+    fn open_for(in_file: &str, member: Option<&str>, reading: Reading) 
+        -> Result<Self> 
+    {
+        let mut input = Self::file(Path::new(in_file));
+        if ampack::http::is_url(in_file) {
+            #[cfg(feature = "http")]
+            if input.open_remote(in_file, reading == Reading::Table)? {
+                return Ok(input)
+            }
+            #[cfg(not(feature = "http"))]
+            {
+                eprintln!("Input '{}' is a URL, but ampack was built without \
+                    the http feature, download it first", in_file);
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Unsupported, "URL input").into())
+            }
+        } else if in_file == stdio::STDIO {
+            let path = temporary_path("stdin");
+            input.temporaries.push(path.clone());
+            stdio::spool_stdin(&path)?;
+            input.source = Source::File(path)
+        }
+        if reading == Reading::Raw {
+            return Ok(input)
+        }
+        #[cfg(feature = "compress")]
+        input.decompress()?;
+        #[cfg(feature = "zip")]
+        input.open_zip(member)?;
+        Ok(input)
+    }
+
+    /// Probe the remote input at `url`, read it in place if only its table is
+    /// needed and the server serves ranges, or download it into the user 
+    /// cache otherwise, returning whether it's read in place
+    #[cfg(feature = "http")]
+    fn open_remote(&mut self, url: &str, table_only: bool) -> Result<bool> {
+        use std::io::Read;
+        use ampack::{archive::ZIP_MAGIC, compress::Compression, 
+            http::RemoteFile};
+
+        let remote = RemoteFile::probe(url)?;
+        if table_only && remote.ranges {
+            let mut head = Vec::with_capacity(Compression::SIZE_MAGIC);
+            remote.reader().take(Compression::SIZE_MAGIC as u64)
+                .read_to_end(&mut head)?;
+            if Compression::detect(&head).is_none() && 
+                ! head.starts_with(ZIP_MAGIC) 
+            {
+                println!("Reading the table of remote image '{}' with ranged \
+                    requests", url);
+                self.source = Source::Remote(remote);
+                return Ok(true)
+            }
+            println!("Remote image '{}' is compressed or in an archive, \
+                downloading it whole", url)
+        } else if table_only {
+            println!("Server does not serve ranges of '{}', downloading it \
+                whole", url)
+        }
+        self.source = Source::File(remote.try_download_cached()?);
+        Ok(false)
+    }
+
+    /// Decompress the input to a temporary file if it's compressed, as the
+    /// compressed streams could not be read at the offsets of items
+    #[cfg(feature = "compress")]
+    fn decompress(&mut self) -> Result<()> {
+        use ampack::compress::{self, Compression};
+
+        let path = match &self.source {
+            Source::File(path) => path.clone(),
+            _ => return Ok(()),
+        };
+        let format = match Compression::detect_file(&path)? {
+            Some(format) => format,
+            None => return Ok(()),
+        };
+        let to = temporary_path("decompressed");
+        println!("Decompressing {} compressed image '{}' to temporary file \
+            '{}'", format, self.name.display(), to.display());
+        self.temporaries.push(to.clone());
+        let size = compress::decompress_file(&path, format, &to)
+            .context(||ErrorContext::reading().path(&self.name))?;
+        println!("Decompressed {} stream of '{}' fully, {} of image", format,
+            self.name.display(), ampack::output::size_text_exact(size));
+        self.source = Source::File(to);
+        self.decompressed = Some(format.to_string());
+        if let Some((stem, _)) = self.image_name.rsplit_once('.')
+            .filter(|(_, suffix)|["gz", "xz", "zst"].contains(suffix)) 
+        {
+            self.image_name = stem.into()
+        }
+        Ok(())
+    }
+
+    /// Note that `result` of reading the image failed on the image itself and
+    /// not on the stream it was decompressed from
+    fn inside<T>(&self, result: Result<T>) -> Result<T> {
+        if let (Err(_), Some(format)) = (&result, &self.decompressed) {
+            eprintln!("The {} stream of '{}' was decompressed fine, the image \
+                inside it is corrupt", format, self.name.display())
+        }
+        result
+    }
+
+    /// Look into the input if it's a zip archive: read the image member in
+    /// place if it's stored, or extract it to a temporary file if not
+    #[cfg(feature = "zip")]
+    fn open_zip(&mut self, member: Option<&str>) -> Result<()> {
+        use ampack::archive;
+
+        let path = match &self.source {
+            Source::File(path) => path.clone(),
+            _ => return Ok(()),
+        };
+        if ! archive::is_zip(&path)? {
+            if let Some(member) = member {
+                eprintln!("Input '{}' is not a zip archive to take member '{}' \
+                    from", self.name.display(), member);
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "member of a non-archive").into())
+            }
+            return Ok(())
+        }
+        let member = archive::find_zip_member(&path, member)?;
+        let name = format!("{}:{}", self.name.display(), member.name);
+        match member.offset {
+            Some(offset) => {
+                println!("Reading image '{}' stored in zip archive '{}' in \
+                    place", member.name, self.name.display());
+                self.source = Source::Region { path, offset,
+                    size: member.size }
+            },
+            None => {
+                let to = temporary_path("zip");
+                warning!(Extracted, "image '{}' is compressed in zip \
+                    archive '{}', extracting it to temporary file '{}' first, \
+                    taking {} of space", member.name, self.name.display(),
+                    to.display(), ampack::output::size_text(member.size));
+                self.temporaries.push(to.clone());
+                archive::extract_zip_member(&path, &member, &to)
+                    .context(||ErrorContext::reading().path(&name))?;
+                self.source = Source::File(to)
+            },
+        }
+        self.name = name.into();
+        self.image_name = base_name(&member.name).into();
+        Ok(())
+    }
+
+    /// The input as shown in messages and recorded in the JSON document
+    pub(crate) fn path(&self) -> &Path {
+        &self.name
+    }
+
+    pub(crate) fn image_name(&self) -> &str {
+        &self.image_name
+    }
+
+    /// The image file as given, if it's read as-is, e.g. to find sidecar 
+    /// files next to it
+    pub(crate) fn given_file_path(&self) -> Option<&Path> {
+        self.file_path().filter(|path| *path == self.name)
+    }
+
+    /// The input as an image file of its own, if it is one, e.g. to tell
+    /// whether an output is the same file
+    pub(crate) fn file_path(&self) -> Option<&Path> {
+        match &self.source {
+            Source::File(path) => Some(path),
+            _ => None,
+        }
+    }
+
+    /// A reader of the image, for sources with no path-taking counterpart
+    fn reader(&self) -> Result<Box<dyn ReadSeek>> {
+        Ok(match &self.source {
+            Source::File(path) => Box::new(File::open(path)?),
+            Source::Region { path, offset, size } => 
+                Box::new(FileRegion::open(path, *offset, *size)
+                    .context(||ErrorContext::reading().path(&self.name))?),
+            #[cfg(feature = "http")]
+            Source::Remote(remote) => Box::new(remote.reader()),
+        })
+    }
+
+    pub(crate) fn read_image(&self, options: &ReadFileOptions) -> Result<Image> {
+        Ok(self.read_image_with_table(options, false)?.image)
+    }
+
+    pub(crate) fn read_image_ignore_errors(&self, options: &ReadFileOptions)
+        -> Result<(Image, Vec<(String, Error)>)>
+    {
+        let read = self.read_image_with_table(options, true)?;
+        Ok((read.image, read.failures))
+    }
+
+    /// Read the image, and print its header and item table as read
+    fn read_image_with_table(&self, options: &ReadFileOptions,
+                             ignore_errors: bool)
+        -> Result<ImageRead>
+    {
+        let read = self.inside(match &self.source {
+            Source::File(path) =>
+                Image::try_read_file_with_table(path, options, ignore_errors),
+            _ => Image::try_read_from_with_table(self.reader()?, options,
+                    ignore_errors)
+                .context(||ErrorContext::reading().path(&self.name)),
+        })?;
+        println!("{}", read.header);
+        image::print_item_infos_table_stdout(&read.infos, &[])?;
+        Ok(read)
+    }
+
+    pub(crate) fn verify(&self, image: &Image, with_crc32: bool,
+                         digest: Option<DigestAlgo>, fail_fast: bool)
+        -> Result<VerifyReport>
+    {
+        match &self.source {
+            Source::File(path) => 
+                image.verify(path, with_crc32, digest, fail_fast),
+            _ => image.verify_from(self.reader()?, with_crc32, digest, 
+                fail_fast),
+        }
+    }
+
+    pub(crate) fn read_table(&self) -> Result<(ImageHeader, Vec<RawItemInfo>)> {
+        self.inside(match &self.source {
+            Source::File(path) => Image::try_read_table_only(path),
+            _ => Image::try_read_table_from(
+                &mut BufReader::new(self.reader()?)),
+        })
+    }
+
+    pub(crate) fn identify(&self, infos: &[RawItemInfo])
+        -> Result<Vec<PayloadKind>>
+    {
+        match &self.source {
+            Source::File(path) => image::identify_item_infos(path, infos),
+            _ => image::identify_item_infos_from(&mut self.reader()?, infos),
+        }
+    }
+
+    pub(crate) fn print_unknown_version(&self) -> Result<()> {
+        image::print_unknown_version_from(&mut self.reader()?)
+    }
+
+    /// Hash the input for both CRC32 variants and return its size, see
+    /// `Crc32Hasher::try_hash_from`
+    pub(crate) fn hash_crc32(&self, raw: bool) 
+        -> Result<(Crc32Hasher, Crc32Hasher, u64)> 
+    {
+        Crc32Hasher::try_hash_from(self.reader()?, raw)
+            .context(||ErrorContext::reading().path(&self.name))
+    }
+
+    /// Print how much of a remote image was fetched, if it's read in place
+    pub(crate) fn print_fetched(&self) {
+        #[cfg(feature = "http")]
+        if let Source::Remote(remote) = &self.source {
+            println!("Fetched {} of {} of '{}' with ranged requests", 
+                ampack::output::size_text_exact(remote.fetched()),
+                ampack::output::size_text_exact(remote.size), 
+                self.name.display())
+        }
+    }
+}
+
+impl Drop for Input {
+    fn drop(&mut self) {
+        for path in self.temporaries.iter() {
+            match std::fs::remove_file(path) {
+                Ok(()) => (),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+                Err(e) => eprintln!("Failed to remove temporary file '{}': {}",
+                    path.display(), e),
+            }
+        }
+    }
+}
@@ -0,0 +1,182 @@
+/*
+ampack, to unpack and pack Aml burning images: logging module
+Copyright (C) 2024-present Guoxin "7Ji" Pu
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as
+published by the Free Software Foundation, either version 3 of the
+License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::{ffi::OsString, fs::{File, OpenOptions}, io::Write, path::{Path, PathBuf}, str::FromStr, sync::OnceLock, time::{SystemTime, UNIX_EPOCH}};
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+/// The environment variable to set the console verbosity with, as a level 
+/// name (`off`, `error`, `warn`, `info`, `debug` or `trace`)
+pub(crate) const ENV_LOG: &str = "AMPACK_LOG";
+
+/// Backend of the `log` facade. Status lines, warnings and errors are logged
+/// at info, warn and error as they're printed by the `output` module, which 
+/// owns how they show on the console, so only debug and trace records are
+/// printed on the console here, on stderr, with progress bars hidden around
+/// them. All records up to debug also go to the log file, if any.
+struct Logger {
+    console: OnceLock<LevelFilter>,
+    file: OnceLock<File>,
+}
+
+static LOGGER: Logger = Logger { 
+    console: OnceLock::new(), 
+    file: OnceLock::new(),
+};
+
+impl Logger {
+    fn console(&self) -> LevelFilter {
+        self.console.get().copied().unwrap_or(LevelFilter::Info)
+    }
+
+    /// The most verbose level any of the sinks takes
+    fn max_level(&self) -> LevelFilter {
+        if self.file.get().is_some() {
+            self.console().max(LevelFilter::Debug)
+        } else {
+            self.console()
+        }
+    }
+
+    /// Append `record` to the log file, every line prefixed with the time and
+    /// the PID, all in a single write, so lines of concurrent runs appending
+    /// to the same file are never torn
+    fn write_file(&self, file: &File, record: &Record) {
+        let prefix = format!("{} [{}] {:<5} ", timestamp(), std::process::id(),
+            record.level());
+        let mut lines = String::new();
+        for line in record.args().to_string().lines() {
+            lines.push_str(&prefix);
+            lines.push_str(line);
+            lines.push('\n')
+        }
+        let mut file = file;
+        let _ = file.write_all(lines.as_bytes());
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if let Some(file) = self.file.get() {
+            if record.level() <= Level::Debug {
+                self.write_file(file, record)
+            }
+        }
+        if record.level() > Level::Info && record.level() <= self.console() {
+            ampack::progress::suspend(|| std::eprintln!("[{}] {}", 
+                record.level(), record.args()))
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// The current UTC time as e.g. `2024-05-01T12:34:56.789Z`
+fn timestamp() -> String {
+    let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    // Days to civil date, from Howard Hinnant's algorithm
+    let days = (secs / 86400) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 
+        - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - 
+        (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_shifted = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_shifted + 2) / 5 + 1;
+    let month = if month_shifted < 10 { month_shifted + 3 } else { month_shifted - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z", year, month, day,
+        secs / 3600 % 24, secs / 60 % 60, secs % 60, since_epoch.subsec_millis())
+}
+
+fn register() {
+    let _ = log::set_logger(&LOGGER);
+    log::set_max_level(LOGGER.max_level())
+}
+
+/// Find the value of `--log-file` in `args` before they're parsed, so the 
+/// log file could be opened before anything else, even if the arguments turn
+/// out to be invalid
+pub(crate) fn log_file_in_args<I: IntoIterator<Item = OsString>>(args: I) 
+    -> Option<PathBuf> 
+{
+    let mut args = args.into_iter();
+    let mut path = None;
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            break
+        }
+        if arg == "--log-file" {
+            path = args.next().map(PathBuf::from)
+        } else if let Some(value) = arg.to_str()
+            .and_then(|arg|arg.strip_prefix("--log-file=")) 
+        {
+            path = Some(value.into())
+        }
+    }
+    path
+}
+
+/// Open the log file at `path` to append the transcript of this run to, at 
+/// debug verbosity regardless of the console
+pub(crate) fn open_log_file(path: &Path) -> std::io::Result<()> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let _ = LOGGER.file.set(file);
+    register();
+    Ok(())
+}
+
+/// Set up the logger, with the console verbosity raised to debug by one 
+/// `verbose`, or trace by two or more, or set to what's in `AMPACK_LOG` if
+/// not `verbose`. Only the first call takes effect.
+pub(crate) fn init(verbose: u8) {
+    let level = match verbose {
+        0 => env_level().unwrap_or(LevelFilter::Info),
+        1 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+    let _ = LOGGER.console.set(level);
+    register()
+}
+
+/// The console verbosity set in `AMPACK_LOG`, if valid
+fn env_level() -> Option<LevelFilter> {
+    match std::env::var(ENV_LOG) {
+        Ok(value) => match LevelFilter::from_str(&value) {
+            Ok(level) => Some(level),
+            Err(_) => {
+                warning!(LogLevel, "ignoring invalid {}={}, expecting one of \
+                    off, error, warn, info, debug and trace", ENV_LOG, value);
+                None
+            },
+        },
+        Err(_) => None,
+    }
+}
+
+/// Whether records are written to a log file
+pub(crate) fn logging_to_file() -> bool {
+    LOGGER.file.get().is_some()
+}
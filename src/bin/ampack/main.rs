@@ -0,0 +1,1824 @@
+/*
+ampack, to unpack and pack Aml burning images: command line module
+Copyright (C) 2024-present Guoxin "7Ji" Pu
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as
+published by the Free Software Foundation, either version 3 of the
+License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::{path::{Path, PathBuf}, process::ExitCode, time::{Duration, Instant}};
+
+use clap::{parser::ValueSource, CommandFactory, FromArgMatches, Parser};
+
+// Printing hides the progress bars for the moment and goes through the output
+// module of the library, the same as the library does itself
+macro_rules! println {
+    ($($arg:tt)*) => {
+        ampack::progress::suspend(|| 
+            ampack::output::print_info(format_args!($($arg)*)))
+    };
+}
+
+macro_rules! eprintln {
+    ($($arg:tt)*) => {
+        ampack::progress::suspend(|| 
+            ampack::output::print_error(format_args!($($arg)*)))
+    };
+}
+
+macro_rules! warning {
+    ($class:ident, $($arg:tt)*) => {
+        ampack::progress::suspend(|| ampack::output::print_warning(
+            ampack::output::WarningClass::$class, format_args!($($arg)*)))
+    };
+}
+
+mod hook;
+mod input;
+mod logger;
+mod prompt;
+mod report;
+mod stdio;
+
+use ampack::{atomic, cache, crc32, image, output, payload, progress, retry, Error, Result};
+use image::{ImageError, ImageSummary, ImageVersion, ReadDirOptions, ReadFileOptions, VerifyReport, WriteDirOptions, WriteDirReport};
+
+use ampack::{atomic::WriteFileOptions, checksum::{self, ChecksumAlgo, ChecksumError, DigestAlgo, FileDigest}, cache::{ChecksumCache, CHECKSUM_CACHE_NAME}, compress::Compression, image::Image, manifest::{Manifest, MANIFEST_NAME}, output::{ColorMode, OutputFormat, RunSummary, SortKey, WarningClass}, payload::SpillDir, progress::ProgressMode, stamp::Stamp};
+use crate::{hook::Hook, input::Input, report::VerifyOutcome};
+
+
+#[derive(clap::Args, Debug, Clone)]
+struct OverwriteArgs {
+    /// Overwrite the output if it already exists
+    #[arg(long)]
+    force: bool,
+    /// Rename the output to [output].bak if it already exists
+    #[arg(long, conflicts_with = "force")]
+    backup: bool,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+struct ImageOutputArgs {
+    /// Version of the output image
+    #[arg(long, env = "AMPACK_OUT_VER", default_value_t)]
+    out_ver: ImageVersion,
+    /// Alignment of the output image, multiply of 4, 8 for Android >= 11
+    #[arg(long, env = "AMPACK_OUT_ALIGN", default_value_t = 4, 
+        value_parser = parse_align)]
+    out_align: u8,
+    /// Write the output file directly instead of via a temporary file 
+    /// renamed to it on success
+    #[arg(long)]
+    no_atomic: bool,
+    /// Flush the output file and its dir to the storage before declaring
+    /// success, e.g. when writing onto a USB stick to be unplugged right after
+    #[arg(long)]
+    fsync: bool,
+    /// Keep item payloads held in memory (e.g. normalized text) within this
+    /// size (suffixes K/M/G/T accepted), spilling the rest to --spill-dir, 0 
+    /// for unlimited
+    #[arg(long, default_value = "0", value_parser = parse_size)]
+    max_memory: u64,
+    /// Dir to spill payloads beyond --max-memory into, --temp-dir by default
+    #[arg(long)]
+    spill_dir: Option<PathBuf>,
+    /// Pad the output image with zeros after the last item to this total 
+    /// size (suffixes K/M/G/T accepted), counted in its CRC32 like official
+    /// images, 0 for no padding
+    #[arg(long, default_value = "0", value_parser = parse_size)]
+    pad_to: u64,
+    /// Also write the digest of the output image in this algorithm into 
+    /// `[out file].[algo]` as sha256sum(1) and friends do, hashed as it's 
+    /// written; could be given multiple times for multiple algorithms
+    #[arg(long, value_enum)]
+    emit_checksum: Vec<DigestAlgo>,
+    #[command(flatten)]
+    overwrite: OverwriteArgs,
+}
+
+impl ImageOutputArgs {
+    fn write_file_options(&self) -> WriteFileOptions {
+        WriteFileOptions { atomic: ! self.no_atomic, fsync: self.fsync }
+    }
+
+    /// The algorithms of digests to emit, refusing them if the image is 
+    /// written to stdout, as there's no file to put them next to
+    fn digests(&self, to_stdout: bool) -> Result<Vec<DigestAlgo>> {
+        if to_stdout && ! self.emit_checksum.is_empty() {
+            eprintln!("Refuse to emit checksum files of the image written to \
+                stdout, as there's no file to put them next to, pipe it \
+                through e.g. sha256sum(1) instead");
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput,
+                "checksum files of image written to stdout").into())
+        }
+        let mut digests = Vec::new();
+        for algo in self.emit_checksum.iter() {
+            if ! digests.contains(algo) {
+                digests.push(*algo)
+            }
+        }
+        Ok(digests)
+    }
+
+    fn spill_dir(&self) -> SpillDir {
+        match &self.spill_dir {
+            Some(dir) => SpillDir::new(dir),
+            None => SpillDir::new(&input::temp_dir()),
+        }
+    }
+}
+
+#[derive(clap::Args, Debug, Clone)]
+struct SortArgs {
+    /// Sort the item table by this, only for display, the ID column still
+    /// shows the index in the image
+    #[arg(long, value_enum)]
+    sort: Option<SortKey>,
+    /// Sort in reverse, rows with the same key still keep their order
+    #[arg(long, requires = "sort")]
+    reverse: bool,
+}
+
+#[derive(clap::Args, Debug, Clone, Default)]
+struct VerifyReportArgs {
+    /// Also write a JSON report of the verification to this file, even if it
+    /// fails
+    #[arg(long, conflicts_with = "sample")]
+    report: Option<PathBuf>,
+    /// Also write a JUnit XML report of the verification to this file, even 
+    /// if it fails, with a test case for each item
+    #[arg(long, conflicts_with = "sample")]
+    report_junit: Option<PathBuf>,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+struct UnpackArgs {
+    #[arg(long)]
+    /// Do not verify items
+    no_verify: bool,
+    #[arg(long)]
+    /// Keep extracting past items that failed to read or verify, items
+    /// failed to verify are written with a .corrupt suffix
+    ignore_errors: bool,
+    #[arg(long)]
+    /// Also write a manifest (ampack.yaml) into the output dir, recording
+    /// metadata not kept in the item files, for pack to pick up later
+    emit_manifest: bool,
+    #[command(flatten)]
+    overwrite: OverwriteArgs,
+    /// Write into the output dir if it exists instead of wiping it, only
+    /// overwriting files of items when their content differs
+    #[arg(long, conflicts_with_all = ["force", "backup"])]
+    keep: bool,
+    /// Number of items written concurrently, 0 for one per worker thread (see
+    /// --threads), or 1 if the output dir is detected to be on a rotational 
+    /// disk
+    #[arg(long, default_value_t = 0)]
+    jobs: usize,
+    /// Write all blocks of item files, instead of leaving blocks of all zeros
+    /// as holes to get sparse files
+    #[arg(long)]
+    no_sparse_files: bool,
+    /// What to unpack into
+    #[arg(long, value_enum, default_value_t)]
+    format: UnpackFormat,
+    /// Only unpack items whose names match one of these globs, with * and ?
+    #[arg(long, value_delimiter = ',')]
+    only: Vec<String>,
+    /// Do not unpack items whose names match one of these globs
+    #[arg(long, value_delimiter = ',')]
+    exclude: Vec<String>,
+    /// Run this command on each item file after all are written, e.g. 
+    /// simg2img or a scanner, with {file} replaced by the item file and 
+    /// {name} by the item name; failures are reported at the end without
+    /// stopping the others
+    #[arg(long, value_name = "COMMAND")]
+    hook: Option<String>,
+}
+
+impl UnpackArgs {
+    /// Drop the items not to be unpacked by --only and --exclude from 
+    /// `image`, and from `failures` of it
+    fn filter_items(&self, image: &mut Image, 
+                    failures: &mut Vec<(String, Error)>) 
+    {
+        if self.only.is_empty() && self.exclude.is_empty() {
+            return
+        }
+        let wanted = |name: &str|
+            (self.only.is_empty() || hook::glob_match_any(&self.only, name)) &&
+                ! hook::glob_match_any(&self.exclude, name);
+        let count = image.items().len();
+        image.retain_items(wanted);
+        failures.retain(|(name, _)|wanted(name));
+        println!("Unpacking {} of {} items, filtered by --only and --exclude", 
+            image.items().len(), count);
+        if image.items().is_empty() {
+            warning!(Filter, "no item matched by --only and --exclude")
+        }
+    }
+}
+
+#[derive(clap::Args, Debug, Clone)]
+struct PackArgs {
+    /// Sanitize item names: trim whitespaces and replace inner ones with
+    /// _, and refuse names not representable in the output image
+    #[arg(long)]
+    sanitize_names: bool,
+    /// Also lowercase item stems (except USB ones) when sanitizing names
+    #[arg(long, requires = "sanitize_names")]
+    lowercase_names: bool,
+    /// Convert CRLF line endings to LF and strip UTF-8 BOM in text items
+    /// before packing, and record that in a manifest next to the output
+    #[arg(long)]
+    normalize_text: bool,
+    /// Extensions of items considered as text by --normalize-text
+    #[arg(long, value_delimiter = ',', default_value = "ini,conf,txt")]
+    text_extensions: Vec<String>,
+    /// Run this command on each item matched by --hook-items before packing
+    /// it, e.g. a signer, with {in} replaced by the item file, {out} by the 
+    /// file to write the payload actually packed into, and {name} by the 
+    /// item name; a failing command aborts the pack
+    #[arg(long, value_name = "COMMAND")]
+    hook: Option<String>,
+    /// Globs of names of items to run --hook on, with * and ?
+    #[arg(long, value_delimiter = ',', default_value = "*", requires = "hook")]
+    hook_items: Vec<String>,
+    /// Run the hooks recorded for items in the manifest of the input dir, 
+    /// which are only listed and ignored without this
+    #[arg(long)]
+    manifest_hooks: bool,
+    /// Hash all items, even those unchanged since their sha1sums were 
+    /// recorded by unpack in the input dir, or by an earlier pack in the
+    /// user cache, and do not update the user cache
+    #[arg(long)]
+    no_cache: bool,
+    /// Check the written image: 'self' (the default without a value) checks
+    /// its table, header and CRC32 against what was written without reading
+    /// payloads back, 'read-back' reads and verifies the whole image again
+    #[arg(long, value_enum, num_args = 0..=1, require_equals = true, 
+        default_missing_value = "self")]
+    verify: Option<PackVerify>,
+    /// Stamp the image with "made by ampack vX.Y at [time]" in the reserved
+    /// bytes of its header, off by default so packing stays reproducible
+    #[arg(long)]
+    stamp: bool,
+    /// Stamp with this time, in seconds since the unix epoch, instead of now,
+    /// for reproducible builds, implies --stamp
+    #[arg(long, value_name = "UNIX_TIME")]
+    stamp_date: Option<u64>,
+    /// Only print the planned layout of the image: item order, offsets, 
+    /// backups and the image size, and exit without hashing or writing
+    #[arg(long, conflicts_with = "verify")]
+    plan: bool,
+    /// Do everything a real pack does, but discard the image instead of 
+    /// writing it, and report the size and CRC32 it would have
+    #[arg(long, conflicts_with_all = ["verify", "plan"])]
+    dry_run: bool,
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum UnpackFormat {
+    /// A dir with a file for each item
+    #[default]
+    Dir,
+    /// A tar archive with an entry for each item, after the manifest, - for
+    /// stdout
+    #[cfg(feature = "tar")]
+    Tar,
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy)]
+enum PackVerify {
+    /// Check the table and CRC32 of the written image, from what's in memory
+    #[value(name = "self")]
+    SelfCheck,
+    /// Read the written image back and verify it as a whole
+    ReadBack,
+}
+
+#[derive(clap::Subcommand, Debug, Clone)]
+enum CacheAction {
+    /// Remove all sha1sums cached by pack and images downloaded from URLs
+    Clear,
+}
+
+#[derive(clap::Subcommand, Debug, Clone)]
+enum Action {
+    /// Read and verify and image without unpacking it
+    Verify {
+        /// Path of image to verify, - for stdin, or an http(s) URL
+        in_file: String,
+        /// Spot check items one after another instead of in parallel, so 
+        /// only a single chunk is in memory at any time, for devices with 
+        /// little RAM; a full verification is always a single sequential pass
+        /// with only a few chunks in memory
+        #[arg(long)]
+        low_memory: bool,
+        /// Only spot check the image: verify items up to twice this size 
+        /// (suffixes K/M/G/T accepted), and only read the first and last this
+        /// many bytes of larger ones, which is NOT a verification of them
+        #[arg(long, value_parser = parse_size)]
+        sample: Option<u64>,
+        /// Also calculate the standard CRC32 (as crc32(1) or 7-Zip gives, not
+        /// the Amlogic variant in the image header) of each item, in the same
+        /// pass, and show them in the item table
+        #[arg(long, conflicts_with = "sample")]
+        with_crc32: bool,
+        /// Also check the sha1, sha256 or md5 digest of the whole file, 
+        /// calculated in the same pass, against this checksum file, holding
+        /// either a bare digest or `[digest]  [file]` lines as sha256sum(1)
+        /// writes, matched on the file name. By default the `.sha256`, 
+        /// `.sha1` or `.md5` file next to the image is taken, if any
+        #[arg(long, conflicts_with = "sample")]
+        checksum_file: Option<PathBuf>,
+        /// Also check the image holds exactly the items in this manifest, as
+        /// `unpack --emit-manifest` writes (YAML, or JSON), and nothing else,
+        /// with the same version, align, and sizes, sha1sums and payload 
+        /// kinds of items, as far as they're recorded in the manifest
+        #[arg(long, conflicts_with = "sample")]
+        expect: Option<PathBuf>,
+        /// Fix stale checksums in place: rewrite the VERIFY items of 
+        /// partitions not matching their recorded sha1sums with the 
+        /// calculated ones and patch the CRC32 in the header, refusing images
+        /// with structural problems; asks to confirm unless --yes
+        #[arg(long, conflicts_with_all = ["sample", "checksum_file", "expect",
+            "with_crc32", "summary"])]
+        repair: bool,
+        #[command(flatten)]
+        reports: VerifyReportArgs,
+        /// Stop at the first structural problem or mismatched item, instead
+        /// of checking everything and reporting all of them
+        #[arg(long, conflicts_with = "sample")]
+        fail_fast: bool,
+        /// Print a one-paragraph overview of the image instead of the item 
+        /// table
+        #[arg(long, conflicts_with_all = ["sample", "sort", "format"])]
+        summary: bool,
+        #[command(flatten)]
+        sort: SortArgs,
+        /// How the item table is printed
+        #[arg(long, value_enum, default_value_t)]
+        format: OutputFormat,
+    },
+    /// Show the header, item infos and non-zero reserved bytes of an image,
+    /// without reading any item unless asked to identify them
+    Info {
+        /// Path of image to show, - for stdin, or an http(s) URL
+        in_file: String,
+        /// Identify the payload of each item (Android sparse, ext4, boot 
+        /// image, dtb, ...) by reading its first few KiB, and show that in an
+        /// extra column
+        #[arg(long)]
+        identify: bool,
+        /// Print a one-paragraph overview of the image instead of the header,
+        /// the item info table and reserved bytes
+        #[arg(long, conflicts_with_all = ["identify", "sort", "format"])]
+        summary: bool,
+        #[command(flatten)]
+        sort: SortArgs,
+        /// How the item info table is printed
+        #[arg(long, value_enum, default_value_t)]
+        format: OutputFormat,
+    },
+    /// Unpack an image to get partition files
+    Unpack {
+        /// Path of image to unpack, - for stdin, or an http(s) URL
+        in_file: String,
+        /// Path of dir to output, if it exists it would only be deleted (and
+        /// then created) with --force
+        #[arg(required_unless_present = "output")]
+        out_dir: Option<String>,
+        /// Path to output, instead of [OUT_DIR], e.g. of the tar archive with
+        /// --format tar
+        #[arg(short, long, value_name = "PATH", conflicts_with = "out_dir")]
+        output: Option<String>,
+        #[command(flatten)]
+        unpack: UnpackArgs,
+    },
+    /// Convert an image to another image
+    Convert {
+        /// Path of the input file, - for stdin, or an http(s) URL
+        in_file: String,
+        /// Path of the output file, - for stdout
+        out_file: String,
+        #[arg(long)]
+        /// Do not verify input image
+        no_verify: bool,
+        /// Compress the output image in this format
+        #[cfg(feature = "compress")]
+        #[arg(long, value_enum, value_name = "FORMAT")]
+        compress: Option<Compression>,
+        #[command(flatten)]
+        output: ImageOutputArgs,
+    },
+    /// (Re)pack partition files into an image
+    Pack {
+        /// Path of dir that contains files
+        in_dir: String,
+        /// Path of image to pack into, - for stdout
+        out_file: String,
+        #[command(flatten)]
+        output: ImageOutputArgs,
+        /// Allow the output image to be inside the input dir and files in the
+        /// input dir to be Amlogic images themselves
+        #[arg(long)]
+        allow_nested_image: bool,
+        /// When files' names only differ in case, only pack the 
+        /// lexicographically first one instead of refusing to pack
+        #[arg(long)]
+        case_insensitive_merge: bool,
+        /// Fail instead of warning when entries in the input dir are skipped,
+        /// i.e. not files named as [stem].[extension]
+        #[arg(long)]
+        error_on_skip: bool,
+        #[command(flatten)]
+        pack: PackArgs,
+    },
+    /// Print the checksums of items in an image, in the format of sha1sum(1)
+    /// and alike, to check unpacked or other files against
+    Checksums {
+        /// Path of image to hash items of, - for stdin, or an http(s) URL
+        in_file: String,
+        /// Algorithm of the checksums
+        #[arg(long, value_enum, default_value_t)]
+        algo: ChecksumAlgo,
+        /// How the checksums is printed
+        #[arg(long, value_enum, default_value_t)]
+        format: OutputFormat,
+    },
+    /// Calculate the CRC32 checksums of images or other files, both the 
+    /// Amlogic variant stored in the image header and the standard one of 
+    /// the whole file, one line for each file
+    Crc32 {
+        /// Paths of files to calculate the CRC32 of, - for stdin (read as it
+        /// comes, never spooled), or http(s) URLs
+        #[arg(required = true)]
+        in_files: Vec<String>,
+        /// Only print the standard CRC32 of the whole file, as crc32(1) or
+        /// 7-Zip give, which is NOT the one stored in the image header
+        #[arg(long)]
+        standard: bool,
+        /// Hash the files as-is for the Amlogic variant too, without skipping
+        /// the 4-byte CRC field of an image, e.g. for partition files, and
+        /// never decompress them nor look into archives
+        #[arg(long)]
+        raw: bool,
+    },
+    /// Manage the caches private to the user
+    Cache {
+        #[command(subcommand)]
+        action: CacheAction,
+    },
+}
+
+#[derive(Parser, Debug)]
+#[command(version, after_help = "Options showing [env: AMPACK_...] take their \
+    defaults from those environment variables, options given on the command \
+    line override them.")]
+struct Arg {
+    #[command(subcommand)]
+    action: Action,
+    /// Times to retry a transient I/O error (EIO, timeout) before giving up,
+    /// interrupted calls (EINTR) are always retried at once
+    #[arg(long, global = true, default_value_t = 3)]
+    io_retries: u32,
+    /// Milliseconds to wait before the first retry, multiplied for later ones
+    #[arg(long, global = true, default_value_t = 500)]
+    io_retry_delay: u64,
+    /// Dir of caches private to the user, $XDG_CACHE_HOME/ampack or 
+    /// ~/.cache/ampack by default
+    #[arg(long, global = true, env = "AMPACK_CACHE_DIR", value_name = "DIR")]
+    cache_dir: Option<PathBuf>,
+    /// Dir of temporary files, i.e. input images spooled from stdin, 
+    /// decompressed or extracted from archives, the system temporary dir by
+    /// default
+    #[arg(long, global = true, value_name = "DIR")]
+    temp_dir: Option<PathBuf>,
+    /// Refuse items larger than this size (suffixes K/M/G/T accepted), 
+    /// checked before any of their bytes are read, 0 for unlimited
+    #[arg(long, global = true, default_value = "16G", value_parser = parse_size)]
+    max_item_size: u64,
+    /// Granularity of reading, writing and hashing payloads, and the step of
+    /// progress bars (suffixes K/M/G/T accepted)
+    #[arg(long, global = true, default_value = "1M", value_parser = parse_chunk_size)]
+    chunk_size: usize,
+    /// Member of zip archives given as input images to read the image from,
+    /// the only one named as *.img by default
+    #[cfg(feature = "zip")]
+    #[arg(long, global = true, value_name = "NAME")]
+    member: Option<String>,
+    /// Map input images into memory instead of reading them, the images must
+    /// not be modified by others while ampack runs
+    #[cfg(feature = "mmap")]
+    #[arg(long, global = true)]
+    mmap: bool,
+    /// Number of worker threads for reading and hashing items in parallel, 0 
+    /// for one per CPU, 1 to run everything on a single thread in order
+    #[arg(long, global = true, env = "AMPACK_THREADS", default_value_t = 0)]
+    threads: usize,
+    /// Read items one after another even if the storage is not detected as 
+    /// rotational, e.g. for disks behind USB bridges
+    #[arg(long, global = true)]
+    sequential_reads: bool,
+    /// Limit of buffers held by items read in parallel (suffixes K/M/G/T
+    /// accepted), large items are read with fewer others once it's reached,
+    /// 0 for a quarter of the physical memory
+    #[arg(long, global = true, default_value = "0", value_parser = parse_size)]
+    buffer_memory: u64,
+    /// How progress of items processed in parallel is shown
+    #[arg(long, global = true, value_enum, default_value_t)]
+    progress: ProgressMode,
+    /// Show no progress at all, same as --progress none, conflicts with 
+    /// --progress and --plain, which override AMPACK_NO_PROGRESS though
+    #[arg(long, global = true, env = "AMPACK_NO_PROGRESS", 
+        value_parser = clap::builder::FalseyValueParser::new())]
+    no_progress: bool,
+    /// Print progress as plain status lines every few seconds instead of 
+    /// bars, the default if stderr is not a terminal
+    #[arg(long, global = true)]
+    plain: bool,
+    /// Print a single JSON document of the result, warnings and errors on 
+    /// stdout at the end, moving everything else to stderr
+    #[arg(long, global = true)]
+    json: bool,
+    /// Only print warnings, errors and what's asked for (e.g. checksums), 
+    /// without progress; twice to drop warnings too
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    quiet: u8,
+    /// Show sizes in tables and summaries in binary units (KiB, MiB, ...) 
+    /// instead of exact bytes in hex, JSON and CSV always take exact bytes
+    #[arg(long, global = true)]
+    human: bool,
+    /// When to color warnings, errors, tables and progress bars, NO_COLOR 
+    /// set to anything non-empty means never for auto
+    #[arg(long, global = true, env = "AMPACK_COLOR", value_enum, 
+        default_value_t)]
+    color: ColorMode,
+    /// Also print debug details (offsets read, hashes computed, padding 
+    /// inserted), twice for chunk-level I/O too, overrides AMPACK_LOG (off, 
+    /// error, warn, info, debug or trace)
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
+    /// Append a timestamped plain-text transcript of the run to this file, at
+    /// debug verbosity regardless of the console
+    #[arg(long, global = true, value_name = "FILE")]
+    log_file: Option<PathBuf>,
+    /// Answer yes to all confirmations, e.g. of overwriting an existing 
+    /// output, which are refused without asking if stdin is not a terminal
+    #[arg(short, long, global = true)]
+    yes: bool,
+    /// Fail if any warning was printed, listing them at the end
+    #[arg(long, global = true)]
+    strict: bool,
+    /// With --strict, do not fail on warnings of this class, could be given
+    /// multiple times
+    #[arg(long, global = true, value_enum, requires = "strict")]
+    allow: Vec<WarningClass>,
+}
+
+impl Action {
+    /// How the item table of the action is printed, if it has one
+    fn format(&self) -> OutputFormat {
+        match self {
+            Action::Verify { format, .. } |
+            Action::Info { format, .. } |
+            Action::Checksums { format, .. } => *format,
+            _ => OutputFormat::Table,
+        }
+    }
+
+    /// Whether the action writes an image to stdout
+    fn writes_stdout(&self) -> bool {
+        match self {
+            Action::Convert { out_file, .. } |
+            Action::Pack { out_file, .. } => out_file == stdio::STDIO,
+            #[cfg(feature = "tar")]
+            Action::Unpack { out_dir, output, unpack, .. } => 
+                unpack.format == UnpackFormat::Tar && 
+                    output.as_ref().or(out_dir.as_ref()).is_some_and(|out|
+                        out == stdio::STDIO),
+            _ => false,
+        }
+    }
+
+    /// What the item table of the action is sorted by and whether in reverse
+    fn sort(&self) -> Option<(SortKey, bool)> {
+        match self {
+            Action::Verify { sort, .. } |
+            Action::Info { sort, .. } => 
+                sort.sort.map(|key|(key, sort.reverse)),
+            _ => None,
+        }
+    }
+}
+
+/// Parse a non-zero chunk size that could be held in memory
+fn parse_chunk_size(arg: &str) -> std::result::Result<usize, String> {
+    match usize::try_from(parse_size(arg)?) {
+        Ok(0) => Err("chunk size must not be 0".into()),
+        Ok(size) => Ok(size),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Parse an alignment, a non-zero multiple of 4
+fn parse_align(arg: &str) -> std::result::Result<u8, String> {
+    match arg.trim().parse::<u8>() {
+        Ok(align) if align != 0 && align % 4 == 0 => Ok(align),
+        Ok(align) => Err(format!("alignment {} is not a non-zero multiple of 4",
+            align)),
+        Err(e) => Err(format!("invalid alignment '{}': {}", arg, e)),
+    }
+}
+
+/// Parse a size with optional binary suffix, e.g. 4096, 4K, 16M, 7G
+fn parse_size(arg: &str) -> std::result::Result<u64, String> {
+    let arg = arg.trim();
+    let (number, shift) = match arg.chars().last() {
+        Some('k' | 'K') => (&arg[..arg.len() - 1], 10),
+        Some('m' | 'M') => (&arg[..arg.len() - 1], 20),
+        Some('g' | 'G') => (&arg[..arg.len() - 1], 30),
+        Some('t' | 'T') => (&arg[..arg.len() - 1], 40),
+        _ => (arg, 0),
+    };
+    let number: u64 = number.parse().map_err(|e| 
+        format!("invalid size '{}': {}", arg, e))?;
+    number.checked_mul(1 << shift).ok_or_else(|| 
+        format!("size '{}' too large", arg))
+}
+
+/// Check what's already at the output path, refuse to continue unless told 
+/// to overwrite it or to back it up
+fn guard_output(path: &Path, overwrite: &OverwriteArgs) -> Result<()> {
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => {
+            eprintln!("Failed to check existing output '{}': {}", 
+                path.display(), e);
+            return Err(e.into())
+        },
+    };
+    let description = if metadata.is_dir() {
+        format!("a dir with {} entries", std::fs::read_dir(path)?.count())
+    } else {
+        format!("a file of {} bytes", metadata.len())
+    };
+    if overwrite.force {
+        println!("Overwriting existing output '{}', {}", 
+            path.display(), description);
+        return Ok(())
+    }
+    if overwrite.backup {
+        let mut name = path.file_name().unwrap_or_default().to_os_string();
+        name.push(".bak");
+        let path_backup = path.with_file_name(name);
+        if std::fs::symlink_metadata(&path_backup).is_ok() {
+            eprintln!("Backup path '{}' for existing output '{}' also exists, \
+                refuse to continue", path_backup.display(), path.display());
+            return Err(std::io::Error::new(std::io::ErrorKind::AlreadyExists,
+                "backup of output already exists").into())
+        }
+        println!("Backing up existing output '{}', {}, to '{}'", 
+            path.display(), description, path_backup.display());
+        std::fs::rename(path, &path_backup)?;
+        return Ok(())
+    }
+    let action = format!("Overwrite existing output '{}', {}{}", 
+        path.display(), description, 
+        if metadata.is_dir() { ", deleting everything in it" } else { "" });
+    if let Err(e) = prompt::confirm(&action) {
+        eprintln!("Use --force or --yes to overwrite existing output '{}', or \
+            --backup to rename it to .bak first", path.display());
+        return Err(e)
+    }
+    Ok(())
+}
+
+fn verify(input: &Input, with_crc32: bool, checksum_file: Option<&Path>, 
+          expect: Option<&Path>, reports: &VerifyReportArgs, summary: bool, 
+          read_options: &ReadFileOptions) 
+    -> Result<()> 
+{
+    let expect = match expect {
+        Some(path) => Some((path, Manifest::try_read_file(path)
+            .inspect_err(|e|eprintln!("Failed to read expected manifest \
+                '{}': {}", path.display(), e))?)),
+        None => None,
+    };
+    let checksum_file = match checksum_file {
+        Some(path) => Some(path.to_path_buf()),
+        None => input.given_file_path().and_then(checksum::find_sidecar)
+            .inspect(|path|println!("Taking digest of image '{}' from \
+                checksum file '{}' next to it", input.path().display(), 
+                path.display())),
+    };
+    let expected = match &checksum_file {
+        Some(path) => Some(checksum::try_read_digest(path, 
+            input.image_name())?),
+        None => None,
+    };
+    let (image, report) = verify_image(input, with_crc32, 
+        expected.as_ref().map(|digest|digest.algo), reports, summary, 
+        read_options)?;
+    let manifest_error = match &expect {
+        Some((path, manifest)) => check_manifest(&image, path, manifest)?,
+        None => None,
+    };
+    let digest_error = match (&checksum_file, expected, &report.digest) {
+        (Some(path), Some(expected), Some(calculated)) => 
+            check_digest(input, path, expected, calculated),
+        _ => None,
+    };
+    let (passed, failed, skipped) = report.tally();
+    RunSummary::default()
+        .count("items checked", report.items.len())
+        .count("passed", passed)
+        .count("failed", failed)
+        .count("skipped", skipped)
+        .bytes("hashed", report.items.iter().map(|item|item.size).sum())
+        .emit();
+    match report.error().or(digest_error).or(manifest_error) {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// Compare the digest of the whole image file with the one `expected` from
+/// `checksum_file`, print and record the result, and return the error to 
+/// fail with if they differ
+fn check_digest(input: &Input, checksum_file: &Path, expected: FileDigest, 
+                calculated: &FileDigest) 
+    -> Option<Error> 
+{
+    let matches = expected == *calculated;
+    output::record("checksum_file", &serde_json::json!({
+        "path": checksum_file,
+        "algo": expected.algo,
+        "expected": expected.digest,
+        "calculated": calculated.digest,
+        "matches": matches,
+    }));
+    if matches {
+        println!("Digest {} of image '{}' matches checksum file '{}'", 
+            calculated, input.path().display(), checksum_file.display());
+        None
+    } else {
+        eprintln!("Digest {} of image '{}' does not match {} in checksum \
+            file '{}'", calculated, input.path().display(), expected.digest, 
+            checksum_file.display());
+        Some(ChecksumError::Mismatch { expected, 
+            calculated: calculated.digest.clone() }.into())
+    }
+}
+
+/// Compare `image` against the `expected` manifest read from `path`, print
+/// and record additions, omissions and mismatches, and return the error to
+/// fail with if there's any
+fn check_manifest(image: &Image, path: &Path, expected: &Manifest) 
+    -> Result<Option<Error>> 
+{
+    let diff = image.diff_manifest(expected)?;
+    output::record("expect", &serde_json::json!({
+        "path": path,
+        "matches": diff.is_empty(),
+        "diff": &diff,
+    }));
+    if diff.is_empty() {
+        println!("Image matches expected manifest '{}'", path.display());
+        return Ok(None)
+    }
+    for name in diff.added.iter() {
+        eprintln!("Added: item '{}' not in expected manifest", name)
+    }
+    for name in diff.missing.iter() {
+        eprintln!("Missing: item '{}' in expected manifest", name)
+    }
+    for mismatch in diff.mismatched.iter() {
+        eprintln!("Mismatched: {}", mismatch)
+    }
+    eprintln!("Image does not match expected manifest '{}': {} items added, \
+        {} missing, {} fields mismatched", path.display(), diff.added.len(), 
+        diff.missing.len(), diff.mismatched.len());
+    Ok(Some(ImageError::ManifestMismatch { added: diff.added.len(), 
+        missing: diff.missing.len(), mismatched: diff.mismatched.len() }.into()))
+}
+
+/// Verify the image of `input` and print the results, the returned report
+/// could still have mismatches
+fn verify_image(input: &Input, with_crc32: bool, digest: Option<DigestAlgo>,
+                reports: &VerifyReportArgs, summary: bool, 
+                read_options: &ReadFileOptions) 
+    -> Result<(Image, VerifyReport)> 
+{
+    let in_file = input.path();
+    output::set_collect_messages(reports.report.is_some());
+    println!("Verifying image at '{}'", in_file.display());
+    let time_start = Instant::now();
+    let image = input.read_image(read_options);
+    let duration_read = time_start.elapsed();
+    let result = image.and_then(|image| {
+        let report = input.verify(&image, with_crc32, digest,
+            read_options.fail_fast)?;
+        Ok((image, report))
+    });
+    let outcome = VerifyOutcome { image: in_file, duration_read, 
+        duration: time_start.elapsed(), result: &result };
+    if let Some(path) = &reports.report {
+        report::write_json(path, &outcome)?
+    }
+    if let Some(path) = &reports.report_junit {
+        report::write_junit(path, &outcome)?
+    }
+    let (image, report) = result?;
+    output::record("image", in_file);
+    output::record("verify", &report);
+    if summary {
+        output::print_results(|| println!("{}", image))
+    } else {
+        image.print_verify_table_stdout(&report)?
+    }
+    if report.error().is_none() {
+        println!("Verified image at '{}'", in_file.display())
+    }
+    Ok((image, report))
+}
+
+/// Verify the image file of `input`, then rewrite the VERIFY items of the
+/// partitions that did not match their sha1sums and patch the CRC32 in the
+/// header in place after confirming, see `image::try_repair_file`
+fn repair(input: &Input, reports: &VerifyReportArgs, 
+          read_options: &ReadFileOptions) 
+    -> Result<()> 
+{
+    let path = match input.given_file_path() {
+        Some(path) => path,
+        None => {
+            eprintln!("Refuse to repair '{}' in place, as it's not an image \
+                file read as-is", input.path().display());
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput,
+                "repairing image not read as a file").into())
+        },
+    };
+    let (header, infos) = Image::try_read_table_only(path)?;
+    let problems = image::structural_problems(&header, &infos, 
+        path.metadata()?.len());
+    if ! problems.is_empty() {
+        for problem in problems.iter() {
+            eprintln!("Structural problem: {}", problem)
+        }
+        eprintln!("Refuse to repair image '{}' with {} structural problems, \
+            only stale checksums could be repaired", path.display(), 
+            problems.len());
+        return Err(ImageError::StructuralProblems { 
+            count: problems.len() }.into())
+    }
+    let report = match verify_image(input, false, None, reports, false, 
+        read_options) 
+    {
+        Ok((_, report)) => report,
+        Err(e) => {
+            eprintln!("Refuse to repair image '{}' that could not be verified",
+                path.display());
+            return Err(e)
+        },
+    };
+    let verifies = image::repair_plan(&infos, &report)?;
+    let crc32_stale = report.crc32_recorded != report.crc32_calculated;
+    if verifies.is_empty() && ! crc32_stale {
+        println!("Nothing to repair in image '{}'", path.display());
+        return Ok(())
+    }
+    for (check, offset, _) in verifies.iter() {
+        println!("To rewrite VERIFY of '{}' at 0x{:x}: {} => {}", check.name, 
+            offset, check.sha1sum_recorded.as_deref().unwrap_or_default(), 
+            check.sha1sum_calculated.as_deref().unwrap_or_default())
+    }
+    prompt::confirm(&format!("Rewrite {} VERIFY items and the CRC32 in the \
+        header of image '{}' in place", verifies.len(), path.display()))?;
+    let (crc32_recorded, crc32) = image::try_repair_file(path, 
+        &image::repair_patches(&verifies))?;
+    for (check, offset, sha1sum) in verifies.iter() {
+        println!("Rewrote VERIFY of '{}' at 0x{:x} with sha1sum {}", 
+            check.name, offset, sha1sum)
+    }
+    println!("Patched CRC32 in header: 0x{:08x} => 0x{:08x}", 
+        crc32_recorded, crc32);
+    output::record("repair", &serde_json::json!({
+        "verifies": verifies.iter().map(|(check, offset, _)| 
+            serde_json::json!({
+                "name": check.name,
+                "offset": offset,
+                "recorded": check.sha1sum_recorded,
+                "calculated": check.sha1sum_calculated,
+            })).collect::<Vec<_>>(),
+        "crc32_recorded": crc32_recorded,
+        "crc32": crc32,
+    }));
+    RunSummary::default()
+        .count("verifies rewritten", verifies.len())
+        .crc32(crc32)
+        .emit();
+    println!("Repaired image '{}'", path.display());
+    Ok(())
+}
+
+fn spot_check(input: &Input, sample: u64, low_memory: bool, 
+              read_options: &ReadFileOptions) 
+    -> Result<()> 
+{
+    let in_file = input.path();
+    println!("Spot checking image at '{}', sampling 0x{:x} bytes at both ends \
+        of large items", in_file.display(), sample);
+    let image = input.read_image(read_options)?;
+    output::record("image", in_file);
+    let failures = image.spot_check(sample, low_memory)?;
+    RunSummary::default()
+        .count("items", image.items_size().0)
+        .count("failed", failures.len())
+        .emit();
+    if failures.is_empty() {
+        println!("Spot checked image at '{}': no problem found, but it was NOT \
+            fully verified", in_file.display());
+        Ok(())
+    } else {
+        eprintln!("Spot checked image at '{}': {} items failed", 
+            in_file.display(), failures.len());
+        Err(ImageError::ItemsFailed { count: failures.len() }.into())
+    }
+}
+
+fn info(input: &Input, identify: bool, summary: bool) -> Result<()> {
+    let in_file = input.path();
+    let (header, infos) = match input.read_table() {
+        Ok(table) => table,
+        Err(e) => {
+            // Still show what could be shown for versions not understood
+            if matches!(e, Error::ImageError(ImageError::InvalidVersion {..})) {
+                output::print_results(|| input.print_unknown_version())?
+            }
+            return Err(e)
+        },
+    };
+    let kinds = if identify {
+        input.identify(&infos)?
+    } else {
+        Vec::new()
+    };
+    input.print_fetched();
+    output::record("image", in_file);
+    output::record("header", &header);
+    output::record("infos", &infos);
+    if identify {
+        output::record("payloads", &kinds);
+    }
+    if summary {
+        output::print_results(|| println!("{}", 
+            ImageSummary::from_infos(&header, &infos)));
+        return Ok(())
+    }
+    output::print_results(|| {
+        println!("{}", header);
+        if output::csv() {
+            image::print_item_infos_csv(&infos, &kinds)?
+        } else {
+            image::print_item_infos_table_stdout(&infos, &kinds)?
+        }
+        image::print_reserves_hexdump(&header, &infos);
+        image::print_unknown_fields(&infos);
+        Ok(())
+    })
+}
+
+/// Print what merging into the existing `out_dir` with `--keep` left alone
+fn print_kept(image: &Image, out_dir: &Path, options: &WriteDirOptions, 
+              report: &WriteDirReport) 
+{
+    if ! options.keep {
+        return
+    }
+    println!("{} items written, {} items already existing with the same \
+        content were left untouched", 
+        image.items().len() - report.unchanged.len(), report.unchanged.len());
+    if report.extra.is_empty() {
+        return
+    }
+    println!("Entries already in '{}' not belonging to any item:", 
+        out_dir.display());
+    for name in report.extra.iter() {
+        println!("  - {}", name)
+    }
+}
+
+fn unpack<P: AsRef<Path>>(input: &Input, out_dir: P, args: &UnpackArgs, 
+                          read_options: &ReadFileOptions) 
+    -> Result<()>
+{
+    let in_file = input.path();
+    let out_dir = out_dir.as_ref();
+    #[cfg(feature = "tar")]
+    if args.format == UnpackFormat::Tar {
+        return unpack_tar(input, out_dir, args, read_options)
+    }
+    println!("Unpacking image '{}' to '{}'", in_file.display(), out_dir.display());
+    let write_options = WriteDirOptions { 
+        keep: args.keep, 
+        jobs: args.jobs,
+        verify: ! args.no_verify,
+        keep_corrupted: args.ignore_errors,
+        sparse: ! args.no_sparse_files,
+    };
+    let hook = args.hook.as_deref().map(Hook::parse).transpose()?;
+    if args.ignore_errors {
+        return unpack_ignore_errors(input, out_dir, args, hook.as_ref(),
+            read_options, &write_options)
+    }
+    // Only after the image is opened, as it could be in the output dir, and
+    // its items are read from the opened file
+    let mut image = input.read_image(read_options)?;
+    if ! args.keep {
+        guard_output(out_dir, &args.overwrite)?;
+    }
+    args.filter_items(&mut image, &mut Vec::new());
+    image.print_table_stdout()?;
+    record_unpacked(&image, in_file, "out_dir", out_dir);
+    print_kept(&image, out_dir, &write_options, 
+        &image.try_write_dir(out_dir, &write_options)?);
+    if args.emit_manifest {
+        image.to_manifest()?.try_write_file(out_dir.join(MANIFEST_NAME))?
+    }
+    let hook_failures = match &hook {
+        Some(hook) => hook::run_unpack_hooks(&image, hook, out_dir, &[]),
+        None => Vec::new(),
+    };
+    let (count, size) = image.items_size();
+    let mut summary = RunSummary::default()
+        .count("items written", count);
+    if hook.is_some() {
+        summary = summary.count("hooks failed", hook_failures.len())
+    }
+    summary.bytes("written", size).emit();
+    if hook_failures.is_empty() {
+        println!("Unpacked image '{}' to '{}'", in_file.display(), 
+            out_dir.display());
+        Ok(())
+    } else {
+        eprintln!("Unpacked image '{}' to '{}' with hook failed on {} items", 
+            in_file.display(), out_dir.display(), hook_failures.len());
+        Err(ImageError::ItemsFailed { count: hook_failures.len() }.into())
+    }
+}
+
+fn unpack_ignore_errors(input: &Input, out_dir: &Path, args: &UnpackArgs,
+                        hook: Option<&Hook>, read_options: &ReadFileOptions, 
+                        write_options: &WriteDirOptions) 
+    -> Result<()> 
+{
+    let in_file = input.path();
+    let (mut image, mut failures) = 
+        input.read_image_ignore_errors(read_options)?;
+    if ! args.keep {
+        guard_output(out_dir, &args.overwrite)?;
+    }
+    args.filter_items(&mut image, &mut failures);
+    image.print_table_stdout()?;
+    record_unpacked(&image, in_file, "out_dir", out_dir);
+    let report = image.try_write_dir(out_dir, write_options)?;
+    print_kept(&image, out_dir, write_options, &report);
+    for (name, e) in report.corrupted {
+        eprintln!("Item '{}' failed to verify, written with .corrupt \
+            suffix: {}", name, e);
+        failures.push((name, e))
+    }
+    if args.emit_manifest {
+        image.to_manifest()?.try_write_file(out_dir.join(MANIFEST_NAME))?
+    }
+    if let Some(hook) = hook {
+        let failed: Vec<String> = failures.iter()
+            .map(|(name, _)|name.clone()).collect();
+        failures.extend(hook::run_unpack_hooks(&image, hook, out_dir, &failed))
+    }
+    image.print_status_table_stdout(&failures)?;
+    output::record("failures", &failures.iter().map(|(name, e)| 
+        serde_json::json!({"name": name, "error": e.to_string()}))
+        .collect::<Vec<_>>());
+    let (count, size) = image.items_size();
+    RunSummary::default()
+        .count("items", count)
+        .count("failed", failures.len())
+        .bytes("written", size)
+        .emit();
+    if failures.is_empty() {
+        println!("Unpacked image '{}' to '{}'", in_file.display(), out_dir.display());
+        Ok(())
+    } else {
+        eprintln!("Unpacked image '{}' to '{}' with {} items failed", 
+            in_file.display(), out_dir.display(), failures.len());
+        Err(ImageError::ItemsFailed { count: failures.len() }.into())
+    }
+}
+
+/// Unpack the image of `input` as entries of a tar archive at `out_file`, or
+/// to stdout
+#[cfg(feature = "tar")]
+fn unpack_tar(input: &Input, out_file: &Path, args: &UnpackArgs, 
+              read_options: &ReadFileOptions) 
+    -> Result<()> 
+{
+    let in_file = input.path();
+    println!("Unpacking image '{}' into tar archive '{}'", in_file.display(), 
+        out_file.display());
+    for (set, arg) in [(args.keep, "--keep"), 
+                       (args.ignore_errors, "--ignore-errors")] 
+    {
+        if set {
+            eprintln!("Refuse to unpack into a tar archive with {}, as \
+                entries are streamed and could not be taken back", arg);
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput,
+                "unpacking into tar archive with incompatible argument").into())
+        }
+    }
+    if args.hook.is_some() {
+        eprintln!("Refuse to unpack into a tar archive with --hook, as items \
+            are not written as files to run it on");
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput,
+            "unpacking into tar archive with incompatible argument").into())
+    }
+    let to_stdout = stdio::is_stdio(out_file);
+    let mut image = input.read_image(read_options)?;
+    if to_stdout {
+        println!("Writing the tar archive to stdout")
+    } else {
+        guard_output(out_file, &args.overwrite)?;
+    }
+    args.filter_items(&mut image, &mut Vec::new());
+    image.print_table_stdout()?;
+    record_unpacked(&image, in_file, "output", out_file);
+    let size = if to_stdout {
+        stdio::write_tar_stdout(&image, ! args.no_verify)?
+    } else {
+        // Atomic so an item failing to verify leaves no partial archive
+        image.try_write_tar_file(out_file, 
+            &WriteFileOptions { atomic: true, fsync: false }, 
+            ! args.no_verify)?
+    };
+    println!("Unpacked image '{}' into tar archive '{}'", in_file.display(), 
+        out_file.display());
+    RunSummary::default()
+        .count("items written", image.items_size().0)
+        .bytes("written", size)
+        .emit();
+    Ok(())
+}
+
+/// Record the items of `image` unpacked from `in_file` to files in `out`, 
+/// recorded as `key`
+fn record_unpacked(image: &Image, in_file: &Path, key: &str, out: &Path) {
+    if ! output::json() {
+        return
+    }
+    let mut items = image.summaries();
+    for (item, name) in items.iter_mut().zip(image.file_names()) {
+        item.file = Some(name)
+    }
+    output::record("image", in_file);
+    output::record(key, out);
+    output::record("items", &items);
+}
+
+/// Record the layout, size and CRC32 of the image `out_file` written from
+/// `image`
+fn record_written(image: &Image, out_file: &Path, size: u64, crc32: u32) 
+    -> Result<()> 
+{
+    if ! output::json() {
+        return Ok(())
+    }
+    output::record("output", out_file);
+    output::record("size", &size);
+    output::record("crc32", &crc32);
+    output::record("items", &image.summaries());
+    output::record("layout", &image.layout()?);
+    Ok(())
+}
+
+/// Whether the two paths point to the same existing file
+fn is_same_file(some: &Path, other: &Path) -> bool {
+    match (some.canonicalize(), other.canonicalize()) {
+        (Ok(some), Ok(other)) => some == other,
+        _ => false
+    }
+}
+
+/// The original file is only replaced after the temporary file is fully 
+/// written, so both need to co-exist during an in-place convert
+fn check_space_in_place(image: &Image, path: &Path) -> Result<()> {
+    let dir = match path.parent() {
+        Some(parent) if ! parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let available = match atomic::available_space(dir)? {
+        Some(available) => available,
+        None => return Ok(()),
+    };
+    let needed = image.size_upper_bound();
+    if needed > available {
+        eprintln!("Converting '{}' in place needs up to {} bytes of transient \
+            space for the temporary file but only {} bytes are available on \
+            its filesystem, free some space or convert it to another \
+            filesystem instead", path.display(), needed, available);
+        return Err(ImageError::InsufficientSpace { needed, available }.into())
+    }
+    Ok(())
+}
+
+fn convert<P: AsRef<Path>>(input: &Input, out_file: P, no_verify: bool, 
+                           compress: Option<Compression>,
+                           output: &ImageOutputArgs, 
+                           read_options: &ReadFileOptions) 
+    -> Result<()>
+{
+    let in_file = input.path();
+    let out_file = out_file.as_ref();
+    println!("Converting image '{}' to '{}'", in_file.display(), out_file.display());
+    let to_stdout = stdio::is_stdio(out_file);
+    let digests = output.digests(to_stdout)?;
+    let in_place = input.file_path().is_some_and(|in_file| 
+        is_same_file(in_file, out_file));
+    if let Some(format) = compress {
+        println!("Compressing the output image as {}", format)
+    }
+    if to_stdout {
+        println!("Writing the image to stdout")
+    } else if in_place {
+        if output.no_atomic {
+            eprintln!("Refuse to convert '{}' in place with --no-atomic, as a \
+                failed write would destroy the only copy of the image", 
+                in_file.display());
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput,
+                "in-place convert without atomic write").into())
+        }
+        if output.overwrite.backup {
+            eprintln!("Refuse to convert '{}' in place with --backup, convert \
+                it to another path to keep the original instead", 
+                in_file.display());
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput,
+                "in-place convert with backup").into())
+        }
+        println!("Input and output are the same file, converting in place via \
+            a temporary file next to it");
+    } else {
+        guard_output(out_file, &output.overwrite)?;
+    }
+    let mut image = input.read_image(read_options)?;
+    image.print_table_stdout()?;
+    if no_verify {
+        image.clear_verify()
+    }
+    // Only the items without recorded sha1sums, i.e. not partitions, are read
+    // here to dedup backups, partitions are read once as they're copied, and
+    // verified then
+    image.fill_verify()?;
+    image.set_ver_align(output.out_ver.clone(), output.out_align);
+    image.set_pad_to(output.pad_to);
+    image.print_layout_stdout()?;
+    let mut spill = output.spill_dir();
+    if let Some(report) = image.spill_payloads(output.max_memory, &mut spill) {
+        println!("{}", report)
+    }
+    if in_place {
+        check_space_in_place(&image, out_file)?
+    }
+    let (crc32, size, digests) = match (to_stdout, compress) {
+        (true, _) => {
+            let (crc32, size) = stdio::write_image_stdout(&image, ! no_verify, 
+                compress)?;
+            (crc32, size, Vec::new())
+        },
+        #[cfg(feature = "compress")]
+        (false, Some(format)) => {
+            let (crc32, size, digests) = image.try_write_file_compressed(
+                out_file, &output.write_file_options(), ! no_verify, format,
+                &digests)?;
+            let size_compressed = out_file.metadata()?.len();
+            println!("Compressed image of {} to {} as {}", 
+                output::size_text_exact(size), 
+                output::size_text_exact(size_compressed), format);
+            (crc32, size, digests)
+        },
+        _ if ! digests.is_empty() => {
+            let (crc32, digests) = image.try_write_file_digested(out_file, 
+                &output.write_file_options(), ! no_verify, &digests)?;
+            (crc32, out_file.metadata()?.len(), digests)
+        },
+        _ => {
+            let crc32 = image.try_write_file(
+                out_file, &output.write_file_options(), ! no_verify)?;
+            (crc32, out_file.metadata()?.len(), Vec::new())
+        },
+    };
+    checksum::try_write_sidecars(out_file, &digests, 
+        &output.write_file_options())?;
+    output::record("image", in_file);
+    record_written(&image, out_file, size, crc32)?;
+    println!("Converted image '{}' to '{}'", in_file.display(), out_file.display());
+    RunSummary::default()
+        .count("items", image.items_size().0)
+        .bytes("written", size)
+        .crc32(crc32)
+        .digests(&digests)
+        .emit();
+    Ok(())
+}
+
+/// Whether the file at `path` would be inside `dir`, neither need to exist
+fn is_path_in_dir(path: &Path, dir: &Path) -> bool {
+    let dir = match dir.canonicalize() {
+        Ok(dir) => dir,
+        Err(_) => return false,
+    };
+    let parent = match path.parent() {
+        Some(parent) if parent.as_os_str().is_empty() => Path::new("."),
+        Some(parent) => parent,
+        None => return false,
+    };
+    match parent.canonicalize() {
+        Ok(parent) => parent.starts_with(dir),
+        Err(_) => false,
+    }
+}
+
+fn pack<P1, P2>(in_dir: P1, out_file: P2, output: &ImageOutputArgs, 
+                read_options: &ReadDirOptions, args: &PackArgs,
+                read_file_options: &ReadFileOptions) 
+    -> Result<()> 
+where
+    P1: AsRef<Path>,
+    P2: AsRef<Path>
+{
+    let in_dir = in_dir.as_ref();
+    let out_file = out_file.as_ref();
+    println!("Packing '{}' to '{}'", in_dir.display(), out_file.display());
+    let to_stdout = stdio::is_stdio(out_file);
+    let digests = output.digests(to_stdout)?;
+    if to_stdout && args.verify.is_some() {
+        eprintln!("Refuse to check the image written to stdout with --verify, \
+            as it could not be read back");
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput,
+            "checking image written to stdout").into())
+    }
+    if ! to_stdout && ! read_options.allow_nested_image && 
+        is_path_in_dir(out_file, in_dir) 
+    {
+        eprintln!("Output image '{}' is inside input dir '{}', it would be \
+            packed as an item next time, refuse to continue (use \
+            --allow-nested-image to override)", 
+            out_file.display(), in_dir.display());
+        return Err(ImageError::NestedImage { 
+            path: out_file.display().to_string() }.into())
+    }
+    if ! args.plan && ! args.dry_run && ! to_stdout {
+        guard_output(out_file, &output.overwrite)?
+    }
+    let time_scan = Instant::now();
+    let mut image = Image::try_read_dir(in_dir, read_options)?;
+    let time_scan = time_scan.elapsed();
+    let path_manifest = in_dir.join(MANIFEST_NAME);
+    let manifest = if path_manifest.exists() {
+        println!("Applying manifest '{}'", path_manifest.display());
+        let manifest = Manifest::try_read_file(&path_manifest)?;
+        image.apply_manifest(&manifest)?;
+        Some(manifest)
+    } else {
+        None
+    };
+    let renamed = if args.sanitize_names {
+        let renames = image.sanitize_names(args.lowercase_names, 
+            &output.out_ver)?;
+        image::print_renames_table_stdout(&renames)?;
+        ! renames.is_empty()
+    } else {
+        false
+    };
+    let normalized = if args.normalize_text {
+        let normalized = image.normalize_text(&args.text_extensions)?;
+        image::print_normalized_table_stdout(&normalized)?;
+        ! normalized.is_empty()
+    } else {
+        false
+    };
+    let hook = args.hook.as_deref().map(Hook::parse).transpose()?;
+    let mut spill = output.spill_dir();
+    let hooked = hook::run_pack_hooks(&mut image, hook.as_ref(), 
+        &args.hook_items, manifest.as_ref(), args.manifest_hooks, &mut spill)?;
+    if ! hooked.is_empty() {
+        output::record("hooked", &hooked)
+    }
+    image.print_table_stdout()?;
+    // Items are only read twice: once here for their sha1sums, which must be
+    // known before laying out the image to dedup backups, and once when 
+    // written, with the CRC calculated as they are
+    let user_cache = if args.no_cache {
+        None
+    } else {
+        let hits = image.apply_checksum_cache(
+            &ChecksumCache::read_dir_or_default(in_dir));
+        if hits > 0 {
+            println!("Took sha1sums of {} unchanged items from checksum cache \
+                '{}'", hits, in_dir.join(CHECKSUM_CACHE_NAME).display())
+        }
+        ChecksumCache::read_user_cache(in_dir)
+    };
+    if let Some((cache, path)) = &user_cache {
+        let hits = image.apply_checksum_cache(cache);
+        if hits > 0 {
+            println!("Took sha1sums of {} unchanged items from user cache \
+                '{}'", hits, path.display())
+        }
+    }
+    output::record("in_dir", in_dir);
+    if args.plan {
+        image.set_ver_align(output.out_ver.clone(), output.out_align);
+        image.set_pad_to(output.pad_to);
+        return image.print_plan()
+    }
+    if let Some(report) = image.spill_payloads(output.max_memory, &mut spill) {
+        println!("{}", report)
+    }
+    image.fill_verify()?;
+    if let Some((cache, path)) = &user_cache {
+        let mut cache = cache.emptied();
+        image.record_checksum_cache(&mut cache);
+        if let Err(e) = cache.try_write_file(path) {
+            warning!(Cache, "failed to write user cache '{}': {}", 
+                path.display(), e)
+        }
+    }
+    image.set_ver_align(output.out_ver.clone(), output.out_align);
+    image.set_pad_to(output.pad_to);
+    if args.stamp || args.stamp_date.is_some() {
+        image.set_stamp(&Stamp::new(args.stamp_date))
+    }
+    image.print_layout_stdout()?;
+    if args.dry_run {
+        let (crc32, size) = image.try_write_discard(true)?;
+        record_written(&image, out_file, size, crc32)?;
+        println!("Dry run: packing '{}' to '{}' would produce an image of \
+            {} with CRC32 0x{:08x}, nothing written", in_dir.display(), 
+            out_file.display(), output::size_text_exact(size), crc32);
+        RunSummary::default()
+            .count("items", image.items_size().0)
+            .bytes("discarded", size)
+            .crc32(crc32)
+            .stage("scanned", time_scan)
+            .emit();
+        return Ok(())
+    }
+    let (crc32, size, digests) = if to_stdout {
+        let (crc32, size) = stdio::write_image_stdout(&image, false, None)?;
+        (crc32, size, Vec::new())
+    } else if ! digests.is_empty() {
+        let (crc32, digests) = image.try_write_file_digested(out_file, 
+            &output.write_file_options(), false, &digests)?;
+        (crc32, out_file.metadata()?.len(), digests)
+    } else {
+        let crc32 = image.try_write_file(
+            out_file, &output.write_file_options(), false)?;
+        (crc32, out_file.metadata()?.len(), Vec::new())
+    };
+    record_written(&image, out_file, size, crc32)?;
+    match args.verify {
+        Some(PackVerify::SelfCheck) => image.try_self_check_file(out_file, crc32)?,
+        Some(PackVerify::ReadBack) => 
+            verify_read_back(out_file, crc32, read_file_options)?,
+        None => (),
+    }
+    if (normalized || renamed) && to_stdout {
+        println!("Text normalization and sanitized names not recorded in a \
+            manifest, as the image is written to stdout")
+    } else if normalized || renamed {
+        let mut path_manifest = out_file.as_os_str().to_os_string();
+        path_manifest.push(".");
+        path_manifest.push(MANIFEST_NAME);
+        println!("Recording text normalization and sanitized names in \
+            manifest '{}'", Path::new(&path_manifest).display());
+        image.to_manifest()?.try_write_file(&path_manifest)?
+    }
+    checksum::try_write_sidecars(out_file, &digests, 
+        &output.write_file_options())?;
+    println!("Packed '{}' to '{}'", in_dir.display(), out_file.display());
+    RunSummary::default()
+        .count("items", image.items_size().0)
+        .bytes("written", size)
+        .crc32(crc32)
+        .digests(&digests)
+        .stage("scanned", time_scan)
+        .emit();
+    Ok(())
+}
+
+/// Read the image just written back in whole, verify its items and check its
+/// CRC32 is still the one calculated while writing
+fn verify_read_back(out_file: &Path, crc32: u32, 
+                    read_options: &ReadFileOptions) 
+    -> Result<()> 
+{
+    if let Some(e) = verify_image(&Input::file(out_file), false, None,
+        &VerifyReportArgs::default(), false, read_options)?.1.error() 
+    {
+        return Err(e)
+    }
+    let crc32_read = crc32::Crc32Hasher::try_hash_image_file(out_file)?.0.value;
+    if crc32_read != crc32 {
+        eprintln!("CRC32 of image '{}' read back (0x{:08x}) != written \
+            (0x{:08x})", out_file.display(), crc32_read, crc32);
+        return Err(ImageError::SelfCheckFailed { 
+            reason: "CRC32 read back differs".into() }.into())
+    }
+    println!("CRC32 of image '{}' read back matches written", out_file.display());
+    Ok(())
+}
+
+fn checksums(input: &Input, algo: ChecksumAlgo, 
+             read_options: &ReadFileOptions) 
+    -> Result<()> 
+{
+    let in_file = input.path();
+    let image = input.read_image(read_options)?;
+    let checksums = image.checksums(algo)?;
+    output::record("image", in_file);
+    output::record("algo", &algo);
+    output::record("items", &checksums.iter().map(|(name, checksum)| 
+        serde_json::json!({"name": name, "checksum": checksum}))
+        .collect::<Vec<_>>());
+    if output::csv() {
+        return output::print_csv(&["item", "checksum"], 
+            checksums.into_iter().map(|(name, checksum)| vec![name, checksum]))
+    }
+    println!("Checksums of items in '{}':", in_file.display());
+    let count = checksums.len();
+    output::print_results(|| for (name, checksum) in checksums {
+        println!("{}  {}", checksum, name)
+    });
+    RunSummary::default()
+        .count("items hashed", count)
+        .bytes("hashed", image.items_size().1)
+        .emit();
+    Ok(())
+}
+
+/// Hash `in_file` for both CRC32 variants and return its size, stdin read
+/// as it comes and never spooled, as it's only read once
+fn hash_crc32(in_file: &str, member: Option<&str>, raw: bool) 
+    -> Result<(crc32::Crc32Hasher, crc32::Crc32Hasher, u64)> 
+{
+    if in_file == stdio::STDIO {
+        crc32::Crc32Hasher::try_hash_from(stdio::stdin_reader()?, raw)
+    } else if raw {
+        Input::open_raw(in_file)?.hash_crc32(true)
+    } else {
+        Input::open(in_file, member)?.hash_crc32(false)
+    }
+}
+
+fn do_crc32(in_files: &[String], member: Option<&str>, standard: bool, 
+            raw: bool) 
+    -> Result<()> 
+{
+    if standard {
+        println!("Printing the standard CRC32 of each file (of the whole \
+            file, as crc32(1) or 7-Zip give, NOT the one in the image header)")
+    } else if raw {
+        println!("Printing the Amlogic variant of CRC32 of each whole file (no \
+            final XOR, i.e. the standard one inverted), then the standard \
+            CRC32 (as crc32(1) or 7-Zip give)")
+    } else {
+        println!("Printing the Amlogic CRC32 of each file (after the 4-byte \
+            CRC field, no final XOR, as stored in the image header), then the \
+            standard CRC32 (of the whole file, as crc32(1) or 7-Zip give)")
+    }
+    let mut files = Vec::new();
+    let mut failed = None;
+    let mut size_hashed = 0;
+    for in_file in in_files {
+        let (amlogic, whole, size) = match hash_crc32(in_file, member, raw) {
+            Ok(hashed) => hashed,
+            Err(e) => {
+                eprintln!("Failed to calculate CRC32 of '{}': {}", in_file, e);
+                failed.get_or_insert(e);
+                continue
+            },
+        };
+        size_hashed += size;
+        output::print_results(|| if standard {
+            println!("{:08x}  {}", whole.finalize_standard(), in_file)
+        } else {
+            println!("{:08x}  {:08x}  {}", amlogic.value, 
+                whole.finalize_standard(), in_file)
+        });
+        files.push(serde_json::json!({
+            "file": in_file,
+            "size": size,
+            "crc32_amlogic": amlogic.value,
+            "crc32_standard": whole.finalize_standard(),
+        }))
+    }
+    if let ([file], 1) = (&files[..], in_files.len()) {
+        output::record("image", &file["file"]);
+        output::record("crc32_amlogic", &file["crc32_amlogic"]);
+        output::record("crc32_standard", &file["crc32_standard"]);
+    }
+    output::record("files", &files);
+    RunSummary::default()
+        .count("files", files.len())
+        .bytes("hashed", size_hashed)
+        .emit();
+    match failed {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// Fail if any warning was promoted to an error by --strict, listing them
+fn check_strict() -> Result<()> {
+    let promoted = output::promoted_warnings();
+    if promoted.is_empty() {
+        return Ok(())
+    }
+    eprintln!("{} warnings promoted to errors by --strict (use --allow [class] \
+        to allow a class of them):{}", promoted.len(), promoted.iter()
+            .map(|warning|format!("\n  - {}", warning.replace('\n', "\n    ")))
+            .collect::<String>());
+    Err(Error::PromotedWarnings(promoted.len()))
+}
+
+/// Exit with the argument error `e`, also logged to the log file, if any
+fn exit_with(e: clap::Error) -> ! {
+    if e.use_stderr() {
+        log::error!("{}", e.render())
+    }
+    e.exit()
+}
+
+fn main() -> ExitCode {
+    output::set_console(true);
+    if let Some(path) = logger::log_file_in_args(std::env::args_os().skip(1)) {
+        if let Err(e) = logger::open_log_file(&path) {
+            eprintln!("Failed to open log file '{}': {}", path.display(), e);
+            return ExitCode::FAILURE
+        }
+    }
+    output::set_start(Instant::now());
+    log::info!("ampack {} started as {:?}", env!("CARGO_PKG_VERSION"), 
+        std::env::args_os().collect::<Vec<_>>());
+    let matches = match Arg::command().try_get_matches() {
+        Ok(matches) => matches,
+        Err(e) => exit_with(e),
+    };
+    let mut arg = match Arg::from_arg_matches(&matches) {
+        Ok(arg) => arg,
+        Err(e) => exit_with(e),
+    };
+    if arg.json && arg.action.format() == OutputFormat::Csv {
+        exit_with(Arg::command().error(
+            clap::error::ErrorKind::ArgumentConflict, 
+            "the argument '--json' cannot be used with '--format csv'"))
+    }
+    if arg.json && arg.action.writes_stdout() {
+        exit_with(Arg::command().error(
+            clap::error::ErrorKind::ArgumentConflict, 
+            "the argument '--json' cannot be used with '-' as the output, as \
+            stdout carries the data"))
+    }
+    // Checked here as clap would also count AMPACK_NO_PROGRESS as conflicting
+    let progress_set = arg.plain || 
+        matches.value_source("progress") == Some(ValueSource::CommandLine);
+    if arg.no_progress && progress_set {
+        if matches.value_source("no_progress") == Some(ValueSource::CommandLine) {
+            exit_with(Arg::command().error(
+                clap::error::ErrorKind::ArgumentConflict, 
+                "the argument '--no-progress' cannot be used with '--progress' \
+                or '--plain'"))
+        }
+        arg.no_progress = false
+    }
+    output::set_json(arg.json);
+    output::set_stdout_data(arg.action.writes_stdout());
+    output::set_quiet(arg.quiet);
+    output::set_human(arg.human);
+    output::set_color(arg.color);
+    output::set_format(arg.action.format());
+    output::set_sort(arg.action.sort());
+    // Before the logger, which warns about an invalid AMPACK_LOG
+    output::set_strict(arg.strict, arg.allow.clone());
+    logger::init(arg.verbose);
+    output::set_log_tables(logger::logging_to_file());
+    prompt::set_assume_yes(arg.yes);
+    let result = run(arg).and_then(|_|check_strict());
+    let emitted = output::emit(matches.subcommand_name().unwrap_or_default(), 
+        &result);
+    match result.and(emitted) {
+        Ok(()) => {
+            log::info!("ampack finished successfully");
+            ExitCode::SUCCESS
+        },
+        Err(e) => {
+            eprintln!("Error: {}", e.display_chain());
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(arg: Arg) -> Result<()> {
+    retry::set_policy(retry::RetryPolicy { 
+        attempts: arg.io_retries, 
+        backoff: Duration::from_millis(arg.io_retry_delay),
+    });
+    payload::set_chunk_size(arg.chunk_size);
+    cache::set_user_cache_dir(arg.cache_dir);
+    input::set_temp_dir(arg.temp_dir);
+    payload::set_reads_sequential(arg.sequential_reads);
+    payload::set_buffer_memory(arg.buffer_memory);
+    progress::set_progress_mode(if arg.no_progress || arg.quiet > 0 { 
+        ProgressMode::None 
+    } else { 
+        arg.progress 
+    });
+    progress::set_plain(arg.plain);
+    if let Err(e) = rayon::ThreadPoolBuilder::new()
+        .num_threads(arg.threads).build_global() 
+    {
+        eprintln!("Failed to set up {} worker threads: {}", arg.threads, e);
+        return Err(e.into())
+    }
+    if arg.threads != 0 {
+        println!("Using {} worker threads", rayon::current_num_threads())
+    }
+    // No reader threads either, for fully deterministic runs when debugging
+    payload::set_read_ahead(arg.threads != 1);
+    let read_file_options = ReadFileOptions {
+        max_item_size: arg.max_item_size,
+        #[cfg(feature = "mmap")]
+        mmap: arg.mmap,
+        fail_fast: false,
+    };
+    #[cfg(feature = "zip")]
+    let member = arg.member.as_deref();
+    #[cfg(not(feature = "zip"))]
+    let member = None;
+    #[cfg(feature = "compress")]
+    let compress = match &arg.action {
+        Action::Convert { compress, .. } => *compress,
+        _ => None,
+    };
+    #[cfg(not(feature = "compress"))]
+    let compress = None;
+    match arg.action {
+        Action::Verify { in_file, low_memory, sample: Some(sample), .. } =>
+            spot_check(&Input::open(&in_file, member)?, sample, low_memory, 
+                &read_file_options),
+        Action::Verify { in_file, reports, fail_fast, repair: true, .. } => 
+            repair(&Input::open(&in_file, member)?, &reports, 
+                &ReadFileOptions { fail_fast, ..read_file_options }),
+        Action::Verify { in_file, with_crc32, checksum_file, expect, 
+                         reports, summary, fail_fast, .. } => 
+            verify(&Input::open(&in_file, member)?, with_crc32, 
+                checksum_file.as_deref(), expect.as_deref(), &reports, summary,
+                &ReadFileOptions { fail_fast, ..read_file_options }),
+        Action::Info { in_file, identify, summary, .. } => 
+            info(&Input::open_table(&in_file, member)?, identify, summary),
+        Action::Unpack { in_file, out_dir, output, unpack: args } => 
+            unpack(&Input::open(&in_file, member)?, 
+                output.or(out_dir).unwrap_or_default(), &args, 
+                &read_file_options),
+        Action::Convert { in_file, out_file, no_verify, output, .. } => 
+            convert(&Input::open(&in_file, member)?, out_file, no_verify,
+                compress, &output, &read_file_options),
+        Action::Pack { in_dir, out_file, output, allow_nested_image, 
+                        case_insensitive_merge, error_on_skip, pack: args } => 
+            pack(in_dir, out_file, &output, &ReadDirOptions { 
+                allow_nested_image, 
+                max_item_size: arg.max_item_size,
+                case_insensitive_merge,
+                error_on_skip,
+            }, &args, &read_file_options),
+        Action::Checksums { in_file, algo, .. } => 
+            checksums(&Input::open(&in_file, member)?, algo, 
+                &read_file_options),
+        Action::Crc32 { in_files, standard, raw } => 
+            do_crc32(&in_files, member, standard, raw),
+        Action::Cache { action: CacheAction::Clear } => 
+            cache::try_clear_user_cache(),
+    }
+}
@@ -0,0 +1,64 @@
+/*
+ampack, to unpack and pack Aml burning images: confirmation prompt module
+Copyright (C) 2024-present Guoxin "7Ji" Pu
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as
+published by the Free Software Foundation, either version 3 of the
+License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::{io::{BufRead, IsTerminal, Write}, sync::OnceLock};
+
+use ampack::{Error, Result};
+
+static ASSUME_YES: OnceLock<bool> = OnceLock::new();
+
+/// Set whether all confirmations are answered yes without asking, only the 
+/// first call takes effect
+pub(crate) fn set_assume_yes(yes: bool) {
+    let _ = ASSUME_YES.set(yes);
+}
+
+fn assume_yes() -> bool {
+    ASSUME_YES.get().copied().unwrap_or(false)
+}
+
+/// Ask the user on stderr to confirm `action`, which must state exactly what
+/// would be destroyed, e.g. `Overwrite existing output 'a.img', a file of 
+/// 1024 bytes`. Confirmed right away with `--yes`, and refused without asking
+/// if stdin is not a terminal, so scripts never hang on it. Every prompt goes
+/// through this, so none of them could bypass `--yes`.
+pub(crate) fn confirm(action: &str) -> Result<()> {
+    if assume_yes() {
+        println!("{}: yes, assumed by --yes", action);
+        return Ok(())
+    }
+    if ! std::io::stdin().is_terminal() {
+        eprintln!("{}: refused, as stdin is not a terminal to confirm it", 
+            action);
+        return Err(Error::NotConfirmed)
+    }
+    let answer = ampack::progress::suspend(|| {
+        std::eprint!("{}? [y/N] ", action);
+        std::io::stderr().flush()?;
+        let mut answer = String::new();
+        std::io::stdin().lock().read_line(&mut answer).map(|_|answer)
+    })?;
+    let answer = answer.trim().to_lowercase();
+    log::info!("{}? {}", action, answer);
+    if answer == "y" || answer == "yes" {
+        Ok(())
+    } else {
+        eprintln!("{}: refused", action);
+        Err(Error::NotConfirmed)
+    }
+}
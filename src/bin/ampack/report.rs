@@ -0,0 +1,196 @@
+/*
+ampack, to unpack and pack Aml burning images: verification report module
+Copyright (C) 2024-present Guoxin "7Ji" Pu
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as
+published by the Free Software Foundation, either version 3 of the
+License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::{fmt::Write as _, io::Write, path::Path, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use serde::Serialize;
+
+use ampack::{atomic::{self, WriteFileOptions}, image::{CheckStatus, Image, VerifyReport}, output::{self, Message}, Result};
+
+/// How verifying an image went, for the reports
+pub(crate) struct VerifyOutcome<'a> {
+    pub(crate) image: &'a Path,
+    /// Time taken to read the header and item infos
+    pub(crate) duration_read: Duration,
+    /// Time taken in total
+    pub(crate) duration: Duration,
+    pub(crate) result: &'a Result<(Image, VerifyReport)>,
+}
+
+impl VerifyOutcome<'_> {
+    fn report(&self) -> Option<&VerifyReport> {
+        self.result.as_ref().ok().map(|(_, report)|report)
+    }
+
+    fn error(&self) -> Option<String> {
+        match self.result {
+            Ok((_, report)) => report.error().map(|e|e.to_string()),
+            Err(e) => Some(e.to_string()),
+        }
+    }
+}
+
+/// The report written by `verify --report`
+#[derive(Serialize)]
+struct JsonReport<'a> {
+    schema_version: u32,
+    ampack_version: &'static str,
+    /// Seconds since the Unix epoch when the report was written
+    timestamp: u64,
+    image: &'a Path,
+    size: Option<u64>,
+    ok: bool,
+    error: Option<String>,
+    /// Seconds the verification took
+    duration: f64,
+    verify: Option<&'a VerifyReport>,
+    messages: Vec<Message>,
+}
+
+/// Write `content` to the report file at `path`
+fn write_report(path: &Path, kind: &str, content: &[u8]) -> Result<()> {
+    if let Err(e) = atomic::write_file(path, &WriteFileOptions::default(),
+        |file| Ok(file.write_all(content)?))
+    {
+        eprintln!("Failed to write {} to '{}': {}", kind, path.display(), e);
+        return Err(e)
+    }
+    println!("Wrote {} to '{}'", kind, path.display());
+    Ok(())
+}
+
+/// Write the JSON report of `outcome` to `path`
+pub(crate) fn write_json(path: &Path, outcome: &VerifyOutcome) -> Result<()> {
+    let error = outcome.error();
+    let report = JsonReport {
+        schema_version: output::JSON_SCHEMA_VERSION,
+        ampack_version: env!("CARGO_PKG_VERSION"),
+        timestamp: SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|time|time.as_secs()).unwrap_or_default(),
+        image: outcome.image,
+        size: std::fs::metadata(outcome.image)
+            .map(|metadata|metadata.len()).ok(),
+        ok: error.is_none(),
+        error,
+        duration: outcome.duration.as_secs_f64(),
+        verify: outcome.report(),
+        messages: output::messages(),
+    };
+    write_report(path, "verification report",
+        &serde_json::to_vec_pretty(&report)?)
+}
+
+/// Escape `text` to be put in XML attributes and text
+fn xml_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// A test case in the JUnit report
+struct TestCase {
+    name: String,
+    duration: f64,
+    /// Type and message of the failure, and the details
+    failure: Option<(&'static str, String, String)>,
+    /// Why it's skipped
+    skipped: Option<&'static str>,
+}
+
+impl TestCase {
+    fn passed(name: &str, duration: f64) -> Self {
+        Self { name: name.into(), duration, failure: None, skipped: None }
+    }
+}
+
+/// Write the JUnit XML report of `outcome` to `path`: the structural check
+/// (reading the header and item infos), the CRC32 check of the header and the
+/// check of each item are test cases, if the image could not be read at all
+/// the suite has a single failed case
+pub(crate) fn write_junit(path: &Path, outcome: &VerifyOutcome) -> Result<()> {
+    let duration_read = outcome.duration_read.as_secs_f64();
+    let mut cases = Vec::new();
+    match outcome.result {
+        Err(e) => cases.push(TestCase {
+            failure: Some(("structure", e.to_string(), String::new())),
+            ..TestCase::passed("structure", duration_read)
+        }),
+        Ok((_, report)) => {
+            cases.push(TestCase::passed("structure", duration_read));
+            let mut case = TestCase::passed("crc32",
+                (outcome.duration - outcome.duration_read).as_secs_f64());
+            if report.crc32_recorded != report.crc32_calculated {
+                case.failure = Some(("crc32-mismatch",
+                    "CRC32 in header does not match".into(),
+                    format!("expected: 0x{:08x}\nactual: 0x{:08x}",
+                        report.crc32_recorded, report.crc32_calculated)))
+            }
+            cases.push(case);
+            for item in report.items.iter() {
+                let mut case = TestCase::passed(&item.name, item.duration);
+                match item.status {
+                    CheckStatus::Verified => (),
+                    CheckStatus::Mismatch => case.failure = Some((
+                        "sha1sum-mismatch", "sha1sum does not match".into(),
+                        format!("expected: {}\nactual: {}",
+                            item.sha1sum_recorded.as_deref().unwrap_or_default(),
+                            item.sha1sum_calculated.as_deref().unwrap_or_default()))),
+                    CheckStatus::NoChecksum =>
+                        case.skipped = Some("no checksum recorded"),
+                }
+                cases.push(case)
+            }
+        },
+    }
+    let failures = cases.iter().filter(|case|case.failure.is_some()).count();
+    let skipped = cases.iter().filter(|case|case.skipped.is_some()).count();
+    let name = xml_escape(&outcome.image.display().to_string());
+    let duration = outcome.duration.as_secs_f64();
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    let _ = writeln!(xml, "<testsuites name=\"ampack verify\" tests=\"{}\" \
+        failures=\"{}\" errors=\"0\" skipped=\"{}\" time=\"{:.3}\">",
+        cases.len(), failures, skipped, duration);
+    let _ = writeln!(xml, "  <testsuite name=\"{}\" tests=\"{}\" \
+        failures=\"{}\" errors=\"0\" skipped=\"{}\" time=\"{:.3}\">",
+        name, cases.len(), failures, skipped, duration);
+    for case in cases.iter() {
+        let _ = write!(xml, "    <testcase classname=\"{}\" name=\"{}\" \
+            time=\"{:.3}\"", name, xml_escape(&case.name), case.duration);
+        if let Some((kind, message, details)) = &case.failure {
+            let _ = writeln!(xml, ">\n      <failure type=\"{}\" message=\"{}\">\
+                {}</failure>\n    </testcase>",
+                kind, xml_escape(message), xml_escape(details));
+        } else if let Some(reason) = case.skipped {
+            let _ = writeln!(xml, ">\n      <skipped message=\"{}\"/>\n    \
+                </testcase>", reason);
+        } else {
+            let _ = writeln!(xml, "/>");
+        }
+    }
+    xml.push_str("  </testsuite>\n</testsuites>\n");
+    write_report(path, "JUnit report", xml.as_bytes())
+}
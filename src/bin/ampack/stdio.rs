@@ -0,0 +1,98 @@
+/*
+ampack, to unpack and pack Aml burning images: stdin and stdout module
+Copyright (C) 2024-present Guoxin "7Ji" Pu
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as
+published by the Free Software Foundation, either version 3 of the
+License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::{fs::File, io::{BufWriter, IsTerminal, StdinLock, StdoutLock, Write}, path::Path};
+
+use ampack::{compress::Compression, image::Image, output, payload::chunk_size, Result};
+
+/// The path standing for stdin as an input image, or stdout as an output one
+pub(crate) const STDIO: &str = "-";
+
+pub(crate) fn is_stdio(path: &Path) -> bool {
+    path.as_os_str() == STDIO
+}
+
+/// Spool stdin into a new file at `path`, refusing if it's a terminal, as 
+/// payloads of an input image are read at their offsets, and some of them 
+/// more than once
+pub(crate) fn spool_stdin(path: &Path) -> Result<()> {
+    if std::io::stdin().is_terminal() {
+        eprintln!("Refuse to read image from stdin, as it's a terminal");
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput,
+            "stdin is a terminal").into())
+    }
+    println!("Spooling image from stdin to temporary file '{}'",
+        path.display());
+    let mut file = File::options().write(true).create_new(true).open(path)?;
+    let size = std::io::copy(&mut std::io::stdin().lock(), &mut file)?;
+    println!("Spooled {} from stdin", output::size_text_exact(size));
+    Ok(())
+}
+
+/// Stdin to read data from sequentially, refusing if it's a terminal
+pub(crate) fn stdin_reader() -> Result<StdinLock<'static>> {
+    let stdin = std::io::stdin();
+    if stdin.is_terminal() {
+        eprintln!("Refuse to read data from stdin, as it's a terminal");
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput,
+            "stdin is a terminal").into())
+    }
+    Ok(stdin.lock())
+}
+
+/// A buffered writer of data to stdout, refusing if it's a terminal
+pub(crate) fn stdout_writer() -> Result<BufWriter<StdoutLock<'static>>> {
+    let stdout = std::io::stdout();
+    if stdout.is_terminal() {
+        eprintln!("Refuse to write data to stdout, as it's a terminal");
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput,
+            "stdout is a terminal").into())
+    }
+    Ok(BufWriter::with_capacity(chunk_size(), stdout.lock()))
+}
+
+/// Write items of `image` as a tar archive to stdout, refusing if it's a 
+/// terminal, and return the size of the archive
+#[cfg(feature = "tar")]
+pub(crate) fn write_tar_stdout(image: &Image, verify: bool) -> Result<u64> {
+    let (mut writer, size) = image.try_write_tar(stdout_writer()?, verify)?;
+    writer.flush()?;
+    Ok(size)
+}
+
+/// Write `image` to stdout, compressed as `compress` if set, refusing if it's
+/// a terminal, and return its CRC32 and size before compression
+pub(crate) fn write_image_stdout(image: &Image, verify: bool, 
+                                 compress: Option<Compression>)
+    -> Result<(u32, u64)>
+{
+    let mut writer = stdout_writer()?;
+    let result = match compress {
+        #[cfg(feature = "compress")]
+        Some(format) => {
+            let mut encoder = ampack::compress::Encoder::new(&mut writer, 
+                format)?;
+            let result = image.try_write_stream(&mut encoder, verify)?;
+            encoder.finish()?;
+            result
+        },
+        _ => image.try_write_stream(&mut writer, verify)?,
+    };
+    writer.flush()?;
+    Ok(result)
+}
@@ -0,0 +1,204 @@
+/*
+ampack, to unpack and pack Aml burning images: checksum cache module
+Copyright (C) 2024-present Guoxin "7Ji" Pu
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as
+published by the Free Software Foundation, either version 3 of the
+License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Sha1sums of unchanged item files cached between packs, next to the
+//! items or in the user cache dir.
+
+use std::{collections::BTreeMap, fs::{remove_dir_all, File, Metadata}, path::{Path, PathBuf}, sync::OnceLock, time::UNIX_EPOCH};
+
+use serde::{Serialize, Deserialize};
+
+use crate::{atomic::{self, WriteFileOptions}, sha1sum::Sha1sum, Result};
+
+/// Name of the sidecar file written by unpack, recording the sha1sums of the
+/// item files it has verified, for pack to skip hashing them again
+pub const CHECKSUM_CACHE_NAME: &str = "ampack.sha1sums.yaml";
+
+/// Sha1sum of a file, only valid as long as its size and mtime (and inode, if
+/// recorded) stay the same
+#[derive(Serialize, Deserialize)]
+struct CachedChecksum {
+    size: u64,
+    mtime_secs: u64,
+    mtime_nanos: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    inode: Option<u64>,
+    sha1sum: String,
+}
+
+#[cfg(unix)]
+fn inode_from_metadata(metadata: &Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Some(metadata.ino())
+}
+
+#[cfg(not(unix))]
+fn inode_from_metadata(_metadata: &Metadata) -> Option<u64> {
+    None
+}
+
+impl CachedChecksum {
+    fn try_from_metadata(metadata: &Metadata, sha1sum: &Sha1sum, inode: bool) 
+        -> Result<Self> 
+    {
+        let mtime = metadata.modified()?.duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        Ok(Self {
+            size: metadata.len(),
+            mtime_secs: mtime.as_secs(),
+            mtime_nanos: mtime.subsec_nanos(),
+            inode: if inode { inode_from_metadata(metadata) } else { None },
+            sha1sum: sha1sum.to_string(),
+        })
+    }
+}
+
+/// Sha1sums of files in a dir, keyed by file names
+#[derive(Default, Serialize, Deserialize)]
+pub struct ChecksumCache {
+    /// Also record and match inodes, so a file replaced by another one with
+    /// the same size and mtime is not mistaken. Not for caches that travel 
+    /// with the dir, as copying the dir changes all inodes.
+    #[serde(default)]
+    inodes: bool,
+    #[serde(default)]
+    files: BTreeMap<String, CachedChecksum>,
+}
+
+static USER_CACHE_DIR: OnceLock<Option<PathBuf>> = OnceLock::new();
+
+/// Set the dir of caches private to the user instead of the default one, only
+/// the first call takes effect
+pub fn set_user_cache_dir(dir: Option<PathBuf>) {
+    let _ = USER_CACHE_DIR.set(dir);
+}
+
+/// The dir of caches private to the user, the one set, or 
+/// `$XDG_CACHE_HOME/ampack`, or `~/.cache/ampack` if that's not set, None if 
+/// neither is known
+pub fn user_cache_dir() -> Option<PathBuf> {
+    if let Some(Some(dir)) = USER_CACHE_DIR.get() {
+        return Some(dir.clone())
+    }
+    let base = match std::env::var_os("XDG_CACHE_HOME") {
+        Some(dir) if Path::new(&dir).is_absolute() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".cache"),
+    };
+    Some(base.join("ampack"))
+}
+
+/// Remove all caches private to the user
+pub fn try_clear_user_cache() -> Result<()> {
+    let dir = match user_cache_dir() {
+        Some(dir) => dir,
+        None => {
+            println!("No user cache dir on this system, nothing to clear");
+            return Ok(())
+        },
+    };
+    if ! dir.exists() {
+        println!("User cache dir '{}' does not exist, nothing to clear", 
+            dir.display());
+        return Ok(())
+    }
+    if let Err(e) = remove_dir_all(&dir) {
+        eprintln!("Failed to remove user cache dir '{}': {}", dir.display(), e);
+        return Err(e.into())
+    }
+    println!("Cleared user cache dir '{}'", dir.display());
+    Ok(())
+}
+
+impl ChecksumCache {
+    pub fn try_read_file<P: AsRef<Path>>(file: P) -> Result<Self> {
+        Ok(serde_yaml::from_reader(File::open(file.as_ref())?)?)
+    }
+
+    /// Read the cache in `dir` if there's one, any failure only results in an
+    /// empty cache, as everything would just be hashed again
+    pub fn read_dir_or_default(dir: &Path) -> Self {
+        let path = dir.join(CHECKSUM_CACHE_NAME);
+        if ! path.exists() {
+            return Self::default()
+        }
+        match Self::try_read_file(&path) {
+            Ok(cache) => cache,
+            Err(e) => {
+                warning!(Cache, "ignoring unreadable checksum cache '{}': {}",
+                    path.display(), e);
+                Self::default()
+            },
+        }
+    }
+
+    pub fn try_write_file<P: AsRef<Path>>(&self, file: P) -> Result<()> {
+        atomic::write_file(file, &WriteFileOptions::default(), |file|
+            Ok(serde_yaml::to_writer(file, self)?))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    /// The cache of sha1sums of files in `dir` private to the user, which 
+    /// could be missing or unusable, and the path it should be written to, 
+    /// None if there's no user cache dir
+    pub fn read_user_cache(dir: &Path) -> Option<(Self, PathBuf)> {
+        let dir = dir.canonicalize().ok()?;
+        let key = Sha1sum::from_data(dir.as_os_str().to_string_lossy().as_bytes());
+        let path = user_cache_dir()?.join("dirs").join(format!("{}.yaml", key));
+        let cache = match Self::try_read_file(&path) {
+            Ok(cache) if cache.inodes => cache,
+            _ => Self { inodes: true, files: BTreeMap::new() },
+        };
+        Some((cache, path))
+    }
+
+    /// An empty cache to record sha1sums into, keeping the way it matches 
+    /// files
+    pub fn emptied(&self) -> Self {
+        Self { inodes: self.inodes, files: BTreeMap::new() }
+    }
+
+    /// Record that the file `name` currently at `path` has `sha1sum`
+    pub fn insert(&mut self, name: &str, path: &Path, sha1sum: &Sha1sum)
+        -> Result<()>
+    {
+        let cached = CachedChecksum::try_from_metadata(
+            &path.metadata()?, sha1sum, self.inodes)?;
+        self.files.insert(name.into(), cached);
+        Ok(())
+    }
+
+    /// The cached sha1sum of the file `name` at `path`, if it's still the same
+    /// size and has not been modified since it was cached
+    pub fn get(&self, name: &str, path: &Path) -> Option<Sha1sum> {
+        let cached = self.files.get(name)?;
+        let metadata = path.metadata().ok()?;
+        let current = CachedChecksum::try_from_metadata(
+            &metadata, &Sha1sum::default(), self.inodes).ok()?;
+        if current.size != cached.size ||
+            current.mtime_secs != cached.mtime_secs ||
+            current.mtime_nanos != cached.mtime_nanos ||
+            current.inode != cached.inode
+        {
+            return None
+        }
+        Sha1sum::from_hex(cached.sha1sum.as_bytes()).ok()
+    }
+}
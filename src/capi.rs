@@ -0,0 +1,316 @@
+/*
+ampack, to unpack and pack Aml burning images: C API module
+Copyright (C) 2024-present Guoxin "7Ji" Pu
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as
+published by the Free Software Foundation, either version 3 of the
+License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! C API of the cdylib, see `capi/ampack.h` generated from it by cbindgen.
+//!
+//! Ownership: an `AmpackImage` is created by `ampack_image_open` and owned by
+//! the caller until passed to `ampack_image_free`. Strings handed out (item
+//! names, error messages) are owned by the library, never freed by the
+//! caller, and only valid as documented on each function. Buffers passed in
+//! are owned by the caller and never kept after a call returns.
+
+use std::{cell::RefCell, ffi::{c_char, c_void, CStr, CString}, panic::{catch_unwind, AssertUnwindSafe}, path::{Path, PathBuf}, ptr::null, sync::{Mutex, Once}};
+
+use crate::{image::{Image, ImageError, ImageRead, RawItemInfo, ReadFileOptions}, progress::{set_progress_callback, ProgressCallback}, Error};
+
+/// Result of a call, 0 for success and non-zero for failures, like the exit
+/// code of the command, with the kind of failure told apart for callers
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AmpackStatus {
+    Ok = 0,
+    /// Any failure not of the kinds below
+    Error = 1,
+    /// A null pointer, a path not in UTF-8, or an index out of range
+    InvalidArgument = 2,
+    /// The image could not be read
+    Io = 3,
+    /// The image is malformed
+    InvalidImage = 4,
+    /// The image was read, but items or the CRC32 do not match
+    Mismatch = 5,
+}
+
+impl From<&Error> for AmpackStatus {
+    fn from(e: &Error) -> Self {
+        match e {
+            Error::IOError(_) => Self::Io,
+            Error::ImageError(ImageError::ReadFailed { .. }) => Self::Io,
+            Error::ImageError(_) => Self::InvalidImage,
+            Error::Context(_, e) => e.as_ref().into(),
+            _ => Self::Error,
+        }
+    }
+}
+
+/// A failed call, with the message for `ampack_last_error`
+struct Failure {
+    status: AmpackStatus,
+    message: String,
+}
+
+impl Failure {
+    fn invalid(message: &str) -> Self {
+        Self { status: AmpackStatus::InvalidArgument, message: message.into() }
+    }
+}
+
+impl From<Error> for Failure {
+    fn from(e: Error) -> Self {
+        Self { status: (&e).into(), message: e.to_string() }
+    }
+}
+
+/// An image opened from a file, with its payloads read only when asked
+pub struct AmpackImage {
+    path: PathBuf,
+    image: Image,
+    /// Item infos of items, VERIFY ones excluded, in the order of items
+    infos: Vec<RawItemInfo>,
+    /// Names of items, handed out as C strings
+    names: Vec<CString>,
+}
+
+/// Metadata of an item, `name` is owned by the image and valid until it's
+/// freed
+#[repr(C)]
+pub struct AmpackItemInfo {
+    /// `[stem].[extension]`, e.g. `boot.PARTITION`
+    pub name: *const c_char,
+    pub size: u64,
+    /// Offset of the payload in the image file
+    pub offset: u64,
+    /// File type recorded in the item info, 0 for normal, 0xfe for sparse
+    pub file_type: u32,
+}
+
+/// Receives the progress of verification: `position` of `length` bytes of
+/// `stage` are done, with the `user_data` passed alongside it. `stage` is
+/// only valid during the call.
+pub type AmpackProgressCallback = Option<unsafe extern "C" fn(
+    stage: *const c_char, position: u64, length: u64, user_data: *mut c_void)>;
+
+thread_local! {
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+/// The C callback of the verification running, if any
+#[derive(Clone, Copy)]
+struct CCallback {
+    callback: unsafe extern "C" fn(*const c_char, u64, u64, *mut c_void),
+    user_data: *mut c_void,
+}
+
+// The user data is only handed back to the callback, making it safe to call
+// from other threads is on the caller, as documented
+unsafe impl Send for CCallback {}
+
+static CALLBACK: Mutex<Option<CCallback>> = Mutex::new(None);
+
+/// Only one verification reports its progress at a time
+static VERIFYING: Mutex<()> = Mutex::new(());
+
+/// Forwards progress to the C callback of the verification running
+struct Dispatcher;
+
+impl ProgressCallback for Dispatcher {
+    fn progress(&self, stage: &str, position: u64, length: u64) {
+        // Not called with the lock held, so a callback taking long or calling
+        // back into the library could not block or deadlock other threads
+        let callback = *CALLBACK.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(callback) = callback {
+            let stage = CString::new(stage).unwrap_or_default();
+            unsafe {
+                (callback.callback)(stage.as_ptr(), position, length,
+                    callback.user_data)
+            }
+        }
+    }
+}
+
+/// Run `f`, turning failures and panics into statuses with the message kept
+/// for `ampack_last_error`
+fn guard<F>(f: F) -> AmpackStatus 
+where
+    F: FnOnce() -> std::result::Result<(), Failure>
+{
+    let (status, message) = match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => (AmpackStatus::Ok, String::new()),
+        Ok(Err(failure)) => (failure.status, failure.message),
+        Err(_) => (AmpackStatus::Error, "panicked".into()),
+    };
+    LAST_ERROR.with(|last| *last.borrow_mut() =
+        CString::new(message).unwrap_or_default());
+    status
+}
+
+/// The message of the last failed call on this thread, empty if it
+/// succeeded. Owned by the library and valid until the next call on this
+/// thread.
+#[no_mangle]
+pub extern "C" fn ampack_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ptr())
+}
+
+/// Open the image file at `path` and store a new image owned by the caller
+/// into `out`, to be freed with `ampack_image_free`
+///
+/// # Safety
+/// `path` must be a NUL-terminated string and `out` must be valid for writes
+#[no_mangle]
+pub unsafe extern "C" fn ampack_image_open(
+    path: *const c_char, out: *mut *mut AmpackImage
+)
+    -> AmpackStatus
+{
+    guard(|| {
+        if path.is_null() || out.is_null() {
+            return Err(Failure::invalid("path or out is null"))
+        }
+        let path = match CStr::from_ptr(path).to_str() {
+            Ok(path) => Path::new(path),
+            Err(_) => return Err(Failure::invalid("path is not in UTF-8")),
+        };
+        let ImageRead { image, infos, .. } = Image::try_read_file_with_table(
+            path, &ReadFileOptions::default(), false)?;
+        let infos: Vec<RawItemInfo> = infos.into_iter()
+            .filter(|info|info.item_main_type != "VERIFY").collect();
+        let names = image.items().iter().map(|item|
+            CString::new(item.name()).unwrap_or_default()).collect();
+        *out = Box::into_raw(Box::new(AmpackImage { 
+            path: path.into(), image, infos, names }));
+        Ok(())
+    })
+}
+
+/// Free an image opened by `ampack_image_open`, doing nothing on null
+///
+/// # Safety
+/// `image` must be null or from `ampack_image_open` and not freed yet
+#[no_mangle]
+pub unsafe extern "C" fn ampack_image_free(image: *mut AmpackImage) {
+    if ! image.is_null() {
+        drop(Box::from_raw(image))
+    }
+}
+
+/// Count of items in the image, VERIFY ones excluded, 0 for null
+///
+/// # Safety
+/// `image` must be null or a valid image
+#[no_mangle]
+pub unsafe extern "C" fn ampack_image_item_count(image: *const AmpackImage)
+    -> usize
+{
+    image.as_ref().map(|image|image.image.items().len()).unwrap_or_default()
+}
+
+/// Store the metadata of the item at `index` into `out`
+///
+/// # Safety
+/// `image` must be a valid image and `out` must be valid for writes
+#[no_mangle]
+pub unsafe extern "C" fn ampack_image_item_info(
+    image: *const AmpackImage, index: usize, out: *mut AmpackItemInfo
+)
+    -> AmpackStatus
+{
+    guard(|| {
+        let image = match image.as_ref() {
+            Some(image) if ! out.is_null() => image,
+            _ => return Err(Failure::invalid("image or out is null")),
+        };
+        let (item, info) = match (image.image.items().get(index),
+            image.infos.get(index))
+        {
+            (Some(item), Some(info)) => (item, info),
+            _ => return Err(Failure::invalid("index out of range")),
+        };
+        *out = AmpackItemInfo {
+            name: image.names.get(index).map(|name|name.as_ptr())
+                .unwrap_or(null()),
+            size: item.size(),
+            offset: info.offset_in_image,
+            file_type: info.file_type,
+        };
+        Ok(())
+    })
+}
+
+/// Read up to `len` bytes of the item at `index` from `offset` in it into
+/// `buffer`, and store the count of bytes read into `read`, which is less
+/// than `len` only at the end of the item
+///
+/// # Safety
+/// `image` must be a valid image, `buffer` must be valid for `len` bytes of
+/// writes, and `read` must be valid for writes
+#[no_mangle]
+pub unsafe extern "C" fn ampack_image_read_item(
+    image: *const AmpackImage, index: usize, offset: u64, buffer: *mut u8,
+    len: usize, read: *mut usize
+)
+    -> AmpackStatus
+{
+    guard(|| {
+        let item = match image.as_ref() {
+            Some(image) if ! buffer.is_null() && ! read.is_null() =>
+                match image.image.items().get(index) {
+                    Some(item) => item,
+                    None => return Err(Failure::invalid("index out of range")),
+                },
+            _ => return Err(Failure::invalid("image, buffer or read is null")),
+        };
+        *read = item.try_read_at(offset, 
+            std::slice::from_raw_parts_mut(buffer, len))?;
+        Ok(())
+    })
+}
+
+/// Verify the image against the file it was opened from, calling `callback`
+/// with `user_data` as it goes if it's not null. Returns `Mismatch` if any
+/// item or the CRC32 does not match. Verifications run one at a time.
+///
+/// # Safety
+/// `image` must be a valid image, and `callback` must be safe to call with
+/// `user_data` until this returns
+#[no_mangle]
+pub unsafe extern "C" fn ampack_image_verify(
+    image: *const AmpackImage, callback: AmpackProgressCallback, 
+    user_data: *mut c_void
+)
+    -> AmpackStatus
+{
+    static DISPATCHER: Once = Once::new();
+    guard(|| {
+        let image = match image.as_ref() {
+            Some(image) => image,
+            None => return Err(Failure::invalid("image is null")),
+        };
+        DISPATCHER.call_once(|| set_progress_callback(Box::new(Dispatcher)));
+        let _verifying = VERIFYING.lock().unwrap_or_else(|e| e.into_inner());
+        *CALLBACK.lock().unwrap_or_else(|e| e.into_inner()) =
+            callback.map(|callback| CCallback { callback, user_data });
+        let report = image.image.verify(&image.path, false, None, false);
+        *CALLBACK.lock().unwrap_or_else(|e| e.into_inner()) = None;
+        match report?.error() {
+            Some(e) => Err(Failure { 
+                status: AmpackStatus::Mismatch, message: e.to_string() }),
+            None => Ok(()),
+        }
+    })
+}
@@ -0,0 +1,350 @@
+/*
+ampack, to unpack and pack Aml burning images: item checksum module
+Copyright (C) 2024-present Guoxin "7Ji" Pu
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as
+published by the Free Software Foundation, either version 3 of the
+License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Checksums of items and digests of whole files, and the sidecar files
+//! they're published in.
+
+use std::{fmt::Display, fs::read_to_string, io::Write, path::{Path, PathBuf}};
+
+use sha2::Digest;
+
+use crate::{atomic::{self, WriteFileOptions}, crc32::Crc32Hasher, sha1sum::Sha1sumHasher, Error, Result};
+
+/// Algorithms to list checksums of items with. Only sha1 is recorded in 
+/// images, the others are only for comparing items and never written into 
+/// images.
+#[derive(Default, Debug, Clone, Copy, serde::Serialize)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+#[serde(rename_all = "lowercase")]
+pub enum ChecksumAlgo {
+    #[default]
+    Sha1,
+    /// The standard CRC32, not the Amlogic variant in the image header
+    Crc32,
+    /// Much faster than sha1 on large items
+    #[cfg(feature = "blake3")]
+    Blake3,
+}
+
+impl std::fmt::Display for ChecksumAlgo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Sha1 => "sha1",
+            Self::Crc32 => "crc32",
+            #[cfg(feature = "blake3")]
+            Self::Blake3 => "blake3",
+        })
+    }
+}
+
+/// Incremental hasher of any of the `ChecksumAlgo`s
+pub enum ChecksumHasher {
+    Sha1(Sha1sumHasher),
+    Crc32(Crc32Hasher),
+    #[cfg(feature = "blake3")]
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl ChecksumHasher {
+    pub fn new(algo: ChecksumAlgo) -> Self {
+        match algo {
+            ChecksumAlgo::Sha1 => Self::Sha1(Sha1sumHasher::new()),
+            ChecksumAlgo::Crc32 => Self::Crc32(Crc32Hasher::new()),
+            #[cfg(feature = "blake3")]
+            ChecksumAlgo::Blake3 => Self::Blake3(Box::default()),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Sha1(hasher) => hasher.update(data),
+            Self::Crc32(hasher) => hasher.update(data),
+            #[cfg(feature = "blake3")]
+            Self::Blake3(hasher) => { hasher.update(data); },
+        }
+    }
+
+    /// The checksum in lowercase hex, as the usual `[algo]sum` tools print
+    pub fn finalize(self) -> String {
+        match self {
+            Self::Sha1(hasher) => hasher.finalize().to_string(),
+            Self::Crc32(hasher) => format!("{:08x}", hasher.finalize_standard()),
+            #[cfg(feature = "blake3")]
+            Self::Blake3(hasher) => hasher.finalize().to_hex().to_string(),
+        }
+    }
+}
+
+/// Algorithms of digests of whole image files, as published next to 
+/// downloads in sidecar files like `firmware.img.sha256` or `md5sums.txt`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+#[serde(rename_all = "lowercase")]
+pub enum DigestAlgo {
+    Sha1,
+    Sha256,
+    Md5,
+}
+
+impl Display for DigestAlgo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Sha1 => "sha1",
+            Self::Sha256 => "sha256",
+            Self::Md5 => "md5",
+        })
+    }
+}
+
+impl DigestAlgo {
+    pub const ALL: [Self; 3] = [Self::Sha256, Self::Sha1, Self::Md5];
+
+    /// The algorithm of a digest in hex of `len` chars, they all differ
+    pub fn from_hex_len(len: usize) -> Option<Self> {
+        Self::ALL.into_iter().find(|algo|algo.hex_len() == len)
+    }
+
+    pub fn hex_len(&self) -> usize {
+        match self {
+            DigestAlgo::Sha1 => 40,
+            DigestAlgo::Sha256 => 64,
+            DigestAlgo::Md5 => 32,
+        }
+    }
+
+    /// Suffix of the sidecar file of an image, e.g. `sha256` for 
+    /// `firmware.img.sha256`
+    pub fn extension(&self) -> &'static str {
+        match self {
+            DigestAlgo::Sha1 => "sha1",
+            DigestAlgo::Sha256 => "sha256",
+            DigestAlgo::Md5 => "md5",
+        }
+    }
+}
+
+/// Incremental hasher of any of the `DigestAlgo`s
+pub enum DigestHasher {
+    Sha1(Sha1sumHasher),
+    Sha256(sha2::Sha256),
+    Md5(md5::Context),
+}
+
+impl DigestHasher {
+    pub fn new(algo: DigestAlgo) -> Self {
+        match algo {
+            DigestAlgo::Sha1 => Self::Sha1(Sha1sumHasher::new()),
+            DigestAlgo::Sha256 => Self::Sha256(sha2::Sha256::new()),
+            DigestAlgo::Md5 => Self::Md5(md5::Context::new()),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Sha1(hasher) => hasher.update(data),
+            Self::Sha256(hasher) => hasher.update(data),
+            Self::Md5(hasher) => hasher.consume(data),
+        }
+    }
+
+    pub fn finalize(self) -> FileDigest {
+        let (algo, digest) = match self {
+            Self::Sha1(hasher) => 
+                (DigestAlgo::Sha1, hasher.finalize().to_string()),
+            Self::Sha256(hasher) => 
+                (DigestAlgo::Sha256, hex::encode(hasher.finalize())),
+            Self::Md5(hasher) => 
+                (DigestAlgo::Md5, format!("{:x}", hasher.compute())),
+        };
+        FileDigest { algo, digest }
+    }
+}
+
+/// A writer hashing everything written through it with any number of the
+/// `DigestAlgo`s, e.g. to digest an output file as it's written
+pub struct DigestWriter<W: Write> {
+    inner: W,
+    hashers: Vec<DigestHasher>,
+}
+
+impl<W: Write> DigestWriter<W> {
+    pub fn new(inner: W, algos: &[DigestAlgo]) -> Self {
+        Self { inner, 
+            hashers: algos.iter().copied().map(DigestHasher::new).collect() }
+    }
+
+    /// Return the inner writer and the digests of everything written
+    pub fn finish(self) -> (W, Vec<FileDigest>) {
+        (self.inner, 
+            self.hashers.into_iter().map(DigestHasher::finalize).collect())
+    }
+}
+
+impl<W: Write> Write for DigestWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let size = self.inner.write(buf)?;
+        for hasher in self.hashers.iter_mut() {
+            hasher.update(&buf[..size])
+        }
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A digest of a whole file, in lowercase hex
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct FileDigest {
+    pub algo: DigestAlgo,
+    pub digest: String,
+}
+
+impl Display for FileDigest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.algo, self.digest)
+    }
+}
+
+#[derive(Debug)]
+pub enum ChecksumError {
+    /// No digest for the file in the checksum file
+    NoEntry {
+        name: String,
+        path: PathBuf,
+    },
+    /// The digest calculated does not match the one expected
+    Mismatch {
+        expected: FileDigest,
+        calculated: String,
+    },
+}
+
+impl From<ChecksumError> for Error {
+    fn from(value: ChecksumError) -> Self {
+        Self::ChecksumError(value)
+    }
+}
+
+impl Display for ChecksumError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Checksum Error: ")?;
+        match self {
+            ChecksumError::NoEntry { name, path } =>
+                write!(f, "No Digest of '{}' in '{}'", name, path.display()),
+            ChecksumError::Mismatch { expected, calculated } =>
+                write!(f, "{} Mismatch: expected {}, calculated {}", 
+                    expected.algo, expected.digest, calculated),
+        }
+    }
+}
+
+impl std::error::Error for ChecksumError {}
+
+/// Find the digest of the file named `name` in the content of a checksum 
+/// file, either a bare digest taken for any file, or lines of the coreutils
+/// format `[digest]  [file]` (`*[file]` for binary mode) matched on the
+/// basename of `[file]`. The algorithm is told by the length of the digest.
+pub fn find_digest(content: &str, name: &str) -> Option<FileDigest> {
+    let parse = |digest: &str| DigestAlgo::from_hex_len(digest.len())
+        .filter(|_|digest.bytes().all(|byte|byte.is_ascii_hexdigit()))
+        .map(|algo|FileDigest { algo, digest: digest.to_ascii_lowercase() });
+    let mut bare = None;
+    for line in content.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue
+        }
+        match line.split_once(char::is_whitespace) {
+            Some((digest, file)) => {
+                let file = file.trim_start();
+                let file = file.strip_prefix('*').unwrap_or(file);
+                if file.rsplit(['/', '\\']).next() == Some(name) {
+                    if let Some(digest) = parse(digest) {
+                        return Some(digest)
+                    }
+                }
+            },
+            None => if bare.is_none() {
+                bare = parse(line)
+            },
+        }
+    }
+    bare
+}
+
+/// Read the checksum file at `path` for the digest of the file named `name`,
+/// see `find_digest`
+pub fn try_read_digest<P: AsRef<Path>>(path: P, name: &str) 
+    -> Result<FileDigest> 
+{
+    let path = path.as_ref();
+    let content = match read_to_string(path) {
+        Ok(content) => content,
+        Err(e) => {
+            eprintln!("Failed to read checksum file '{}': {}", 
+                path.display(), e);
+            return Err(e.into())
+        },
+    };
+    match find_digest(&content, name) {
+        Some(digest) => Ok(digest),
+        None => {
+            eprintln!("Checksum file '{}' has no sha1, sha256 or md5 digest \
+                of '{}', neither bare nor in a '[digest]  {}' line", 
+                path.display(), name, name);
+            Err(ChecksumError::NoEntry { name: name.into(), path: path.into() }
+                .into())
+        },
+    }
+}
+
+/// The first existing sidecar file of the image at `path`, i.e. 
+/// `[path].sha256`, `[path].sha1` or `[path].md5`
+pub fn find_sidecar<P: AsRef<Path>>(path: P) -> Option<PathBuf> {
+    DigestAlgo::ALL.into_iter().map(|algo| {
+        let mut sidecar = path.as_ref().as_os_str().to_owned();
+        sidecar.push(".");
+        sidecar.push(algo.extension());
+        PathBuf::from(sidecar)
+    }).find(|sidecar|sidecar.is_file())
+}
+
+/// Write each of `digests` of the file at `path` into its sidecar file 
+/// `[path].[algo]`, in the coreutils format `[digest]  [file name]` so it
+/// could be checked with e.g. `sha256sum -c` in the same dir, and return the
+/// sidecar paths
+pub fn try_write_sidecars<P: AsRef<Path>>(
+    path: P, digests: &[FileDigest], options: &WriteFileOptions
+) 
+    -> Result<Vec<PathBuf>>
+{
+    let path = path.as_ref();
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    digests.iter().map(|digest| {
+        let mut sidecar = path.as_os_str().to_owned();
+        sidecar.push(".");
+        sidecar.push(digest.algo.extension());
+        let sidecar = PathBuf::from(sidecar);
+        atomic::write_file(&sidecar, options, |file| 
+            Ok(writeln!(file, "{}  {}", digest.digest, name)?))?;
+        println!("Wrote {} digest of '{}' to '{}'", digest.algo, 
+            path.display(), sidecar.display());
+        Ok(sidecar)
+    }).collect()
+}
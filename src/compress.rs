@@ -0,0 +1,215 @@
+/*
+ampack, to unpack and pack Aml burning images: compress module
+Copyright (C) 2024-present Guoxin "7Ji" Pu
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as
+published by the Free Software Foundation, either version 3 of the
+License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Compressed images, detected by their magic, decompressed to be read
+//! and compressed when written.
+
+use std::{fmt::Display, fs::File, io::Read, path::Path};
+#[cfg(feature = "compress")]
+use std::io::Write;
+
+use crate::{Error, Result};
+
+/// Formats images could be compressed in as whole files, e.g. `.img.xz` on
+/// mirrors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum Compression {
+    Gz,
+    Xz,
+    Zst,
+}
+
+impl Display for Compression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Compression::Gz => "gzip",
+            Compression::Xz => "xz",
+            Compression::Zst => "zstd",
+        })
+    }
+}
+
+impl Compression {
+    const MAGICS: [(Self, &'static [u8]); 3] = [
+        (Self::Gz, b"\x1f\x8b"),
+        (Self::Xz, b"\xfd7zXZ\x00"),
+        (Self::Zst, b"\x28\xb5\x2f\xfd"),
+    ];
+
+    /// Length of the longest magic, enough of a head to `detect`
+    pub const SIZE_MAGIC: usize = 6;
+
+    /// The format a file starting with `head` is compressed in, by its magic,
+    /// or None if it's not compressed in any of them
+    pub fn detect(head: &[u8]) -> Option<Self> {
+        Self::MAGICS.iter()
+            .find(|(_, prefix)|head.starts_with(prefix))
+            .map(|(compression, _)|*compression)
+    }
+
+    /// The format the file at `path` is compressed in, see `detect`
+    pub fn detect_file<P: AsRef<Path>>(path: P) -> Result<Option<Self>> {
+        let mut head = Vec::with_capacity(Self::SIZE_MAGIC);
+        File::open(path)?.take(Self::SIZE_MAGIC as u64)
+            .read_to_end(&mut head)?;
+        Ok(Self::detect(&head))
+    }
+}
+
+#[derive(Debug)]
+pub enum CompressError {
+    /// The stream ended before its end marker, e.g. a partial download
+    Truncated {
+        format: Compression
+    },
+    /// The stream itself could not be decoded, regardless of what's inside
+    Corrupt {
+        format: Compression,
+        reason: String
+    },
+}
+
+impl From<CompressError> for Error {
+    fn from(value: CompressError) -> Self {
+        Self::CompressError(value)
+    }
+}
+
+impl Display for CompressError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Compress Error: ")?;
+        match self {
+            CompressError::Truncated { format } =>
+                write!(f, "Truncated {} Stream", format),
+            CompressError::Corrupt { format, reason } =>
+                write!(f, "Corrupt {} Stream: {}", format, reason),
+        }
+    }
+}
+
+impl std::error::Error for CompressError {}
+
+/// The compressed file being decoded, remembering whether an error came from
+/// reading the file itself rather than from decoding it
+#[cfg(feature = "compress")]
+struct Source {
+    file: File,
+    failed: bool,
+}
+
+#[cfg(feature = "compress")]
+impl Read for Source {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let result = self.file.read(buf);
+        self.failed = result.is_err();
+        result
+    }
+}
+
+/// Decompress the file at `path` compressed as `format` into a new file at
+/// `to`, and return the decompressed size. A stream ending early fails as
+/// `CompressError::Truncated`, one not decodable as `CompressError::Corrupt`,
+/// so neither is mistaken for a corrupt image inside.
+#[cfg(feature = "compress")]
+pub fn decompress_file<P1, P2>(path: P1, format: Compression, to: P2)
+    -> Result<u64>
+where
+    P1: AsRef<Path>,
+    P2: AsRef<Path>
+{
+    let path = path.as_ref();
+    let mut source = Source { file: File::open(path)?, failed: false };
+    let mut out_file = File::options().write(true).create_new(true)
+        .open(to.as_ref())?;
+    let result = match format {
+        Compression::Gz => std::io::copy(
+            &mut flate2::read::MultiGzDecoder::new(&mut source), &mut out_file),
+        Compression::Xz => std::io::copy(
+            &mut xz2::read::XzDecoder::new_multi_decoder(&mut source),
+            &mut out_file),
+        Compression::Zst => match zstd::Decoder::new(&mut source) {
+            Ok(mut decoder) => std::io::copy(&mut decoder, &mut out_file),
+            Err(e) => Err(e),
+        },
+    };
+    match result {
+        Ok(size) => Ok(size),
+        Err(e) if source.failed => Err(e.into()),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+            eprintln!("The {} stream of '{}' ended early, the file is \
+                truncated, e.g. not fully downloaded", format, path.display());
+            Err(CompressError::Truncated { format }.into())
+        },
+        Err(e) => {
+            eprintln!("The {} stream of '{}' could not be decoded: {}",
+                format, path.display(), e);
+            Err(CompressError::Corrupt { format, reason: e.to_string() }.into())
+        },
+    }
+}
+
+/// A writer compressing everything written into it as one of the
+/// `Compression`s
+#[cfg(feature = "compress")]
+pub enum Encoder<W: Write> {
+    Gz(flate2::write::GzEncoder<W>),
+    Xz(xz2::write::XzEncoder<W>),
+    Zst(zstd::Encoder<'static, W>),
+}
+
+#[cfg(feature = "compress")]
+impl<W: Write> Encoder<W> {
+    /// Compress into `writer` with the default level of `format`
+    pub fn new(writer: W, format: Compression) -> Result<Self> {
+        Ok(match format {
+            Compression::Gz => Self::Gz(flate2::write::GzEncoder::new(
+                writer, flate2::Compression::default())),
+            Compression::Xz => Self::Xz(xz2::write::XzEncoder::new(writer, 6)),
+            Compression::Zst => Self::Zst(zstd::Encoder::new(writer, 0)?),
+        })
+    }
+
+    /// Write the end of the stream, and return the inner writer
+    pub fn finish(self) -> Result<W> {
+        Ok(match self {
+            Encoder::Gz(encoder) => encoder.finish()?,
+            Encoder::Xz(encoder) => encoder.finish()?,
+            Encoder::Zst(encoder) => encoder.finish()?,
+        })
+    }
+}
+
+#[cfg(feature = "compress")]
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Encoder::Gz(encoder) => encoder.write(buf),
+            Encoder::Xz(encoder) => encoder.write(buf),
+            Encoder::Zst(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Encoder::Gz(encoder) => encoder.flush(),
+            Encoder::Xz(encoder) => encoder.flush(),
+            Encoder::Zst(encoder) => encoder.flush(),
+        }
+    }
+}
@@ -16,20 +16,22 @@ You should have received a copy of the GNU Affero General Public License
 along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
-use std::{fs::File, io::Read, path::Path};
+//! The CRC32 in the image header, calculated as the image is written.
 
-use indicatif::ProgressBar;
+use std::{fs::File, io::{Read, Seek, SeekFrom, Write}, path::Path, sync::OnceLock};
 
-use crate::Result;
+use crate::{payload::chunk_size, Result};
 
-#[derive(Clone, Copy)]
+/// Lookup tables for slicing-by-8: the first one is the classic byte-wise
+/// table of the reflected polynomial 0xedb88320, and each later one advances
+/// a byte's contribution through one more zero byte
 struct Crc32Table {
-    table: [u32; 0x100]
+    tables: [[u32; 0x100]; 8]
 }
 
 impl Default for Crc32Table {
     fn default() -> Self {
-        let mut table = [0; 0x100];
+        let mut tables = [[0; 0x100]; 8];
         for id in 0..0x100 {
             let mut byte = id;
             for _ in 0..8 {
@@ -40,62 +42,256 @@ impl Default for Crc32Table {
                     byte = int ^ 0xedb88320;
                 }
             }
-            table[id as usize] = byte
+            tables[0][id as usize] = byte
         }
-        Self { table }
+        for id in 0..0x100 {
+            for slice in 1..8 {
+                let previous = tables[slice - 1][id];
+                tables[slice][id] =
+                    previous >> 8 ^ tables[0][(previous & 0xff) as usize]
+            }
+        }
+        Self { tables }
     }
 }
 
-pub(crate) struct Crc32Hasher {
-    pub(crate) value: u32,
-    table: Crc32Table,
+static TABLE: OnceLock<Crc32Table> = OnceLock::new();
+
+/// The Amlogic variant of CRC32 as stored in the image header: initialized to
+/// 0xffffffff like the standard one, but without the final XOR
+pub struct Crc32Hasher {
+    pub value: u32,
 }
 
 impl Default for Crc32Hasher {
     fn default() -> Self {
         Self { 
             value: 0xffffffff,
-            table: Crc32Table::default()
         }
     }
 }
 
 impl Crc32Hasher {
-    pub(crate) fn new() -> Self {
+    pub fn new() -> Self {
         Self::default()
     }
 
-    pub(crate) fn update(&mut self, data: &[u8]) {
-        for byte in data.iter() {
-            self.value = self.table.table[
-                ((self.value ^ *byte as u32) & 0xff) as usize
-            ] ^ self.value >> 8;
+    pub fn update(&mut self, data: &[u8]) {
+        let tables = &TABLE.get_or_init(Crc32Table::default).tables;
+        let mut value = self.value;
+        let mut chunks = data.chunks_exact(8);
+        for chunk in &mut chunks {
+            let low = value ^ u32::from_le_bytes(
+                [chunk[0], chunk[1], chunk[2], chunk[3]]);
+            let high = u32::from_le_bytes(
+                [chunk[4], chunk[5], chunk[6], chunk[7]]);
+            value =
+                tables[7][(low & 0xff) as usize] ^ 
+                tables[6][(low >> 8 & 0xff) as usize] ^
+                tables[5][(low >> 16 & 0xff) as usize] ^
+                tables[4][(low >> 24) as usize] ^
+                tables[3][(high & 0xff) as usize] ^
+                tables[2][(high >> 8 & 0xff) as usize] ^
+                tables[1][(high >> 16 & 0xff) as usize] ^
+                tables[0][(high >> 24) as usize];
         }
+        for byte in chunks.remainder() {
+            value = tables[0][((value ^ *byte as u32) & 0xff) as usize] ^ 
+                value >> 8;
+        }
+        self.value = value
+    }
+
+    /// The standard CRC32, as given by e.g. crc32(1) or 7-Zip, which applies
+    /// the final XOR the Amlogic variant in `value` lacks
+    pub fn finalize_standard(&self) -> u32 {
+        ! self.value
     }
 
-    pub(crate) fn udpate_with_bar(&mut self, data: &[u8], bar: &ProgressBar) {
-        for chunk in data.chunks(0x100000) {
-            self.update(chunk);
-            bar.inc(1)
+    /// Hash an image file for both variants in one pass: the Amlogic one as 
+    /// recorded in the header, over everything after the CRC field, and the
+    /// one over the whole file, to be finalized to the standard CRC32
+    pub fn try_hash_image_file<P: AsRef<Path>>(file: P) 
+        -> Result<(Self, Self)> 
+    {
+        Self::try_hash_image_from(File::open(file)?)
+    }
+
+    /// Hash the image read from `reader`, see `try_hash_image_file`
+    pub fn try_hash_image_from<R: Read>(file: R) -> Result<(Self, Self)> {
+        let (amlogic, whole, _) = Self::try_hash_from(file, false)?;
+        Ok((amlogic, whole))
+    }
+
+    /// Hash everything read from `reader` sequentially, never seeking, for
+    /// both variants as `try_hash_image_file` does, and also return the size
+    /// read. With `raw` the Amlogic variant is over the whole input too, 
+    /// instead of skipping the CRC field of an image, e.g. for a partition
+    /// file; then it's the standard one without the final XOR.
+    pub fn try_hash_from<R: Read>(mut file: R, raw: bool) 
+        -> Result<(Self, Self, u64)> 
+    {
+        let mut whole = Self::new();
+        let mut size_read = 0;
+        if ! raw {
+            let mut buffer = [0; 4];
+            file.read_exact(&mut buffer)?;
+            println!("CRC32 checksum recorded in file is \
+                0x{:02x}{:02x}{:02x}{:02x}", 
+                buffer[3], buffer[2], buffer[1], buffer[0]);
+            whole.update(&buffer);
+            size_read += 4
+        }
+        let mut amlogic = Self::new();
+        let mut buffer = vec![0; chunk_size()];
+        loop {
+            let size = match file.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(size) => size,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+            amlogic.update(&buffer[0..size]);
+            whole.update(&buffer[0..size]);
+            size_read += size as u64
         }
+        Ok((amlogic, whole, size_read))
     }
+}
 
-    pub(crate) fn from_reader<R: Read>(mut reader: R) -> Self {
-        let mut crc32 = Self::new();
-        let mut buffer = [0; 0x100000];
-        while let Ok(size) = reader.read(&mut buffer) {
-            if size == 0 { break };
-            crc32.update(&buffer[0..size])
+/// A writer hashing the image written through it in the same pass, except 
+/// the leading CRC field of the header, which `finish` patches in at the end
+pub struct Crc32ImageWriter<'a, W: Write + Seek> {
+    inner: &'a mut W,
+    hasher: Crc32Hasher,
+    written: u64,
+}
+
+impl<'a, W: Write + Seek> Crc32ImageWriter<'a, W> {
+    pub fn new(inner: &'a mut W) -> Self {
+        Self {
+            inner,
+            hasher: Crc32Hasher::new(),
+            written: 0,
         }
-        crc32
     }
 
-    pub(crate) fn try_hash_image_file<P: AsRef<Path>>(file: P) -> Result<Self> {
-        let mut file = File::open(file)?;
-        let mut buffer = [0; 4];
-        file.read_exact(&mut buffer)?;
-        println!("CRC32 checksum recorded in file is 0x{:02x}{:02x}{:02x}{:02x}",
-            buffer[3], buffer[2], buffer[1], buffer[0]);
-        Ok(Self::from_reader(file))
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    /// Patch the CRC into the header, leave the inner writer at the end, and
+    /// return the CRC
+    pub fn finish(self) -> Result<u32> {
+        self.inner.seek(SeekFrom::Start(0))?;
+        self.inner.write_all(&self.hasher.value.to_le_bytes())?;
+        self.inner.seek(SeekFrom::End(0))?;
+        Ok(self.hasher.value)
+    }
+}
+
+impl<W: Write + Seek> Write for Crc32ImageWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let size = self.inner.write(buf)?;
+        let skip = 4_u64.saturating_sub(self.written).min(size as u64);
+        self.hasher.update(&buf[skip as usize..size]);
+        self.written += size as u64;
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The classic byte-at-a-time CRC32 without the final XOR, computing its
+    /// table bit by bit, for the slicing-by-8 one to be checked against
+    fn reference(data: &[u8]) -> u32 {
+        let mut value = 0xffffffff_u32;
+        for byte in data {
+            value ^= *byte as u32;
+            for _ in 0..8 {
+                value = if value & 1 == 0 {
+                    value >> 1
+                } else {
+                    value >> 1 ^ 0xedb88320
+                }
+            }
+        }
+        value
+    }
+
+    fn hash(data: &[u8]) -> Crc32Hasher {
+        let mut hasher = Crc32Hasher::new();
+        hasher.update(data);
+        hasher
+    }
+
+    #[test]
+    fn check_value() {
+        let hasher = hash(b"123456789");
+        assert_eq!(hasher.finalize_standard(), 0xcbf43926);
+        assert_eq!(hasher.value, !0xcbf43926);
+    }
+
+    #[test]
+    fn lengths_around_slices() {
+        let data: Vec<u8> = (0..4097_u32).map(|id|(id * 131 + 7) as u8)
+            .collect();
+        for len in [0, 1, 7, 8, 9, 4097] {
+            assert_eq!(hash(&data[..len]).value, reference(&data[..len]), 
+                "length {}", len)
+        }
+    }
+
+    #[test]
+    fn split_updates() {
+        let data: Vec<u8> = (0..1000_u32).map(|id|(id * 7) as u8).collect();
+        let mut hasher = Crc32Hasher::new();
+        for chunk in data.chunks(13) {
+            hasher.update(chunk)
+        }
+        assert_eq!(hasher.value, reference(&data));
+    }
+
+    #[test]
+    fn standard_known_answers() {
+        assert_eq!(hash(b"").finalize_standard(), 0);
+        assert_eq!(hash(b"123456789").finalize_standard(), 0xcbf43926);
+        assert_eq!(hash(b"\x12\x34\x56\x78123456789").finalize_standard(), 
+            0x9468a7eb);
+    }
+
+    #[test]
+    fn hash_from_image_skips_crc_field() {
+        let image = b"\x12\x34\x56\x78123456789";
+        let (amlogic, whole, size) = 
+            Crc32Hasher::try_hash_from(&image[..], false).unwrap();
+        assert_eq!(amlogic.value, !0xcbf43926);
+        assert_eq!(whole.finalize_standard(), 0x9468a7eb);
+        assert_eq!(size, 13);
+        let (amlogic_image, whole_image) = 
+            Crc32Hasher::try_hash_image_from(&image[..]).unwrap();
+        assert_eq!(amlogic_image.value, amlogic.value);
+        assert_eq!(whole_image.value, whole.value);
+    }
+
+    #[test]
+    fn hash_from_raw_hashes_everything() {
+        let (amlogic, whole, size) = 
+            Crc32Hasher::try_hash_from(&b"123456789"[..], true).unwrap();
+        assert_eq!(amlogic.value, !0xcbf43926);
+        assert_eq!(amlogic.finalize_standard(), 0xcbf43926);
+        assert_eq!(whole.finalize_standard(), 0xcbf43926);
+        assert_eq!(size, 9);
+    }
+
+    #[test]
+    fn hash_from_image_too_short() {
+        assert!(Crc32Hasher::try_hash_from(&b"\x12\x34"[..], false).is_err())
     }
 }
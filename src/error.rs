@@ -16,15 +16,110 @@ You should have received a copy of the GNU Affero General Public License
 along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
-use std::fmt::Display;
+//! The error type of the crate, one variant per module, with the context
+//! of what was being done when it happened.
+
+use std::{fmt::Display, path::{Path, PathBuf}};
+
+use crate::output;
 
 #[derive(Debug)]
-pub(crate) enum Error {
+#[allow(clippy::enum_variant_names)]
+pub enum Error {
     IOError (std::io::Error),
     NulError (std::ffi::NulError),
     FromHexError (hex::FromHexError),
+    #[cfg(feature = "progress-bars")]
     TemplateError (indicatif::style::TemplateError),
     ImageError (crate::image::ImageError),
+    ArchiveError (crate::archive::ArchiveError),
+    #[cfg(feature = "zip")]
+    ZipError (zip::result::ZipError),
+    CompressError (crate::compress::CompressError),
+    ChecksumError (crate::checksum::ChecksumError),
+    #[cfg(feature = "http")]
+    HttpError (Box<ureq::Error>),
+    YAMLError (serde_yaml::Error),
+    JSONError (serde_json::Error),
+    CSVError (csv::Error),
+    ThreadPoolBuildError (rayon::ThreadPoolBuildError),
+    /// An error with where it happened
+    Context (ErrorContext, Box<Error>),
+    /// Count of warnings promoted to errors by --strict
+    PromotedWarnings (usize),
+    /// A destructive action not confirmed by the user
+    NotConfirmed,
+    /// Name of the item and how the hook command run on it failed
+    HookFailed (String, String),
+}
+
+/// Where an error happened: what was being done to which file, and to which
+/// item and region in it when applicable
+#[derive(Debug, Default)]
+pub struct ErrorContext {
+    writing: bool,
+    path: Option<PathBuf>,
+    item: Option<String>,
+    /// Offset and size of the region involved
+    region: Option<(u64, u64)>,
+}
+
+impl ErrorContext {
+    pub fn reading() -> Self {
+        Self::default()
+    }
+
+    pub fn writing() -> Self {
+        Self { writing: true, ..Default::default() }
+    }
+
+    pub fn path<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.path = Some(path.as_ref().into());
+        self
+    }
+
+    pub fn item(mut self, name: &str) -> Self {
+        self.item = Some(name.into());
+        self
+    }
+
+    pub fn region(mut self, offset: u64, size: u64) -> Self {
+        self.region = Some((offset, size));
+        self
+    }
+
+    /// Fill the fields missing in this context from the outer `context`
+    fn fill(&mut self, context: Self) {
+        if self.path.is_none() {
+            self.path = context.path
+        }
+        if self.item.is_none() {
+            self.item = context.item
+        }
+        if self.region.is_none() {
+            self.region = context.region
+        }
+    }
+}
+
+impl Display for ErrorContext {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "while {}", if self.writing { "writing" } else { "reading" })?;
+        if let Some(item) = &self.item {
+            write!(f, " item '{}'", item)?
+        }
+        if let Some((offset, size)) = self.region {
+            write!(f, " (offset 0x{:x}, {})", offset, output::size_text(size))?
+        }
+        if let Some(path) = &self.path {
+            match (&self.item, self.writing) {
+                (None, _) => write!(f, " '{}'", path.display())?,
+                (Some(_), false) => write!(f, " from '{}'", path.display())?,
+                (Some(_), true) => write!(f, " to '{}'", path.display())?,
+            }
+        }
+        Ok(())
+    }
 }
 
 impl From<std::io::Error> for Error {
@@ -45,12 +140,44 @@ impl From<hex::FromHexError> for Error {
     }
 }
 
+#[cfg(feature = "progress-bars")]
 impl From<indicatif::style::TemplateError> for Error {
     fn from(value: indicatif::style::TemplateError) -> Self {
         Self::TemplateError(value)
     }
 }
 
+#[cfg(feature = "zip")]
+impl From<zip::result::ZipError> for Error {
+    fn from(value: zip::result::ZipError) -> Self {
+        Self::ZipError(value)
+    }
+}
+
+impl From<serde_yaml::Error> for Error {
+    fn from(value: serde_yaml::Error) -> Self {
+        Self::YAMLError(value)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(value: serde_json::Error) -> Self {
+        Self::JSONError(value)
+    }
+}
+
+impl From<csv::Error> for Error {
+    fn from(value: csv::Error) -> Self {
+        Self::CSVError(value)
+    }
+}
+
+impl From<rayon::ThreadPoolBuildError> for Error {
+    fn from(value: rayon::ThreadPoolBuildError) -> Self {
+        Self::ThreadPoolBuildError(value)
+    }
+}
+
 impl Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -60,12 +187,163 @@ impl Display for Error {
                 write!(f, "Nul Error: {}", e),
             Error::FromHexError(e) => 
                 write!(f, "From Hex Error: {}", e),
+            #[cfg(feature = "progress-bars")]
             Error::TemplateError(e) =>
                 write!(f, "Progress Error: {}", e),
             Error::ImageError(e) =>
-                write!(f, "Image Error: {}", e),
+                write!(f, "{}", e),
+            Error::ArchiveError(e) =>
+                write!(f, "{}", e),
+            #[cfg(feature = "zip")]
+            Error::ZipError(e) =>
+                write!(f, "Zip Error: {}", e),
+            Error::CompressError(e) =>
+                write!(f, "{}", e),
+            Error::ChecksumError(e) =>
+                write!(f, "{}", e),
+            #[cfg(feature = "http")]
+            Error::HttpError(e) =>
+                write!(f, "HTTP Error: {}", e),
+            Error::YAMLError(e) =>
+                write!(f, "YAML Error: {}", e),
+            Error::JSONError(e) =>
+                write!(f, "JSON Error: {}", e),
+            Error::CSVError(e) =>
+                write!(f, "CSV Error: {}", e),
+            Error::ThreadPoolBuildError(e) =>
+                write!(f, "Thread Pool Error: {}", e),
+            Error::Context(context, e) =>
+                write!(f, "{}: {}", context, e),
+            Error::PromotedWarnings(count) =>
+                write!(f, "{} Warnings Promoted to Errors", count),
+            Error::NotConfirmed =>
+                write!(f, "Not Confirmed"),
+            Error::HookFailed(name, reason) =>
+                write!(f, "Hook Failed on Item '{}': {}", name, reason),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::IOError(e) => Some(e),
+            Error::NulError(e) => Some(e),
+            Error::FromHexError(e) => Some(e),
+            #[cfg(feature = "progress-bars")]
+            Error::TemplateError(e) => Some(e),
+            Error::ImageError(e) => Some(e),
+            Error::ArchiveError(e) => Some(e),
+            #[cfg(feature = "zip")]
+            Error::ZipError(e) => Some(e),
+            Error::CompressError(e) => Some(e),
+            Error::ChecksumError(e) => Some(e),
+            #[cfg(feature = "http")]
+            Error::HttpError(e) => Some(e.as_ref()),
+            Error::YAMLError(e) => Some(e),
+            Error::JSONError(e) => Some(e),
+            Error::CSVError(e) => Some(e),
+            Error::ThreadPoolBuildError(e) => Some(e),
+            Error::Context(_, e) => Some(e.as_ref()),
+            Error::PromotedWarnings(_) | Error::NotConfirmed | 
+                Error::HookFailed(..) => None,
+        }
+    }
+}
+
+impl Error {
+    /// Attach `context` to this error, if it already carries one only the 
+    /// fields missing in it are filled, so the innermost, most specific 
+    /// context wins
+    pub fn with_context(self, context: ErrorContext) -> Self {
+        match self {
+            Error::Context(mut inner, e) => {
+                inner.fill(context);
+                Error::Context(inner, e)
+            },
+            e => Error::Context(context, Box::new(e)),
+        }
+    }
+
+    /// The error without its context
+    pub fn root(&self) -> &Self {
+        match self {
+            Error::Context(_, e) => e.root(),
+            e => e,
+        }
+    }
+
+    /// The message of this error followed by those of its sources, skipping
+    /// sources already included in the messages before them, as most variants
+    /// show what they wrap
+    pub fn display_chain(&self) -> String {
+        let mut chain = self.to_string();
+        let mut source = std::error::Error::source(self);
+        while let Some(e) = source {
+            let message = e.to_string();
+            if ! chain.contains(&message) {
+                chain.push_str(": ");
+                chain.push_str(&message)
+            }
+            source = e.source()
         }
+        chain
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Attach context to the error of a result, see `Error::with_context`
+pub trait Context<T> {
+    fn context<F: FnOnce() -> ErrorContext>(self, f: F) -> Result<T>;
+}
+
+impl<T, E: Into<Error>> Context<T> for std::result::Result<T, E> {
+    fn context<F: FnOnce() -> ErrorContext>(self, f: F) -> Result<T> {
+        self.map_err(|e| e.into().with_context(f()))
     }
 }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-pub(crate) type Result<T> = std::result::Result<T, Error>;
\ No newline at end of file
+    fn eof() -> Error {
+        std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into()
+    }
+
+    #[test]
+    fn context_display() {
+        crate::output::set_human(true);
+        let e = eof().with_context(ErrorContext::reading()
+            .item("super.PARTITION").region(0x1a400000, 0x86666666)
+            .path("/path/img"));
+        assert_eq!(e.to_string(), "while reading item 'super.PARTITION' \
+            (offset 0x1a400000, 2.10 GiB) from '/path/img': IO Error: \
+            unexpected end of file");
+        let e = eof().with_context(ErrorContext::writing().item("boot.PARTITION")
+            .path("/out"));
+        assert_eq!(e.to_string(), "while writing item 'boot.PARTITION' to \
+            '/out': IO Error: unexpected end of file");
+        let e = eof().with_context(ErrorContext::reading().path("/in"));
+        assert_eq!(e.to_string(), 
+            "while reading '/in': IO Error: unexpected end of file");
+    }
+
+    #[test]
+    fn inner_context_wins() {
+        let e = eof()
+            .with_context(ErrorContext::reading().item("boot.PARTITION")
+                .region(0x100, 0x200))
+            .with_context(ErrorContext::reading().item("other").path("/img"));
+        match &e {
+            Error::Context(context, inner) => {
+                assert_eq!(context.item.as_deref(), Some("boot.PARTITION"));
+                assert_eq!(context.region, Some((0x100, 0x200)));
+                assert_eq!(context.path.as_deref(), Some(Path::new("/img")));
+                assert!(matches!(inner.as_ref(), Error::IOError(_)))
+            },
+            _ => panic!("no context attached"),
+        }
+        assert!(matches!(e.root(), Error::IOError(_)));
+    }
+}
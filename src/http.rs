@@ -0,0 +1,300 @@
+/*
+ampack, to unpack and pack Aml burning images: http module
+Copyright (C) 2024-present Guoxin "7Ji" Pu
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as
+published by the Free Software Foundation, either version 3 of the
+License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Images read from URLs with the `http` feature, through ranges if the 
+//! server serves them, or downloaded into the user cache dir otherwise.
+
+#[cfg(feature = "http")]
+use std::{collections::{btree_map::Entry, BTreeMap}, fs::{create_dir_all, rename, File}, io::{Read, Seek, SeekFrom, Write}, path::PathBuf, sync::{Arc, Mutex}, time::Duration};
+
+#[cfg(feature = "http")]
+use crate::{cache::user_cache_dir, output, payload::chunk_size, progress::{finish_bar, progress_bar_with_template, template_bytes}, Error, Result};
+
+/// Whether an input given on the command line is an http(s) URL rather than
+/// a path
+pub fn is_url(input: &str) -> bool {
+    input.starts_with("http://") || input.starts_with("https://")
+}
+
+/// Name of the dir in the user cache dir downloads are kept in
+#[cfg(feature = "http")]
+pub const DOWNLOADS_DIR_NAME: &str = "downloads";
+
+/// Size of blocks fetched by `RemoteReader`, large enough for the header and
+/// item table of an image in one request
+#[cfg(feature = "http")]
+const REMOTE_BLOCK: u64 = 0x10000;
+
+#[cfg(feature = "http")]
+impl From<ureq::Error> for Error {
+    fn from(value: ureq::Error) -> Self {
+        Self::HttpError(Box::new(value))
+    }
+}
+
+#[cfg(feature = "http")]
+fn agent() -> ureq::Agent {
+    ureq::AgentBuilder::new()
+        .timeout_connect(Duration::from_secs(30))
+        .timeout_read(Duration::from_secs(60))
+        .build()
+}
+
+/// Send `request`, telling failures apart in messages
+#[cfg(feature = "http")]
+fn call(url: &str, request: ureq::Request) -> Result<ureq::Response> {
+    match request.call() {
+        Ok(response) => Ok(response),
+        Err(ureq::Error::Status(code, response)) => {
+            eprintln!("Server returned HTTP {} {} for '{}'", code,
+                response.status_text(), url);
+            Err(ureq::Error::Status(code, response).into())
+        },
+        Err(e) => {
+            eprintln!("Failed to request '{}': {}", url, e);
+            Err(e.into())
+        },
+    }
+}
+
+/// A file on an http(s) server, as probed with a ranged request for its
+/// first byte
+#[cfg(feature = "http")]
+#[derive(Clone)]
+pub struct RemoteFile {
+    pub url: String,
+    pub size: u64,
+    /// Whether the server serves ranges of the file
+    pub ranges: bool,
+    /// ETag, or Last-Modified if there's no ETag, to tell a changed file
+    validator: Option<String>,
+    etag: Option<String>,
+    agent: ureq::Agent,
+    /// Blocks fetched by readers, shared by clones
+    blocks: Arc<Mutex<BTreeMap<u64, Vec<u8>>>>,
+}
+
+#[cfg(feature = "http")]
+impl RemoteFile {
+    pub fn probe(url: &str) -> Result<Self> {
+        let agent = agent();
+        let response = call(url, agent.get(url).set("Range", "bytes=0-0"))?;
+        let ranges = response.status() == 206;
+        let size = if ranges {
+            response.header("Content-Range")
+                .and_then(|range|range.rsplit_once('/'))
+                .and_then(|(_, size)|size.parse().ok())
+        } else {
+            response.header("Content-Length")
+                .and_then(|size|size.parse().ok())
+        };
+        let size = match size {
+            Some(size) => size,
+            None => {
+                eprintln!("Server did not tell the size of '{}'", url);
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "remote file of unknown size").into())
+            },
+        };
+        let etag = response.header("ETag").map(String::from);
+        let validator = etag.clone().or_else(||response.header("Last-Modified")
+            .map(|modified|format!("modified {}", modified)));
+        log::debug!("Probed '{}': 0x{:x} bytes, ranges {}, validator {:?}",
+            url, size, ranges, validator);
+        Ok(Self { url: url.into(), size, ranges, validator, etag, agent, 
+            blocks: Default::default() })
+    }
+
+    /// Path of the download in the user cache, keyed by the URL and the
+    /// validator, so a changed file is downloaded anew
+    fn cache_path(&self) -> Result<PathBuf> {
+        let dir = match user_cache_dir() {
+            Some(dir) => dir.join(DOWNLOADS_DIR_NAME),
+            None => {
+                eprintln!("No user cache dir on this system to download '{}' \
+                    into, set one with --cache-dir", self.url);
+                return Err(std::io::Error::new(std::io::ErrorKind::NotFound,
+                    "no user cache dir").into())
+            },
+        };
+        let key = md5::compute(format!("{}\n{}\n{}", self.url,
+            self.validator.as_deref().unwrap_or_default(), self.size));
+        let name = self.url.rsplit('/').next()
+            .filter(|name| ! name.is_empty() &&
+                name.chars().all(|c|c.is_ascii_alphanumeric() ||
+                    "._-".contains(c)))
+            .unwrap_or("image");
+        Ok(dir.join(format!("{:x}-{}", key, name)))
+    }
+
+    /// Download the file into the user cache, resuming a partial download
+    /// if the server serves ranges, or take the complete one already there,
+    /// and return its path
+    pub fn try_download_cached(&self) -> Result<PathBuf> {
+        let path = self.cache_path()?;
+        if path.metadata().is_ok_and(|metadata|metadata.len() == self.size) {
+            println!("Using download of '{}' cached at '{}'", self.url,
+                path.display());
+            return Ok(path)
+        }
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent)?
+        }
+        let mut path_part = path.clone().into_os_string();
+        path_part.push(".part");
+        let path_part = PathBuf::from(path_part);
+        let mut done = match path_part.metadata() {
+            Ok(metadata) if self.ranges && metadata.len() < self.size =>
+                metadata.len(),
+            _ => 0,
+        };
+        let mut request = self.agent.get(&self.url);
+        if done > 0 {
+            request = request.set("Range", &format!("bytes={}-", done));
+            if let Some(etag) = &self.etag {
+                request = request.set("If-Range", etag)
+            }
+        }
+        let response = call(&self.url, request)?;
+        let mut file = if done > 0 && response.status() == 206 {
+            println!("Resuming download of '{}' at {}", self.url,
+                output::size_text_exact(done));
+            File::options().append(true).open(&path_part)?
+        } else {
+            done = 0;
+            println!("Downloading '{}' of {} to '{}'", self.url,
+                output::size_text_exact(self.size), path.display());
+            File::create(&path_part)?
+        };
+        let progress_bar = progress_bar_with_template(
+            self.size, template_bytes("Downloading", ""))?;
+        progress_bar.set_position(done);
+        let mut reader = response.into_reader();
+        let mut buffer = vec![0; chunk_size()];
+        loop {
+            let size = reader.read(&mut buffer)?;
+            if size == 0 {
+                break
+            }
+            file.write_all(&buffer[..size])?;
+            done += size as u64;
+            progress_bar.set_position(done)
+        }
+        finish_bar(&progress_bar);
+        file.sync_all()?;
+        if done != self.size {
+            eprintln!("Download of '{}' ended at {} of {}, run again to \
+                resume it", self.url, output::size_text_exact(done),
+                output::size_text_exact(self.size));
+            return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof,
+                "download ended early").into())
+        }
+        rename(&path_part, &path)?;
+        println!("Downloaded '{}' to '{}'", self.url, path.display());
+        Ok(path)
+    }
+
+    /// A reader of the file fetching only the blocks read with ranged
+    /// requests, the server must serve ranges. Blocks fetched are kept and 
+    /// shared by all readers of the file.
+    pub fn reader(&self) -> RemoteReader {
+        RemoteReader { remote: self.clone(), position: 0 }
+    }
+
+    /// Bytes fetched by readers so far
+    pub fn fetched(&self) -> u64 {
+        self.blocks.lock().unwrap_or_else(|e| e.into_inner()).values()
+            .map(|block|block.len() as u64).sum()
+    }
+
+    fn try_fetch_block(&self, start: u64) -> Result<Vec<u8>> {
+        let end = (start + REMOTE_BLOCK).min(self.size) - 1;
+        log::debug!("Fetching 0x{:x} to 0x{:x} of '{}'", start, end, self.url);
+        let mut request = self.agent.get(&self.url)
+            .set("Range", &format!("bytes={}-{}", start, end));
+        if let Some(etag) = &self.etag {
+            request = request.set("If-Match", etag)
+        }
+        let response = call(&self.url, request)?;
+        if response.status() != 206 {
+            eprintln!("Server did not serve range 0x{:x} to 0x{:x} of '{}'",
+                start, end, self.url);
+            return Err(std::io::Error::new(std::io::ErrorKind::Unsupported,
+                "range not served").into())
+        }
+        let mut block = Vec::with_capacity((end + 1 - start) as usize);
+        response.into_reader().read_to_end(&mut block)?;
+        if block.len() as u64 != end + 1 - start {
+            return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof,
+                "range served partially").into())
+        }
+        Ok(block)
+    }
+}
+
+/// A seekable reader of a `RemoteFile`, fetching blocks of it on demand, so
+/// only what's read is transferred
+#[cfg(feature = "http")]
+pub struct RemoteReader {
+    remote: RemoteFile,
+    position: u64,
+}
+
+#[cfg(feature = "http")]
+impl Read for RemoteReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.position >= self.remote.size || buf.is_empty() {
+            return Ok(0)
+        }
+        let start = self.position / REMOTE_BLOCK * REMOTE_BLOCK;
+        let mut blocks = self.remote.blocks.lock()
+            .unwrap_or_else(|e| e.into_inner());
+        let block = match blocks.entry(start) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(
+                self.remote.try_fetch_block(start)
+                    .map_err(std::io::Error::other)?),
+        };
+        let block = &block[(self.position - start) as usize..];
+        let size = block.len().min(buf.len());
+        buf[..size].copy_from_slice(&block[..size]);
+        self.position += size as u64;
+        Ok(size)
+    }
+}
+
+#[cfg(feature = "http")]
+impl Seek for RemoteReader {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.remote.size.checked_add_signed(offset),
+            SeekFrom::Current(offset) =>
+                self.position.checked_add_signed(offset),
+        };
+        match position {
+            Some(position) => {
+                self.position = position;
+                Ok(position)
+            },
+            None => Err(std::io::Error::new(std::io::ErrorKind::InvalidInput,
+                "seek to a negative position")),
+        }
+    }
+}
@@ -0,0 +1,232 @@
+/*
+ampack, to unpack and pack Aml burning images: payload identification module
+Copyright (C) 2024-present Guoxin "7Ji" Pu
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as
+published by the Free Software Foundation, either version 3 of the
+License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! What a payload is, guessed from its first bytes.
+
+use std::fmt::Display;
+
+use serde::{Deserialize, Serialize};
+
+/// Bytes at the start of a payload needed to identify it, enough to cover the
+/// superblocks of filesystems at offset 1024
+pub const SIZE_IDENTIFY_HEAD: usize = 2048;
+
+pub const SPARSE_MAGIC: u32 = 0xed26ff3a;
+const EXT4_MAGIC: u16 = 0xef53;
+const F2FS_MAGIC: u32 = 0xf2f52010;
+const EROFS_MAGIC: u32 = 0xe0f5e1e2;
+const FDT_MAGIC: u32 = 0xd00dfeed;
+const UIMAGE_MAGIC: u32 = 0x27051956;
+/// Offset of the superblocks of ext4, f2fs and erofs
+const OFFSET_SUPERBLOCK: usize = 1024;
+/// Offset of the magic in the ext4 superblock
+const OFFSET_EXT4_MAGIC: usize = OFFSET_SUPERBLOCK + 0x38;
+
+/// What a payload looks like from its first bytes, for the handful of types
+/// found in Amlogic firmwares
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PayloadKind {
+    AndroidSparse,
+    Ext4,
+    F2fs,
+    Erofs,
+    AndroidBoot,
+    Gzip,
+    Fdt,
+    UbootLegacy,
+    Vbmeta,
+    Text,
+    Unknown,
+}
+
+impl Display for PayloadKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            PayloadKind::AndroidSparse => "Android sparse",
+            PayloadKind::Ext4 => "ext4",
+            PayloadKind::F2fs => "f2fs",
+            PayloadKind::Erofs => "erofs",
+            PayloadKind::AndroidBoot => "Android boot image",
+            PayloadKind::Gzip => "gzip",
+            PayloadKind::Fdt => "FDT (dtb)",
+            PayloadKind::UbootLegacy => "U-Boot legacy image",
+            PayloadKind::Vbmeta => "AVB vbmeta",
+            PayloadKind::Text => "text",
+            PayloadKind::Unknown => "unknown",
+        })
+    }
+}
+
+fn u16_le_at(head: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(head.get(offset..offset + 2)?.try_into().ok()?))
+}
+
+fn u32_le_at(head: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(head.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+fn u32_be_at(head: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(head.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+/// Whether `head` looks like plain text: not empty, and only printable ASCII,
+/// whitespace or UTF-8 multi-byte sequences
+fn is_text(head: &[u8]) -> bool {
+    if head.is_empty() {
+        return false
+    }
+    // The head could cut a multi-byte sequence at the end
+    let valid = match std::str::from_utf8(head) {
+        Ok(text) => text,
+        Err(e) if e.error_len().is_none() =>
+            match std::str::from_utf8(&head[..e.valid_up_to()]) {
+                Ok(text) => text,
+                Err(_) => return false,
+            },
+        Err(_) => return false,
+    };
+    valid.chars().all(|c| ! c.is_control() || matches!(c, '\t' | '\n' | '\r'))
+}
+
+/// Identify a payload by `head`, its first bytes, up to `SIZE_IDENTIFY_HEAD`
+pub fn identify(head: &[u8]) -> PayloadKind {
+    if u32_le_at(head, 0) == Some(SPARSE_MAGIC) {
+        PayloadKind::AndroidSparse
+    } else if head.starts_with(b"ANDROID!") {
+        PayloadKind::AndroidBoot
+    } else if head.starts_with(b"AVB0") {
+        PayloadKind::Vbmeta
+    } else if head.starts_with(&[0x1f, 0x8b]) {
+        PayloadKind::Gzip
+    } else if u32_be_at(head, 0) == Some(FDT_MAGIC) {
+        PayloadKind::Fdt
+    } else if u32_be_at(head, 0) == Some(UIMAGE_MAGIC) {
+        PayloadKind::UbootLegacy
+    } else if u16_le_at(head, OFFSET_EXT4_MAGIC) == Some(EXT4_MAGIC) {
+        PayloadKind::Ext4
+    } else if u32_le_at(head, OFFSET_SUPERBLOCK) == Some(F2FS_MAGIC) {
+        PayloadKind::F2fs
+    } else if u32_le_at(head, OFFSET_SUPERBLOCK) == Some(EROFS_MAGIC) {
+        PayloadKind::Erofs
+    } else if is_text(head) {
+        PayloadKind::Text
+    } else {
+        PayloadKind::Unknown
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `prefix` at the start of a head padded with zeros to `len`
+    fn head(prefix: &[u8], len: usize) -> Vec<u8> {
+        let mut head = prefix.to_vec();
+        head.resize(len.max(prefix.len()), 0);
+        head
+    }
+
+    /// A head with `magic` put at `offset`, as in filesystem superblocks
+    fn head_with_at(magic: &[u8], offset: usize) -> Vec<u8> {
+        let mut head = vec![0; SIZE_IDENTIFY_HEAD];
+        head[offset..offset + magic.len()].copy_from_slice(magic);
+        head
+    }
+
+    #[test]
+    fn android_sparse() {
+        // Magic, version 1.0, header sizes 28 and 12, 4096-byte blocks
+        let prefix = [0x3a, 0xff, 0x26, 0xed, 0x01, 0x00, 0x00, 0x00, 
+            0x1c, 0x00, 0x0c, 0x00, 0x00, 0x10, 0x00, 0x00];
+        assert_eq!(identify(&head(&prefix, SIZE_IDENTIFY_HEAD)), 
+            PayloadKind::AndroidSparse);
+    }
+
+    #[test]
+    fn android_boot() {
+        // Magic, then kernel size 0x01234567 and load address 0x01080000
+        let prefix = b"ANDROID!\x67\x45\x23\x01\x00\x00\x08\x01";
+        assert_eq!(identify(&head(prefix, SIZE_IDENTIFY_HEAD)), 
+            PayloadKind::AndroidBoot);
+    }
+
+    #[test]
+    fn fdt() {
+        // Magic, total size 0x1388, struct offset 0x38, strings offset
+        let prefix = [0xd0, 0x0d, 0xfe, 0xed, 0x00, 0x00, 0x13, 0x88,
+            0x00, 0x00, 0x00, 0x38, 0x00, 0x00, 0x11, 0xcc];
+        assert_eq!(identify(&head(&prefix, 0x1388)), PayloadKind::Fdt);
+    }
+
+    #[test]
+    fn gzip() {
+        // Magic, deflate, no flags, no mtime, no extra flags, Unix
+        let prefix = [0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 
+            0x00, 0x03];
+        assert_eq!(identify(&head(&prefix, SIZE_IDENTIFY_HEAD)), 
+            PayloadKind::Gzip);
+    }
+
+    #[test]
+    fn uboot_legacy_and_vbmeta() {
+        assert_eq!(identify(&head(&[0x27, 0x05, 0x19, 0x56], 64)), 
+            PayloadKind::UbootLegacy);
+        assert_eq!(identify(&head(b"AVB0\x00\x00\x00\x01", 256)), 
+            PayloadKind::Vbmeta);
+    }
+
+    #[test]
+    fn filesystems() {
+        assert_eq!(identify(&head_with_at(&[0x53, 0xef], OFFSET_EXT4_MAGIC)), 
+            PayloadKind::Ext4);
+        assert_eq!(identify(&head_with_at(&[0x10, 0x20, 0xf5, 0xf2], 
+            OFFSET_SUPERBLOCK)), PayloadKind::F2fs);
+        assert_eq!(identify(&head_with_at(&[0xe2, 0xe1, 0xf5, 0xe0], 
+            OFFSET_SUPERBLOCK)), PayloadKind::Erofs);
+    }
+
+    #[test]
+    fn text() {
+        assert_eq!(identify(b"[common]\r\nerase_bootloader = 1\r\n\
+            erase_flash = 0\r\nreboot = 0\r\n"), PayloadKind::Text);
+        assert_eq!(identify(b"platform:0x0811\nDDRLoad:0xfffc0000\n"), 
+            PayloadKind::Text);
+        // A multi-byte sequence cut by the end of the head
+        assert_eq!(identify("name = 固件".as_bytes().split_last().unwrap().1), 
+            PayloadKind::Text);
+        assert_eq!(identify(b"text\0with NUL"), PayloadKind::Unknown);
+    }
+
+    #[test]
+    fn short_heads() {
+        const { assert!(SIZE_IDENTIFY_HEAD > OFFSET_EXT4_MAGIC + 2) };
+        assert_eq!(identify(&[]), PayloadKind::Unknown);
+        // Magics cut short are not matched, nor read past the end
+        assert_eq!(identify(&[0x3a, 0xff, 0x26]), PayloadKind::Unknown);
+        assert_eq!(identify(&[0xd0, 0x0d, 0xfe]), PayloadKind::Unknown);
+        assert_eq!(identify(&[0x1f]), PayloadKind::Unknown);
+        // A superblock beyond the head of a tiny payload
+        let mut ext4 = head_with_at(&[0x53, 0xef], OFFSET_EXT4_MAGIC);
+        ext4.truncate(OFFSET_EXT4_MAGIC + 1);
+        assert_eq!(identify(&ext4), PayloadKind::Unknown);
+        // Short but still whole magics
+        assert_eq!(identify(b"ANDROID!"), PayloadKind::AndroidBoot);
+        assert_eq!(identify(&[0x1f, 0x8b]), PayloadKind::Gzip);
+    }
+}
@@ -16,13 +16,16 @@ You should have received a copy of the GNU Affero General Public License
 along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
-use std::{cmp::{min, Ordering}, ffi::{c_char, CStr}, fmt::Display, fs::{create_dir_all, read_dir, remove_dir_all, remove_file, File}, io::{Read, Seek, Write}, path::Path, time::Duration};
+//! Images and their items: read, verified, modified, unpacked and packed.
 
-use cli_table::{Cell, Style, Table, format::Justify};
-use indicatif::MultiProgress;
-use serde::{Serialize, Deserialize};
+use std::{cmp::{min, Ordering}, ffi::{c_char, CStr}, fmt::Display, fs::{create_dir_all, read_dir, remove_dir_all, remove_file, rename, DirEntry, File, OpenOptions}, io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write}, path::{Path, PathBuf}, sync::{Arc, atomic::{AtomicBool, Ordering as AtomicOrdering}}, time::{Duration, Instant}};
 
-use crate::{progress::{progress_bar_with_template, progress_bar_with_template_multi}, sha1sum::Sha1sum, Error, Result};
+use cli_table::{Cell, CellStruct, Color, Style, Table, format::Justify};
+use crate::progress::Bar;
+use rayon::prelude::*;
+use serde::{Serialize, Serializer, Deserialize};
+
+use crate::{atomic::{self, WriteFileOptions}, checksum::{ChecksumAlgo, ChecksumHasher, DigestAlgo, DigestHasher, DigestWriter, FileDigest}, cache::{ChecksumCache, CHECKSUM_CACHE_NAME}, crc32::{Crc32Hasher, Crc32ImageWriter}, error::{Context, ErrorContext}, identify::{identify, PayloadKind, SIZE_IDENTIFY_HEAD, SPARSE_MAGIC}, manifest::{BackupPolicy, Manifest, ManifestDiff, ManifestItem, TextNormalization, MANIFEST_NAME}, retry::{self, TransferError}, output, progress::{finish_bar, finish_stage, progress_bar_with_template, progress_bar_with_template_optional, template_bytes, ItemBars}, payload::{chunk_size, map_jobs, paths_read_in_parallel, reads_in_parallel, Payload, SharedReader, SourceFile, SpillDir}, sha1sum::{Sha1sum, Sha1sumHasher}, stamp::Stamp, storage, Error, Result};
 
 /* These values are always the same for any images */
 
@@ -32,7 +35,7 @@ const FILE_TYPE_SPARSE: u32 = 254;
 const ANDROID_SPARSE_IMAGE_MAGIC_BYTES: [u8; 4] = [0x3a, 0xff, 0x26, 0xed];
 
 #[derive(Debug)]
-pub(crate) enum ImageError {
+pub enum ImageError {
     InvalidMagic {
         magic: u32
     },
@@ -57,11 +60,110 @@ pub(crate) enum ImageError {
         exptected: usize,
         actual: usize
     },
+    InvalidItemCount {
+        count: u32
+    },
+    ItemsFailed {
+        count: usize
+    },
+    NestedImage {
+        path: String
+    },
+    SkippedEntries {
+        count: usize
+    },
+    InvalidItemName {
+        name: String
+    },
+    ItemTooLarge {
+        name: String,
+        size: u64,
+        limit: u64,
+    },
+    InsufficientSpace {
+        needed: u64,
+        available: u64,
+    },
+    TooLargeForPlatform {
+        name: String,
+        size: u64,
+    },
+    ReadFailed {
+        name: String,
+        offset: u64,
+        size: u64,
+        transferred: u64,
+        error: std::io::Error,
+    },
+    WriteFailed {
+        name: String,
+        offset: u64,
+        size: u64,
+        transferred: u64,
+        error: std::io::Error,
+    },
+    /// Outstanding work given up as another job failed
+    Aborted,
+    IllegalSparse {
+        name: String,
+        reason: String,
+    },
+    SelfCheckFailed {
+        reason: String,
+    },
+    CrcMismatch {
+        recorded: u32,
+        calculated: u32,
+    },
+    LargerThanPadTo {
+        size: u64,
+        pad_to: u64,
+    },
+    StructuralProblems {
+        count: usize
+    },
+    InvalidAlign {
+        align: u8
+    },
+    /// The image does not hold what the expected manifest lists
+    ManifestMismatch {
+        added: usize,
+        missing: usize,
+        mismatched: usize,
+    },
+    /// A backup directive in the manifest could not be followed
+    InvalidBackup {
+        name: String,
+        reason: String,
+    },
+    /// An item to be a backup of another is not identical to it
+    BackupMismatch {
+        name: String,
+        sha1sum: String,
+        of: String,
+        sha1sum_of: String,
+    },
+}
+
+impl ImageError {
+    pub fn read_failed(name: &str, offset: u64, size: u64, e: TransferError) 
+        -> Self 
+    {
+        Self::ReadFailed { name: name.into(), offset, size, 
+            transferred: e.transferred as u64, error: e.error }
+    }
+
+    fn write_failed(name: &str, offset: u64, size: u64, e: TransferError) 
+        -> Self 
+    {
+        Self::WriteFailed { name: name.into(), offset, size, 
+            transferred: e.transferred as u64, error: e.error }
+    }
 }
 
-impl Into<Error> for ImageError {
-    fn into(self) -> Error {
-        Error::ImageError(self)
+impl From<ImageError> for Error {
+    fn from(value: ImageError) -> Self {
+        Self::ImageError(value)
     }
 }
 
@@ -86,13 +188,76 @@ impl Display for ImageError {
             ImageError::SizeMismatch { exptected, actual } => 
                 write!(f, "Size Mismatch (expected {} != actual {})",
                     exptected, actual),
+            ImageError::InvalidItemCount { count } =>
+                write!(f, "Invalid Item Count: {}", count),
+            ImageError::ItemsFailed { count } =>
+                write!(f, "{} Items Failed", count),
+            ImageError::NestedImage { path } =>
+                write!(f, "Nested Image '{}'", path),
+            ImageError::SkippedEntries { count } =>
+                write!(f, "{} Entries Skipped", count),
+            ImageError::InvalidItemName { name } =>
+                write!(f, "Invalid Item Name '{}'", name),
+            ImageError::ItemTooLarge { name, size, limit } =>
+                write!(f, "Item '{}' Too Large (0x{:x} > limit 0x{:x})", 
+                    name, size, limit),
+            ImageError::InsufficientSpace { needed, available } =>
+                write!(f, "Insufficient Space (needed 0x{:x} > available \
+                    0x{:x})", needed, available),
+            ImageError::TooLargeForPlatform { name, size } =>
+                write!(f, "'{}' Too Large for Platform (0x{:x} bytes)", 
+                    name, size),
+            ImageError::ReadFailed { name, offset, size, transferred, error } =>
+                write!(f, "Failed to read '{}' at offset 0x{:x} after \
+                    0x{:x}/0x{:x} bytes: {}", 
+                    name, offset, transferred, size, error),
+            ImageError::WriteFailed { name, offset, size, transferred, error } =>
+                write!(f, "Failed to write '{}' at offset 0x{:x} after \
+                    0x{:x}/0x{:x} bytes: {}", 
+                    name, offset, transferred, size, error),
+            ImageError::Aborted =>
+                write!(f, "Aborted"),
+            ImageError::IllegalSparse { name, reason } =>
+                write!(f, "Illegal Android Sparse Header in '{}': {}", 
+                    name, reason),
+            ImageError::SelfCheckFailed { reason } =>
+                write!(f, "Self Check Failed: {}", reason),
+            ImageError::CrcMismatch { recorded, calculated } =>
+                write!(f, "CRC32 Mismatch (recorded 0x{:08x} != calculated \
+                    0x{:08x})", recorded, calculated),
+            ImageError::LargerThanPadTo { size, pad_to } =>
+                write!(f, "Image Larger Than Size to Pad to (0x{:x} > 0x{:x})",
+                    size, pad_to),
+            ImageError::StructuralProblems { count } =>
+                write!(f, "{} Structural Problems", count),
+            ImageError::InvalidAlign { align } =>
+                write!(f, "Invalid Align: {}", align),
+            ImageError::ManifestMismatch { added, missing, mismatched } =>
+                write!(f, "Manifest Mismatch ({} items added, {} missing, {} \
+                    fields mismatched)", added, missing, mismatched),
+            ImageError::InvalidBackup { name, reason } =>
+                write!(f, "Invalid Backup of '{}': {}", name, reason),
+            ImageError::BackupMismatch { name, sha1sum, of, sha1sum_of } =>
+                write!(f, "Backup Mismatch ('{}' {} != '{}' {})", 
+                    name, sha1sum, of, sha1sum_of),
+        }
+    }
+}
+
+impl std::error::Error for ImageError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ImageError::ReadFailed { error, .. } |
+            ImageError::WriteFailed { error, .. } => Some(error),
+            _ => None,
         }
     }
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Eq, PartialOrd, Ord, 
-    clap::ValueEnum, Serialize, Deserialize)]
-pub(crate) enum ImageVersion {
+    Serialize, Deserialize)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum ImageVersion {
     V1,
     #[default]
     V2,
@@ -114,9 +279,9 @@ impl TryFrom<RawImageVersion> for ImageVersion {
     }
 }
 
-impl Into<RawImageVersion> for &ImageVersion {
-    fn into(self) -> RawImageVersion {
-        match self {
+impl From<&ImageVersion> for RawImageVersion {
+    fn from(value: &ImageVersion) -> Self {
+        match value {
             ImageVersion::V1 => 1,
             ImageVersion::V2 => 2,
         }
@@ -141,9 +306,33 @@ impl ImageVersion {
             ImageVersion::V2 => SIZE_RAW_ITEM_INFO_V2,
         }
     }
+
+    fn size_item_type(&self) -> usize {
+        match self {
+            ImageVersion::V1 => SIZE_ITEM_TYPE_V1,
+            ImageVersion::V2 => SIZE_ITEM_TYPE_V2,
+        }
+    }
+
+    /// Max length of the stem or extension of an item name, the item types
+    /// in item infos are NUL-terminated
+    fn max_len_name_part(&self) -> usize {
+        self.size_item_type() - 1
+    }
+
+    /// Whether an item named `[stem].[extension]` could be recorded in an
+    /// image of this version: each part 1 to `max_len_name_part` bytes without
+    /// control characters
+    fn is_name_representable(&self, stem: &str, extension: &str) -> bool {
+        [stem, extension].into_iter().all(|part| ! part.is_empty() && 
+            part.len() <= self.max_len_name_part() && 
+            ! part.contains(char::is_control))
+    }
 }
 
-#[repr(packed)]
+/// The image header as laid out on disk, `C` is needed along with `packed` as
+/// otherwise fields could be reordered
+#[repr(C, packed)]
 struct RawImageHead {
     crc: u32,
     version: u32,
@@ -151,29 +340,34 @@ struct RawImageHead {
     image_size: u64,
     item_align_size: u32,
     item_count: u32,
-    _reserve: [u8; 36],
+    reserve: [u8; SIZE_HEAD_RESERVE],
 }
 
 impl RawImageHead {
-    fn new(version: &ImageVersion, item_align_size: u32) -> Self {
-        Self {
+    fn new(version: &ImageVersion, item_align_size: u32, reserve: &[u8]) -> Self {
+        let mut head = Self {
             crc: 0,
             version: version.into(),
             magic: MAGIC,
             image_size: 0,
             item_align_size,
             item_count: 0,
-            _reserve: [0; 36],
-        }
+            reserve: [0; SIZE_HEAD_RESERVE],
+        };
+        bytes_fill_from_slice(&mut head.reserve, reserve);
+        head
     }
 }
 
+const SIZE_HEAD_RESERVE: usize = 36;
+const SIZE_INFO_RESERVE: usize = 24;
+
 const SIZE_RAW_IMAGE_HEAD: usize = std::mem::size_of::<RawImageHead>();
 const SIZE_ITEM_TYPE_V1: usize = 32;
 const SIZE_ITEM_TYPE_V2: usize = 256;
 
-
-#[repr(packed)]
+/// An item info as laid out on disk, `LEN` being the length of both type names
+#[repr(C, packed)]
 struct RawItemInfoVariableLength<const LEN: usize> {
     item_id: u32,
     file_type: u32,
@@ -185,7 +379,7 @@ struct RawItemInfoVariableLength<const LEN: usize> {
     verify: u32,
     is_backup_item: u16,
     backup_item_id: u16,
-    _reserve: [u8; 24],
+    reserve: [u8; SIZE_INFO_RESERVE],
 }
 
 type RawItemInfoV1 = RawItemInfoVariableLength<SIZE_ITEM_TYPE_V1>;
@@ -196,6 +390,19 @@ const SIZE_RAW_ITEM_INFO_V1: usize = std::mem::size_of::<RawItemInfoV1>();
 const SIZE_RAW_ITEM_INFO_V2: usize = std::mem::size_of::<RawItemInfoV2>();
 // const SIZE_RAW_ITEM_INFO_V3: usize = std::mem::size_of::<RawItemInfoV3>();
 
+const _: () = {
+    use std::mem::offset_of;
+    assert!(SIZE_RAW_IMAGE_HEAD == 0x40);
+    assert!(offset_of!(RawImageHead, image_size) == 12);
+    assert!(offset_of!(RawImageHead, reserve) == 28);
+    assert!(SIZE_RAW_ITEM_INFO_V1 == 0x80);
+    assert!(SIZE_RAW_ITEM_INFO_V2 == 0x240);
+    assert!(offset_of!(RawItemInfoV2, offset_in_image) == 16);
+    assert!(offset_of!(RawItemInfoV2, item_size) == 24);
+    assert!(offset_of!(RawItemInfoV2, verify) == 544);
+    assert!(offset_of!(RawItemInfoV2, reserve) == 552);
+};
+
 fn cstr_from_slice_u8_c_string(slice: &[u8]) -> &CStr {
     unsafe {CStr::from_ptr(slice.as_ptr() as *const c_char)}
 }
@@ -204,17 +411,178 @@ fn string_from_slice_u8_c_string(slice: &[u8]) -> String {
     cstr_from_slice_u8_c_string(slice).to_string_lossy().into()
 }
 
-struct RawItemInfo {
-    item_id: u32,
-    file_type: u32,
-    current_offset_in_item: u64,
-    offset_in_image: u64,
-    item_size: u64,
-    item_main_type: String,
-    item_sub_type: String,
-    verify: u32,
-    is_backup_item: u16,
-    backup_item_id: u16,
+/// Reserved bytes are only kept if any of them is not zero
+fn vec_from_reserve(reserve: &[u8]) -> Vec<u8> {
+    if reserve.iter().all(|byte| *byte == 0) {
+        Vec::new()
+    } else {
+        reserve.into()
+    }
+}
+
+fn bytes_fill_from_slice(dest: &mut [u8], src: &[u8]) {
+    let len = min(dest.len(), src.len());
+    dest[0..len].copy_from_slice(&src[0..len])
+}
+
+/// Print a canonical hexdump of `data` which starts at `offset` in the image
+fn print_hexdump(data: &[u8], offset: usize) {
+    for (id, line) in data.chunks(16).enumerate() {
+        let mut text = format!("{:08x} ", offset + id * 16);
+        for byte in line.iter() {
+            text.push_str(&format!(" {:02x}", byte))
+        }
+        for _ in line.len()..16 {
+            text.push_str("   ")
+        }
+        text.push_str("  |");
+        for byte in line.iter() {
+            if byte.is_ascii_graphic() || *byte == b' ' {
+                text.push(*byte as char)
+            } else {
+                text.push('.')
+            }
+        }
+        println!("{}|", text)
+    }
+}
+
+/// Serialize reserved bytes as hex
+fn serialize_hex<S: Serializer>(bytes: &[u8], serializer: S) 
+    -> std::result::Result<S::Ok, S::Error> 
+{
+    serializer.serialize_str(&hex::encode(bytes))
+}
+
+/// Basic info of an image, parsed from its head
+#[derive(Clone, Serialize)]
+pub struct ImageHeader {
+    pub version: ImageVersion,
+    pub align: u32,
+    pub item_count: u32,
+    pub image_size: u64,
+    pub crc: u32,
+    #[serde(serialize_with = "serialize_hex")]
+    pub reserve: [u8; SIZE_HEAD_RESERVE],
+    /// The stamp of ampack in the reserved bytes, if it was stamped
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stamp: Option<Stamp>,
+}
+
+/// Read the raw image head from `reader`, only checking its magic
+fn try_read_raw_head_from<R: Read>(reader: &mut R) -> Result<RawImageHead> {
+    let mut buffer = [0; SIZE_RAW_IMAGE_HEAD];
+    reader.read_exact(&mut buffer)?;
+    let header = unsafe {
+        (buffer.as_ptr() as *const RawImageHead).read()};
+    if header.magic != MAGIC {
+        eprintln!("Image magic invalid: expected 0x{:08x}, found 0x{:08x}", 
+            MAGIC, {header.magic});
+        return Err(ImageError::InvalidMagic{magic: header.magic}.into())
+    }
+    Ok(header)
+}
+
+/// Bytes after the image header dumped for images of unknown versions, 
+/// enough to cover a few item infos of any known size
+const SIZE_DUMP_UNKNOWN: usize = 0x400;
+
+/// Print the fields of the header of an image of a version ampack does not 
+/// understand, its non-zero reserved bytes, and a hexdump of what follows 
+/// the header, where item infos are in known versions, so the data needed to
+/// support the version could be reported
+pub fn print_unknown_version_from<R: Read>(reader: &mut R) -> Result<()> {
+    let header = try_read_raw_head_from(reader)?;
+    println!("Image header: version unknown ({}), align {} bytes, {} items, \
+        size 0x{:x}, CRC32 0x{:08x}", {header.version}, 
+        {header.item_align_size}, {header.item_count}, {header.image_size}, 
+        {header.crc});
+    if header.reserve.iter().any(|byte| *byte != 0) {
+        println!("Reserved bytes in image header:");
+        print_hexdump(&header.reserve, SIZE_RAW_IMAGE_HEAD - SIZE_HEAD_RESERVE);
+    }
+    let mut after = Vec::with_capacity(SIZE_DUMP_UNKNOWN);
+    reader.take(SIZE_DUMP_UNKNOWN as u64).read_to_end(&mut after)?;
+    println!("First 0x{:x} bytes after image header, where item infos are in \
+        known versions:", after.len());
+    print_hexdump(&after, SIZE_RAW_IMAGE_HEAD);
+    Ok(())
+}
+
+impl ImageHeader {
+    fn try_read_from<R: Read>(reader: &mut R) -> Result<Self> {
+        let header = try_read_raw_head_from(reader)?;
+        Ok(Self {
+            version: ImageVersion::try_from(header.version)?,
+            align: header.item_align_size,
+            item_count: header.item_count,
+            image_size: header.image_size,
+            crc: header.crc,
+            reserve: header.reserve,
+            stamp: Stamp::from_bytes(&header.reserve),
+        })
+    }
+
+    /// Where payloads could start, right after the item info table
+    fn offset_body(&self) -> u64 {
+        SIZE_RAW_IMAGE_HEAD as u64 + 
+            self.version.size_raw_info() as u64 * self.item_count as u64
+    }
+
+    fn try_read_infos_from<R: Read + Seek>(&self, reader: &mut R) 
+        -> Result<Vec<RawItemInfo>> 
+    {
+        let size_info = self.version.size_raw_info();
+        let size_infos = size_info as u64 * self.item_count as u64;
+        if SIZE_RAW_IMAGE_HEAD as u64 + size_infos > self.image_size {
+            eprintln!("Item info table of {} items (0x{:x} bytes) does not fit \
+                in image of 0x{:x} bytes", self.item_count, size_infos, 
+                self.image_size);
+            return Err(ImageError::InvalidItemCount { 
+                count: self.item_count }.into())
+        }
+        reader.seek(std::io::SeekFrom::Start(SIZE_RAW_IMAGE_HEAD as u64))?;
+        let mut buffer = vec![0; usize_from_u64("item info table", size_infos)?];
+        reader.read_exact(&mut buffer)?;
+        let mut infos = Vec::new();
+        for buffer_info in buffer.chunks_exact(size_info) {
+            let pointer = buffer_info.as_ptr();
+            infos.push(match self.version {
+                ImageVersion::V1 => unsafe {(pointer as *const RawItemInfoV1).read()}.into(),
+                ImageVersion::V2 => unsafe {(pointer as *const RawItemInfoV2).read()}.into(),
+            })
+        }
+        Ok(infos)
+    }
+}
+
+impl Display for ImageHeader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Image header: version {}, align {} bytes, {} items, \
+            size 0x{:x}, CRC32 0x{:08x}", self.version, self.align, 
+            self.item_count, self.image_size, self.crc)?;
+        if let Some(stamp) = &self.stamp {
+            write!(f, ", {}", stamp)?
+        }
+        Ok(())
+    }
+}
+
+/// An item info entry in the image table, as recorded in the image
+#[derive(Clone, Serialize)]
+pub struct RawItemInfo {
+    pub item_id: u32,
+    pub file_type: u32,
+    pub current_offset_in_item: u64,
+    pub offset_in_image: u64,
+    pub item_size: u64,
+    pub item_main_type: String,
+    pub item_sub_type: String,
+    pub verify: u32,
+    pub is_backup_item: u16,
+    pub backup_item_id: u16,
+    #[serde(serialize_with = "serialize_hex")]
+    pub reserve: [u8; SIZE_INFO_RESERVE],
 }
 
 impl<const LEN: usize> From<RawItemInfoVariableLength<LEN>> for RawItemInfo {
@@ -232,6 +600,7 @@ impl<const LEN: usize> From<RawItemInfoVariableLength<LEN>> for RawItemInfo {
             verify: value.verify,
             is_backup_item: value.is_backup_item,
             backup_item_id: value.backup_item_id,
+            reserve: value.reserve,
         }
     }
 }
@@ -243,632 +612,3970 @@ fn bytes_fill_from_str(dest: &mut [u8], src: &str) {
 }
 
 
-impl<const LEN: usize> Into<RawItemInfoVariableLength<LEN>> for &RawItemInfo {
-    fn into(self) -> RawItemInfoVariableLength<LEN> {
+impl<const LEN: usize> From<&RawItemInfo> for RawItemInfoVariableLength<LEN> {
+    fn from(value: &RawItemInfo) -> Self {
         let mut item_main_type = [0; LEN];
-        bytes_fill_from_str(&mut item_main_type, &self.item_main_type);
+        bytes_fill_from_str(&mut item_main_type, &value.item_main_type);
         let mut item_sub_type = [0; LEN];
-        bytes_fill_from_str(&mut item_sub_type, &self.item_sub_type);
-        RawItemInfoVariableLength { 
-            item_id: self.item_id,
-            file_type: self.file_type,
-            current_offset_in_item: self.current_offset_in_item,
-            offset_in_image: self.offset_in_image,
-            item_size: self.item_size,
+        bytes_fill_from_str(&mut item_sub_type, &value.item_sub_type);
+        Self { 
+            item_id: value.item_id,
+            file_type: value.file_type,
+            current_offset_in_item: value.current_offset_in_item,
+            offset_in_image: value.offset_in_image,
+            item_size: value.item_size,
             item_main_type,
             item_sub_type,
-            verify: self.verify,
-            is_backup_item: self.is_backup_item,
-            backup_item_id: self.backup_item_id, 
-            _reserve: [0; 24]
+            verify: value.verify,
+            is_backup_item: value.is_backup_item,
+            backup_item_id: value.backup_item_id, 
+            reserve: value.reserve,
         }
     }
 }
 
 
-#[derive(Default, Serialize, Deserialize)]
-struct Item {
-    data: Vec<u8>,
-    extension: String, // main type
-    stem: String, // sub type
-    sha1sum: Option<Sha1sum>,
+/// Convert a size or offset of `name` from the image to a usize, which is only
+/// 32-bit wide on 32-bit hosts, refusing instead of silently truncating it
+fn usize_from_u64(name: &str, size: u64) -> Result<usize> {
+    usize::try_from(size).map_err(|_| {
+        eprintln!("'{}' needs 0x{:x} bytes, too large for this platform \
+            (at most 0x{:x} bytes)", name, size, usize::MAX);
+        ImageError::TooLargeForPlatform { name: name.into(), size }.into()
+    })
 }
 
-#[derive(Default, Serialize, Deserialize)]
-pub(crate) struct Image {
-    version: ImageVersion,
-    align: u32,
-    items: Vec<Item>,
+/// Refuse items larger than `limit` (0 for unlimited) or than what could be
+/// held on this platform before reading them
+fn check_item_size(name: &str, size: u64, limit: u64) -> Result<()> {
+    usize_from_u64(name, size)?;
+    if limit != 0 && size > limit {
+        eprintln!("Item '{}' is 0x{:x} bytes, larger than the limit 0x{:x} \
+            bytes (use --max-item-size to change it)", name, size, limit);
+        return Err(ImageError::ItemTooLarge { 
+            name: name.into(), size, limit }.into())
+    }
+    Ok(())
 }
 
-impl Display for Image {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Amlogic image {}, align {} bytes, {} entries: [", 
-            self.version, self.align, self.items.len())?;
-        let mut start = false;
-        for item in self.items.iter() {
-            if start {
-                write!(f, ", ")?;
-            } else {
-                start = true
-            }
-            write!(f, "{{ {}.{}, 0x{} bytes, ",
-                item.stem, item.extension, item.data.len())?;
-            if let Some(sha1sum) = &item.sha1sum {
-                write!(f, "sha1sum: {}}}", sha1sum)?
-            } else {
-                write!(f, "no verify }}")?
-            }
+/// Summary of an item, for the JSON output
+#[derive(Serialize)]
+pub struct ItemSummary {
+    name: String,
+    stem: String,
+    extension: String,
+    size: u64,
+    sha1sum: Option<String>,
+    /// Name of the file it's unpacked to, if it is
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+}
+
+/// An item hashed in the single pass of `Image::verify`
+struct ItemInPass<'a> {
+    item: &'a Item,
+    /// Index of the item in the image
+    id: usize,
+    name: String,
+    /// Offset in the image file, None if not from it
+    start: Option<u64>,
+    sha1sum: Option<Sha1sumHasher>,
+    crc32: Option<Crc32Hasher>,
+    /// When the first and the last chunk of it were hashed
+    time_first: Option<Instant>,
+    time_last: Option<Instant>,
+}
+
+impl ItemInPass<'_> {
+    fn update(&mut self, data: &[u8]) {
+        let now = Instant::now();
+        self.time_first.get_or_insert(now);
+        self.time_last = Some(now);
+        if let Some(hasher) = self.sha1sum.as_mut() {
+            hasher.update(data)
         }
-        write!(f, "]")?;
-        Ok(())
+        if let Some(hasher) = self.crc32.as_mut() {
+            hasher.update(data)
+        }
+    }
+
+    /// Whether the sha1sum of what's hashed so far does not match the 
+    /// recorded one, without finalizing the hasher
+    fn mismatched(&self) -> bool {
+        match (&self.item.sha1sum, &self.sha1sum) {
+            (Some(sha1sum), Some(hasher)) => 
+                sha1sum != &hasher.clone().finalize(),
+            _ => false,
+        }
+    }
+
+    /// Check the sha1sum, if any, and calculate the standard CRC32, if asked
+    /// for
+    fn finalize(self) -> ItemCheck {
+        let mut check = ItemCheck {
+            size: self.item.data.len(),
+            algorithm: None,
+            sha1sum_recorded: None,
+            sha1sum_calculated: None,
+            crc32: self.crc32.map(|hasher|hasher.finalize_standard()),
+            status: CheckStatus::NoChecksum,
+            duration: match (self.time_first, self.time_last) {
+                (Some(first), Some(last)) => (last - first).as_secs_f64(),
+                _ => 0.0,
+            },
+            name: self.name,
+        };
+        let (sha1sum_record, sha1sum_hasher) = 
+            match (&self.item.sha1sum, self.sha1sum) 
+        {
+            (Some(sha1sum), Some(hasher)) => (sha1sum, hasher),
+            _ => return check,
+        };
+        let sha1sum_calculated = sha1sum_hasher.finalize();
+        log::debug!("Sha1sum of item '{}' is {}, recorded {}", 
+            check.name, sha1sum_calculated, sha1sum_record);
+        check.status = if sha1sum_record == &sha1sum_calculated {
+            CheckStatus::Verified
+        } else {
+            eprintln!("Recorded SHA1sum ({}) different from calculated \
+                SHA1sum ({}) for item '{}'", sha1sum_record, 
+                sha1sum_calculated, check.name);
+            CheckStatus::Mismatch
+        };
+        check.algorithm = Some(ChecksumAlgo::Sha1);
+        check.sha1sum_recorded = Some(sha1sum_record.to_string());
+        check.sha1sum_calculated = Some(sha1sum_calculated.to_string());
+        check
     }
 }
 
-macro_rules! cell_right {
-    ($raw: expr) => {
-        $raw.cell().justify(Justify::Right)
-    };
+/// How an item fared in `Image::verify`
+#[derive(Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum CheckStatus {
+    /// The sha1sum matches the recorded one
+    Verified,
+    /// The sha1sum does not match the recorded one
+    Mismatch,
+    /// No sha1sum recorded, only covered by the CRC32 of the image
+    NoChecksum,
 }
-macro_rules! cell_bold_center {
-    ($raw: expr) => {
-        $raw.cell().bold(true).justify(Justify::Center)
-    };
+
+/// The result of verifying an item
+#[derive(Serialize)]
+pub struct ItemCheck {
+    pub name: String,
+    pub size: u64,
+    /// The algorithm of the recorded checksum, if any
+    pub algorithm: Option<ChecksumAlgo>,
+    pub sha1sum_recorded: Option<String>,
+    pub sha1sum_calculated: Option<String>,
+    /// The standard CRC32, if asked for
+    pub crc32: Option<u32>,
+    pub status: CheckStatus,
+    /// Seconds from the first to the last chunk of it hashed in the pass
+    pub duration: f64,
 }
 
-fn sort_ref_items_by_name(some: &&Item, other: &&Item) -> Ordering {
-    let order_stem = some.stem.cmp(&other.stem);
-    if order_stem == std::cmp::Ordering::Equal {
-        some.extension.cmp(&other.extension)
-    } else {
-        order_stem
-    }
+/// The result of `Image::verify`
+#[derive(Serialize)]
+pub struct VerifyReport {
+    pub header: ImageHeader,
+    pub items: Vec<ItemCheck>,
+    pub crc32_recorded: u32,
+    pub crc32_calculated: u32,
+    /// The digest of the whole file, if asked for
+    pub digest: Option<FileDigest>,
 }
 
-fn sort_items_by_name(some: &Item, other: &Item) -> Ordering {
-    let order_stem = some.stem.cmp(&other.stem);
-    if order_stem == std::cmp::Ordering::Equal {
-        some.extension.cmp(&other.extension)
-    } else {
-        order_stem
+impl CheckStatus {
+    /// What to show in the status column of the verify table
+    fn label(&self) -> &'static str {
+        match self {
+            CheckStatus::Verified => "PASS",
+            CheckStatus::Mismatch => "FAIL",
+            CheckStatus::NoChecksum => "SKIPPED",
+        }
     }
 }
 
-impl Image {
-    fn find_item(&self, stem: &str, extension: &str) -> Result<&Item> {
-        let mut result = None;
+impl VerifyReport {
+    /// How many items passed, failed, and had no checksum to verify
+    pub fn tally(&self) -> (usize, usize, usize) {
+        let count = |status| self.items.iter()
+            .filter(|item|item.status == status).count();
+        (count(CheckStatus::Verified), count(CheckStatus::Mismatch), 
+            count(CheckStatus::NoChecksum))
+    }
 
-        for item in self.items.iter() {
-            if item.stem == stem && item.extension == extension {
-                if result.is_some() {
-                    eprintln!("Duplicated image item: {}.{}", stem, extension);
-                    return Err(ImageError::DuplicatedItem { 
-                        stem: stem.into(), extension: extension.into()}.into());
-                }
-                result = Some(item);
-            }
-        }
-        match result {
-            Some(item) => Ok(item),
-            None => {
-                eprintln!("Missing image item: {}.{}", stem, extension);
-                Err(ImageError::MissingItem { 
-                    stem: stem.into(), extension: extension.into() }.into())
-            }
+    /// The error to fail with, if any item or the CRC32 of the image did not 
+    /// match
+    pub fn error(&self) -> Option<Error> {
+        let (_, failed, _) = self.tally();
+        if failed > 0 {
+            Some(ImageError::ItemsFailed { count: failed }.into())
+        } else if self.crc32_recorded != self.crc32_calculated {
+            Some(ImageError::CrcMismatch { recorded: self.crc32_recorded, 
+                calculated: self.crc32_calculated }.into())
+        } else {
+            None
         }
     }
+}
+
+/// Options controlling how `Image::try_read_file` reads the image
+#[derive(Default, Clone)]
+pub struct ReadFileOptions {
+    /// Refuse items larger than this, 0 for unlimited
+    pub max_item_size: u64,
+    /// Map the image into memory and use payloads as slices of the mapping
+    #[cfg(feature = "mmap")]
+    pub mmap: bool,
+    /// Stop at the first structural problem, instead of reporting all of them
+    pub fail_fast: bool,
+}
+
+/// An image read, along with its header and the item infos of its table as
+/// read, e.g. for the offsets and file types of items, and the items skipped
+/// for failures with their errors when reading while ignoring errors
+pub struct ImageRead {
+    pub image: Image,
+    pub header: ImageHeader,
+    pub infos: Vec<RawItemInfo>,
+    pub failures: Vec<(String, Error)>,
+}
+
+/// Options controlling how `Image::try_write_dir` writes into the dir
+#[derive(Default)]
+pub struct WriteDirOptions {
+    /// Write into the existing dir instead of wiping it, only overwrite files
+    /// of items, and only when their content differs
+    pub keep: bool,
+    /// Number of items written concurrently, 0 to decide by the storage
+    pub jobs: usize,
+    /// Verify items having sha1sums against the bytes as they're written
+    pub verify: bool,
+    /// Keep items failed to verify with a .corrupt suffix and go on, instead
+    /// of removing them and failing
+    pub keep_corrupted: bool,
+    /// Seek over blocks of all zeros instead of writing them, leaving holes
+    /// in the files on filesystems supporting sparse files
+    pub sparse: bool,
+}
+
+/// What `Image::try_write_dir` did to the dir
+#[derive(Default)]
+pub struct WriteDirReport {
+    /// Items failed to verify and kept with a .corrupt suffix, with why
+    pub corrupted: Vec<(String, Error)>,
+    /// File names of items already existing with the same content, left 
+    /// untouched, only with `keep`
+    pub unchanged: Vec<String>,
+    /// Entries already in the dir not belonging to any item, sorted, only
+    /// with `keep`
+    pub extra: Vec<String>,
+}
+
+/// What happened to an item in `Image::try_write_dir`
+enum WriteOutcome {
+    Written,
+    /// Already existing with the same content, left untouched
+    Unchanged,
+    /// Written with a .corrupt suffix as it failed to verify
+    Corrupted(Error),
+}
+
+/// Size of the blocks checked for all zeros when writing sparse files, the
+/// common filesystem block size
+const SIZE_SPARSE_BLOCK: usize = 0x1000;
 
-    fn find_essentials(&self) -> Result<(&Item, &Item, &Item, &Item, &Item)> {
-        Ok((
-            self.find_item("DDR", "USB")?,
-            self.find_item("UBOOT", "USB")?,
-            self.find_item("aml_sdc_burn", "ini")?,
-            self.find_item("meson1", "dtb")?,
-            self.find_item("platform", "conf")?,
-        ))
+/// Write `chunk` into `file` at its current offset, but seek over runs of 
+/// blocks of all zeros instead of writing them, so they become holes
+fn write_all_sparse(file: &mut File, chunk: &[u8]) 
+    -> std::result::Result<(), TransferError> 
+{
+    let is_zeros = |block: &[u8]| block.iter().all(|byte| *byte == 0);
+    let mut blocks = chunk.chunks(SIZE_SPARSE_BLOCK).peekable();
+    let mut offset = 0;
+    while let Some(block) = blocks.next() {
+        let zeros = is_zeros(block);
+        let mut len = block.len();
+        while let Some(block) = blocks.next_if(|block| is_zeros(block) == zeros) {
+            len += block.len()
+        }
+        if zeros {
+            file.seek(SeekFrom::Current(len as i64)).map_err(|error| 
+                TransferError { error, transferred: offset })?;
+        } else {
+            retry::write_all(file, &chunk[offset..offset + len])
+                .map_err(|mut e| {
+                    e.transferred += offset;
+                    e
+                })?;
+        }
+        offset += len
     }
+    Ok(())
+}
 
-    pub(crate) fn verify(&self) -> Result<()> {
-        let _ = self.find_essentials();
-        let need_verifies: Vec<&Item> = self.items.iter().filter(
-            |item|item.sha1sum.is_some()).collect();
-        let multi_progress = MultiProgress::new();
-        let template_prefix = 
-            "Verifying item => [{elapsed_precise}] {bar:40.cyan/blue} \
-            {pos:>5}/{len:>5} MiB ".to_string();
-        let mut mapped = Vec::new();
-        for item in need_verifies.iter() {
-            let name = format!("{}.{}", item.stem, item.extension);
-            let mut template = template_prefix.clone();
-            template.push_str(&name);
-            let progress_bar = progress_bar_with_template_multi(
-                &multi_progress, 
-                item.data.len() as u64 / 0x100000, 
-                &template)?;
-            mapped.push((*item, name, progress_bar))
-        }
-        use rayon::prelude::*;
-        let result = mapped.par_iter_mut().map(|(item, name, progress_bar)| {
-            let sha1sum_record = match &item.sha1sum {
-                Some(sha1sum) => sha1sum,
-                None => {
-                    eprintln!("Verify item not found for {}.{}", 
-                        &item.stem, &item.extension);
-                    return Err(ImageError::MissingItem { 
-                        stem: item.stem.clone(), extension: "VERIFY".into()
-                    }.into());
-                },
-            };
-            let sha1sum_calculated = Sha1sum::from_data_with_bar(&item.data, progress_bar);
-            if sha1sum_record != &sha1sum_calculated {
-                eprintln!("Recorded SHA1sum ({}) different from calculated \
-                    SHA1sum ({}) for item '{}'", sha1sum_record, 
-                    sha1sum_calculated, name);
-                return Err(ImageError::IllegalVerify.into());
+/// Write the payload of item `name` to a new file at `path`, feeding the same
+/// chunks to `hasher` if any, giving up as soon as `abort` is set, the partial
+/// file is removed on any failure. With `sparse`, blocks of all zeros are left
+/// as holes.
+fn write_item_file(path: &Path, data: &Payload, name: &str, 
+                   mut hasher: Option<&mut Sha1sumHasher>, sparse: bool,
+                   progress_bar: &Bar, abort: &AtomicBool) 
+    -> Result<()> 
+{
+    let result = File::create(path).map_err(Error::from).and_then(|mut file| {
+        let mut written = 0;
+        data.try_for_each_chunk(name, |chunk| {
+            if abort.load(AtomicOrdering::Relaxed) {
+                return Err(ImageError::Aborted.into())
             }
-            Ok(())
-        }).find_first(|r|r.is_err());
-        multi_progress.clear()?;
-        if let Some(r) = result {
-            if let Err(e) = r {
-                return Err(e)
+            let result = if sparse {
+                write_all_sparse(&mut file, chunk)
             } else {
-                eprintln!("Unexpected: Filtered error still results in OK");
+                retry::write_all(&mut file, chunk)
+            };
+            result.map_err(|mut e| {
+                e.transferred += written;
+                ImageError::write_failed(name, 0, data.len(), e)
+            })?;
+            if let Some(hasher) = hasher.as_mut() {
+                hasher.update(chunk)
             }
+            written += chunk.len();
+            progress_bar.inc(chunk.len() as u64);
+            Ok(())
+        })?;
+        if sparse {
+            // A trailing hole is only skipped over, not in the file yet
+            file.set_len(data.len())?
         }
         Ok(())
+    });
+    if result.is_err() && path.exists() {
+        if let Err(e) = remove_file(path) {
+            eprintln!("Failed to remove partially written file '{}': {}", 
+                path.display(), e)
+        }
+    }
+    result
+}
+
+const SIZE_SPARSE_HEADER: usize = 28;
+
+/// Sanity check the Android sparse header at the start of `head` of item 
+/// `name`, returning whether there's one at all
+fn check_sparse_header(name: &str, head: &[u8]) -> Result<bool> {
+    let field_u16 = |offset: usize| 
+        u16::from_le_bytes([head[offset], head[offset + 1]]);
+    let field_u32 = |offset: usize| u32::from_le_bytes(
+        [head[offset], head[offset + 1], head[offset + 2], head[offset + 3]]);
+    if head.len() < SIZE_SPARSE_HEADER || field_u32(0) != SPARSE_MAGIC {
+        return Ok(false)
     }
+    let reason = if field_u16(4) != 1 {
+        format!("unsupported major version {}", field_u16(4))
+    } else if field_u16(8) as usize != SIZE_SPARSE_HEADER {
+        format!("unexpected header size {}", field_u16(8))
+    } else if field_u16(10) != 12 {
+        format!("unexpected chunk header size {}", field_u16(10))
+    } else if field_u32(12) == 0 || field_u32(12) % 4 != 0 {
+        format!("illegal block size {}", field_u32(12))
+    } else {
+        return Ok(true)
+    };
+    eprintln!("Item '{}' has an illegal Android sparse header: {}", name, reason);
+    Err(ImageError::IllegalSparse { name: name.into(), reason }.into())
+}
 
-    pub(crate) fn clear_verify(&mut self) {
-        for item in self.items.iter_mut() {
-            item.sha1sum = None
+/// Record the sha1sums of the item files just verified in `dir`, along with 
+/// their file names, for pack to skip hashing them again. When keeping the 
+/// dir, the entries of other files are kept too.
+fn try_write_checksum_cache(dir: &Path, verified: &[(&Item, String)], keep: bool)
+    -> Result<()>
+{
+    let mut cache = if keep {
+        ChecksumCache::read_dir_or_default(dir)
+    } else {
+        ChecksumCache::default()
+    };
+    for (item, name) in verified.iter() {
+        if let Some(sha1sum) = &item.sha1sum {
+            cache.insert(name, &dir.join(name), sha1sum)?
         }
     }
+    if ! cache.is_empty() {
+        cache.try_write_file(dir.join(CHECKSUM_CACHE_NAME))?
+    }
+    Ok(())
+}
 
-    pub(crate) fn fill_verify(&mut self) -> Result<()> {
-        let mut need_verifies: Vec<&mut Item> = self.items.iter_mut().filter(
-            |item|item.sha1sum.is_none()).collect();
-        let multi_progress = MultiProgress::new();
-        let mut mapped = Vec::new();
-        let template_prefix = 
-            "Generating verify => [{elapsed_precise}] {bar:40.cyan/blue} \
-            {pos:>5}/{len:>5} MiB ".to_string();
-        for item in need_verifies.iter_mut() {
-            let name = format!("{}.{}", item.stem, item.extension);
-            let mut template = template_prefix.clone();
-            template.push_str(&name);
-            let progress_bar = progress_bar_with_template_multi(
-                &multi_progress, 
-                item.data.len() as u64 / 0x100000,
-                &template)?;
-            mapped.push((item, progress_bar))
-        }
-        use rayon::prelude::*;
-        let sha1sums: Vec<Sha1sum> = mapped.par_iter_mut().map(|(item, progress_bar)| {
-            Sha1sum::from_data_with_bar(&item.data, progress_bar)
-        }).collect();
-        multi_progress.clear()?;
-        for (item, sha1sum) in need_verifies.iter_mut().zip(sha1sums.into_iter()) {
-            item.sha1sum = Some(sha1sum)
+/// Whether the file at `path` exists and has exactly the content of the 
+/// payload of item `name`, if compared the payload is also fed to `hasher`
+fn file_content_equals(path: &Path, data: &Payload, name: &str, 
+                       mut hasher: Option<&mut Sha1sumHasher>) 
+    -> Result<bool> 
+{
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e.into()),
+    };
+    if file.metadata()?.len() != data.len() {
+        return Ok(false)
+    }
+    let mut buffer = vec![0; chunk_size()];
+    let mut equal = true;
+    data.try_for_each_chunk(name, |chunk| {
+        if equal {
+            let buffer = &mut buffer[0..chunk.len()];
+            file.read_exact(buffer)?;
+            equal = buffer == chunk;
+        }
+        if let Some(hasher) = hasher.as_mut() {
+            hasher.update(chunk)
         }
         Ok(())
+    })?;
+    Ok(equal)
+}
+
+/// Options controlling how `Image::try_read_dir` treats the files in the dir
+#[derive(Default)]
+pub struct ReadDirOptions {
+    /// Accept files that are Amlogic images themselves as generic items
+    pub allow_nested_image: bool,
+    /// Refuse files larger than this, 0 for unlimited
+    pub max_item_size: u64,
+    /// Keep only the lexicographically first of files whose names only differ
+    /// in case, instead of refusing them
+    pub case_insensitive_merge: bool,
+    /// Refuse entries that would be skipped instead of only warning about them
+    pub error_on_skip: bool,
+}
+
+/// What an entry in the dir to pack turned out to be
+enum ProbedEntry {
+    Item(Box<Item>),
+    /// A file ampack writes next to item files, not an item itself
+    Sidecar,
+    /// Not a file named as [stem].[extension]
+    Skipped,
+}
+
+/// Stat `entry` and peek into its head without reading it in whole, so this
+/// could run for many entries in parallel cheaply
+fn probe_dir_entry(entry: &DirEntry, options: &ReadDirOptions) 
+    -> Result<ProbedEntry> 
+{
+    let path_entry = entry.path();
+    let file_name = match path_entry.file_name() {
+        Some(file_name) => file_name.to_string_lossy(),
+        None => {
+            return Err(Error::IOError(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "Cannot figure out the file name of part")));
+        },
+    };
+    if file_name == MANIFEST_NAME || file_name == CHECKSUM_CACHE_NAME {
+        return Ok(ProbedEntry::Sidecar)
     }
+    let metadata = std::fs::metadata(&path_entry)?;
+    let (stem, extension) = match 
+        file_name.split_once('.') 
+    {
+        Some((stem, extension)) if ! metadata.is_dir() => 
+            (stem, extension),
+        _ => return Ok(ProbedEntry::Skipped)
+    };
+    check_item_size(&file_name, metadata.len(), options.max_item_size)?;
+    let data = Payload::Region { 
+        source: SourceFile::open(&path_entry)?, offset: 0, size: metadata.len() };
+    if ! options.allow_nested_image && 
+        data.try_head(&file_name, 12)?.get(8..12) == 
+            Some(&MAGIC.to_le_bytes()) 
+    {
+        eprintln!("File '{}' is an Amlogic image itself, probably \
+            the output of an earlier pack, refuse to pack it into \
+            the new image (use --allow-nested-image to override)", 
+            path_entry.display());
+        return Err(ImageError::NestedImage { 
+            path: path_entry.display().to_string() }.into())
+    }
+    Ok(ProbedEntry::Item(Box::new(Item {
+        data,
+        extension: extension.into(),
+        stem: stem.into(),
+        sha1sum: None,
+        reserve: Vec::new(),
+        verify_reserve: Vec::new(),
+        text_normalization: None,
+        original_id: None,
+        original_name: None,
+        backup_of: None,
+        backup: BackupPolicy::Auto,
+    })))
+}
 
-    pub(crate) fn try_read_file<P: AsRef<Path>>(file: P) -> Result<Self> {
-        let path_file = file.as_ref();
-        let mut file = File::open(path_file)?;
-        let mut buffer = [0; 0x10000];
-        file.read_exact(&mut buffer[0..SIZE_RAW_IMAGE_HEAD])?;
-        let header = unsafe {
-            (buffer.as_ptr() as *const RawImageHead).read()};
-        if header.magic != MAGIC {
-            eprintln!("Image magic invalid: expected 0x{}, found 0x{}", 
-                MAGIC, {header.magic});
-            return Err(ImageError::InvalidMagic{magic: header.magic}.into())
-        }
-        let version = 
-            ImageVersion::try_from(header.version)?;
-        let size_info = version.size_raw_info();
-        let buffer_info = &mut buffer[0..size_info];
-        let mut items = Vec::new();
-        let mut need_verify: Option<Item> = None;
-        let mut rows = Vec::new();
-        let progress_bar = progress_bar_with_template(
-            header.item_count.into(), 
-            "Reading image => [{elapsed_precise}] {bar:40.cyan/blue} \
-                                        {pos:>7}/{len:7} {msg}")?;
-        progress_bar.enable_steady_tick(Duration::from_secs(1));
-        for item_id in 0..header.item_count {
-            file.seek(std::io::SeekFrom::Start(
-                SIZE_RAW_IMAGE_HEAD as u64 + 
-                    size_info as u64 * item_id as u64))?;
-            file.read_exact(buffer_info)?;
-            let pointer = buffer_info.as_ptr();
-            let item_info: RawItemInfo = match version {
-                ImageVersion::V1 => unsafe {(pointer as *const RawItemInfoV1).read()}.into(),
-                ImageVersion::V2 => unsafe {(pointer as *const RawItemInfoV2).read()}.into(),
-            };
-            progress_bar.set_message(format!("{}.{}", 
-                item_info.item_sub_type, item_info.item_main_type));
-            file.seek(std::io::SeekFrom::Start(item_info.offset_in_image))?;
-            let mut data = vec![0; item_info.item_size as usize];
-            file.read_exact(&mut data)?;
-            if let Some(mut item_need_verify) = need_verify {
+/// Find entries whose names are the same when compared case-insensitively,
+/// these would become items that burning tools can't tell apart. Either 
+/// refuse them, or keep only the lexicographically first one of each group.
+fn dedup_entries_by_name(mut entries: Vec<DirEntry>, merge: bool) 
+    -> Result<Vec<DirEntry>> 
+{
+    entries.sort_by_key(|entry|entry.file_name());
+    let mut kept: Vec<DirEntry> = Vec::new();
+    let mut collision = None;
+    for entry in entries {
+        let name = entry.file_name().to_string_lossy().to_lowercase();
+        let first = match kept.iter().find(|kept|
+            kept.file_name().to_string_lossy().to_lowercase() == name) 
+        {
+            Some(first) => first,
+            None => {
+                kept.push(entry);
+                continue
+            },
+        };
+        if merge {
+            warning!(CaseCollision, "'{}' collides with '{}' case-insensitively, \
+                only the latter would be packed", entry.path().display(), 
+                first.path().display());
+        } else {
+            eprintln!("'{}' collides with '{}' case-insensitively, they would \
+                become items that could not be told apart (use \
+                --case-insensitive-merge to only pack the latter)", 
+                entry.path().display(), first.path().display());
+            collision.get_or_insert(
+                entry.file_name().to_string_lossy().into_owned());
+        }
+    }
+    if let Some(name) = collision {
+        let (stem, extension) = name.split_once('.').unwrap_or((&name, ""));
+        return Err(ImageError::DuplicatedItem { 
+            stem: stem.into(), extension: extension.into() }.into())
+    }
+    Ok(kept)
+}
+
+/// An item in an image, i.e. a partition or a file for the burning tool, 
+/// named as `[stem].[extension]`, or `[sub type].[main type]` in item infos
+#[derive(Default, Serialize, Deserialize)]
+pub struct Item {
+    data: Payload,
+    extension: String, // main type
+    stem: String, // sub type
+    sha1sum: Option<Sha1sum>,
+    /// Reserved bytes in the item info, empty if all zero
+    reserve: Vec<u8>,
+    /// Reserved bytes in the VERIFY item info, empty if all zero
+    verify_reserve: Vec<u8>,
+    /// How the data was normalized from the file by pack, if it was
+    text_normalization: Option<TextNormalization>,
+    /// ID in the item info table of the image it was read from, or recorded
+    /// in the manifest, to be kept when written if all items have one
+    original_id: Option<u32>,
+    /// Name before `Image::sanitize_names` changed it, if it did
+    original_name: Option<String>,
+    /// Name of the item this must be written as a backup of, from manifest
+    backup_of: Option<String>,
+    /// Whether this could be deduped against identical items, from manifest
+    backup: BackupPolicy,
+}
+
+impl Item {
+    /// A new item held in memory, its sha1sum is to be calculated by 
+    /// `Image::fill_verify` before the image is written
+    pub fn new(stem: &str, extension: &str, data: Vec<u8>) -> Self {
+        Self {
+            data: Payload::Owned(data),
+            extension: extension.into(),
+            stem: stem.into(),
+            ..Default::default()
+        }
+    }
+
+    /// The name as `[stem].[extension]`, e.g. `boot.PARTITION`
+    pub fn name(&self) -> String {
+        format!("{}.{}", self.stem, self.extension)
+    }
+
+    /// The stem, or the sub type in item infos, e.g. `boot`
+    pub fn stem(&self) -> &str {
+        &self.stem
+    }
+
+    /// The extension, or the main type in item infos, e.g. `PARTITION`
+    pub fn extension(&self) -> &str {
+        &self.extension
+    }
+
+    pub fn size(&self) -> u64 {
+        self.data.len()
+    }
+
+    /// The sha1sum recorded in its VERIFY item, or calculated when packing
+    pub fn sha1sum(&self) -> Option<&Sha1sum> {
+        self.sha1sum.as_ref()
+    }
+
+    /// The ID in the image it was read from, or recorded in the manifest
+    pub fn original_id(&self) -> Option<u32> {
+        self.original_id
+    }
+
+    /// The name before `Image::sanitize_names` changed it, if it did
+    pub fn original_name(&self) -> Option<&str> {
+        self.original_name.as_deref()
+    }
+
+    /// Whether the burning tool could not do without it
+    pub fn is_essential(&self) -> bool {
+        ESSENTIALS.contains(&(self.stem.as_str(), self.extension.as_str()))
+    }
+
+    /// Read the whole payload into memory
+    pub fn try_to_vec(&self) -> Result<Vec<u8>> {
+        self.data.try_to_vec(&self.name())
+    }
+
+    /// Read the payload from `offset` in it into `buffer`, returning the count
+    /// of bytes read, which is less than the length of `buffer` only at the 
+    /// end of the payload
+    pub fn try_read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize> {
+        let mut done = 0;
+        self.data.slice(offset, buffer.len() as u64).try_for_each_chunk(
+            &self.name(), |chunk| 
+        {
+            buffer[done..done + chunk.len()].copy_from_slice(chunk);
+            done += chunk.len();
+            Ok(())
+        })?;
+        Ok(done)
+    }
+}
+
+/// Sha1sums of `items`, in their order, items hashed in parallel if they 
+/// could be read so, with bars labelled `label`
+fn sha1sums_of(items: Vec<&Item>, label: &'static str) 
+    -> Result<Vec<Sha1sum>> 
+{
+    if items.is_empty() {
+        return Ok(Vec::new())
+    }
+    let bars = ItemBars::new(label, 
+        items.iter().map(|item|item.data.len()).sum())?;
+    let parallel = reads_in_parallel(items.iter().map(|item|&item.data));
+    let results = map_jobs(items, parallel, |item: &Item| {
+        let name = format!("{}.{}", item.stem, item.extension);
+        let progress_bar = bars.start(&name, item.data.len())?;
+        let _reservation = item.data.reserve_buffers();
+        let result = Sha1sum::try_from_payload_with_bar(
+            &item.data, &name, &progress_bar);
+        bars.done(&progress_bar);
+        result
+    });
+    bars.finish("Hashed");
+    results.into_iter().collect()
+}
+
+/// An Amlogic burning image, read from an image file or a dir of item files,
+/// to be verified, or written as an image file or into a dir of item files
+#[derive(Default, Serialize, Deserialize)]
+pub struct Image {
+    version: ImageVersion,
+    align: u32,
+    items: Vec<Item>,
+    /// Reserved bytes in the image header, empty if all zero
+    reserve: Vec<u8>,
+    /// Size to pad the image to with zeros when writing it, 0 for no padding
+    pad_to: u64,
+}
+
+/// Where `Image::spill_payloads` put the in-memory payloads
+pub struct SpillReport {
+    /// Names and sizes of in-memory items, with the files they were spilled
+    /// to, or `None` if kept in memory
+    pub items: Vec<(String, u64, Option<PathBuf>)>,
+    pub max_memory: u64,
+    /// Size of the buffers used to stream the payloads
+    pub size_staging: u64,
+    /// Size of the payloads kept in memory
+    pub in_memory: u64,
+    /// Size of the payloads spilled
+    pub spilled: u64,
+}
+
+impl Display for SpillReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (name, size, path) in self.items.iter() {
+            match path {
+                Some(path) => writeln!(f, "Item '{}' (0x{:x} bytes) spilled \
+                    to '{}'", name, size, path.display())?,
+                None => writeln!(f, "Item '{}' (0x{:x} bytes) kept in memory", 
+                    name, size)?,
+            }
+        }
+        write!(f, "Payloads in memory: 0x{:x} bytes plus 0x{:x} bytes of \
+            streaming buffers (budget 0x{:x} bytes), spilled: 0x{:x} bytes", 
+            self.in_memory, self.size_staging, self.max_memory, self.spilled)
+    }
+}
+
+/// A one-paragraph overview of an image, for scripts and posts
+pub struct ImageSummary {
+    version: ImageVersion,
+    align: u32,
+    /// Names and sizes of items, VERIFY ones excluded
+    items: Vec<(String, u64)>,
+    /// How many items have sha1sums
+    verified: usize,
+}
+
+impl ImageSummary {
+    /// Summarize the image from its header and item infos only
+    pub fn from_infos(header: &ImageHeader, infos: &[RawItemInfo]) 
+        -> Self 
+    {
+        Self {
+            version: header.version.clone(),
+            align: header.align,
+            items: infos.iter()
+                .filter(|info|info.item_main_type != "VERIFY")
+                .map(|info|(format!("{}.{}", info.item_sub_type, 
+                    info.item_main_type), info.item_size))
+                .collect(),
+            verified: infos.iter()
+                .filter(|info|info.item_main_type == "VERIFY").count(),
+        }
+    }
+}
+
+impl Display for ImageSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Amlogic image {}, align {} bytes, {} items of {} in total, \
+            {} with sha1sums: ", self.version, self.align, self.items.len(), 
+            output::size_text(self.items.iter().map(|(_, size)|size).sum()),
+            self.verified)?;
+        for (id, (name, size)) in self.items.iter().enumerate() {
+            if id > 0 {
+                write!(f, ", ")?
+            }
+            write!(f, "{} ({})", name, output::size(*size))?
+        }
+        Ok(())
+    }
+}
+
+impl Display for Image {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.summary().fmt(f)
+    }
+}
+
+macro_rules! cell_right {
+    ($raw: expr) => {
+        $raw.cell().justify(Justify::Right)
+    };
+}
+macro_rules! cell_bold_center {
+    ($raw: expr) => {
+        $raw.cell().bold(true).justify(Justify::Center)
+    };
+}
+
+/// Map a part of item name through the sanitization rules: trim whitespaces,
+/// replace inner whitespaces with `_`, and optionally lowercase
+fn sanitize_name_part(part: &str, lowercase: bool) -> String {
+    let part = part.trim().replace(char::is_whitespace, "_");
+    if lowercase {
+        part.to_lowercase()
+    } else {
+        part
+    }
+}
+
+const UTF8_BOM: [u8; 3] = [0xef, 0xbb, 0xbf];
+
+/// Device names reserved by Windows, no matter the extension
+const WINDOWS_RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL",
+    "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Map item names (`[stem].[extension]`) to file names that could all be 
+/// created on Windows/NTFS, in the same order. The escaping scheme:
+/// - characters illegal in Windows file names (`<>:"/\|?*` and control
+///   characters) are replaced with `_`
+/// - trailing dots and spaces are replaced with `_`
+/// - stems that are reserved device names (`CON`, `AUX`, `COM1`, etc, 
+///   case-insensitively) are prefixed with `_`
+/// - names only differing in case from an earlier name get their stem
+///   suffixed with `~[n]`, with the smallest `n` making it unique
+/// 
+/// Names needing none of these are kept as-is.
+pub fn windows_file_names(names: &[String]) -> Vec<String> {
+    let mut taken: Vec<String> = Vec::new();
+    let mut file_names = Vec::new();
+    for name in names.iter() {
+        let mut file_name: String = name.chars().map(|c| 
+            if c.is_control() || "<>:\"/\\|?*".contains(c) { '_' } else { c }
+        ).collect();
+        let trimmed_len = file_name.trim_end_matches(['.', ' ']).len();
+        if trimmed_len < file_name.len() {
+            let trailing = file_name.len() - trimmed_len;
+            file_name.truncate(trimmed_len);
+            file_name.push_str(&"_".repeat(trailing));
+        }
+        let (stem, extension) = match file_name.split_once('.') {
+            Some((stem, extension)) => (stem.to_string(), Some(extension.to_string())),
+            None => (file_name.clone(), None),
+        };
+        let mut stem = stem;
+        if WINDOWS_RESERVED_NAMES.iter().any(|reserved| 
+            reserved.eq_ignore_ascii_case(stem.trim_end())) 
+        {
+            stem.insert(0, '_')
+        }
+        let join = |stem: &str| match &extension {
+            Some(extension) => format!("{}.{}", stem, extension),
+            None => stem.to_string(),
+        };
+        let mut file_name = join(&stem);
+        let mut n = 0;
+        while taken.contains(&file_name.to_lowercase()) {
+            n += 1;
+            file_name = join(&format!("{}~{}", stem, n));
+        }
+        taken.push(file_name.to_lowercase());
+        file_names.push(file_name)
+    }
+    file_names
+}
+
+/// The items in the order of their original IDs, if all of them have one and
+/// these, along with those of VERIFY items right after their partitions, are
+/// exactly 0 to the count of item infos, so writing them in this order gives 
+/// each the same ID. None if no item has one, or why they can't be kept.
+fn order_by_original_ids(items: &[Item]) 
+    -> std::result::Result<Option<Vec<&Item>>, String> 
+{
+    if items.iter().all(|item|item.original_id.is_none()) {
+        return Ok(None)
+    }
+    let count = items.len() + 
+        items.iter().filter(|item|item.extension == "PARTITION").count();
+    let mut taken: Vec<Option<String>> = vec![None; count];
+    for item in items.iter() {
+        let name = format!("{}.{}", item.stem, item.extension);
+        let id = match item.original_id {
+            Some(id) => id,
+            None => return Err(format!("item '{}' has none", name)),
+        };
+        let mut ids = vec![(id, name)];
+        if item.extension == "PARTITION" {
+            ids.push((id.saturating_add(1), format!("{}.VERIFY", item.stem)))
+        }
+        for (id, name) in ids {
+            match taken.get_mut(id as usize) {
+                Some(Some(other)) => return Err(format!(
+                    "'{}' and '{}' both have ID {}", other, name, id)),
+                Some(slot) => *slot = Some(name),
+                None => return Err(format!(
+                    "ID {} of '{}' is not below the item count {}", 
+                    id, name, count)),
+            }
+        }
+    }
+    let mut items: Vec<&Item> = items.iter().collect();
+    items.sort_by_key(|item|item.original_id);
+    Ok(Some(items))
+}
+
+fn sort_ref_items_by_name(some: &&Item, other: &&Item) -> Ordering {
+    let order_stem = some.stem.cmp(&other.stem);
+    if order_stem == std::cmp::Ordering::Equal {
+        some.extension.cmp(&other.extension)
+    } else {
+        order_stem
+    }
+}
+
+fn sort_items_by_name(some: &Item, other: &Item) -> Ordering {
+    let order_stem = some.stem.cmp(&other.stem);
+    if order_stem == std::cmp::Ordering::Equal {
+        some.extension.cmp(&other.extension)
+    } else {
+        order_stem
+    }
+}
+
+/// Hexdump the reserved regions in header and item infos that are not all zero
+pub fn print_reserves_hexdump(header: &ImageHeader, infos: &[RawItemInfo]) {
+    let mut found = false;
+    if header.reserve.iter().any(|byte| *byte != 0) {
+        println!("Reserved bytes in image header:");
+        print_hexdump(&header.reserve, SIZE_RAW_IMAGE_HEAD - SIZE_HEAD_RESERVE);
+        found = true
+    }
+    let size_info = header.version.size_raw_info();
+    for (id, info) in infos.iter().enumerate() {
+        if info.reserve.iter().all(|byte| *byte == 0) {
+            continue
+        }
+        println!("Reserved bytes in item info {} ({}.{}):", id, 
+            info.item_sub_type, info.item_main_type);
+        print_hexdump(&info.reserve, SIZE_RAW_IMAGE_HEAD + 
+            size_info * (id + 1) - SIZE_INFO_RESERVE);
+        found = true
+    }
+    if ! found {
+        println!("All reserved bytes in image header and item infos are zero")
+    }
+}
+
+/// Whether ampack knows what the file type of an item info means
+fn is_file_type_known(file_type: u32) -> bool {
+    matches!(file_type, FILE_TYPE_GENERIC | FILE_TYPE_SPARSE)
+}
+
+/// Point out item infos with fields whose values ampack does not know the 
+/// meanings of, so they could be reported
+pub fn print_unknown_fields(infos: &[RawItemInfo]) {
+    for (id, info) in infos.iter().enumerate() {
+        if ! is_file_type_known(info.file_type) {
+            println!("Item info {} ({}.{}) has unknown file type {} (0x{:x}), \
+                known ones are {} (normal) and {} (Android sparse)", id, 
+                info.item_sub_type, info.item_main_type, info.file_type, 
+                info.file_type, FILE_TYPE_GENERIC, FILE_TYPE_SPARSE)
+        }
+    }
+}
+
+/// Print the item infos as CSV on stdout, offsets and sizes as raw integers,
+/// with an extra column of what their payloads are if `kinds` is not empty
+pub fn print_item_infos_csv(infos: &[RawItemInfo], kinds: &[PayloadKind]) 
+    -> Result<()> 
+{
+    let mut header = vec!["id", "file_type", "item_offset", "image_offset", 
+        "size", "main_type", "sub_type", "verify", "is_backup", "backup_id"];
+    if ! kinds.is_empty() {
+        header.push("payload")
+    }
+    output::print_csv(&header, 
+        item_infos_display_order(infos).into_iter().map(|id| {
+            let item_info = &infos[id];
+            let mut row = vec![
+                item_info.item_id.to_string(),
+                item_info.file_type.to_string(),
+                item_info.current_offset_in_item.to_string(),
+                item_info.offset_in_image.to_string(),
+                item_info.item_size.to_string(),
+                item_info.item_main_type.clone(),
+                item_info.item_sub_type.clone(),
+                item_info.verify.to_string(),
+                item_info.is_backup_item.to_string(),
+                item_info.backup_item_id.to_string(),
+            ];
+            if let Some(kind) = kinds.get(id) {
+                row.push(kind.to_string())
+            }
+            row
+        }))
+}
+
+/// Identify the payloads of items in the image file at `path` by their first
+/// bytes, only reading those
+pub fn identify_item_infos(path: &Path, infos: &[RawItemInfo]) 
+    -> Result<Vec<PayloadKind>> 
+{
+    identify_item_infos_from(&mut File::open(path)?, infos)
+}
+
+/// Identify the payloads of `infos` in the image read from `reader`, see
+/// `identify_item_infos`
+pub fn identify_item_infos_from<R: Read + Seek>(
+    reader: &mut R, infos: &[RawItemInfo]
+) 
+    -> Result<Vec<PayloadKind>> 
+{
+    infos.iter().map(|info| {
+        let name = format!("{}.{}", info.item_sub_type, info.item_main_type);
+        let mut head = vec![
+            0; info.item_size.min(SIZE_IDENTIFY_HEAD as u64) as usize];
+        reader.seek(SeekFrom::Start(info.offset_in_image))?;
+        retry::read_exact(reader, &mut head).map_err(|e|
+            ImageError::read_failed(&name, info.offset_in_image, 
+                info.item_size, e))?;
+        Ok(identify(&head))
+    }).collect()
+}
+
+/// Problems in the table of an image file of `size_file` bytes that no 
+/// rewriting of checksums could fix: an image size not the file size, items
+/// outside the payloads after the item info table, items overlapping others
+/// they do not share the exact region with as backups do, and VERIFY items 
+/// not right after their partitions or not 48 bytes
+pub fn structural_problems(header: &ImageHeader, infos: &[RawItemInfo], 
+                           size_file: u64) 
+    -> Vec<String> 
+{
+    let mut problems = Vec::new();
+    if header.image_size != size_file {
+        problems.push(format!("image size 0x{:x} in header != file size \
+            0x{:x}", header.image_size, size_file))
+    }
+    let offset_body = header.offset_body();
+    let mut regions = Vec::new();
+    for (id, info) in infos.iter().enumerate() {
+        let name = format!("{}.{}", info.item_sub_type, info.item_main_type);
+        match info.offset_in_image.checked_add(info.item_size) {
+            Some(end) if info.offset_in_image >= offset_body && 
+                end <= size_file => 
+                regions.push((info.offset_in_image, end, name.clone())),
+            _ => problems.push(format!("{} (0x{:x} bytes at 0x{:x}) is out of \
+                image body (0x{:x}..0x{:x})", name, info.item_size, 
+                info.offset_in_image, offset_body, size_file)),
+        }
+        if info.item_main_type != "VERIFY" {
+            continue
+        }
+        let follows_partition = id.checked_sub(1)
+            .and_then(|id|infos.get(id))
+            .is_some_and(|last| last.item_main_type == "PARTITION" && 
+                last.item_sub_type == info.item_sub_type);
+        if ! follows_partition {
+            problems.push(format!("{} is not right after {}.PARTITION", 
+                name, info.item_sub_type))
+        }
+        if info.item_size != 48 {
+            problems.push(format!("{} is 0x{:x} bytes instead of 48", 
+                name, info.item_size))
+        }
+    }
+    regions.sort();
+    // The region reaching the furthest so far, which later ones must not 
+    // start inside of, unless they're exactly the same region
+    let mut furthest: Option<&(u64, u64, String)> = None;
+    for region in regions.iter() {
+        if let Some(last) = furthest {
+            let same = last.0 == region.0 && last.1 == region.1;
+            if region.0 < last.1 && ! same {
+                problems.push(format!("{} (0x{:x}..0x{:x}) overlaps {} \
+                    (0x{:x}..0x{:x})", region.2, region.0, region.1, last.2, 
+                    last.0, last.1))
+            }
+            if region.1 <= last.1 {
+                continue
+            }
+        }
+        furthest = Some(region)
+    }
+    problems
+}
+
+/// The VERIFY items to rewrite to repair the partitions failing to verify in
+/// `report` of an image with item `infos`: each check with the offset of the
+/// VERIFY item right after its partition and the sha1sum calculated
+pub fn repair_plan<'a>(infos: &[RawItemInfo], report: &'a VerifyReport) 
+    -> Result<Vec<(&'a ItemCheck, u64, Sha1sum)>> 
+{
+    let mut plan = Vec::new();
+    for check in report.items.iter()
+        .filter(|check|check.status == CheckStatus::Mismatch) 
+    {
+        let id = infos.iter().position(|info| info.item_main_type == 
+            "PARTITION" && format!("{}.PARTITION", info.item_sub_type) == 
+                check.name);
+        let (info, sha1sum) = match (id.and_then(|id|infos.get(id + 1)), 
+            &check.sha1sum_calculated) 
+        {
+            (Some(info), Some(sha1sum)) => 
+                (info, Sha1sum::from_hex(sha1sum.as_bytes())?),
+            _ => {
+                eprintln!("Could not find the VERIFY item of '{}' to repair",
+                    check.name);
+                return Err(ImageError::UnmatchedVerify.into())
+            },
+        };
+        plan.push((check, info.offset_in_image, sha1sum))
+    }
+    Ok(plan)
+}
+
+/// The VERIFY items to patch by `try_repair_file` for `plan`, each only once
+/// by its offset, as backups share the VERIFY items of the partitions they 
+/// back up
+pub fn repair_patches(plan: &[(&ItemCheck, u64, Sha1sum)]) 
+    -> Vec<(u64, Sha1sum)> 
+{
+    let mut patches: Vec<(u64, Sha1sum)> = plan.iter()
+        .map(|(_, offset, sha1sum)|(*offset, sha1sum.clone())).collect();
+    patches.sort_by_key(|(offset, _)|*offset);
+    patches.dedup_by_key(|(offset, _)|*offset);
+    patches
+}
+
+/// Rewrite the VERIFY items at the `verifies` offsets in the image file at
+/// `path` in place with the sha1sums of their partitions, then recalculate
+/// the CRC32 of the image and patch it into the header, leaving everything 
+/// else untouched. Returns the CRC32 recorded before and the one patched in.
+/// The table must have been checked with `structural_problems` first.
+pub fn try_repair_file<P: AsRef<Path>>(path: P, verifies: &[(u64, Sha1sum)]) 
+    -> Result<(u32, u32)> 
+{
+    let path = path.as_ref();
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+    for (offset, sha1sum) in verifies {
+        let content = format!("sha1sum {}", sha1sum);
+        file.seek(SeekFrom::Start(*offset))?;
+        retry::write_all(&mut file, content.as_bytes()).map_err(|e|
+            ImageError::write_failed("VERIFY", *offset, 
+                content.len() as u64, e))?;
+    }
+    file.seek(SeekFrom::Start(0))?;
+    let crc32 = Crc32Hasher::try_hash_image_from(
+        BufReader::new(&mut file))?.0.value;
+    let mut recorded = [0; 4];
+    file.seek(SeekFrom::Start(0))?;
+    file.read_exact(&mut recorded)?;
+    file.seek(SeekFrom::Start(0))?;
+    retry::write_all(&mut file, &crc32.to_le_bytes()).map_err(|e|
+        ImageError::write_failed("header", 0, 4, e))?;
+    file.sync_all()?;
+    Ok((u32::from_le_bytes(recorded), crc32))
+}
+
+/// The order to show item infos in, see `output::display_order`
+fn item_infos_display_order(infos: &[RawItemInfo]) -> Vec<usize> {
+    output::display_order(infos.len(), |id| output::SortFields {
+        size: infos[id].item_size,
+        name: format!("{}.{}", infos[id].item_sub_type, infos[id].item_main_type),
+        offset: Some(infos[id].offset_in_image),
+        kind: infos[id].item_main_type.clone(),
+    })
+}
+
+/// Print the table of item infos, with an extra column of what their 
+/// payloads are if `kinds` is not empty
+pub fn print_item_infos_table_stdout(infos: &[RawItemInfo], 
+                                            kinds: &[PayloadKind]) 
+    -> Result<()> 
+{
+    let mut rows = Vec::new();
+    for id in item_infos_display_order(infos) {
+        let item_info = &infos[id];
+        let mut row = vec![
+            cell_right!(item_info.item_id),
+            cell_right!(item_info.file_type),
+            cell_right!(format!("0x{:x}", item_info.current_offset_in_item)),
+            cell_right!(format!("0x{:x}", item_info.offset_in_image)),
+            cell_right!(output::size(item_info.item_size)),
+            cell_right!(&item_info.item_main_type),
+            cell_right!(&item_info.item_sub_type),
+            cell_right!(item_info.verify),
+            if item_info.is_backup_item == 0 {
+                format!("no ({})", item_info.backup_item_id).cell()
+            } else {
+                format!("yes ({})", item_info.backup_item_id).cell()
+            }.justify(Justify::Right)
+        ];
+        if let Some(kind) = kinds.get(id) {
+            row.push(cell_right!(kind))
+        }
+        rows.push(row)
+    }
+    let mut title = vec![
+        cell_bold_center!("ID"),
+        cell_bold_center!("type"),
+        cell_bold_center!("item off"),
+        cell_bold_center!("image off"),
+        cell_bold_center!("size"),
+        cell_bold_center!("main type"),
+        cell_bold_center!("sub type"),
+        cell_bold_center!("verify"),
+        cell_bold_center!("backup (id)")
+    ];
+    if ! kinds.is_empty() {
+        title.push(cell_bold_center!("payload"))
+    }
+    let table = rows.table().title(title).bold(true);
+    println!("Item infos in raw image:");
+    output::print_table(table)?;
+    Ok(())
+}
+
+/// Print the item names changed by `Image::sanitize_names`
+pub fn print_renames_table_stdout(renames: &[(String, String)]) -> Result<()> {
+    if renames.is_empty() {
+        println!("No item names changed by sanitization");
+        return Ok(())
+    }
+    let rows: Vec<_> = renames.iter().map(|(old, new)|[
+        cell_right!(old),
+        cell_right!(new),
+    ]).collect();
+    let table = rows.table().title([
+        cell_bold_center!("old name"),
+        cell_bold_center!("new name"),
+    ]).bold(true);
+    println!("Item names changed by sanitization:");
+    output::print_table(table)
+}
+
+/// Print the text items changed by `Image::normalize_text`
+pub fn print_normalized_table_stdout(
+    normalized: &[(String, TextNormalization)]
+) 
+    -> Result<()> 
+{
+    if normalized.is_empty() {
+        println!("No text items changed by normalization");
+        return Ok(())
+    }
+    let rows: Vec<_> = normalized.iter().map(|(name, normalization)|[
+        cell_right!(name),
+        cell_right!(normalization.crlf),
+        cell_right!(if normalization.bom { "stripped" } else { "" }),
+        cell_right!(normalization.crlf + 
+            if normalization.bom { UTF8_BOM.len() } else { 0 }),
+    ]).collect();
+    let table = rows.table().title([
+        cell_bold_center!("name"),
+        cell_bold_center!("CRLF"),
+        cell_bold_center!("BOM"),
+        cell_bold_center!("bytes removed"),
+    ]).bold(true);
+    println!("Text items changed by normalization:");
+    output::print_table(table)
+}
+
+/// Items the burning tool could not do without, as `(stem, extension)`
+const ESSENTIALS: [(&str, &str); 5] = [("DDR", "USB"), ("UBOOT", "USB"), 
+    ("aml_sdc_burn", "ini"), ("meson1", "dtb"), ("platform", "conf")];
+
+impl Image {
+    fn find_item(&self, stem: &str, extension: &str) -> Result<&Item> {
+        let mut result = None;
+
+        for item in self.items.iter() {
+            if item.stem == stem && item.extension == extension {
+                if result.is_some() {
+                    eprintln!("Duplicated image item: {}.{}", stem, extension);
+                    return Err(ImageError::DuplicatedItem { 
+                        stem: stem.into(), extension: extension.into()}.into());
+                }
+                result = Some(item);
+            }
+        }
+        match result {
+            Some(item) => Ok(item),
+            None => {
+                eprintln!("Missing image item: {}.{}", stem, extension);
+                Err(ImageError::MissingItem { 
+                    stem: stem.into(), extension: extension.into() }.into())
+            }
+        }
+    }
+
+    /// Look for all essential items, returning the problems with all of them
+    /// instead of stopping at the first
+    fn check_essentials(&self) -> Vec<Error> {
+        ESSENTIALS.into_iter()
+            .filter_map(|(stem, extension)|
+                self.find_item(stem, extension).err())
+            .collect()
+    }
+
+    /// Names and checksums of all items with `algo`, in the order of items,
+    /// each item streamed through the hasher in chunks, items in parallel
+    pub fn checksums(&self, algo: ChecksumAlgo) 
+        -> Result<Vec<(String, String)>> 
+    {
+        let bars = ItemBars::new("Hashing item", 
+            self.items.iter().map(|item|item.data.len()).sum())?;
+        let parallel = reads_in_parallel(self.items.iter().map(|item|&item.data));
+        let results = map_jobs(self.items.iter().collect(), parallel, 
+            |item: &Item| 
+        {
+            let name = format!("{}.{}", item.stem, item.extension);
+            let progress_bar = bars.start(&name, item.data.len())?;
+            let _reservation = item.data.reserve_buffers();
+            let mut hasher = ChecksumHasher::new(algo);
+            let result = item.data.try_for_each_chunk(&name, |chunk| {
+                hasher.update(chunk);
+                progress_bar.inc(chunk.len() as u64);
+                Ok(())
+            });
+            bars.done(&progress_bar);
+            result.map(|_|(name, hasher.finalize()))
+        });
+        bars.finish("Hashed");
+        results.into_iter().collect()
+    }
+
+    /// Spot check items instead of verifying them: items up to twice as large
+    /// as `sample` are verified in whole, only the first and last `sample` 
+    /// bytes of larger ones are read to check they're readable, and that their
+    /// Android sparse headers, if any, are sane. Prints a table of what was 
+    /// checked and returns the items that failed.
+    pub fn spot_check(&self, sample: u64, low_memory: bool) 
+        -> Result<Vec<(String, Error)>> 
+    {
+        // Small items are read in whole, large ones only at both ends
+        let size_checked = |item: &Item| 
+            item.data.len().min(sample.saturating_mul(2));
+        let progress_bar = progress_bar_with_template(
+            self.items.iter().map(size_checked).sum(), 
+            template_bytes("Spot checking items", ""))?;
+        let parallel = ! low_memory && reads_in_parallel(
+            self.items.iter().map(|item|&item.data));
+        let results = map_jobs(self.items.iter().collect(), parallel, 
+            |item: &Item| 
+        {
+            let name = format!("{}.{}", item.stem, item.extension);
+            let size = item.data.len();
+            let _reservation = item.data.reserve_buffers();
+            let result = if size <= sample.saturating_mul(2) {
+                match &item.sha1sum {
+                    Some(sha1sum_record) => Sha1sum::try_from_payload_with_bar(
+                        &item.data, &name, &progress_bar
+                    ).and_then(|sha1sum_calculated| 
+                        if sha1sum_record == &sha1sum_calculated {
+                            Ok("sha1sum verified")
+                        } else {
+                            eprintln!("Recorded SHA1sum ({}) different from \
+                                calculated SHA1sum ({}) for item '{}'", 
+                                sha1sum_record, sha1sum_calculated, name);
+                            Err(ImageError::IllegalVerify.into())
+                        }),
+                    None => item.data.try_for_each_chunk(&name, |chunk|{
+                        progress_bar.inc(chunk.len() as u64);
+                        Ok(())
+                    }).map(|_|"read in whole"),
+                }
+            } else {
+                item.data.try_head(&name, SIZE_SPARSE_HEADER)
+                    .and_then(|head| check_sparse_header(&name, &head))
+                    .and_then(|sparse| {
+                        let mut read = |chunk: &[u8]| {
+                            progress_bar.inc(chunk.len() as u64);
+                            Ok(())
+                        };
+                        item.data.slice(0, sample)
+                            .try_for_each_chunk(&name, &mut read)?;
+                        item.data.slice(size - sample, sample)
+                            .try_for_each_chunk(&name, &mut read)?;
+                        Ok(if sparse {
+                            "head and tail read, sparse header sane"
+                        } else {
+                            "head and tail read"
+                        })
+                    })
+            };
+            (name, result)
+        });
+        finish_stage(&progress_bar, "Spot checked");
+        let mut rows = Vec::new();
+        let mut failures = Vec::new();
+        output::record("items", &results.iter().map(|(name, result)| 
+            serde_json::json!({
+                "name": name,
+                "checked": result.as_ref().ok(),
+                "error": result.as_ref().err().map(|e|e.to_string()),
+            })).collect::<Vec<_>>());
+        if output::csv() {
+            output::print_csv(&["item", "checked", "error"], 
+                results.iter().map(|(name, result)| match result {
+                    Ok(checked) => vec![name.clone(), checked.to_string(), 
+                        String::new()],
+                    Err(e) => vec![name.clone(), "failed".into(), e.to_string()],
+                }))?;
+        }
+        for (name, result) in results {
+            match result {
+                Ok(checked) => rows.push(
+                    [cell_right!(&name), cell_right!(checked), "".cell()]),
+                Err(e) => {
+                    rows.push([cell_right!(&name), cell_right!("failed"), 
+                        e.to_string().cell()]);
+                    failures.push((name, e))
+                },
+            }
+        }
+        let table = rows.table().title([
+            cell_bold_center!("item"),
+            cell_bold_center!("checked"),
+            cell_bold_center!("error"),
+        ]).bold(true);
+        if ! output::csv() {
+            println!("Spot check of items (NOT a full verification):");
+            output::print_table(table)?;
+        }
+        Ok(failures)
+    }
+
+    /// Verify the image file at `path` this image was read from, in a single
+    /// sequential pass: every chunk after the CRC field is fed into the 
+    /// running CRC32, and into the hashers of the items it falls inside 
+    /// (padding only into the former), so all items and the CRC32 in the 
+    /// header are verified at the cost of reading the file once, with only a
+    /// few chunks in memory. Only fails if the file could not be read, whether
+    /// each item and the CRC32 match is reported, see `VerifyReport::error`.
+    /// With `with_crc32`, the standard CRC32s of all items are calculated in
+    /// the same pass and reported too, and with `digest` the digest of the 
+    /// whole file, e.g. to compare with a sidecar file of a download. With
+    /// `fail_fast`, fails as soon as an item from the file is fully hashed 
+    /// and mismatches instead.
+    pub fn verify(&self, path: &Path, with_crc32: bool, 
+                  digest: Option<DigestAlgo>, fail_fast: bool) 
+        -> Result<VerifyReport> 
+    {
+        let header = Self::try_read_header_only(path)?;
+        let size_file = path.metadata()?.len();
+        let whole = Payload::Region { source: SourceFile::open(path)?, 
+            offset: 4, size: size_file.saturating_sub(4) };
+        self.verify_whole(header, whole, &path.display(), with_crc32, digest, 
+            fail_fast)
+    }
+
+    /// Verify the image this image was read from with `try_read_from`, read
+    /// again from `reader`, see `verify`
+    pub fn verify_from<R>(&self, mut reader: R, with_crc32: bool, 
+                          digest: Option<DigestAlgo>, fail_fast: bool) 
+        -> Result<VerifyReport> 
+    where
+        R: Read + Seek + Send + 'static
+    {
+        let size = reader.seek(SeekFrom::End(0))?;
+        reader.seek(SeekFrom::Start(0))?;
+        let header = ImageHeader::try_read_from(&mut reader)?;
+        let whole = Payload::Shared { 
+            reader: Arc::new(SharedReader::new(reader)), 
+            offset: 4, size: size.saturating_sub(4) };
+        self.verify_whole(header, whole, &"reader", with_crc32, digest, 
+            fail_fast)
+    }
+
+    /// Verify against `whole`, the image `source` with `header` from offset
+    /// 4 on, see `verify`
+    fn verify_whole(
+        &self, header: ImageHeader, whole: Payload, source: &dyn Display, 
+        with_crc32: bool, digest: Option<DigestAlgo>, fail_fast: bool
+    ) 
+        -> Result<VerifyReport> 
+    {
+        // Only reported, they don't fail the verification
+        let _ = self.check_essentials();
+        let mut items: Vec<ItemInPass> = self.items.iter().enumerate().map(
+            |(id, item)| ItemInPass { 
+                item, 
+                id,
+                name: format!("{}.{}", item.stem, item.extension),
+                start: item.data.offset_in_file(),
+                sha1sum: item.sha1sum.as_ref().map(|_|Sha1sumHasher::new()),
+                crc32: with_crc32.then(Crc32Hasher::new),
+                time_first: None,
+                time_last: None,
+            }).collect();
+        // Sorted by offset with those not from the file last, so the items a
+        // chunk falls inside are found by binary search
+        items.sort_by_key(|item|
+            (item.start.is_none(), item.start, item.item.data.len()));
+        let starts: Vec<u64> = items.iter().map_while(|item|item.start)
+            .collect();
+        // The furthest end of items up to each, still sorted when items 
+        // overlap, e.g. backups
+        let ends_max: Vec<u64> = items.iter().zip(&starts)
+            .scan(0, |end_max, (item, start)| {
+                *end_max = (*end_max).max(start + item.item.data.len());
+                Some(*end_max)
+            }).collect();
+        let progress_bar = progress_bar_with_template(
+            whole.len(),
+            template_bytes("Verifying image", ""))?;
+        let _reservation = whole.reserve_buffers();
+        let mut crc32 = Crc32Hasher::new();
+        // The CRC field is not in the pass, but is in the file
+        let mut digest = digest.map(DigestHasher::new);
+        if let Some(digest) = &mut digest {
+            digest.update(&header.crc.to_le_bytes())
+        }
+        let mut offset = 4;
+        whole.try_for_each_chunk("image", |chunk| {
+            let end = offset + chunk.len() as u64;
+            let first = ends_max.partition_point(|end_max|*end_max <= offset);
+            let last = starts.partition_point(|start|*start < end).max(first);
+            // The CRC32 (and digest) of the image, and sha1sums of the items
+            // the chunk falls inside each on their own threads
+            let (_, failed) = rayon::join(|| {
+                crc32.update(chunk);
+                if let Some(digest) = &mut digest {
+                    digest.update(chunk)
+                }
+            }, || items[first..last].par_iter_mut().zip(&starts[first..last])
+                .enumerate().filter_map(|(id, (item, &start))| {
+                    let end_item = start + item.item.data.len();
+                    if end_item <= offset {
+                        return None
+                    }
+                    item.update(&chunk[
+                        (start.max(offset) - offset) as usize..
+                        (end_item.min(end) - offset) as usize]);
+                    (fail_fast && end_item <= end && item.mismatched())
+                        .then_some(first + id)
+                }).min());
+            if let Some(id) = failed {
+                // Only for the message of the mismatch
+                let _ = items.swap_remove(id).finalize();
+                return Err(ImageError::IllegalVerify.into())
+            }
+            offset = end;
+            progress_bar.set_position(offset - 4);
+            Ok(())
+        })?;
+        finish_stage(&progress_bar, "Read");
+        // Not from the file, i.e. already in memory
+        for item in items.iter_mut().filter(|item|item.start.is_none()) {
+            let (data, name) = (&item.item.data, item.name.clone());
+            data.try_for_each_chunk(&name, |chunk| {
+                item.update(chunk);
+                Ok(())
+            })?
+        }
+        items.sort_by_key(|item|item.id);
+        let items = items.into_iter().map(ItemInPass::finalize).collect();
+        if crc32.value != header.crc {
+            eprintln!("Recorded CRC32 (0x{:08x}) different from calculated \
+                CRC32 (0x{:08x}) of image '{}'", header.crc, crc32.value, 
+                source);
+        }
+        Ok(VerifyReport { 
+            crc32_recorded: header.crc, 
+            crc32_calculated: crc32.value,
+            header,
+            items, 
+            digest: digest.map(DigestHasher::finalize),
+        })
+    }
+
+    /// Sanitize item names for burning tools, and refuse names still not 
+    /// representable in an image of `version`. Extensions and stems of USB 
+    /// items are never lowercased, as they're types the burning tools look 
+    /// for. Returns the changed names as `(old, new)`, the old names are also
+    /// kept in the items to be recorded in their manifest.
+    pub fn sanitize_names(&mut self, lowercase: bool, version: &ImageVersion) 
+        -> Result<Vec<(String, String)>> 
+    {
+        let mut renames = Vec::new();
+        for item in self.items.iter_mut() {
+            let stem = sanitize_name_part(
+                &item.stem, lowercase && item.extension != "USB");
+            let extension = sanitize_name_part(&item.extension, false);
+            if stem != item.stem || extension != item.extension {
+                let name = item.name();
+                renames.push((name.clone(), format!("{}.{}", stem, extension)));
+                item.original_name.get_or_insert(name);
+                item.stem = stem;
+                item.extension = extension;
+            }
+        }
+        // Backup directives follow the items they name
+        for item in self.items.iter_mut() {
+            if let Some(of) = &mut item.backup_of {
+                if let Some((_, name)) = renames.iter().find(|(old, _)|old == of) {
+                    *of = name.clone()
+                }
+            }
+        }
+        let max_len = version.max_len_name_part();
+        for (id, item) in self.items.iter().enumerate() {
+            let name = format!("{}.{}", item.stem, item.extension);
+            if ! version.is_name_representable(&item.stem, &item.extension) {
+                eprintln!("Item name '{}' is still not representable in {} \
+                    image after sanitization: each part must be 1 to {} bytes \
+                    without control characters", 
+                    name.escape_debug(), version, max_len);
+                return Err(ImageError::InvalidItemName { name }.into())
+            }
+            if self.items[..id].iter().any(|other|
+                other.stem == item.stem && other.extension == item.extension) 
+            {
+                eprintln!("Multiple items are named '{}' after sanitization", 
+                    name);
+                return Err(ImageError::DuplicatedItem { 
+                    stem: item.stem.clone(), 
+                    extension: item.extension.clone() }.into())
+            }
+        }
+        Ok(renames)
+    }
+
+    /// Convert CRLF line endings to LF and strip the leading UTF-8 BOM in
+    /// items with one of the `extensions` (case-insensitively), skipping those
+    /// looking binary (containing NUL). Returns the names of the touched 
+    /// items along with what was done to them.
+    pub fn normalize_text(&mut self, extensions: &[String]) 
+        -> Result<Vec<(String, TextNormalization)>> 
+    {
+        let mut normalized_items = Vec::new();
+        for item in self.items.iter_mut() {
+            if ! extensions.iter().any(|extension| 
+                extension.eq_ignore_ascii_case(&item.extension)) 
+            {
+                continue
+            }
+            let name = format!("{}.{}", item.stem, item.extension);
+            let data = item.data.try_to_vec(&name)?;
+            if data.contains(&0) {
+                warning!(BinaryText, "not normalizing '{}' as it looks binary", 
+                    name);
+                continue
+            }
+            let bom = data.starts_with(&UTF8_BOM);
+            let data = if bom {
+                &data[UTF8_BOM.len()..]
+            } else {
+                &data[..]
+            };
+            let mut normalized = Vec::with_capacity(data.len());
+            let mut crlf = 0;
+            for (id, byte) in data.iter().enumerate() {
+                if *byte == b'\r' && data.get(id + 1) == Some(&b'\n') {
+                    crlf += 1
+                } else {
+                    normalized.push(*byte)
+                }
+            }
+            if crlf == 0 && ! bom {
+                continue
+            }
+            item.data = Payload::Owned(normalized);
+            item.sha1sum = None;
+            let normalization = TextNormalization { crlf, bom };
+            item.text_normalization = Some(normalization.clone());
+            normalized_items.push((name, normalization));
+        }
+        Ok(normalized_items)
+    }
+
+    /// Keep only items for whose names `keep` gives true, along with their
+    /// VERIFY items, e.g. to unpack only some of them
+    pub fn retain_items<F>(&mut self, mut keep: F)
+    where
+        F: FnMut(&str) -> bool
+    {
+        self.items.retain(|item|
+            keep(&format!("{}.{}", item.stem, item.extension)))
+    }
+
+    /// Replace the payloads of items for which `replace`, called with the 
+    /// name and the payload of each item in order, gives new ones, e.g. files
+    /// written by pack hooks. Their sha1sums are dropped, so they're hashed
+    /// from the new payloads. Returns the names of the replaced items.
+    pub fn try_replace_payloads<F>(&mut self, mut replace: F) 
+        -> Result<Vec<String>>
+    where
+        F: FnMut(&str, &mut Payload) -> Result<Option<Payload>>
+    {
+        let mut replaced = Vec::new();
+        for item in self.items.iter_mut() {
+            let name = format!("{}.{}", item.stem, item.extension);
+            if let Some(data) = replace(&name, &mut item.data)? {
+                item.data = data;
+                item.sha1sum = None;
+                replaced.push(name)
+            }
+        }
+        Ok(replaced)
+    }
+
+    /// Keep in-memory payloads within `max_memory` (0 for unlimited) together
+    /// with the buffers used to stream the rest, by spilling those beyond the
+    /// budget into `spill`. Spilling only changes where the bytes live: if it
+    /// fails the payload just stays in memory. Returns where the in-memory 
+    /// payloads ended up, or `None` if there's no budget.
+    pub fn spill_payloads(&mut self, max_memory: u64, spill: &mut SpillDir) 
+        -> Option<SpillReport> 
+    {
+        if max_memory == 0 {
+            return None
+        }
+        // Each parallel reader and the image writer hold a chunk each
+        let size_staging = chunk_size() as u64 * 
+            (rayon::current_num_threads() as u64 + 1);
+        let mut report = SpillReport {
+            items: Vec::new(),
+            max_memory,
+            size_staging,
+            in_memory: 0,
+            spilled: 0,
+        };
+        let budget = max_memory.saturating_sub(size_staging);
+        for item in self.items.iter_mut() {
+            let size = match &item.data {
+                Payload::Owned(data) => data.len() as u64,
+                _ => continue,
+            };
+            let name = format!("{}.{}", item.stem, item.extension);
+            if report.in_memory + size <= budget {
+                report.in_memory += size;
+                report.items.push((name, size, None));
+                continue
+            }
+            match spill.try_spill(&mut item.data) {
+                Ok(path) => {
+                    report.spilled += size;
+                    report.items.push((name, size, Some(path)))
+                },
+                Err(e) => {
+                    warning!(Spill, "failed to spill item '{}', keeping it \
+                        in memory beyond the budget: {}", name, e);
+                    report.in_memory += size;
+                    report.items.push((name, size, None))
+                },
+            }
+        }
+        Some(report)
+    }
+
+    /// Names of the files the items would be unpacked as, escaped through
+    /// `windows_file_names` on Windows, or the same as item names otherwise
+    pub fn file_names(&self) -> Vec<String> {
+        let names: Vec<String> = self.items.iter().map(|item|
+            format!("{}.{}", item.stem, item.extension)).collect();
+        if cfg!(windows) {
+            windows_file_names(&names)
+        } else {
+            names
+        }
+    }
+
+    /// Record metadata that would be lost when unpacked into a manifest, 
+    /// along with the size, sha1sum and payload kind of each item for 
+    /// `verify --expect`, hashing items without recorded sha1sums
+    pub fn to_manifest(&self) -> Result<Manifest> {
+        let mut sha1sums = sha1sums_of(
+            self.items.iter().filter(|item|item.sha1sum.is_none()).collect(),
+            "Hashing item")?.into_iter();
+        let mut items = Vec::new();
+        for (item, file_name) in self.items.iter().zip(self.file_names()) {
+            let name = format!("{}.{}", item.stem, item.extension);
+            let sha1sum = item.sha1sum.clone().or_else(||sha1sums.next());
+            let payload = identify(
+                &item.data.try_head(&name, SIZE_IDENTIFY_HEAD)?);
+            items.push(ManifestItem {
+                    file_name: (file_name != name).then_some(file_name),
+                    size: Some(item.data.len()),
+                    sha1sum: sha1sum.map(|sha1sum|sha1sum.to_string()),
+                    payload: Some(payload),
+                    name,
+                    reserve: (! item.reserve.is_empty()).then(||
+                        hex::encode(&item.reserve)),
+                    verify_reserve: (! item.verify_reserve.is_empty()).then(||
+                        hex::encode(&item.verify_reserve)),
+                    text_normalization: item.text_normalization.clone(),
+                    hook: None,
+                    item_id: item.original_id,
+                    original_name: item.original_name.clone(),
+                    backup_of: item.backup_of.clone(),
+                    backup: (item.backup != BackupPolicy::Auto)
+                        .then_some(item.backup),
+                })
+        }
+        Ok(Manifest {
+            version: self.version.clone(),
+            align: self.align,
+            reserve: (! self.reserve.is_empty()).then(||
+                hex::encode(&self.reserve)),
+            items,
+        })
+    }
+
+    /// Compare the image against an `expected` manifest, e.g. one emitted by
+    /// unpacking a known good image, see `Manifest::diff`
+    pub fn diff_manifest(&self, expected: &Manifest) -> Result<ManifestDiff> {
+        Ok(expected.diff(&self.to_manifest()?))
+    }
+
+    /// Restore metadata recorded in a manifest by an earlier unpack
+    pub fn apply_manifest(&mut self, manifest: &Manifest) -> Result<()> {
+        if let Some(reserve) = &manifest.reserve {
+            self.reserve = vec_from_reserve(&hex::decode(reserve)?)
+        }
+        for item in self.items.iter_mut() {
+            let file_name = format!("{}.{}", item.stem, item.extension);
+            let manifest_item = match manifest.items.iter().find(|manifest_item|
+                manifest_item.file_name.as_ref() == Some(&file_name)) 
+            {
+                Some(manifest_item) => manifest_item,
+                None => continue,
+            };
+            let (stem, extension) = manifest_item.name.split_once('.')
+                .unwrap_or((&manifest_item.name, ""));
+            println!("Restoring item name '{}' from escaped file name '{}'", 
+                manifest_item.name, file_name);
+            item.stem = stem.into();
+            item.extension = extension.into();
+        }
+        for item in self.items.iter_mut() {
+            let name = format!("{}.{}", item.stem, item.extension);
+            let manifest_item = match manifest.find_item(&name) {
+                Some(manifest_item) => manifest_item,
+                None => continue,
+            };
+            if let Some(reserve) = &manifest_item.reserve {
+                item.reserve = vec_from_reserve(&hex::decode(reserve)?)
+            }
+            if let Some(reserve) = &manifest_item.verify_reserve {
+                item.verify_reserve = vec_from_reserve(&hex::decode(reserve)?)
+            }
+            if manifest_item.item_id.is_some() {
+                item.original_id = manifest_item.item_id
+            }
+            item.backup_of = manifest_item.backup_of.clone();
+            item.backup = manifest_item.backup.unwrap_or_default();
+        }
+        for manifest_item in manifest.items.iter() {
+            if ! self.items.iter().any(|item| 
+                format!("{}.{}", item.stem, item.extension) == manifest_item.name) 
+            {
+                warning!(Manifest, "item '{}' recorded in manifest does not \
+                    exist", manifest_item.name)
+            }
+        }
+        self.check_backup_directives()
+    }
+
+    /// Check the `backup_of` and `backup: never` directives from manifest 
+    /// could be followed, content aside: each item to be a backup must name
+    /// another existing item that could be backed up, of the same kind as 
+    /// itself, as partitions and their VERIFY items are backups together
+    fn check_backup_directives(&self) -> Result<()> {
+        for item in self.items.iter() {
+            let of = match &item.backup_of {
+                Some(of) => of,
+                None => continue,
+            };
+            let name = item.name();
+            let reason = match self.items.iter().find(|other|other.name() == *of) {
+                _ if item.backup == BackupPolicy::Never => 
+                    "it's also set to be never a backup".into(),
+                _ if name == *of => "it can't be a backup of itself".into(),
+                None => format!("item '{}' does not exist", of),
+                Some(other) if other.backup_of.is_some() => 
+                    format!("'{}' is a backup itself", of),
+                Some(other) if other.backup == BackupPolicy::Never =>
+                    format!("'{}' is set to be never backed up", of),
+                Some(other) if (other.extension == "PARTITION") != 
+                    (item.extension == "PARTITION") => 
+                    "only partitions could be backups of partitions, and \
+                        non-partitions of non-partitions".into(),
+                Some(_) => continue,
+            };
+            eprintln!("Item '{}' can not be a backup of '{}': {}", 
+                name, of, reason);
+            return Err(ImageError::InvalidBackup { name, reason }.into())
+        }
+        Ok(())
+    }
+
+    pub fn clear_verify(&mut self) {
+        for item in self.items.iter_mut() {
+            item.sha1sum = None
+        }
+    }
+
+    pub fn fill_verify(&mut self) -> Result<()> {
+        let sha1sums = sha1sums_of(self.items.iter().filter(
+            |item|item.sha1sum.is_none()).collect(), "Generating verify")?;
+        for (item, sha1sum) in self.items.iter_mut().filter(
+            |item|item.sha1sum.is_none()).zip(sha1sums) 
+        {
+            log::debug!("Sha1sum of item '{}.{}' is {}", 
+                item.stem, item.extension, sha1sum);
+            item.sha1sum = Some(sha1sum)
+        }
+        Ok(())
+    }
+
+    /// Read only the image header, without touching the item table or payloads
+    pub fn try_read_header_only<P: AsRef<Path>>(file: P) 
+        -> Result<ImageHeader> 
+    {
+        ImageHeader::try_read_from(&mut File::open(file.as_ref())?)
+    }
+
+    /// Read the image header and the item info table, without any payloads
+    pub fn try_read_table_only<P: AsRef<Path>>(file: P) 
+        -> Result<(ImageHeader, Vec<RawItemInfo>)> 
+    {
+        Self::try_read_table_from(&mut BufReader::new(File::open(file.as_ref())?))
+    }
+
+    /// Read the image header and the item info table from `reader`, see
+    /// `try_read_table_only`
+    pub fn try_read_table_from<R: Read + Seek>(reader: &mut R) 
+        -> Result<(ImageHeader, Vec<RawItemInfo>)> 
+    {
+        let header = ImageHeader::try_read_from(reader)?;
+        let infos = header.try_read_infos_from(reader)?;
+        Ok((header, infos))
+    }
+
+    pub fn try_read_file<P: AsRef<Path>>(file: P, options: &ReadFileOptions) 
+        -> Result<Self> 
+    {
+        Ok(Self::try_read_file_with_table(file, options, false)?.image)
+    }
+
+    /// Read an image as `try_read_file` does, or as 
+    /// `try_read_file_ignore_errors` does with `ignore_errors`, and also 
+    /// return its header and table as read
+    pub fn try_read_file_with_table<P: AsRef<Path>>(
+        file: P, options: &ReadFileOptions, ignore_errors: bool
+    ) 
+        -> Result<ImageRead> 
+    {
+        let file = file.as_ref();
+        Self::try_read_file_inner(file, options, ignore_errors)
+            .context(||ErrorContext::reading().path(file))
+    }
+
+    /// Read an image, skipping items whose payloads could not be read instead
+    /// of failing, the skipped items are returned alongside with their errors
+    pub fn try_read_file_ignore_errors<P: AsRef<Path>>(
+        file: P, options: &ReadFileOptions
+    ) 
+        -> Result<(Self, Vec<(String, Error)>)> 
+    {
+        let read = Self::try_read_file_with_table(file, options, true)?;
+        Ok((read.image, read.failures))
+    }
+
+    /// Read an image from any seekable reader, e.g. a `Cursor<Vec<u8>>` or a
+    /// decompressor. Like with a file, payloads are only read when consumed,
+    /// so the reader is kept by the image and shared by its items, and each
+    /// read seeks to where it should be first. `options.mmap` has no effect.
+    pub fn try_read_from<R>(reader: R, options: &ReadFileOptions) -> Result<Self> 
+    where
+        R: Read + Seek + Send + 'static
+    {
+        Ok(Self::try_read_from_with_table(reader, options, false)?.image)
+    }
+
+    /// Read an image from `reader` as `try_read_from` does, skipping items 
+    /// whose payloads could not be read as `try_read_file_ignore_errors` does
+    pub fn try_read_from_ignore_errors<R>(reader: R, options: &ReadFileOptions) 
+        -> Result<(Self, Vec<(String, Error)>)> 
+    where
+        R: Read + Seek + Send + 'static
+    {
+        let read = Self::try_read_from_with_table(reader, options, true)?;
+        Ok((read.image, read.failures))
+    }
+
+    /// Read an image from `reader` as `try_read_from` does, or as 
+    /// `try_read_from_ignore_errors` does with `ignore_errors`, and also 
+    /// return its header and table as read
+    pub fn try_read_from_with_table<R>(
+        reader: R, options: &ReadFileOptions, ignore_errors: bool
+    ) 
+        -> Result<ImageRead> 
+    where
+        R: Read + Seek + Send + 'static
+    {
+        let shared = Arc::new(SharedReader::new(reader));
+        let mut cursor = shared.cursor();
+        let size = cursor.seek(SeekFrom::End(0))?;
+        cursor.seek(SeekFrom::Start(0))?;
+        Self::try_read_inner(&mut BufReader::new(cursor), size, 
+            |offset, size| Payload::Shared { 
+                reader: shared.clone(), offset, size }, 
+            None, options, ignore_errors)
+    }
+
+    /// Read an image from `bytes` in memory, e.g. one written by 
+    /// `try_write_vec`, with the default options
+    pub fn try_read_bytes(bytes: Vec<u8>) -> Result<Self> {
+        Self::try_read_from(std::io::Cursor::new(bytes), 
+            &ReadFileOptions::default())
+    }
+
+    fn try_read_file_inner(
+        path_file: &Path, options: &ReadFileOptions, ignore_errors: bool
+    ) 
+        -> Result<ImageRead> 
+    {
+        let size_file = std::fs::metadata(path_file)?.len();
+        let mut file = BufReader::new(File::open(path_file)?);
+        // Opened once for all payloads, so they could be read even if the 
+        // image is removed before they are, e.g. when unpacking into its dir
+        let source = SourceFile::open(path_file)?;
+        #[cfg(feature = "mmap")]
+        let map = if options.mmap {
+            crate::payload::try_map_file(path_file)
+        } else {
+            None
+        };
+        Self::try_read_inner(&mut file, size_file, |offset, size| {
+            #[cfg(feature = "mmap")]
+            if let Some(map) = &map {
+                if offset.checked_add(size)
+                    .is_some_and(|end| end <= map.len() as u64) 
+                {
+                    return Payload::Mapped { map: map.clone(), 
+                        offset: offset as usize, size: size as usize }
+                }
+            }
+            Payload::Region { source: source.clone(), offset, size }
+        }, Some(path_file), options, ignore_errors)
+    }
+
+    /// Parse an image of `size_file` bytes from `file`, with payloads taken
+    /// from `payload_at` by their offsets and sizes, `path_file` is only for
+    /// error contexts
+    fn try_read_inner<R, F>(
+        file: &mut R, size_file: u64, payload_at: F, path_file: Option<&Path>,
+        options: &ReadFileOptions, ignore_errors: bool
+    ) 
+        -> Result<ImageRead> 
+    where
+        R: Read + Seek,
+        F: Fn(u64, u64) -> Payload
+    {
+        let (header, item_infos) = Self::try_read_table_from(file)?;
+        if header.reserve.iter().any(|byte| *byte != 0) {
+            log::debug!("Image header has non-zero reserved bytes {}", 
+                hex::encode(header.reserve))
+        }
+        let table = item_infos.clone();
+        for item_info in item_infos.iter() {
+            check_item_size(
+                &format!("{}.{}", 
+                    item_info.item_sub_type, item_info.item_main_type), 
+                item_info.item_size, options.max_item_size)?
+        }
+        let mut items = Vec::new();
+        let mut failures = Vec::new();
+        let mut need_verify: Option<Item> = None;
+        // Whether the partition waiting for its verify failed to be read
+        let mut need_verify_failed = false;
+        // Structural problems are all collected to be reported at once,
+        // unless failing fast
+        let mut problems: Vec<Error> = Vec::new();
+        macro_rules! problem {
+            ($e:expr) => {{
+                if options.fail_fast {
+                    return Err($e.into())
+                }
+                problems.push($e.into())
+            }};
+        }
+        let progress_bar = progress_bar_with_template(
+            header.item_count.into(), 
+            "Reading image => [{elapsed_precise}] {bar:40.cyan/blue} \
+                                        {pos:>7}/{len:7} {msg}")?;
+        progress_bar.enable_steady_tick(Duration::from_secs(1));
+        for item_info in item_infos {
+            let name = format!("{}.{}", 
+                item_info.item_sub_type, item_info.item_main_type);
+            log::debug!("Reading item {} '{}' at 0x{:x}, 0x{:x} bytes", 
+                item_info.item_id, name, item_info.offset_in_image, 
+                item_info.item_size);
+            if ! is_file_type_known(item_info.file_type) {
+                log::debug!("Item '{}' has unknown file type {}", name, 
+                    item_info.file_type)
+            }
+            if item_info.reserve.iter().any(|byte| *byte != 0) {
+                log::debug!("Item '{}' has non-zero reserved bytes {}", name, 
+                    hex::encode(item_info.reserve))
+            }
+            progress_bar.set_message(name.clone());
+            // Payloads are only read when consumed, except for the tiny VERIFY
+            // items, but those not fully in the file are caught early
+            let mut data = payload_at(item_info.offset_in_image, 
+                item_info.item_size);
+            let result = match item_info.offset_in_image.checked_add(
+                item_info.item_size) 
+            {
+                Some(end) if end <= size_file => 
+                    if item_info.item_main_type == "VERIFY" {
+                        file.seek(std::io::SeekFrom::Start(
+                            item_info.offset_in_image))?;
+                        let mut buffer = vec![
+                            0; usize_from_u64(&name, item_info.item_size)?];
+                        retry::read_exact(file, &mut buffer).map(|_|
+                            data = Payload::Owned(buffer))
+                    } else {
+                        Ok(())
+                    },
+                _ => Err(TransferError { 
+                    error: std::io::ErrorKind::UnexpectedEof.into(), 
+                    transferred: size_file.saturating_sub(
+                        item_info.offset_in_image) as usize }),
+            };
+            let read_ok = match result {
+                Ok(_) => true,
+                Err(e) => {
+                    let mut context = ErrorContext::reading().item(&name)
+                        .region(item_info.offset_in_image, item_info.item_size);
+                    if let Some(path_file) = path_file {
+                        context = context.path(path_file)
+                    }
+                    let e = Error::from(ImageError::read_failed(&name, 
+                        item_info.offset_in_image, item_info.item_size, e
+                    )).with_context(context);
+                    if ! ignore_errors {
+                        return Err(e)
+                    }
+                    eprintln!("Skipping item '{}': {}", name, e);
+                    failures.push((name.clone(), e));
+                    false
+                },
+            };
+            let mut verify_for = None;
+            if let Some(item_need_verify) = need_verify.take() {
                 if item_info.item_sub_type != item_need_verify.stem {
                     eprintln!("Partition {} does not have its verify right \
                         after it, but {}.{}", item_need_verify.stem,
                         item_info.item_sub_type, item_info.item_main_type);
-                    return Err(ImageError::UnmatchedVerify.into())
+                    problem!(ImageError::UnmatchedVerify)
+                } else if item_info.item_main_type != "VERIFY" {
+                    eprintln!("Item after {}.{} that needs verify is not a \
+                        verify item but a non-verify item {}.{}",
+                        item_need_verify.stem, item_need_verify.extension,
+                        item_info.item_sub_type, item_info.item_main_type);
+                    problem!(ImageError::UnmatchedVerify)
+                } else {
+                    verify_for = Some(item_need_verify)
+                }
+            }
+            let partition_failed = std::mem::take(&mut need_verify_failed);
+            if let Some(mut item_need_verify) = verify_for {
+                if partition_failed || ! read_ok {
+                    progress_bar.inc(1);
+                    continue
+                }
+                let data = data.try_to_vec(&name).context(||
+                    ErrorContext::reading().item(&name)
+                        .region(item_info.offset_in_image, item_info.item_size))?;
+                if item_info.item_size == 48 && data.starts_with(b"sha1sum ") && 
+                    item_info.verify == 0
+                {
+                    let sha1sum = Sha1sum::from_hex(&data[8..48])?;
+                    item_need_verify.sha1sum = Some(sha1sum);
+                    item_need_verify.verify_reserve = 
+                        vec_from_reserve(&item_info.reserve);
+                    items.push(item_need_verify);
+                } else {
+                    eprintln!("Verify item content for {} is not sha1sum",
+                        item_need_verify.stem);
+                    problem!(ImageError::IllegalVerify)
+                }
+            } else {
+                let item = Item {
+                    data,
+                    extension: item_info.item_main_type,
+                    stem: item_info.item_sub_type,
+                    sha1sum: None,
+                    reserve: vec_from_reserve(&item_info.reserve),
+                    verify_reserve: Vec::new(),
+                    text_normalization: None,
+                    original_id: Some(item_info.item_id),
+                    original_name: None,
+                    backup_of: None,
+                    backup: BackupPolicy::Auto,
+                };
+                if item.extension == "PARTITION" && item_info.verify != 0 {
+                    need_verify = Some(item);
+                    need_verify_failed = ! read_ok
+                } else {
+                    if item.extension == "PARTITION" {
+                        eprintln!("Partition {} does not have verify",
+                            item.stem);
+                        problem!(ImageError::UnmatchedVerify)
+                    } else if item_info.verify != 0 {
+                        eprintln!("Item {}.{} has verify", item.stem, item.extension);
+                        problem!(ImageError::IllegalVerify)
+                    }
+                    if read_ok {
+                        items.push(item)
+                    }
+                }
+            }
+            progress_bar.inc(1);
+        }
+        finish_bar(&progress_bar);
+        if need_verify.is_some() {
+            eprintln!("Could not found last VERIFY");
+            problem!(ImageError::UnmatchedVerify)
+        }
+        if problems.len() > 1 {
+            eprintln!("Found {} structural problems in image", problems.len());
+            return Err(ImageError::StructuralProblems { 
+                count: problems.len() }.into())
+        }
+        if let Some(e) = problems.pop() {
+            return Err(e)
+        }
+        Ok(ImageRead { image: Self {
+            version: header.version.clone(),
+            align: header.align,
+            items,
+            reserve: vec_from_reserve(&header.reserve),
+            pad_to: 0,
+        }, header, infos: table, failures })
+    }
+
+    pub fn try_read_dir<P: AsRef<Path>>(dir: P, options: &ReadDirOptions) 
+        -> Result<Self> 
+    {
+        let dir = dir.as_ref();
+        Self::try_read_dir_inner(dir, options)
+            .context(||ErrorContext::reading().path(dir))
+    }
+
+    fn try_read_dir_inner(path_dir: &Path, options: &ReadDirOptions) 
+        -> Result<Self> 
+    {
+        let mut entries = Vec::new();
+        for entry in read_dir(path_dir)? {
+            let entry = entry?;
+            entries.push(entry)
+        }
+        let entries = dedup_entries_by_name(entries, 
+            options.case_insensitive_merge)?;
+        let progress_bar = progress_bar_with_template(
+            entries.len() as u64, 
+            "Reading items => [{elapsed_precise}] {bar:40.cyan/blue} \
+                                        {pos:>3}/{len:3} {msg}")?;
+        progress_bar.enable_steady_tick(Duration::from_secs(1));
+        let parallel = paths_read_in_parallel([path_dir]);
+        let probed = map_jobs(entries, parallel, |entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            progress_bar.set_message(name.clone());
+            let result = probe_dir_entry(&entry, options);
+            progress_bar.inc(1);
+            (name, result)
+        });
+        finish_bar(&progress_bar);
+        let mut uboot_usb = None;
+        let mut ddr_usb = None;
+        let mut aml_sdc_burn_ini = None;
+        let mut meson1_dtb = None;
+        let mut platform_conf = None;
+        let mut generic_items = Vec::new();
+        let mut skipped = Vec::new();
+        let mut failures = Vec::new();
+        for (name, result) in probed {
+            let item = match result {
+                Ok(ProbedEntry::Item(item)) => *item,
+                Ok(ProbedEntry::Skipped) => {
+                    skipped.push(name);
+                    continue
+                },
+                Ok(ProbedEntry::Sidecar) => continue,
+                Err(e) => {
+                    let e = e.with_context(
+                        ErrorContext::reading().item(&name).path(path_dir));
+                    failures.push((name, e));
+                    continue
+                },
+            };
+            match (item.stem.as_ref(), item.extension.as_ref()) {
+                ("DDR", "USB") => ddr_usb = Some(item),
+                ("UBOOT", "USB") => uboot_usb = Some(item),
+                ("aml_sdc_burn", "ini") => aml_sdc_burn_ini = Some(item),
+                ("meson1", "dtb") => meson1_dtb = Some(item),
+                ("platform", "conf") => platform_conf = Some(item),
+                _ => generic_items.push(item)
+            }
+        }
+        if ! failures.is_empty() {
+            eprintln!("Failed to read {} entries under '{}':", 
+                failures.len(), path_dir.display());
+            for (name, e) in failures.iter() {
+                eprintln!("  - {}: {}", name, e)
+            }
+            if let Some((_, e)) = failures.into_iter().next() {
+                return Err(e)
+            }
+        }
+        if ! skipped.is_empty() {
+            // A single message, so the list goes with the warning
+            warning!(SkippedEntries, "{} entries under '{}' are not files named as \
+                [stem].[extension] and are not packed:{}", 
+                skipped.len(), path_dir.display(), skipped.iter()
+                    .map(|name|format!("\n  - {}", name)).collect::<String>());
+            if options.error_on_skip {
+                eprintln!("Refuse to continue with skipped entries");
+                return Err(ImageError::SkippedEntries { 
+                    count: skipped.len() }.into())
+            }
+        }
+        let mut items = Vec::new();
+        for (item, stem) in [(ddr_usb, "DDR"), (uboot_usb, "UBOOT")] {
+            match item {
+                Some(item) => items.push(item),
+                None => {
+                    eprintln!("Essential {}.USB file does not exist", stem);
+                    return Err(ImageError::MissingItem { 
+                        stem: stem.into(), extension: "USB".into() }.into());
+                },
+            }
+        }
+        for (item, stem, extension) in [
+            (aml_sdc_burn_ini, "aml_sdc_burn", "ini"),
+            (meson1_dtb, "meson1", "dtb"),
+            (platform_conf, "platform", "conf")] 
+        {
+            match item {
+                Some(item) => generic_items.push(item),
+                None => {
+                    eprintln!("Essential {}.{} file does not exist", stem, extension);
+                    return Err(ImageError::MissingItem { 
+                        stem: stem.into(), extension: extension.into()}.into())
+                }
+            }
+        }
+        generic_items.sort_by(sort_items_by_name);
+        items.append(&mut generic_items);
+        Ok(Self {
+            version: ImageVersion::V2,
+            align: 4,
+            items,
+            reserve: Vec::new(),
+            pad_to: 0,
+        })
+    }
+
+    /// Summaries of all items, in their order, for the JSON output
+    pub fn summaries(&self) -> Vec<ItemSummary> {
+        self.items.iter().map(|item| ItemSummary {
+            name: format!("{}.{}", item.stem, item.extension),
+            stem: item.stem.clone(),
+            extension: item.extension.clone(),
+            size: item.data.len(),
+            sha1sum: item.sha1sum.as_ref().map(|sha1sum|sha1sum.to_string()),
+            file: None,
+        }).collect()
+    }
+
+    /// The item infos as they'd be written
+    pub fn layout(&self) -> Result<Vec<RawItemInfo>> {
+        Ok(ImageToWrite::try_from_image(self)?.infos)
+    }
+
+    /// The items, VERIFY ones excluded as they're part of the items they 
+    /// verify
+    pub fn items(&self) -> &[Item] {
+        &self.items
+    }
+
+    /// The item named `[stem].[extension]`, if any
+    pub fn get_item(&self, stem: &str, extension: &str) -> Option<&Item> {
+        self.items.iter().find(|item|
+            item.stem == stem && item.extension == extension)
+    }
+
+    /// Position of the item named `[stem].[extension]`, or an error if there
+    /// is no such item
+    fn position_item(&self, stem: &str, extension: &str) -> Result<usize> {
+        match self.items.iter().position(|item|
+            item.stem == stem && item.extension == extension) 
+        {
+            Some(id) => Ok(id),
+            None => {
+                eprintln!("Missing image item: {}.{}", stem, extension);
+                Err(ImageError::MissingItem { 
+                    stem: stem.into(), extension: extension.into() }.into())
+            }
+        }
+    }
+
+    /// Refuse to have a second item named `[stem].[extension]`
+    fn check_not_duplicated(&self, stem: &str, extension: &str) -> Result<()> {
+        if self.get_item(stem, extension).is_some() {
+            eprintln!("Duplicated image item: {}.{}", stem, extension);
+            return Err(ImageError::DuplicatedItem { 
+                stem: stem.into(), extension: extension.into() }.into())
+        }
+        Ok(())
+    }
+
+    /// Append `item` as the last item, refusing it if an item of the same 
+    /// name exists
+    pub fn add_item(&mut self, item: Item) -> Result<()> {
+        self.check_not_duplicated(&item.stem, &item.extension)?;
+        self.items.push(item);
+        Ok(())
+    }
+
+    /// Remove the item named `[stem].[extension]` and return it, callers 
+    /// might want to warn if it [`is_essential`](Item::is_essential). Items
+    /// recorded as its backups are kept as items of their own.
+    pub fn remove_item(&mut self, stem: &str, extension: &str) -> Result<Item> {
+        let id = self.position_item(stem, extension)?;
+        let item = self.items.remove(id);
+        let name = item.name();
+        for other in self.items.iter_mut() {
+            if other.backup_of.as_ref() == Some(&name) {
+                other.backup_of = None
+            }
+        }
+        Ok(item)
+    }
+
+    /// Replace the payload of the item named `[stem].[extension]` with 
+    /// `data`, its sha1sum is dropped to be calculated again by `fill_verify`
+    /// before the image is written
+    pub fn replace_item_data(
+        &mut self, stem: &str, extension: &str, data: Vec<u8>
+    ) 
+        -> Result<()> 
+    {
+        let id = self.position_item(stem, extension)?;
+        let item = &mut self.items[id];
+        item.data = Payload::Owned(data);
+        item.sha1sum = None;
+        item.text_normalization = None;
+        Ok(())
+    }
+
+    /// Rename the item named `[stem].[extension]` to 
+    /// `[new_stem].[new_extension]`, refusing if an item of the new name 
+    /// exists. Its sha1sum is kept, as its payload is not touched, and items
+    /// recorded as its backups follow it. Callers might want to warn if the 
+    /// item [`is_essential`](Item::is_essential) before renaming it away.
+    pub fn rename_item(
+        &mut self, stem: &str, extension: &str, 
+        new_stem: &str, new_extension: &str
+    ) 
+        -> Result<()> 
+    {
+        let id = self.position_item(stem, extension)?;
+        if stem == new_stem && extension == new_extension {
+            return Ok(())
+        }
+        self.check_not_duplicated(new_stem, new_extension)?;
+        let item = &mut self.items[id];
+        let name = item.name();
+        item.stem = new_stem.into();
+        item.extension = new_extension.into();
+        let new_name = item.name();
+        for other in self.items.iter_mut() {
+            if other.backup_of.as_ref() == Some(&name) {
+                other.backup_of = Some(new_name.clone())
+            }
+        }
+        Ok(())
+    }
+
+    /// The count of items and the sum of their sizes
+    pub fn items_size(&self) -> (usize, u64) {
+        (self.items.len(), self.items.iter().map(|item|item.data.len()).sum())
+    }
+
+    /// A one-paragraph overview of the image
+    pub fn summary(&self) -> ImageSummary {
+        ImageSummary {
+            version: self.version.clone(),
+            align: self.align,
+            items: self.items.iter().map(|item|(
+                format!("{}.{}", item.stem, item.extension), item.data.len()))
+                .collect(),
+            verified: self.items.iter()
+                .filter(|item|item.sha1sum.is_some()).count(),
+        }
+    }
+
+    /// The order to show items in, see `output::display_order`
+    fn display_order(&self) -> Vec<usize> {
+        output::display_order(self.items.len(), |id|self.sort_fields(id))
+    }
+
+    /// The fields the item at `id` is sorted by in tables
+    fn sort_fields(&self, id: usize) -> output::SortFields {
+        let item = &self.items[id];
+        output::SortFields {
+            size: item.data.len(),
+            name: format!("{}.{}", item.stem, item.extension),
+            offset: item.data.offset_in_file(),
+            kind: item.extension.clone(),
+        }
+    }
+
+    /// Print the table of items
+    pub fn print_table_stdout(&self) -> Result<()> {
+        if output::csv() {
+            return output::print_csv(
+                &["id", "stem", "extension", "size", "sha1sum"], 
+                self.display_order().into_iter().map(|id| {
+                    let item = &self.items[id];
+                    vec![
+                        id.to_string(),
+                        item.stem.clone(),
+                        item.extension.clone(),
+                        item.data.len().to_string(),
+                        item.sha1sum.as_ref().map(|sha1sum|sha1sum.to_string())
+                            .unwrap_or_default(),
+                    ]
+                }))
+        }
+        println!("Items in image:");
+        let mut rows = Vec::new();
+        for id in self.display_order() {
+            let item = &self.items[id];
+            rows.push(vec![
+                cell_right!(id),
+                cell_right!(&item.stem),
+                cell_right!(&item.extension),
+                cell_right!(output::size(item.data.len())),
+                if let Some(sha1sum) = &item.sha1sum {
+                    cell_right!(format!("{}", sha1sum))
+                } else {
+                    cell_right!("None")
+                }
+            ])
+        }
+        let table = rows.table().title(vec![
+            cell_bold_center!("ID"),
+            cell_bold_center!("stem"),
+            cell_bold_center!("extension"),
+            cell_bold_center!("size"),
+            cell_bold_center!("sha1sum")
+        ]).bold(true);
+        output::print_table(table)?;
+        Ok(())
+    }
+
+    /// Print the result of verifying each item in `report` (from `verify` on
+    /// this image), with an extra column of their standard CRC32s if they're
+    /// calculated, then the tally of them. The calculated checksum is only
+    /// shown for those mismatched.
+    pub fn print_verify_table_stdout(&self, report: &VerifyReport) 
+        -> Result<()> 
+    {
+        let with_crc32 = report.items.iter().any(|item|item.crc32.is_some());
+        let order = self.display_order();
+        if output::csv() {
+            let mut header = vec!["id", "item", "size", "algorithm", 
+                "recorded", "calculated", "status"];
+            if with_crc32 {
+                header.push("crc32_standard")
+            }
+            return output::print_csv(&header, order.into_iter().map(|id| {
+                let item = &report.items[id];
+                let mut row = vec![
+                    id.to_string(),
+                    item.name.clone(),
+                    item.size.to_string(),
+                    item.algorithm.map(|algo|algo.to_string())
+                        .unwrap_or_default(),
+                    item.sha1sum_recorded.clone().unwrap_or_default(),
+                    item.sha1sum_calculated.clone().unwrap_or_default(),
+                    item.status.label().into(),
+                ];
+                if let Some(crc32) = item.crc32 {
+                    row.push(crc32.to_string())
+                }
+                row
+            }))
+        }
+        println!("Verification of items:");
+        let mut rows = Vec::new();
+        for id in order {
+            let item = &report.items[id];
+            // The digests that differ and the status in red, if colored
+            let highlight = |cell: CellStruct| 
+                if item.status == CheckStatus::Mismatch {
+                    cell.foreground_color(Some(Color::Red))
+                } else {
+                    cell
+                };
+            let mut row = vec![
+                cell_right!(id),
+                cell_right!(&item.name),
+                cell_right!(output::size(item.size)),
+                cell_right!(item.algorithm.map(|algo|algo.to_string())
+                    .unwrap_or_else(||"None".into())),
+                highlight(cell_right!(
+                    item.sha1sum_recorded.as_deref().unwrap_or("None"))),
+                highlight(cell_right!(match item.status {
+                    CheckStatus::Mismatch => 
+                        item.sha1sum_calculated.as_deref().unwrap_or_default(),
+                    _ => "",
+                })),
+                highlight(cell_right!(item.status.label())),
+            ];
+            if let Some(crc32) = item.crc32 {
+                row.push(cell_right!(format!("0x{:08x}", crc32)))
+            }
+            rows.push(row)
+        }
+        let mut title = vec![
+            cell_bold_center!("ID"),
+            cell_bold_center!("item"),
+            cell_bold_center!("size"),
+            cell_bold_center!("algorithm"),
+            cell_bold_center!("recorded"),
+            cell_bold_center!("calculated"),
+            cell_bold_center!("status"),
+        ];
+        if with_crc32 {
+            title.push(cell_bold_center!("CRC32 (standard)"))
+        }
+        output::print_table(rows.table().title(title).bold(true))?;
+        let (passed, failed, skipped) = report.tally();
+        println!("{} passed, {} failed, {} without verify", 
+            passed, failed, skipped);
+        Ok(())
+    }
+
+    /// Print the status of each item after an error-tolerant operation, with
+    /// the failures recorded in `failures`
+    pub fn print_status_table_stdout(&self, failures: &[(String, Error)]) 
+        -> Result<()> 
+    {
+        let mut rows = Vec::new();
+        for item in self.items.iter() {
+            let name = format!("{}.{}", item.stem, item.extension);
+            if failures.iter().all(|(failed, _)| failed != &name) {
+                rows.push([cell_right!(name), cell_right!("ok"), "".cell()])
+            }
+        }
+        for (name, error) in failures.iter() {
+            let status = if matches!(error.root(), Error::HookFailed(..)) {
+                "hook failed"
+            } else if self.items.iter().any(|item| 
+                format!("{}.{}", item.stem, item.extension) == *name) 
+            {
+                "corrupt"
+            } else {
+                "skipped"
+            };
+            rows.push([cell_right!(name), cell_right!(status), 
+                error.to_string().cell()])
+        }
+        let table = rows.table().title([
+            cell_bold_center!("item"),
+            cell_bold_center!("status"),
+            cell_bold_center!("error"),
+        ]).bold(true);
+        println!("Status of items:");
+        output::print_table(table)?;
+        Ok(())
+    }
+
+    /// Take sha1sums of item files still the same since they were cached, so
+    /// `fill_verify` would skip them, returning how many were taken
+    pub fn apply_checksum_cache(&mut self, cache: &ChecksumCache) -> usize {
+        let mut hits = 0;
+        for item in self.items.iter_mut().filter(|item|item.sha1sum.is_none()) {
+            let (path, name) = match item.data.source_file() {
+                Some(source) => source,
+                None => continue,
+            };
+            if let Some(sha1sum) = cache.get(&name, path) {
+                item.sha1sum = Some(sha1sum);
+                hits += 1
+            }
+        }
+        hits
+    }
+
+    /// Record the sha1sums of items read whole from files into `cache`
+    pub fn record_checksum_cache(&self, cache: &mut ChecksumCache) {
+        for item in self.items.iter() {
+            if let (Some((path, name)), Some(sha1sum)) = 
+                (item.data.source_file(), &item.sha1sum) 
+            {
+                if let Err(e) = cache.insert(&name, path, sha1sum) {
+                    warning!(Cache, "failed to cache sha1sum of '{}': {}", 
+                        path.display(), e)
+                }
+            }
+        }
+    }
+
+    /// Write a single item to `path` for `try_write_dir`, with a progress bar
+    /// of its own, and check it against its sha1sum on the way
+    fn try_write_item_file(&self, item: &Item, path: &Path, name: &str, 
+                           options: &WriteDirOptions, bars: &ItemBars, 
+                           abort: &AtomicBool)
+        -> Result<WriteOutcome> 
+    {
+        let sha1sum_record = item.sha1sum.as_ref().filter(|_|options.verify);
+        let mut hasher = sha1sum_record.map(|_|Sha1sumHasher::new());
+        let mut outcome = WriteOutcome::Written;
+        if options.keep && 
+            file_content_equals(path, &item.data, name, hasher.as_mut())? 
+        {
+            // Leave it untouched so its mtime is kept
+            outcome = WriteOutcome::Unchanged;
+            bars.skip(item.data.len())
+        } else {
+            // The comparison could have stopped before hashing everything
+            hasher = sha1sum_record.map(|_|Sha1sumHasher::new());
+            let item_bar = bars.start(name, item.data.len())?;
+            let result = write_item_file(
+                path, &item.data, name, hasher.as_mut(), options.sparse, 
+                &item_bar, abort);
+            bars.done(&item_bar);
+            result?
+        }
+        let (sha1sum_record, hasher) = match (sha1sum_record, hasher) {
+            (Some(sha1sum_record), Some(hasher)) => (sha1sum_record, hasher),
+            _ => return Ok(outcome),
+        };
+        let sha1sum_calculated = hasher.finalize();
+        if sha1sum_record == &sha1sum_calculated {
+            return Ok(outcome)
+        }
+        eprintln!("Recorded SHA1sum ({}) different from calculated \
+            SHA1sum ({}) for item '{}'", sha1sum_record, 
+            sha1sum_calculated, name);
+        let error = ImageError::IllegalVerify.into();
+        if options.keep_corrupted {
+            let mut path_corrupted = path.as_os_str().to_owned();
+            path_corrupted.push(".corrupt");
+            rename(path, &path_corrupted)?;
+            Ok(WriteOutcome::Corrupted(error))
+        } else {
+            remove_file(path)?;
+            Err(error)
+        }
+    }
+
+    /// Write all items into a dir, verifying them in the same pass if asked
+    /// to, those failing to verify would be kept with an additional `.corrupt`
+    /// suffix if `keep_corrupted`, and returned
+    pub fn try_write_dir<P: AsRef<Path>>(
+        &self, dir: P, options: &WriteDirOptions
+    ) 
+        -> Result<WriteDirReport> 
+    {
+        let dir = dir.as_ref();
+        self.try_write_dir_inner(dir, options)
+            .context(||ErrorContext::writing().path(dir))
+    }
+
+    fn try_write_dir_inner(&self, parent: &Path, options: &WriteDirOptions) 
+        -> Result<WriteDirReport> 
+    {
+        if parent.exists() && ! options.keep {
+            if parent.is_dir() {
+                remove_dir_all(parent)?
+            } else {
+                remove_file(parent)?
+            }
+        }
+        if let Err(e) = create_dir_all(parent) {
+            eprintln!("Failed to create output dir '{}': {}", 
+                parent.display(), e);
+            return Err(e.into())
+        }
+        let jobs = match options.jobs {
+            0 if storage::is_rotational(parent) => {
+                println!("Writing items one after another as '{}' is on a \
+                    rotational disk (use --jobs to override)", parent.display());
+                1
+            },
+            0 => rayon::current_num_threads().min(self.items.len()).max(1),
+            jobs => jobs,
+        };
+        let mut names = Vec::new();
+        let mut escaped = Vec::new();
+        let mut to_write = Vec::new();
+        for (item, name) in self.items.iter().zip(self.file_names()) {
+            let item_name = format!("{}.{}", item.stem, item.extension);
+            if name != item_name {
+                escaped.push([cell_right!(&item_name), cell_right!(&name)])
+            }
+            names.push(name.clone());
+            to_write.push((item, name, item_name))
+        }
+        let bars = ItemBars::new("Writing item", 
+            to_write.iter().map(|(item, _, _)|item.data.len()).sum())?;
+        let abort = AtomicBool::new(false);
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(jobs).build()?;
+        let results: Vec<Result<WriteOutcome>> = pool.install(|| 
+            to_write.into_par_iter().map(|(item, name, item_name)| {
+                if abort.load(AtomicOrdering::Relaxed) {
+                    return Err(ImageError::Aborted.into())
                 }
-                if item_info.item_main_type != "VERIFY" {
-                    eprintln!("Item after {}.{} that needs verify is not a \
-                        verify item but a non-verify item {}.{}",
-                        item_need_verify.stem, item_need_verify.extension,
-                        item_info.item_sub_type, item_info.item_main_type);
-                    return Err(ImageError::UnmatchedVerify.into())
+                let path = parent.join(&name);
+                let result = self.try_write_item_file(item, &path, &item_name, 
+                    options, &bars, &abort);
+                if let Err(e) = &result {
+                    abort.store(true, AtomicOrdering::Relaxed);
+                    eprintln!("Failed to write item '{}' to '{}': {}", 
+                        item_name, path.display(), e);
                 }
-                if ! (item_info.item_size == 48 && 
-                        data.starts_with(b"sha1sum ") && 
-                        item_info.verify == 0) 
+                result.context(||ErrorContext::writing().item(&item_name)
+                    .path(&path))
+            }).collect());
+        bars.finish("Wrote");
+        let mut report = WriteDirReport::default();
+        let mut error = None;
+        let mut verified = Vec::new();
+        for (result, (item, name)) in 
+            results.into_iter().zip(self.items.iter().zip(names.iter_mut())) 
+        {
+            match result {
+                Ok(WriteOutcome::Written) => verified.push((item, name.clone())),
+                Ok(WriteOutcome::Unchanged) => {
+                    report.unchanged.push(name.clone());
+                    verified.push((item, name.clone()))
+                },
+                Ok(WriteOutcome::Corrupted(e)) => {
+                    name.push_str(".corrupt");
+                    report.corrupted.push(
+                        (format!("{}.{}", item.stem, item.extension), e))
+                },
+                Err(e) if matches!(e.root(), 
+                    Error::ImageError(ImageError::Aborted)) => (),
+                Err(e) => if error.is_none() { error = Some(e) },
+            }
+        }
+        if let Some(e) = error {
+            return Err(e)
+        }
+        if options.verify {
+            try_write_checksum_cache(parent, &verified, options.keep)?
+        }
+        if ! escaped.is_empty() {
+            warning!(EscapedNames, "{} items could not be created with their \
+                names on this platform and were written with escaped names, \
+                use --emit-manifest to restore them in a later pack:", 
+                escaped.len());
+            let table = escaped.table().title([
+                cell_bold_center!("item name"),
+                cell_bold_center!("file name"),
+            ]).bold(true);
+            output::print_table_stderr(table)?;
+        }
+        if options.keep {
+            for entry in read_dir(parent)? {
+                let name = entry?.file_name().to_string_lossy().into_owned();
+                if ! names.contains(&name) && name != MANIFEST_NAME && 
+                    name != CHECKSUM_CACHE_NAME 
                 {
-                    eprintln!("Verify item content for {} is not sha1sum",
-                        item_need_verify.stem);
+                    report.extra.push(name)
+                }
+            }
+            report.extra.sort();
+        }
+        Ok(report)
+    }
+
+    /// Write items as entries of a tar archive into `writer`, after the 
+    /// manifest as the first entry, verifying them in the same pass if asked
+    /// to, and return the writer and the size of the archive. Entries come in
+    /// the order of items in the image, named as files in a dir would be, 
+    /// with fixed metadata, so the same image always gives the same archive.
+    /// As entries are streamed, an item failing to verify fails the write.
+    #[cfg(feature = "tar")]
+    pub fn try_write_tar<W: Write>(&self, writer: W, verify: bool) 
+        -> Result<(W, u64)> 
+    {
+        let mut tar = crate::archive::TarWriter::new(writer);
+        let manifest = self.to_manifest()?.try_to_vec()?;
+        tar.start_entry(MANIFEST_NAME, manifest.len() as u64)?;
+        tar.write_all(&manifest)?;
+        tar.finish_entry()?;
+        let progress_bar = progress_bar_with_template(
+            self.items_size().1, template_bytes("Writing tar", ""))?;
+        for (item, file_name) in self.items.iter().zip(self.file_names()) {
+            let name = format!("{}.{}", item.stem, item.extension);
+            log::debug!("Writing item '{}' as tar entry '{}', 0x{:x} bytes", 
+                name, file_name, item.data.len());
+            tar.start_entry(&file_name, item.data.len())?;
+            let mut hasher = item.sha1sum.as_ref().filter(|_|verify)
+                .map(|_|Sha1sumHasher::new());
+            item.data.try_for_each_chunk(&name, |chunk| {
+                if let Some(hasher) = hasher.as_mut() {
+                    hasher.update(chunk)
+                }
+                tar.write_all(chunk)?;
+                progress_bar.inc(chunk.len() as u64);
+                Ok(())
+            }).context(||ErrorContext::writing().item(&name))?;
+            tar.finish_entry()?;
+            if let (Some(sha1sum), Some(hasher)) = (&item.sha1sum, hasher) {
+                let sha1sum_calculated = hasher.finalize();
+                if sha1sum != &sha1sum_calculated {
+                    eprintln!("Recorded SHA1sum ({}) different from \
+                        calculated SHA1sum ({}) for item '{}'", 
+                        sha1sum, sha1sum_calculated, name);
                     return Err(ImageError::IllegalVerify.into())
                 }
-                let sha1sum = Sha1sum::from_hex(&data[8..48])?;
-                item_need_verify.sha1sum = Some(sha1sum);
-                items.push(item_need_verify);
-                need_verify = None;
-            } else {
-                let item = Item {
-                    data,
-                    extension: item_info.item_main_type.clone(),
-                    stem: item_info.item_sub_type.clone(),
-                    sha1sum: None,
-                };
-                if item.extension == "PARTITION" {
-                    if item_info.verify == 0 {
-                        eprintln!("Partition {} does not have verify",
-                            item.stem);
-                        return Err(ImageError::UnmatchedVerify.into())
+            }
+        }
+        finish_bar(&progress_bar);
+        tar.finish()
+    }
+
+    /// Write items as entries of a tar archive into a file, atomic as 
+    /// `try_write_file`, and return the size of the archive. See 
+    /// `try_write_tar`.
+    #[cfg(feature = "tar")]
+    pub fn try_write_tar_file<P: AsRef<Path>>(
+        &self, file: P, options: &WriteFileOptions, verify: bool
+    ) 
+        -> Result<u64> 
+    {
+        let file = file.as_ref();
+        let mut size = 0;
+        atomic::write_file(file, options, |out_file| {
+            let (mut writer, size_tar) = self.try_write_tar(
+                BufWriter::with_capacity(chunk_size(), out_file), verify)?;
+            size = size_tar;
+            Ok(writer.flush()?)
+        }).context(||ErrorContext::writing().path(file))?;
+        Ok(size)
+    }
+
+    /// Write the packed image into any seekable writer, optionally showing
+    /// progress. Payloads are streamed from their sources, and each byte is
+    /// only touched once, as the CRC is calculated as it's written and then
+    /// patched into the header at the end, and returned. With `verify`, 
+    /// partitions are also verified against their recorded sha1sums as they
+    /// are written, failing the write if any does not match.
+    pub fn try_write_to<W: Write + Seek>(
+        &self, writer: &mut W, show_progress: bool, verify: bool
+    ) 
+        -> Result<u32> 
+    {
+        let crc32 = self.try_write_to_inner(writer, show_progress, verify)?;
+        if show_progress {
+            println!("CRC32 of image is 0x{:08x}", crc32);
+        }
+        Ok(crc32)
+    }
+
+    /// `try_write_to` without reporting the CRC32, for callers reporting it
+    /// their own way
+    fn try_write_to_inner<W: Write + Seek>(
+        &self, writer: &mut W, show_progress: bool, verify: bool
+    ) 
+        -> Result<u32> 
+    {
+        let image_to_write = ImageToWrite::try_from_image(self)?;
+        let size = image_to_write.head.image_size;
+        let progress_bar = progress_bar_with_template_optional(
+            show_progress,
+            size,
+            template_bytes("Writing image", ""))?;
+        let mut writer = Crc32ImageWriter::new(writer);
+        let mut write = |chunk: &[u8]| -> Result<()> {
+            let written = writer.written();
+            log::trace!("Writing 0x{:x} bytes at 0x{:x}", chunk.len(), written);
+            retry::write_all(&mut writer, chunk).map_err(|e|
+                ImageError::WriteFailed { name: "image".into(), offset: 0, 
+                    size, transferred: written + e.transferred as u64, 
+                    error: e.error })?;
+            progress_bar.set_position(writer.written());
+            Ok(())
+        };
+        write(&image_to_write.data_head_infos)?;
+        let mut offset = image_to_write.data_head_infos.len() as u64;
+        for part in image_to_write.body.iter() {
+            let context = |name: &str, payload: &Payload| 
+                ErrorContext::writing().item(name).region(offset, payload.len());
+            match part {
+                BodyPart::Padding(len) => 
+                    log::debug!("Padding 0x{:x} bytes at 0x{:x}", len, offset),
+                BodyPart::Payload { name, payload, .. } => 
+                    log::debug!("Writing item '{}' at 0x{:x}, 0x{:x} bytes", 
+                        name, offset, payload.len()),
+                BodyPart::Verify(_) => 
+                    log::debug!("Writing VERIFY at 0x{:x}", offset),
+            }
+            match part {
+                BodyPart::Padding(len) => {
+                    let zeros = vec![0; (*len).min(chunk_size() as u64) as usize];
+                    let mut left = *len;
+                    while left > 0 {
+                        let size = left.min(zeros.len() as u64);
+                        write(&zeros[0..size as usize])?;
+                        left -= size
                     }
-                    need_verify = Some(item)
-                } else {
-                    if item_info.verify != 0 {
-                        eprintln!("Item {}.{} has verify", item.stem, item.extension);
+                },
+                BodyPart::Payload { name, payload, sha1sum: Some(sha1sum) } 
+                    if verify => 
+                {
+                    let mut hasher = Sha1sumHasher::new();
+                    payload.try_for_each_chunk(name, |chunk| {
+                        hasher.update(chunk);
+                        write(chunk)
+                    }).context(||context(name, payload))?;
+                    let sha1sum_calculated = hasher.finalize();
+                    if *sha1sum != &sha1sum_calculated {
+                        eprintln!("Recorded SHA1sum ({}) different from \
+                            calculated SHA1sum ({}) for item '{}'", 
+                            sha1sum, sha1sum_calculated, name);
                         return Err(ImageError::IllegalVerify.into())
                     }
-                    items.push(item)
-                }
+                },
+                BodyPart::Payload { name, payload, .. } => 
+                    payload.try_for_each_chunk(name, &mut write)
+                        .context(||context(name, payload))?,
+                BodyPart::Verify(content) => write(content)?,
             }
-            rows.push([
-                cell_right!(item_info.item_id),
-                cell_right!(item_info.file_type),
-                cell_right!(format!("0x{:x}", item_info.current_offset_in_item)),
-                cell_right!(format!("0x{:x}", item_info.offset_in_image)),
-                cell_right!(format!("0x{:x}", item_info.item_size)),
-                cell_right!(item_info.item_main_type),
-                cell_right!(item_info.item_sub_type),
-                cell_right!(item_info.verify),
-                if item_info.is_backup_item == 0 {
-                    format!("no ({})", item_info.backup_item_id).cell()
-                } else {
-                    format!("yes ({})", item_info.backup_item_id).cell()
-                }.justify(Justify::Right)
-            ]);
-            progress_bar.inc(1);
+            offset += part.len()
         }
-        progress_bar.finish_and_clear();
-        let table = rows.table().title([
-            cell_bold_center!("ID"),
-            cell_bold_center!("type"),
-            cell_bold_center!("item off"),
-            cell_bold_center!("image off"),
-            cell_bold_center!("size"),
-            cell_bold_center!("main type"),
-            cell_bold_center!("sub type"),
-            cell_bold_center!("verify"),
-            cell_bold_center!("backup (id)")
-        ]).bold(true);
-        if need_verify.is_some() {
-            eprintln!("Could not found last VERIFY");
-            return Err(ImageError::UnmatchedVerify.into())
+        if show_progress {
+            finish_stage(&progress_bar, "Wrote")
         }
-        println!("Item infos in raw image:");
-        cli_table::print_stdout(table)?;
-        Ok(Self {
-            version,
-            align: header.item_align_size,
-            items,
-        })
-        // file.as_ref().try_into()
+        if writer.written() != size {
+            eprintln!("Written image size (0x{:x}) != expected (0x{:x})", 
+                writer.written(), size);
+            return Err(ImageError::SizeMismatch { 
+                exptected: size as usize, actual: writer.written() as usize 
+            }.into())
+        }
+        let crc32 = writer.finish()?;
+        if show_progress && image_to_write.size_pad > 0 {
+            println!("Padded image with {} of zeros after the last item to {}",
+                output::size_text_exact(image_to_write.size_pad),
+                output::size_text(size));
+        }
+        Ok(crc32)
     }
 
-    pub(crate) fn try_read_dir<P: AsRef<Path>>(dir: P) -> Result<Self> {
-        let path_dir = dir.as_ref();
-        let mut entries = Vec::new();
-        for entry in read_dir(path_dir)? {
-            let entry = entry?;
-            entries.push(entry)
+    /// Pack the image silently into memory and return the final bytes
+    pub fn try_write_vec(&self) -> Result<Vec<u8>> {
+        let mut buffer = std::io::Cursor::new(Vec::new());
+        self.try_write_to(&mut buffer, false, false)?;
+        Ok(buffer.into_inner())
+    }
+
+    /// Pack the image exactly as when writing it, optionally showing progress,
+    /// but discard the bytes, and return the CRC32 and size it would have,
+    /// which are left to the caller to report
+    pub fn try_write_discard(&self, show_progress: bool) -> Result<(u32, u64)> {
+        let mut discard = Discard::default();
+        let crc32 = self.try_write_to_inner(&mut discard, show_progress, false)?;
+        Ok((crc32, discard.len))
+    }
+
+    /// Write the packed image into a writer that could not seek, e.g. stdout,
+    /// and return its CRC32 and size. As the CRC32 comes first in the header,
+    /// the image is packed twice: discarded once to calculate the CRC32, and
+    /// then written with it, so payloads are read twice. See `try_write_to`
+    /// for `verify`.
+    pub fn try_write_stream<W: Write>(&self, writer: &mut W, verify: bool)
+        -> Result<(u32, u64)>
+    {
+        println!("Calculating CRC32 of image before streaming it");
+        let (crc32, size) = self.try_write_discard(false)?;
+        println!("Streaming image of {} with CRC32 0x{:08x}", 
+            output::size_text_exact(size), crc32);
+        let mut stream = Stream::new(writer, crc32);
+        self.try_write_to_inner(&mut stream, true, verify)?;
+        Ok((crc32, stream.len))
+    }
+
+    /// Write the packed image into a file, in atomic mode via a temporary file
+    /// renamed to the final path only on success, and return its CRC32. See
+    /// `try_write_to` for `verify`.
+    pub fn try_write_file<P: AsRef<Path>>(
+        &self, file: P, options: &WriteFileOptions, verify: bool
+    ) 
+        -> Result<u32> 
+    {
+        let file = file.as_ref();
+        let mut crc32 = 0;
+        atomic::write_file(file, options, |out_file| {
+            let mut writer = BufWriter::with_capacity(chunk_size(), out_file);
+            crc32 = self.try_write_to(&mut writer, true, verify)?;
+            Ok(writer.flush()?)
+        }).context(||ErrorContext::writing().path(file))?;
+        Ok(crc32)
+    }
+
+    /// Write the packed image into a file as `try_write_file` does, and also
+    /// return the `digests` of the file, hashed as it's written. The CRC32 
+    /// comes first in the file, so it must be known before the digests could
+    /// be hashed, and the image is packed twice as `try_write_stream` does
+    /// instead of patching the CRC32 in at the end, while the file is still
+    /// never read back.
+    pub fn try_write_file_digested<P: AsRef<Path>>(
+        &self, file: P, options: &WriteFileOptions, verify: bool,
+        digests: &[DigestAlgo]
+    ) 
+        -> Result<(u32, Vec<FileDigest>)> 
+    {
+        let file = file.as_ref();
+        let mut result = (0, Vec::new());
+        atomic::write_file(file, options, |out_file| {
+            let mut writer = DigestWriter::new(
+                BufWriter::with_capacity(chunk_size(), out_file), digests);
+            let (crc32, _) = self.try_write_stream(&mut writer, verify)?;
+            let (mut writer, digests) = writer.finish();
+            result = (crc32, digests);
+            Ok(writer.flush()?)
+        }).context(||ErrorContext::writing().path(file))?;
+        Ok(result)
+    }
+
+    /// Write the packed image compressed as `format` into a file, atomic as
+    /// `try_write_file`, and return its CRC32, the size of the image before
+    /// compression and the `digests` of the compressed file, hashed as it's
+    /// written. The compressed stream could not seek back to the header, so
+    /// the image is packed twice as `try_write_stream` does.
+    #[cfg(feature = "compress")]
+    pub fn try_write_file_compressed<P: AsRef<Path>>(
+        &self, file: P, options: &WriteFileOptions, verify: bool, 
+        format: crate::compress::Compression, digests: &[DigestAlgo]
+    ) 
+        -> Result<(u32, u64, Vec<FileDigest>)> 
+    {
+        let file = file.as_ref();
+        let mut result = (0, 0, Vec::new());
+        atomic::write_file(file, options, |out_file| {
+            let mut encoder = crate::compress::Encoder::new(DigestWriter::new(
+                BufWriter::with_capacity(chunk_size(), out_file), digests), 
+                format)?;
+            let (crc32, size) = self.try_write_stream(&mut encoder, verify)?;
+            let (mut writer, digests) = encoder.finish()?.finish();
+            result = (crc32, size, digests);
+            Ok(writer.flush()?)
+        }).context(||ErrorContext::writing().path(file))?;
+        Ok(result)
+    }
+
+    /// Check the image just written from this image to `file` without reading
+    /// it back whole: the planned table must be consistent, the header and
+    /// item info table in the file must be the planned ones with `crc32` as 
+    /// calculated while writing, the file must be of the planned size, and 
+    /// VERIFY items must record the sha1sums of their partitions. Payloads
+    /// themselves are not read back.
+    pub fn try_self_check_file<P: AsRef<Path>>(&self, file: P, crc32: u32)
+        -> Result<()>
+    {
+        let file = file.as_ref();
+        let fail = |reason: String| -> Result<()> {
+            eprintln!("Self check of image '{}' failed: {}", 
+                file.display(), reason);
+            Err(ImageError::SelfCheckFailed { reason }.into())
+        };
+        let image_to_write = ImageToWrite::try_from_image(self)?;
+        if let Err(reason) = image_to_write.check_table() {
+            return fail(reason)
         }
-        let progress_bar = progress_bar_with_template(
-            entries.len() as u64, 
-            "Reading items => [{elapsed_precise}] {bar:40.cyan/blue} \
-                                        {pos:>3}/{len:3} {msg}")?;
-        progress_bar.enable_steady_tick(Duration::from_secs(1));
-        let mut uboot_usb = None;
-        let mut ddr_usb = None;
-        let mut aml_sdc_burn_ini = None;
-        let mut meson1_dtb = None;
-        let mut platform_conf = None;
-        let mut generic_items = Vec::new();
-        for entry in entries {
-            progress_bar.set_message(entry.file_name().to_string_lossy().into_owned());
-            let path_entry = entry.path();
-            let file_name = match path_entry.file_name() {
-                Some(file_name) => file_name.to_string_lossy(),
-                None => {
-                    return Err(Error::IOError(std::io::Error::new(
-                        std::io::ErrorKind::NotFound,
-                        "Cannot figure out the file name of part")));
-                },
-            };
-            let (stem, extension) = match 
-                file_name.split_once('.') 
-            {
-                Some((stem, extension)) => (stem, extension),
+        let mut reader = File::open(file)?;
+        let size = reader.metadata()?.len();
+        let size_planned = image_to_write.head.image_size;
+        if size != size_planned {
+            return fail(format!("file size 0x{:x} != planned 0x{:x}", 
+                size, size_planned))
+        }
+        let mut head_infos = vec![0; image_to_write.data_head_infos.len()];
+        reader.read_exact(&mut head_infos)?;
+        let crc32_recorded = u32::from_le_bytes(
+            [head_infos[0], head_infos[1], head_infos[2], head_infos[3]]);
+        if crc32_recorded != crc32 {
+            return fail(format!("CRC32 recorded 0x{:08x} != written 0x{:08x}",
+                crc32_recorded, crc32))
+        }
+        if head_infos[4..] != image_to_write.data_head_infos[4..] {
+            return fail("header or item info table differs from planned".into())
+        }
+        for (id, info) in image_to_write.infos.iter().enumerate() {
+            if info.item_main_type != "VERIFY" || id == 0 {
+                continue
+            }
+            let expected = match &image_to_write.sha1sums[id - 1] {
+                Some(sha1sum) => format!("sha1sum {}", sha1sum),
                 None => continue,
             };
-            let mut data = Vec::new();
-            let mut file = File::open(&path_entry)?;
-            file.read_to_end(&mut data)?;
-            let item = Item {
-                data,
-                extension: extension.into(),
-                stem: stem.into(),
-                sha1sum: None,
-            };
-            match (item.stem.as_ref(), item.extension.as_ref()) {
-                ("DDR", "USB") => ddr_usb = Some(item),
-                ("UBOOT", "USB") => uboot_usb = Some(item),
-                ("aml_sdc_burn", "ini") => aml_sdc_burn_ini = Some(item),
-                ("meson1", "dtb") => meson1_dtb = Some(item),
-                ("platform", "conf") => platform_conf = Some(item),
-                _ => generic_items.push(item)
+            let mut content = vec![0; expected.len()];
+            reader.seek(SeekFrom::Start(info.offset_in_image))?;
+            reader.read_exact(&mut content)?;
+            if content != expected.as_bytes() {
+                return fail(format!("{}.VERIFY does not record the sha1sum of \
+                    {}.PARTITION", info.item_sub_type, info.item_sub_type))
             }
-            progress_bar.inc(1);
         }
-        progress_bar.finish_and_clear();
-        let mut items = Vec::new();
-        for (item, stem) in [(ddr_usb, "DDR"), (uboot_usb, "UBOOT")] {
-            match item {
-                Some(item) => items.push(item),
-                None => {
-                    eprintln!("Essential {}.USB file does not exist", stem);
-                    return Err(ImageError::MissingItem { 
-                        stem: stem.into(), extension: "USB".into() }.into());
+        println!("Self checked image '{}': table consistent, CRC32 0x{:08x} \
+            recorded", file.display(), crc32);
+        Ok(())
+    }
+
+    /// Print the layout the image would be written in, with all items hashed:
+    /// where each item lands in the output, the padding before it and its 
+    /// backup status, followed by the result of checking the layout
+    pub fn print_layout_stdout(&self) -> Result<()> {
+        let layout = ImageToWrite::try_from_image(self)?;
+        layout.warn_original_ids_dropped();
+        let mut rows = Vec::new();
+        for (id, ((info, sha1sum), padding)) in layout.infos.iter()
+            .zip(layout.sha1sums.iter()).zip(layout.paddings.iter()).enumerate() 
+        {
+            rows.push([
+                cell_right!(layout.id_text(id)),
+                cell_right!(&info.item_main_type),
+                cell_right!(&info.item_sub_type),
+                cell_right!(info.file_type),
+                cell_right!(format!("0x{:x}", info.offset_in_image)),
+                cell_right!(output::size(*padding)),
+                cell_right!(output::size(info.item_size)),
+                match sha1sum {
+                    Some(sha1sum) if info.item_main_type != "VERIFY" => 
+                        cell_right!(sha1sum),
+                    _ => "".cell(),
                 },
-            }
+                cell_right!(if info.is_backup_item != 0 {
+                    format!("of {}", info.backup_item_id)
+                } else {
+                    "no".into()
+                }),
+            ])
         }
-        for (item, stem, extension) in [
-            (aml_sdc_burn_ini, "aml_sdc_burn", "ini"),
-            (meson1_dtb, "meson1", "dtb"),
-            (platform_conf, "platform", "conf")] 
-        {
-            match item {
-                Some(item) => generic_items.push(item),
-                None => {
-                    eprintln!("Essential {}.{} file does not exist", stem, extension);
-                    return Err(ImageError::MissingItem { 
-                        stem: stem.into(), extension: extension.into()}.into())
-                }
-            }
+        println!("Layout of image {}, align {} bytes:", self.version, self.align);
+        let table = rows.table().title([
+            cell_bold_center!("ID"),
+            cell_bold_center!("main type"),
+            cell_bold_center!("sub type"),
+            cell_bold_center!("file type"),
+            cell_bold_center!("offset"),
+            cell_bold_center!("padding"),
+            cell_bold_center!("size"),
+            cell_bold_center!("sha1sum"),
+            cell_bold_center!("backup"),
+        ]).bold(true);
+        output::print_table(table)?;
+        match layout.check_table() {
+            Ok(()) => println!("Layout checked: items in the body, aligned \
+                and not overlapping, backups matching, VERIFY items right \
+                after their partitions"),
+            Err(reason) => warning!(Layout, "layout check failed: {}", reason),
         }
-        generic_items.sort_by(sort_items_by_name);
-        items.append(&mut generic_items);
-        Ok(Self {
-            version: ImageVersion::V2,
-            align: 4,
-            items,
-        })
+        Ok(())
     }
 
-    pub(crate) fn print_table_stdout(&self) -> Result<()> {
-        println!("Items in image:");
+    /// Print the layout the image would be written in, without hashing any
+    /// payload: items whose sha1sums are not known yet (e.g. from a checksum 
+    /// cache) are assumed not to be backups, those that could be, i.e. of the
+    /// same size as an earlier item, are marked as unknown, as the offsets 
+    /// after them only hold if they're really not
+    pub fn print_plan(&self) -> Result<()> {
+        let plan = ImageToWrite::try_plan_image(self)?;
+        plan.warn_original_ids_dropped();
         let mut rows = Vec::new();
-        for (id, item) in self.items.iter().enumerate() {
+        let mut unknown = 0;
+        for (id, (info, sha1sum)) in 
+            plan.infos.iter().zip(plan.sha1sums.iter()).enumerate() 
+        {
+            let backup = if info.is_backup_item != 0 {
+                format!("of {}", info.backup_item_id)
+            } else if sha1sum.is_none() && info.item_main_type != "VERIFY" &&
+                plan.infos.iter().enumerate().any(|(other_id, other)| 
+                    other_id < id && other.item_main_type != "VERIFY" &&
+                    other.item_size == info.item_size)
+            {
+                unknown += 1;
+                "unknown".into()
+            } else {
+                "no".into()
+            };
             rows.push([
-                cell_right!(id),
-                cell_right!(&item.stem),
-                cell_right!(&item.extension),
-                cell_right!(format!("0x{:x}", item.data.len())),
-                if let Some(sha1sum) = &item.sha1sum {
-                    cell_right!(format!("{}", sha1sum))
-                } else {
-                    cell_right!("None")
-                }
+                cell_right!(plan.id_text(id)),
+                cell_right!(&info.item_main_type),
+                cell_right!(&info.item_sub_type),
+                cell_right!(format!("0x{:x}", info.offset_in_image)),
+                cell_right!(output::size(info.item_size)),
+                cell_right!(backup),
             ])
         }
+        println!("Planned layout of image {}, align {} bytes:", 
+            self.version, self.align);
         let table = rows.table().title([
             cell_bold_center!("ID"),
-            cell_bold_center!("stem"),
-            cell_bold_center!("extension"),
+            cell_bold_center!("main type"),
+            cell_bold_center!("sub type"),
+            cell_bold_center!("offset"),
             cell_bold_center!("size"),
-            cell_bold_center!("sha1sum")
+            cell_bold_center!("backup"),
         ]).bold(true);
-        cli_table::print_stdout(table)?;
+        output::print_table(table)?;
+        let size_table = plan.data_head_infos.len() as u64;
+        let size_image = plan.head.image_size;
+        output::record("layout", &plan.infos);
+        output::record("size", &size_image);
+        output::record("size_pad", &plan.size_pad);
+        println!("Header and item info table: {} ({} infos of 0x{:x} bytes), \
+            items and padding: {}, image size: {}", 
+            output::size_text(size_table), plan.infos.len(), 
+            self.version.size_raw_info(), output::size_text(plan.size_body), 
+            output::size_text_exact(size_image));
+        if plan.size_pad > 0 {
+            println!("Padding after the last item to the image size: {}", 
+                output::size_text_exact(plan.size_pad));
+        }
+        if unknown > 0 {
+            warning!(Layout, "{} items are not hashed yet but could be backups \
+                of items of the same size, they're assumed not to be, the \
+                offsets after them and the image size only hold if so", unknown)
+        }
         Ok(())
     }
 
-    pub(crate) fn try_write_dir<P: AsRef<Path>>(&self, dir: P) -> Result<()> {
-        let parent = dir.as_ref();
-        if parent.exists() {
-            if parent.is_dir() {
-                remove_dir_all(parent)?
-            } else {
-                remove_file(parent)?
+    /// Upper bound of the size of the packed image, larger than the actual
+    /// size by at most the alignment padding before each item, unless padded
+    /// to a larger size
+    pub fn size_upper_bound(&self) -> u64 {
+        let mut count_infos = 0;
+        let mut size_body = 0;
+        let mut sha1sums = Vec::new();
+        for item in self.items.iter() {
+            count_infos += 1;
+            if item.extension == "PARTITION" {
+                count_infos += 1;
+                size_body += 48;
+            }
+            if let Some(sha1sum) = &item.sha1sum {
+                if sha1sums.contains(&sha1sum) {
+                    continue
+                }
+                sha1sums.push(sha1sum)
+            }
+            size_body += item.data.len() + self.align as u64;
+        }
+        (SIZE_RAW_IMAGE_HEAD as u64 + 
+            self.version.size_raw_info() as u64 * count_infos + size_body)
+            .max(self.pad_to)
+    }
+
+    fn guess_align_size(&self) -> u32 {
+        // Only a guess, no super is not an error worth printing
+        if ! self.items.iter().any(|item|
+            item.stem == "super" && item.extension == "PARTITION")
+        {
+            return 4
+        }
+        for item in self.items.iter() {
+            if item.extension != "PARTITION" {
+                continue
+            }
+            if item.stem.ends_with("_a") {
+                println!("Both super partition and _a partition found, this \
+                    image is probably for Android >= 11");
+                return 8
+            }
+        }
+        4   
+    }
+
+    pub fn set_ver_align(&mut self, ver: ImageVersion, align: u8) {
+        self.version = ver;
+        self.align = ((align + 3) >> 2 << 2) as u32;
+        println!("Image version set to {}, alignment set to {}", 
+            self.version, self.align);
+        let guessed_align = self.guess_align_size();
+        if guessed_align != self.align {
+            warning!(Alignment, "alignment size guessed from image items is {}, \
+                but it's set as {}", guessed_align, self.align)
+        }
+    }
+
+    /// Stamp the image with `stamp` in the reserved bytes of its header, 
+    /// replacing what was there
+    pub fn set_stamp(&mut self, stamp: &Stamp) {
+        if ! self.reserve.is_empty() && 
+            Stamp::from_bytes(&self.reserve).is_none() 
+        {
+            println!("Replacing non-zero reserved bytes in image header with \
+                the stamp")
+        }
+        println!("Stamping image: {}", stamp);
+        self.reserve = stamp.to_bytes(SIZE_HEAD_RESERVE)
+    }
+
+    /// Pad the image to `size` with zeros after the last item when writing
+    /// it, 0 for no padding
+    pub fn set_pad_to(&mut self, size: u64) {
+        self.pad_to = size
+    }
+}
+
+/// Builds an image from payloads in memory, with the same checks on it as
+/// when packing a dir of item files
+pub struct ImageBuilder {
+    version: ImageVersion,
+    align: u8,
+    items: Vec<Item>,
+    allow_missing_essentials: bool,
+}
+
+impl Default for ImageBuilder {
+    fn default() -> Self {
+        Self { 
+            version: ImageVersion::default(), 
+            align: 4, 
+            items: Vec::new(), 
+            allow_missing_essentials: false,
+        }
+    }
+}
+
+impl ImageBuilder {
+    /// A builder of a v2 image aligned to 4 bytes, without items
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn version(mut self, version: ImageVersion) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// Alignment of payloads in the image, a non-zero multiple of 4
+    pub fn align(mut self, align: u8) -> Self {
+        self.align = align;
+        self
+    }
+
+    /// Add an item, after those added before it
+    pub fn add_item(mut self, item: Item) -> Self {
+        self.items.push(item);
+        self
+    }
+
+    /// Add `[stem].USB`, e.g. `DDR.USB`
+    pub fn add_usb(self, stem: &str, data: Vec<u8>) -> Self {
+        self.add_item(Item::new(stem, "USB", data))
+    }
+
+    /// Add `[stem].PARTITION`, e.g. `boot.PARTITION`
+    pub fn add_partition(self, stem: &str, data: Vec<u8>) -> Self {
+        self.add_item(Item::new(stem, "PARTITION", data))
+    }
+
+    /// Add `[stem].[extension]`, e.g. `platform.conf`
+    pub fn add_generic(self, stem: &str, extension: &str, data: Vec<u8>) 
+        -> Self 
+    {
+        self.add_item(Item::new(stem, extension, data))
+    }
+
+    /// Build the image even without `aml_sdc_burn.ini`, `meson1.dtb` and 
+    /// `platform.conf`, e.g. for a partial image to be merged later. 
+    /// `DDR.USB` and `UBOOT.USB` are still needed, as an image without them 
+    /// could not be written.
+    pub fn allow_missing_essentials(mut self) -> Self {
+        self.allow_missing_essentials = true;
+        self
+    }
+
+    /// Check the alignment and the items and build the image, ready to be
+    /// written with sha1sums of all items calculated. Items are ordered as 
+    /// they would be written: USB items first, then the others by name.
+    pub fn build(self) -> Result<Image> {
+        if self.align == 0 || ! self.align.is_multiple_of(4) {
+            eprintln!("Alignment {} is not a non-zero multiple of 4", 
+                self.align);
+            return Err(ImageError::InvalidAlign { align: self.align }.into())
+        }
+        let mut image = Image::default();
+        for item in self.items {
+            if ! self.version.is_name_representable(&item.stem, &item.extension)
+            {
+                let name = item.name();
+                eprintln!("Item name '{}' is not representable in {} image: \
+                    each part must be 1 to {} bytes without control characters",
+                    name.escape_debug(), self.version, 
+                    self.version.max_len_name_part());
+                return Err(ImageError::InvalidItemName { name }.into())
+            }
+            if item.extension == "USB" && rank_usb_item(&item.stem).is_none() {
+                eprintln!("Unexpected {}.USB, refuse to build", item.stem);
+                return Err(ImageError::UnexpectedItem { 
+                    stem: item.stem, extension: item.extension }.into())
+            }
+            image.check_not_duplicated(&item.stem, &item.extension)?;
+            image.items.push(item)
+        }
+        for (stem, extension) in ESSENTIALS {
+            if (extension == "USB" || ! self.allow_missing_essentials) && 
+                image.get_item(stem, extension).is_none() 
+            {
+                eprintln!("Essential {}.{} item does not exist", stem, extension);
+                return Err(ImageError::MissingItem { 
+                    stem: stem.into(), extension: extension.into() }.into())
+            }
+        }
+        image.items.sort_by(|some, other| {
+            let rank = |item: &Item| match item.extension.as_str() {
+                "USB" => rank_usb_item(&item.stem),
+                _ => None,
+            };
+            match (rank(some), rank(other)) {
+                (Some(some), Some(other)) => some.cmp(&other),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None) => sort_items_by_name(some, other),
+            }
+        });
+        image.set_ver_align(self.version, self.align);
+        image.fill_verify()?;
+        Ok(image)
+    }
+}
+
+/// Position of the USB item `[stem].USB` when written, None if it's not one 
+/// the burning tool knows
+fn rank_usb_item(stem: &str) -> Option<usize> {
+    ["DDR", "DDR_ENC", "UBOOT", "UBOOT_ENC"].iter().position(|known| 
+        *known == stem)
+}
+
+/// A writer appending to a stream that could not seek, with the CRC32 known
+/// beforehand put into the header as it passes by, so the CRC32 patched at 
+/// the end by seeking back only needs to be found the same
+struct Stream<'a, W: Write> {
+    inner: &'a mut W,
+    crc32: [u8; 4],
+    position: u64,
+    len: u64,
+}
+
+impl<'a, W: Write> Stream<'a, W> {
+    fn new(inner: &'a mut W, crc32: u32) -> Self {
+        Self { inner, crc32: crc32.to_le_bytes(), position: 0, len: 0 }
+    }
+}
+
+impl<W: Write> Write for Stream<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let size_crc32 = self.crc32.len() as u64;
+        if self.position < self.len {
+            let end = self.position + buf.len() as u64;
+            if end <= size_crc32 && 
+                buf == &self.crc32[self.position as usize..end as usize] 
+            {
+                self.position = end;
+                return Ok(buf.len())
             }
+            eprintln!("CRC32 of image streamed differs from the one calculated \
+                before streaming it, payloads were changed in between");
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData,
+                "image changed while streamed"))
         }
-        create_dir_all(parent)?;
-        let progress_bar = progress_bar_with_template(
-            self.items.len() as u64, 
-            "Writing items => [{elapsed_precise}] {bar:40.cyan/blue} \
-                                        {pos:>7}/{len:7} {msg}")?;
-        progress_bar.enable_steady_tick(Duration::from_secs(1));
-        for item in self.items.iter() {
-            let name = format!("{}.{}", item.stem, item.extension);
-            let mut file = File::create(parent.join(&name))?;
-            progress_bar.set_message(name);
-            file.write_all(&item.data)?;
-            progress_bar.inc(1);
-        }
-        Ok(())
+        let size = if self.position < size_crc32 {
+            let mut head = buf.to_vec();
+            for (byte, crc32) in head.iter_mut().zip(
+                &self.crc32[self.position as usize..]) 
+            {
+                *byte = *crc32
+            }
+            self.inner.write(&head)?
+        } else {
+            self.inner.write(buf)?
+        };
+        self.position += size as u64;
+        self.len = self.position;
+        Ok(size)
     }
 
-    pub(crate) fn try_write_file<P: AsRef<Path>>(&self, file: P) -> Result<()> {
-        let image_to_write = ImageToWrite::try_from(self)?;
-        let mut out_file = File::create(file.as_ref())?;
-        let progress_bar = progress_bar_with_template(
-            ((image_to_write.data_head_infos.len() + 
-                    image_to_write.data_body.len()) / 0x100000) as u64,
-            "Writing image => [{elapsed_precise}] {bar:40.cyan/blue} \
-                                        {pos:>5}/{len:5} MiB")?;
-        for chunk in 
-            image_to_write.data_head_infos.chunks(0x100000).chain(
-                image_to_write.data_body.chunks(0x100000)) 
-        {
-            out_file.write_all(chunk)?;
-            progress_bar.inc(1)
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Seek for Stream<'_, W> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => 
+                self.position.checked_add_signed(offset),
+        };
+        match position {
+            Some(position) if position <= self.len => {
+                self.position = position;
+                Ok(position)
+            },
+            _ => Err(std::io::Error::new(std::io::ErrorKind::Unsupported,
+                "seeking past the end of a stream")),
         }
-        progress_bar.finish_and_clear();
+    }
+}
+
+/// A seekable writer throwing away everything written, only keeping track of
+/// the length it would have
+#[derive(Default)]
+struct Discard {
+    position: u64,
+    len: u64,
+}
+
+impl Write for Discard {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.position += buf.len() as u64;
+        self.len = self.len.max(self.position);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
         Ok(())
     }
+}
 
-    fn guess_align_size(&self) -> u32 {
-        if self.find_item("super", "PARTITION").is_err() {
-            return 4
-        }
-        for item in self.items.iter() {
-            if item.extension != "PARTITION" {
-                continue
-            }
-            if item.stem.ends_with("_a") {
-                println!("Both super partition and _a partition found, this \
-                    image is probably for Android >= 11");
-                return 8
-            }
+impl Seek for Discard {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        match position {
+            Some(position) => {
+                self.position = position;
+                Ok(position)
+            },
+            None => Err(std::io::ErrorKind::InvalidInput.into()),
         }
-        4   
     }
+}
 
-    pub(crate) fn set_ver_align(&mut self, ver: ImageVersion, align: u8) {
-        self.version = ver;
-        self.align = ((align + 3) >> 2 << 2) as u32;
-        println!("Image version set to {}, alignment set to {}", 
-            self.version, self.align);
-        let guessed_align = self.guess_align_size();
-        if guessed_align != self.align {
-            println!("Warning: alignment size guessed from image items is {}, \
-                but it's set as {}", guessed_align, self.align)
+/// A piece of the image body after the item info table, in order
+enum BodyPart<'a> {
+    /// Zeros aligning the next payload, or padding the image to its size
+    Padding(u64),
+    /// Payload of a non-backup item
+    Payload {
+        name: String,
+        payload: &'a Payload,
+        /// Recorded sha1sum of a PARTITION item, to verify the payload 
+        /// against as it's written
+        sha1sum: Option<&'a Sha1sum>,
+    },
+    /// Content of a VERIFY item
+    Verify(Vec<u8>),
+}
+
+impl BodyPart<'_> {
+    fn len(&self) -> u64 {
+        match self {
+            BodyPart::Padding(len) => *len,
+            BodyPart::Payload { payload, .. } => payload.len(),
+            BodyPart::Verify(content) => content.len() as u64,
         }
     }
 }
 
-struct ImageToWrite {
+/// The layout of an image to be written: the raw header and item info table,
+/// and the parts of the body to stream after them
+struct ImageToWrite<'a> {
     head: RawImageHead,
     infos: Vec<RawItemInfo>,
-    sha1sums: Vec<Sha1sum>,
+    /// Sha1sums of the items in `infos`, None only when planning for items 
+    /// not hashed yet
+    sha1sums: Vec<Option<Sha1sum>>,
+    /// Zeros inserted before the items in `infos` to align them
+    paddings: Vec<u64>,
+    /// IDs the items in `infos` had in the image they were read from
+    original_ids: Vec<Option<u32>>,
+    /// Why the original IDs could not be kept, if items had any
+    original_ids_dropped: Option<String>,
+    /// Whether the items in `infos` could be backed up by identical items
+    /// found later, i.e. they're not backups and not set to be never deduped
+    dedup: Vec<bool>,
+    /// Names of the items others are set to be backups of by manifest, never
+    /// deduped themselves
+    backup_targets: Vec<String>,
+    /// Items in `infos` set to be backups of the named ones by manifest, 
+    /// resolved when finalizing, as those could come after them
+    forced_backups: Vec<(usize, String)>,
+    /// Only planning the layout, items not hashed yet are assumed not to be
+    /// backups instead of refused
+    plan: bool,
+    /// Zeros after the last item padding the image to the size asked for
+    size_pad: u64,
     data_head_infos: Vec<u8>,
-    data_body: Vec<u8>,
+    body: Vec<BodyPart<'a>>,
+    size_body: u64,
 }
 
-impl ImageToWrite {
+impl<'a> ImageToWrite<'a> {
+    /// Check the finalized layout is consistent: items lie in the body after
+    /// the item info table, which they fill up exactly, non-backup payloads are
+    /// aligned and do not overlap, backup items share the offset, size and
+    /// sha1sum of the items they back up, and each VERIFY item comes right 
+    /// after its partition
+    fn check_table(&self) -> std::result::Result<(), String> {
+        let offset_body = self.data_head_infos.len() as u64;
+        let align = self.head.item_align_size as u64;
+        let image_size = self.head.image_size;
+        let mut end_last = offset_body;
+        for (id, (info, sha1sum)) in 
+            self.infos.iter().zip(self.sha1sums.iter()).enumerate() 
+        {
+            let name = format!("{}.{}", info.item_sub_type, info.item_main_type);
+            let follows_partition = id.checked_sub(1)
+                .and_then(|id|self.infos.get(id))
+                .is_some_and(|last| last.item_main_type == "PARTITION" && 
+                    last.item_sub_type == info.item_sub_type);
+            if info.item_main_type == "VERIFY" && ! follows_partition {
+                return Err(format!("{} is not right after {}.PARTITION", 
+                    name, info.item_sub_type))
+            }
+            let end = info.offset_in_image + info.item_size;
+            if info.offset_in_image < offset_body || end > image_size {
+                return Err(format!("{} (0x{:x}..0x{:x}) is out of image body \
+                    (0x{:x}..0x{:x})", name, info.offset_in_image, end, 
+                    offset_body, image_size))
+            }
+            if info.is_backup_item != 0 {
+                let id = info.backup_item_id as usize;
+                match self.infos.get(id).zip(self.sha1sums.get(id)) {
+                    Some((backup, backup_sha1sum)) if 
+                        backup.is_backup_item == 0 &&
+                        backup.offset_in_image == info.offset_in_image &&
+                        backup.item_size == info.item_size &&
+                        backup_sha1sum == sha1sum => continue,
+                    _ => return Err(format!("{} is not a backup of item {}", 
+                        name, id)),
+                }
+            }
+            if info.item_main_type != "VERIFY" && 
+                ! (info.offset_in_image - offset_body).is_multiple_of(align) 
+            {
+                return Err(format!("{} at 0x{:x} is not aligned to {} bytes",
+                    name, info.offset_in_image, align))
+            }
+            if info.offset_in_image < end_last {
+                return Err(format!("{} at 0x{:x} overlaps the previous item \
+                    ending at 0x{:x}", name, info.offset_in_image, end_last))
+            }
+            end_last = end;
+        }
+        if end_last + self.size_pad != image_size {
+            return Err(format!("items end at 0x{:x} and are padded with 0x{:x} \
+                bytes, but image size is 0x{:x}", 
+                end_last, self.size_pad, image_size))
+        }
+        Ok(())
+    }
+
+    /// The ID of the item info at `id` as shown in layout tables, along with
+    /// the original one if it differs
+    fn id_text(&self, id: usize) -> String {
+        match self.original_ids.get(id).copied().flatten() {
+            Some(original) if original as usize != id => 
+                format!("{} (was {})", id, original),
+            _ => id.to_string(),
+        }
+    }
+
+    fn warn_original_ids_dropped(&self) {
+        if let Some(reason) = &self.original_ids_dropped {
+            warning!(Layout, "original item IDs can not be kept, as \
+                {}, items are numbered sequentially instead", reason)
+        }
+    }
+
     fn find_backup(&self, sha1sum: &Sha1sum) -> (u16, u16, u64) {
-        for (id, (item_sha1sum, item_info)) in 
-            self.sha1sums.iter().zip(self.infos.iter()).enumerate() 
+        for (id, ((item_sha1sum, item_info), dedup)) in self.sha1sums.iter()
+            .zip(self.infos.iter()).zip(self.dedup.iter()).enumerate() 
         {
-            if sha1sum == item_sha1sum && ! (item_info.item_main_type == "USB" && item_info.item_sub_type.ends_with("_ENC")) {
+            if *dedup && Some(sha1sum) == item_sha1sum.as_ref() && ! (item_info.item_main_type == "USB" && item_info.item_sub_type.ends_with("_ENC")) {
                 return (1, id as u16, item_info.offset_in_image)
             }
         }
         (0, 0, 0)
     }
 
-    fn append_item(&mut self, item: &Item) -> Result<()>{
-        let sha1sum = if let Some(sha1sum) = &item.sha1sum {
-            sha1sum
-        } else {
-            eprintln!("Sha1sum for item {}.{} does not exist", 
-                item.stem, item.extension);
-            return Err(ImageError::IllegalVerify.into());
+    fn append_item(&mut self, item: &'a Item) -> Result<()>{
+        let sha1sum = match &item.sha1sum {
+            Some(sha1sum) => Some(sha1sum),
+            None if self.plan => None,
+            None => {
+                eprintln!("Sha1sum for item {}.{} does not exist", 
+                    item.stem, item.extension);
+                return Err(ImageError::IllegalVerify.into());
+            },
+        };
+        let name = format!("{}.{}", item.stem, item.extension);
+        // Backups set by manifest only get their offsets when finalizing
+        let (is_backup_item, backup_item_id, mut offset) = match sha1sum {
+            _ if item.backup_of.is_some() => (1, 0, 0),
+            _ if item.backup == BackupPolicy::Never || 
+                self.backup_targets.contains(&name) => (0, 0, 0),
+            Some(sha1sum) => self.find_backup(sha1sum),
+            None => (0, 0, 0),
         };
-        let (is_backup_item, backup_item_id, offset) 
-            = self.find_backup(sha1sum);
-        let mut offset = offset as usize;
-        let align_size = self.head.item_align_size as usize;
+        let dedup = is_backup_item == 0 && item.backup == BackupPolicy::Auto;
+        if let Some(of) = &item.backup_of {
+            self.forced_backups.push((self.infos.len(), of.clone()))
+        }
+        let align_size = self.head.item_align_size as u64;
+        let mut padding = 0;
         if is_backup_item == 0 { // Not a backup item
-            offset = (self.data_body.len() + align_size - 1) / align_size * align_size;
-            for _ in self.data_body.len() .. offset {
-                self.data_body.push(0)
+            offset = self.size_body.div_ceil(align_size) * align_size;
+            padding = offset - self.size_body;
+            if padding > 0 {
+                self.body.push(BodyPart::Padding(padding))
             }
-            self.data_body.extend_from_slice(&item.data);
+            self.body.push(BodyPart::Payload { 
+                name: name.clone(), payload: &item.data, 
+                sha1sum: sha1sum.filter(|_|item.extension == "PARTITION") });
+            self.size_body = offset + item.data.len();
         }
-        let info = RawItemInfo {
+        let mut info = RawItemInfo {
             item_id: self.infos.len() as u32,
             file_type: 
-                if item.data.starts_with(
-                    &ANDROID_SPARSE_IMAGE_MAGIC_BYTES
-                ) {
+                if item.data.try_head(
+                    &name, ANDROID_SPARSE_IMAGE_MAGIC_BYTES.len()
+                )? == ANDROID_SPARSE_IMAGE_MAGIC_BYTES {
                     FILE_TYPE_SPARSE
                 } else {
                     FILE_TYPE_GENERIC
                 },
             current_offset_in_item: 0,
-            offset_in_image: offset as u64,
-            item_size: item.data.len() as u64,
+            offset_in_image: offset,
+            item_size: item.data.len(),
             item_main_type: item.extension.clone(),
             item_sub_type: item.stem.clone(),
             verify: if item.extension == "PARTITION" {1} else {0},
             is_backup_item,
             backup_item_id,
+            reserve: [0; SIZE_INFO_RESERVE],
         };
+        bytes_fill_from_slice(&mut info.reserve, &item.reserve);
         self.infos.push(info);
-        self.sha1sums.push(sha1sum.clone());
+        self.sha1sums.push(sha1sum.cloned());
+        self.paddings.push(padding);
+        self.original_ids.push(item.original_id);
+        self.dedup.push(dedup);
         self.head.item_count += 1;
         offset += item.data.len();
         if item.extension == "PARTITION" {
-            let content = format!("sha1sum {}", sha1sum);
+            // Only the size of the content matters when planning
+            let content = match sha1sum {
+                Some(sha1sum) => format!("sha1sum {}", sha1sum),
+                None => format!("sha1sum {}", Sha1sum::default()),
+            };
             let bytes = content.as_bytes();
             if bytes.len() != 48 {
                 eprintln!("sha1sum content length != 40");
                 return Err(ImageError::SizeMismatch { 
                     exptected: 48, actual: bytes.len() }.into());
             }
-            self.data_body.extend_from_slice(bytes);
-            self.sha1sums.push(Sha1sum::from_data(bytes));
-            self.infos.push(RawItemInfo { 
+            self.body.push(BodyPart::Verify(bytes.to_vec()));
+            self.size_body += bytes.len() as u64;
+            self.sha1sums.push(sha1sum.map(|_|Sha1sum::from_data(bytes)));
+            let mut info = RawItemInfo { 
                 item_id: self.infos.len() as u32, 
                 file_type: 0, 
                 current_offset_in_item: 0,
-                offset_in_image: offset as u64,
+                offset_in_image: offset,
                 item_size: 48,
                 item_main_type: "VERIFY".into(),
                 item_sub_type: item.stem.clone(),
                 verify: 0,
                 is_backup_item, 
-                backup_item_id: if is_backup_item == 0 { 0 } else { backup_item_id + 1 }
-            });
+                backup_item_id: if is_backup_item == 0 { 0 } else { backup_item_id + 1 },
+                reserve: [0; SIZE_INFO_RESERVE],
+            };
+            bytes_fill_from_slice(&mut info.reserve, &item.verify_reserve);
+            self.infos.push(info);
+            self.paddings.push(0);
+            self.original_ids.push(
+                item.original_id.map(|id|id.saturating_add(1)));
+            self.dedup.push(false);
             self.head.item_count += 1;
         }
         Ok(())
     }
 
-    fn finalize(&mut self, version: &ImageVersion) -> Result<()> {
+    /// Point the backups set by manifest at the items they're set to be 
+    /// backups of, refusing those not identical to them, or not of the same
+    /// size when planning without sha1sums
+    fn resolve_forced_backups(&mut self) -> Result<()> {
+        let name_of = |info: &RawItemInfo| 
+            format!("{}.{}", info.item_sub_type, info.item_main_type);
+        for (id, of) in std::mem::take(&mut self.forced_backups) {
+            let name = name_of(&self.infos[id]);
+            let id_of = match self.infos.iter().position(|info|
+                name_of(info) == of && info.is_backup_item == 0) 
+            {
+                Some(id_of) => id_of,
+                None => {
+                    eprintln!("Item '{}' is set to be a backup of '{}', which \
+                        is not written as an item on its own", name, of);
+                    return Err(ImageError::InvalidBackup { name, 
+                        reason: format!("'{}' is not written", of) }.into())
+                },
+            };
+            match (&self.sha1sums[id], &self.sha1sums[id_of]) {
+                (Some(sha1sum), Some(sha1sum_of)) if sha1sum != sha1sum_of => {
+                    eprintln!("Item '{}' is set to be a backup of '{}', but \
+                        they differ: sha1sum of '{}' is {}, of '{}' is {}", 
+                        name, of, name, sha1sum, of, sha1sum_of);
+                    return Err(ImageError::BackupMismatch { name, 
+                        sha1sum: sha1sum.to_string(), of, 
+                        sha1sum_of: sha1sum_of.to_string() }.into())
+                },
+                _ => (),
+            }
+            let (size, size_of) = 
+                (self.infos[id].item_size, self.infos[id_of].item_size);
+            if size != size_of {
+                eprintln!("Item '{}' is set to be a backup of '{}', but they \
+                    differ: '{}' has 0x{:x} bytes, '{}' has 0x{:x} bytes", 
+                    name, of, name, size, of, size_of);
+                return Err(ImageError::InvalidBackup { name, 
+                    reason: format!("size differs from '{}'", of) }.into())
+            }
+            log::debug!("Item '{}' is a backup of '{}' as set by manifest", 
+                name, of);
+            // A partition and its VERIFY item are backups together
+            let count = if self.infos[id].item_main_type == "PARTITION" {
+                2 
+            } else { 
+                1 
+            };
+            for offset in 0..count {
+                self.infos[id + offset].offset_in_image = 
+                    self.infos[id_of + offset].offset_in_image;
+                self.infos[id + offset].backup_item_id = (id_of + offset) as u16
+            }
+        }
+        Ok(())
+    }
+
+    /// Lay out the header and item info table for `version`, and pad the 
+    /// image with zeros to `pad_to` if that's not 0
+    fn finalize(&mut self, version: &ImageVersion, pad_to: u64) -> Result<()> {
+        self.resolve_forced_backups()?;
         let size_info = version.size_raw_info();
         let offset = (
             SIZE_RAW_IMAGE_HEAD + size_info * self.head.item_count as usize
         ) as u64;
-        self.head.image_size = self.data_body.len() as u64 + offset;
+        let size_content = self.size_body + offset;
+        if pad_to != 0 {
+            if size_content > pad_to {
+                eprintln!("Image content (0x{:x} bytes) is already larger than \
+                    the size to pad to (0x{:x} bytes)", size_content, pad_to);
+                return Err(ImageError::LargerThanPadTo { 
+                    size: size_content, pad_to }.into())
+            }
+            self.size_pad = pad_to - size_content;
+            if self.size_pad > 0 {
+                self.body.push(BodyPart::Padding(self.size_pad));
+                self.size_body += self.size_pad
+            }
+        }
+        self.head.image_size = self.size_body + offset;
         self.head.version = version.into();
         let pointer_head = &self.head as *const RawImageHead as *const u8;
         let len_head = SIZE_RAW_IMAGE_HEAD;
@@ -907,23 +4614,41 @@ impl ImageToWrite {
             eprintln!("Actual head + infos size ({}) != expected ({})",
                 offset_actual, offset);
             return Err(ImageError::SizeMismatch { 
-                exptected: offset as usize, actual: offset_actual as usize 
+                exptected: offset as usize, actual: offset_actual 
             }.into());
         }
         Ok(())
     }
 }
 
-impl TryFrom<&Image> for ImageToWrite {
-    type Error = Error;
+impl<'a> ImageToWrite<'a> {
+    fn try_from_image(image: &'a Image) -> Result<Self> {
+        Self::try_from_image_inner(image, false)
+    }
 
-    fn try_from(image: &Image) -> Result<Self> {
+    /// The layout of the image, with items not hashed yet assumed not to be
+    /// backups, to be shown before really writing it
+    fn try_plan_image(image: &'a Image) -> Result<Self> {
+        Self::try_from_image_inner(image, true)
+    }
+
+    fn try_from_image_inner(image: &'a Image, plan: bool) -> Result<Self> {
         let mut image_to_write = Self {
-            head: RawImageHead::new(&image.version, image.align),
+            head: RawImageHead::new(&image.version, image.align, &image.reserve),
             infos: Vec::new(),
             sha1sums: Vec::new(),
+            paddings: Vec::new(),
+            original_ids: Vec::new(),
+            original_ids_dropped: None,
+            dedup: Vec::new(),
+            backup_targets: image.items.iter()
+                .filter_map(|item|item.backup_of.clone()).collect(),
+            forced_backups: Vec::new(),
+            plan,
+            size_pad: 0,
             data_head_infos: Vec::new(),
-            data_body: Vec::new(),
+            body: Vec::new(),
+            size_body: 0,
         };
         let mut ddr_usb = None;
         let mut uboot_usb = None;
@@ -974,54 +4699,881 @@ impl TryFrom<&Image> for ImageToWrite {
                     stem: "UBOOT".into(), extension: "USB".into() }.into())
             },
         };
+        match order_by_original_ids(&image.items) {
+            Ok(Some(items)) => {
+                for item in items {
+                    image_to_write.append_item(item)?
+                }
+                image_to_write.finalize(&image.version, image.pad_to)?;
+                return Ok(image_to_write)
+            },
+            Ok(None) => (),
+            Err(reason) => image_to_write.original_ids_dropped = Some(reason),
+        }
         generic_items.sort_by(sort_ref_items_by_name);
-        let progress_bar = progress_bar_with_template(
-            image.items.len() as u64,
-            "Combining image => [{elapsed_precise}] {bar:40.cyan/blue} \
-                                            {pos:>3}/{len:3} {msg}")?;
-
-        progress_bar.set_message("DDR.USB");
         image_to_write.append_item(ddr_usb)?;
-        progress_bar.inc(1);
-
         if let Some(ddr_enc_usb) = ddr_enc_usb {
-            progress_bar.set_message("DDR_ENC.USB");
             image_to_write.append_item(ddr_enc_usb)?;
-            progress_bar.inc(1);
         }
-
-        progress_bar.set_message("UBOOT.USB");
         image_to_write.append_item(uboot_usb)?;
-        progress_bar.inc(1);
-
         if let Some(uboot_enc_usb) = uboot_enc_usb {
-            progress_bar.set_message("UBOOT_ENC.USB");
             image_to_write.append_item(uboot_enc_usb)?;
-            progress_bar.inc(1);
         }
-        for item in generic_items.iter_mut() {
-            progress_bar.set_message(format!("{}.{}", item.stem, item.extension));
+        for item in generic_items {
             image_to_write.append_item(item)?;
-            progress_bar.inc(1);
         }
-        progress_bar.set_message("finalizing...");
-        progress_bar.finish_and_clear();
-        image_to_write.finalize(&image.version)?;
-        let progress_bar = progress_bar_with_template(
-            ((image_to_write.data_head_infos.len() + 
-                    image_to_write.data_body.len() - 4) / 0x100000
-                ) as u64,
-            "Calculating CRC32 => [{elapsed_precise}] {bar:40.cyan/blue} \
-                {pos:>5}/{len:5} MiB")?;
-        let mut crc32_hasher = crate::crc32::Crc32Hasher::new();
-        crc32_hasher.udpate_with_bar(&image_to_write.data_head_infos[4..], &progress_bar);
-        crc32_hasher.udpate_with_bar(&image_to_write.data_body, &progress_bar);
-        progress_bar.finish_and_clear();
-        image_to_write.head.crc = crc32_hasher.value;
-        let pointer = 
-            image_to_write.data_head_infos.as_ptr() as *mut u32;
-        unsafe {*pointer = crc32_hasher.value};
-        println!("CRC32 of image is 0x{:08x}", crc32_hasher.value);
+        image_to_write.finalize(&image.version, image.pad_to)?;
         Ok(image_to_write)
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use proptest::prelude::*;
+
+    use super::*;
+
+    /// Payloads of all essential items and two partitions, in the order they
+    /// are added to the builder
+    const NAMES: [(&str, &str); 7] = [
+        ("DDR", "USB"), ("UBOOT", "USB"), ("aml_sdc_burn", "ini"), 
+        ("meson1", "dtb"), ("platform", "conf"), ("boot", "PARTITION"),
+        ("system", "PARTITION"),
+    ];
+
+    fn build(payloads: &[Vec<u8>], version: ImageVersion, align: u8) 
+        -> Image 
+    {
+        let mut builder = ImageBuilder::new().version(version).align(align);
+        for ((stem, extension), data) in NAMES.iter().zip(payloads) {
+            builder = builder.add_generic(stem, extension, data.clone())
+        }
+        builder.build().unwrap()
+    }
+
+    /// A small image: the essential items, and three partitions of which 
+    /// boot and recovery are identical, so sizes tie
+    fn fixture() -> Image {
+        ImageBuilder::new()
+            .add_usb("DDR", vec![0xdd; 0x40])
+            .add_usb("UBOOT", vec![0xbb; 0x100])
+            .add_generic("aml_sdc_burn", "ini", b"[common]\n".to_vec())
+            .add_generic("meson1", "dtb", vec![0xd0; 0x40])
+            .add_generic("platform", "conf", b"Platform:0x0811\n".to_vec())
+            .add_partition("boot", vec![0xb0; 0x200])
+            .add_partition("recovery", vec![0xb0; 0x200])
+            .add_partition("system", vec![0x5e; 0x400])
+            .build().unwrap()
+    }
+
+    #[test]
+    fn display_order_stable_with_ties() {
+        let image = fixture();
+        let fields: Vec<_> = (0..image.items().len())
+            .map(|id|image.sort_fields(id)).collect();
+        let names = |order: Vec<usize>| order.into_iter()
+            .map(|id|image.items()[id].name()).collect::<Vec<_>>();
+        assert_eq!(names(output::sort_order(&fields, output::SortKey::Size, 
+            false)), ["aml_sdc_burn.ini", "platform.conf", "DDR.USB", 
+            "meson1.dtb", "UBOOT.USB", "boot.PARTITION", "recovery.PARTITION",
+            "system.PARTITION"]);
+        assert_eq!(names(output::sort_order(&fields, output::SortKey::Size, 
+            true)), ["system.PARTITION", "boot.PARTITION", "recovery.PARTITION",
+            "UBOOT.USB", "DDR.USB", "meson1.dtb", "platform.conf", 
+            "aml_sdc_burn.ini"]);
+    }
+
+    #[test]
+    fn summary_of_fixture() {
+        // Tests that format sizes all ask for binary units, as only the first
+        // call takes effect in the test process
+        output::set_human(true);
+        assert_eq!(fixture().to_string(), "Amlogic image v2, align 4 bytes, \
+            8 items of 2.40 KiB in total, 8 with sha1sums: DDR.USB (64 B), \
+            UBOOT.USB (256 B), aml_sdc_burn.ini (9 B), boot.PARTITION (512 B), \
+            meson1.dtb (64 B), platform.conf (16 B), recovery.PARTITION \
+            (512 B), system.PARTITION (1.00 KiB)");
+    }
+
+    #[test]
+    fn write_vec_reparse() {
+        let image = fixture();
+        let bytes = image.try_write_vec().unwrap();
+        let mut read = Image::try_read_bytes(bytes.clone()).unwrap();
+        assert_eq!(read.items().len(), image.items().len());
+        for (read, item) in read.items().iter().zip(image.items()) {
+            assert_eq!(read.name(), item.name());
+            assert_eq!(read.try_to_vec().unwrap(), item.try_to_vec().unwrap());
+        }
+        read.fill_verify().unwrap();
+        assert_eq!(read.try_write_vec().unwrap(), bytes);
+    }
+
+    fn windows(names: &[&str]) -> Vec<String> {
+        windows_file_names(&names.iter().map(|name|name.to_string())
+            .collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn windows_names_kept() {
+        let names = ["DDR.USB", "aml_sdc_burn.ini", "meson1.dtb", 
+            "super.PARTITION", "console.PARTITION", "com10.PARTITION", 
+            "LPT.conf", "no_extension"];
+        assert_eq!(windows(&names), names);
+    }
+
+    #[test]
+    fn windows_names_reserved() {
+        assert_eq!(windows(&["CON", "nul.txt", "COM1", "com1.PARTITION", 
+            "Lpt9.img", "aux.tar.gz", "PRN.", "CONX.bin"]), 
+            ["_CON", "_nul.txt", "_COM1", "_com1.PARTITION", "_Lpt9.img", 
+            "_aux.tar.gz", "PRN_", "CONX.bin"]);
+    }
+
+    #[test]
+    fn windows_names_trailing() {
+        assert_eq!(windows(&["boot.", "recovery ", "logo. .", 
+            "logo.PARTITION.", "a.b c"]), 
+            ["boot_", "recovery_", "logo___", "logo.PARTITION_", "a.b c"]);
+    }
+
+    #[test]
+    fn windows_names_forbidden() {
+        assert_eq!(windows(&["a<b.x", "c>d.x", "e:f.x", "g\"h.x", 
+            "i|j.x", "k?l.x", "m*n.x", "o/p\\q.x", "r\ts.x"]),
+            ["a_b.x", "c_d.x", "e_f.x", "g_h.x", "i_j.x", "k_l.x", "m_n.x", 
+            "o_p_q.x", "r_s.x"]);
+    }
+
+    #[test]
+    fn windows_names_collisions() {
+        assert_eq!(windows(&["boot.PARTITION", "BOOT.partition", 
+            "Boot.Partition", "boot~1.PARTITION"]),
+            ["boot.PARTITION", "BOOT~1.partition", "Boot~2.Partition", 
+            "boot~1~1.PARTITION"]);
+        // Names only colliding after escaping, case-insensitively
+        assert_eq!(windows(&["a_b.x", "a?b.x", "A*B.X", "con.x", "_CON.X"]),
+            ["a_b.x", "a_b~1.x", "A_B~2.X", "_con.x", "_CON~1.X"]);
+    }
+
+    /// A path under the temporary dir unique to this test process
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("ampack-test.{}.{}", 
+            std::process::id(), name))
+    }
+
+    #[test]
+    fn context_of_truncated_image() {
+        output::set_human(true);
+        let bytes = fixture().try_write_vec().unwrap();
+        let path = temp_path("truncated.img");
+        // Cut into the last partition, system.PARTITION
+        std::fs::write(&path, &bytes[..bytes.len() - 0x100]).unwrap();
+        let result = Image::try_read_file(&path, &ReadFileOptions::default());
+        std::fs::remove_file(&path).unwrap();
+        let message = match result {
+            Ok(_) => panic!("truncated image read"),
+            Err(e) => e.to_string(),
+        };
+        assert!(message.starts_with("while reading item 'system.PARTITION' \
+            (offset 0x"), "{}", message);
+        assert!(message.contains(&format!("1.00 KiB) from '{}'", 
+            path.display())), "{}", message);
+    }
+
+    #[test]
+    fn context_of_source_read_while_unpacking() {
+        output::set_human(true);
+        let bytes = fixture().try_write_vec().unwrap();
+        let path = temp_path("shrunk.img");
+        let dir = temp_path("shrunk");
+        std::fs::write(&path, &bytes).unwrap();
+        let image = Image::try_read_file(&path, &ReadFileOptions::default())
+            .unwrap();
+        // Cut into the last partition, system.PARTITION, after reading
+        File::options().write(true).open(&path).unwrap()
+            .set_len(bytes.len() as u64 - 0x100).unwrap();
+        let result = image.try_write_dir(&dir, &WriteDirOptions::default());
+        std::fs::remove_file(&path).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        let message = match result {
+            Ok(_) => panic!("item read past the end of image"),
+            Err(e) => e.to_string(),
+        };
+        // Not taken for an error writing into the dir
+        assert!(message.starts_with("while reading item 'system.PARTITION' \
+            (offset 0x"), "{}", message);
+        assert!(message.contains(&format!("1.00 KiB) from '{}'", 
+            path.display())), "{}", message);
+    }
+
+    #[test]
+    fn unpack_into_dir_of_image() {
+        let image = fixture();
+        let dir = temp_path("unpack-into");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("img.img");
+        std::fs::write(&path, image.try_write_vec().unwrap()).unwrap();
+        let read = Image::try_read_file(&path, &ReadFileOptions::default())
+            .unwrap();
+        // The dir is wiped, the image along with it, before any item is read
+        let result = read.try_write_dir(&dir, &WriteDirOptions::default());
+        let files: Vec<_> = image.items().iter().map(|item|
+            std::fs::read(dir.join(item.name()))).collect();
+        let image_left = path.exists();
+        std::fs::remove_dir_all(&dir).unwrap();
+        result.unwrap();
+        assert!(! image_left);
+        for (file, item) in files.into_iter().zip(image.items()) {
+            assert_eq!(file.unwrap(), item.try_to_vec().unwrap(), "{}", 
+                item.name());
+        }
+    }
+
+    #[test]
+    fn context_of_missing_paths() {
+        let path = temp_path("missing");
+        let message = match Image::try_read_dir(&path, 
+            &ReadDirOptions::default()) 
+        {
+            Ok(_) => panic!("missing dir read"),
+            Err(e) => e.to_string(),
+        };
+        assert!(message.starts_with(&format!("while reading '{}': ", 
+            path.display())), "{}", message);
+        // A dir could not be created under a regular file
+        let file = temp_path("file");
+        std::fs::write(&file, b"").unwrap();
+        let result = fixture().try_write_dir(file.join("out"), 
+            &WriteDirOptions::default());
+        std::fs::remove_file(&file).unwrap();
+        let message = match result {
+            Ok(_) => panic!("dir written under a file"),
+            Err(e) => e.to_string(),
+        };
+        assert!(message.starts_with(&format!("while writing '{}': ", 
+            file.join("out").display())), "{}", message);
+    }
+
+    /// The fixture written with the field at `at` of the info of item `id`
+    /// replaced by `value`
+    fn fixture_patched(id: usize, at: usize, value: u64) -> Vec<u8> {
+        let mut bytes = fixture().try_write_vec().unwrap();
+        let start = SIZE_RAW_IMAGE_HEAD + id * SIZE_RAW_ITEM_INFO_V2 + at;
+        bytes[start..start + 8].copy_from_slice(&value.to_le_bytes());
+        bytes
+    }
+
+    fn read_patched(bytes: Vec<u8>, options: &ReadFileOptions) -> Result<Image> {
+        Image::try_read_from(Cursor::new(bytes), options)
+    }
+
+    #[test]
+    fn item_end_overflowing() {
+        use std::mem::offset_of;
+        let offset_in_image = offset_of!(RawItemInfoV2, offset_in_image);
+        let item_size = offset_of!(RawItemInfoV2, item_size);
+        for (at, value) in [(offset_in_image, u64::MAX), (item_size, u64::MAX), 
+            (item_size, u64::MAX - 8)] 
+        {
+            let bytes = fixture_patched(0, at, value);
+            match read_patched(bytes.clone(), &ReadFileOptions::default()) {
+                Ok(_) => panic!("item ending past u64::MAX read"),
+                Err(e) => assert!(matches!(e.root(), 
+                    Error::ImageError(ImageError::ReadFailed { .. })), 
+                    "{}", e),
+            }
+            let (_, failures) = Image::try_read_from_ignore_errors(
+                Cursor::new(bytes), &ReadFileOptions::default()).unwrap();
+            assert_eq!(failures.len(), 1);
+            assert_eq!(failures[0].0, "DDR.USB");
+        }
+    }
+
+    #[test]
+    fn item_above_max_size() {
+        let options = ReadFileOptions { max_item_size: 0x400, 
+            ..Default::default() };
+        // system.PARTITION is exactly at the limit
+        read_patched(fixture().try_write_vec().unwrap(), &options).unwrap();
+        let bytes = fixture_patched(1, 
+            std::mem::offset_of!(RawItemInfoV2, item_size), 0x401);
+        match read_patched(bytes, &options) {
+            Ok(_) => panic!("item above --max-item-size read"),
+            Err(e) => assert!(matches!(e.root(), 
+                Error::ImageError(ImageError::ItemTooLarge { 
+                    size: 0x401, limit: 0x400, .. })), "{}", e),
+        }
+        check_item_size("huge", u64::MAX, 0x400).unwrap_err();
+    }
+
+    #[test]
+    #[cfg(not(target_pointer_width = "64"))]
+    fn item_above_platform_size() {
+        let size = usize::MAX as u64 + 1;
+        assert!(matches!(usize_from_u64("huge", size), 
+            Err(Error::ImageError(ImageError::TooLargeForPlatform { .. }))));
+        let bytes = fixture_patched(0, 
+            std::mem::offset_of!(RawItemInfoV2, item_size), size);
+        match read_patched(bytes, &ReadFileOptions::default()) {
+            Ok(_) => panic!("item above usize::MAX read"),
+            Err(e) => assert!(matches!(e.root(), 
+                Error::ImageError(ImageError::TooLargeForPlatform { .. })), 
+                "{}", e),
+        }
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(32))]
+
+        #[test]
+        fn write_vec_round_trip(
+            payloads in proptest::collection::vec(
+                proptest::collection::vec(any::<u8>(), 0..300), NAMES.len()),
+            align in (1_u8..=16).prop_map(|align|align * 4),
+            v1 in any::<bool>(),
+        ) {
+            let version = if v1 { ImageVersion::V1 } else { ImageVersion::V2 };
+            let image = build(&payloads, version.clone(), align);
+            let bytes = image.try_write_vec().unwrap();
+
+            let (header, infos) = 
+                Image::try_read_table_from(&mut Cursor::new(&bytes)).unwrap();
+            prop_assert_eq!(&header.version, &version);
+            prop_assert_eq!(header.align, align as u32);
+            prop_assert_eq!(header.image_size, bytes.len() as u64);
+            let mut hasher = Crc32Hasher::new();
+            hasher.update(&bytes[4..]);
+            prop_assert_eq!(header.crc, hasher.value);
+            let infos: Vec<_> = infos.iter()
+                .filter(|info|info.item_main_type != "VERIFY").collect();
+            prop_assert_eq!(infos.len(), image.items().len());
+            for (info, item) in infos.iter().zip(image.items()) {
+                prop_assert_eq!(&info.item_sub_type, item.stem());
+                prop_assert_eq!(&info.item_main_type, item.extension());
+                prop_assert_eq!(info.item_size, item.size());
+            }
+
+            let read = Image::try_read_from(
+                Cursor::new(bytes.clone()), &ReadFileOptions::default())
+                .unwrap();
+            prop_assert_eq!(read.items().len(), image.items().len());
+            for (read, item) in read.items().iter().zip(image.items()) {
+                prop_assert_eq!(read.name(), item.name());
+                prop_assert_eq!(read.size(), item.size());
+                prop_assert_eq!(read.try_to_vec().unwrap(), 
+                    item.try_to_vec().unwrap());
+                if item.extension() == "PARTITION" {
+                    prop_assert_eq!(
+                        read.sha1sum().map(ToString::to_string),
+                        item.sha1sum().map(ToString::to_string));
+                }
+            }
+            let mut read = read;
+            read.fill_verify().unwrap();
+            prop_assert_eq!(read.try_write_vec().unwrap(), bytes);
+        }
+    }
+
+    fn duplicated(result: Result<()>) -> bool {
+        matches!(result, Err(Error::ImageError(ImageError::DuplicatedItem { .. })))
+    }
+
+    #[test]
+    fn mutators_refuse_duplicates() {
+        let mut image = fixture();
+        assert!(duplicated(image.add_item(Item::new("boot", "PARTITION", 
+            vec![0; 0x10]))));
+        assert!(duplicated(image.rename_item("boot", "PARTITION", 
+            "system", "PARTITION")));
+        assert_eq!(image.items().len(), 8);
+        // Renaming to the same name is a no-op rather than a duplicate
+        image.rename_item("boot", "PARTITION", "boot", "PARTITION").unwrap();
+        image.add_item(Item::new("logo", "PARTITION", vec![0; 0x10])).unwrap();
+        assert_eq!(image.items().last().unwrap().name(), "logo.PARTITION");
+        assert!(image.rename_item("missing", "PARTITION", "other", "PARTITION")
+            .is_err());
+    }
+
+    #[test]
+    fn replace_drops_sha1sum() {
+        let mut image = fixture();
+        assert!(image.get_item("boot", "PARTITION").unwrap().sha1sum()
+            .is_some());
+        image.replace_item_data("boot", "PARTITION", vec![0x42; 0x30]).unwrap();
+        let item = image.get_item("boot", "PARTITION").unwrap();
+        assert!(item.sha1sum().is_none());
+        assert_eq!(item.size(), 0x30);
+        image.fill_verify().unwrap();
+        assert!(image.get_item("boot", "PARTITION").unwrap().sha1sum() ==
+            Some(&Sha1sum::from_data(&[0x42; 0x30])));
+        // Renaming keeps the sha1sum, as the payload is untouched
+        let sha1sum = image.get_item("system", "PARTITION").unwrap().sha1sum()
+            .cloned();
+        image.rename_item("system", "PARTITION", "super", "PARTITION").unwrap();
+        assert!(image.get_item("super", "PARTITION").unwrap().sha1sum()
+            .cloned() == sha1sum);
+    }
+
+    #[test]
+    fn mutators_follow_backups() {
+        let mut image = fixture();
+        let backup_of = |image: &Image| image.get_item("recovery", "PARTITION")
+            .unwrap().backup_of.clone();
+        image.items.iter_mut().find(|item|item.stem == "recovery").unwrap()
+            .backup_of = Some("boot.PARTITION".into());
+        image.rename_item("boot", "PARTITION", "boot_a", "PARTITION").unwrap();
+        assert_eq!(backup_of(&image).as_deref(), Some("boot_a.PARTITION"));
+        image.check_backup_directives().unwrap();
+        image.remove_item("boot_a", "PARTITION").unwrap();
+        assert_eq!(backup_of(&image), None);
+        image.check_backup_directives().unwrap();
+    }
+
+    #[test]
+    fn sanitize_name_parts() {
+        assert_eq!(sanitize_name_part("boot", false), "boot");
+        assert_eq!(sanitize_name_part(" my boot\t", false), "my_boot");
+        assert_eq!(sanitize_name_part("a \u{3000}b", false), "a__b");
+        assert_eq!(sanitize_name_part(" Logo A ", true), "logo_a");
+        assert_eq!(sanitize_name_part("", true), "");
+    }
+
+    #[test]
+    fn sanitize_names_recorded() {
+        let mut image = ImageBuilder::new()
+            .add_usb("DDR", vec![0xdd; 0x40])
+            .add_usb("UBOOT", vec![0xbb; 0x100])
+            .add_generic("aml_sdc_burn", "ini", b"[common]\n".to_vec())
+            .add_generic("meson1", "dtb", vec![0xd0; 0x40])
+            .add_generic("platform", "conf", b"Platform:0x0811\n".to_vec())
+            .add_partition("Boot A ", vec![0xb0; 0x200])
+            .build().unwrap();
+        let renames = image.sanitize_names(true, &ImageVersion::V2).unwrap();
+        // USB stems are types the burning tools look for, never lowercased
+        assert_eq!(renames, [
+            ("Boot A .PARTITION".to_owned(), "boot_a.PARTITION".to_owned())]);
+        assert!(image.get_item("DDR", "USB").is_some());
+        assert!(image.get_item("UBOOT", "USB").is_some());
+        let item = image.get_item("boot_a", "PARTITION").unwrap();
+        assert_eq!(item.original_name(), Some("Boot A .PARTITION"));
+        let manifest = image.to_manifest().unwrap();
+        let recorded: Vec<_> = manifest.items.iter()
+            .filter_map(|item|item.original_name.as_deref()
+                .map(|original|(item.name.as_str(), original)))
+            .collect();
+        assert_eq!(recorded, [("boot_a.PARTITION", "Boot A .PARTITION")]);
+        // Sanitizing again keeps the name from the item file
+        assert!(image.sanitize_names(true, &ImageVersion::V2).unwrap()
+            .is_empty());
+        assert_eq!(image.get_item("boot_a", "PARTITION").unwrap()
+            .original_name(), Some("Boot A .PARTITION"));
+    }
+
+    /// The table of the fixture, and the ID of the item named `name` in it
+    fn fixture_table(bytes: &[u8], name: &str) 
+        -> (ImageHeader, Vec<RawItemInfo>, usize) 
+    {
+        let (header, infos) = Image::try_read_table_from(
+            &mut Cursor::new(bytes)).unwrap();
+        let id = infos.iter().position(|info|format!("{}.{}", 
+            info.item_sub_type, info.item_main_type) == name).unwrap();
+        (header, infos, id)
+    }
+
+    #[test]
+    fn structural_problems_refused() {
+        let bytes = fixture().try_write_vec().unwrap();
+        let size = bytes.len() as u64;
+        let (header, infos, id) = fixture_table(&bytes, "system.PARTITION");
+        // recovery.PARTITION is a backup of boot.PARTITION at the same region
+        assert!(structural_problems(&header, &infos, size).is_empty());
+        let problems = structural_problems(&header, &infos, size + 1);
+        assert!(problems[0].starts_with("image size"), "{:?}", problems);
+        let mut out = infos.clone();
+        out[id].offset_in_image = size - 0x10;
+        let problems = structural_problems(&header, &out, size);
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("is out of image body"), "{:?}", problems);
+        let mut overlapping = infos.clone();
+        let (_, _, boot) = fixture_table(&bytes, "boot.PARTITION");
+        overlapping[id].offset_in_image = infos[boot].offset_in_image + 0x10;
+        let problems = structural_problems(&header, &overlapping, size);
+        assert!(! problems.is_empty());
+        assert!(problems.iter().all(|problem|problem.contains("overlaps")),
+            "{:?}", problems);
+    }
+
+    #[test]
+    fn repair_stale_verify_and_crc() {
+        let mut bytes = fixture().try_write_vec().unwrap();
+        let (header, infos, id) = fixture_table(&bytes, "boot.PARTITION");
+        // Stale the VERIFY of boot.PARTITION, shared by its backup recovery,
+        // and the CRC32
+        let offset = infos[id + 1].offset_in_image as usize;
+        bytes[offset..offset + 48].copy_from_slice(
+            format!("sha1sum {}", "0".repeat(40)).as_bytes());
+        bytes[0] ^= 0xff;
+        let path = temp_path("stale.img");
+        std::fs::write(&path, &bytes).unwrap();
+        assert!(structural_problems(&header, &infos, bytes.len() as u64)
+            .is_empty());
+        let verify = || Image::try_read_file(&path, 
+            &ReadFileOptions::default()).unwrap()
+                .verify(&path, false, None, false).unwrap();
+        let report = verify();
+        assert!(report.error().is_some());
+        let plan = repair_plan(&infos, &report).unwrap();
+        assert_eq!(plan.iter().map(|(check, offset, _)|
+            (check.name.as_str(), *offset as usize)).collect::<Vec<_>>(),
+            [("boot.PARTITION", offset), ("recovery.PARTITION", offset)]);
+        let patches = repair_patches(&plan);
+        assert_eq!(patches.len(), 1);
+        let (crc32_recorded, crc32) = try_repair_file(&path, &patches)
+            .unwrap();
+        assert_eq!(crc32_recorded, report.crc32_recorded);
+        assert_eq!(crc32, header.crc);
+        let report = verify();
+        let repaired = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(report.error().is_none());
+        assert_eq!(repaired, fixture().try_write_vec().unwrap());
+    }
+
+    #[test]
+    fn unpack_merging_into_dir() {
+        let image = fixture();
+        let dir = temp_path("merge");
+        image.try_write_dir(&dir, &WriteDirOptions::default()).unwrap();
+        let old = std::time::SystemTime::UNIX_EPOCH + Duration::from_secs(1);
+        for item in image.items() {
+            File::options().write(true).open(dir.join(item.name())).unwrap()
+                .set_modified(old).unwrap()
+        }
+        std::fs::write(dir.join("boot.PARTITION"), [0x42; 0x10]).unwrap();
+        std::fs::write(dir.join("extra.bin"), b"extra").unwrap();
+        let options = WriteDirOptions { keep: true, ..Default::default() };
+        let result = image.try_write_dir(&dir, &options);
+        let modified: Vec<_> = image.items().iter().map(|item|
+            std::fs::metadata(dir.join(item.name())).unwrap().modified()
+                .unwrap()).collect();
+        let boot = std::fs::read(dir.join("boot.PARTITION")).unwrap();
+        let extra_left = dir.join("extra.bin").exists();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let report = result.unwrap();
+        assert_eq!(report.unchanged, image.items().iter()
+            .map(Item::name).filter(|name|name != "boot.PARTITION")
+            .collect::<Vec<_>>());
+        assert_eq!(report.extra, ["extra.bin"]);
+        assert!(extra_left);
+        assert_eq!(boot, vec![0xb0; 0x200]);
+        for (item, modified) in image.items().iter().zip(modified) {
+            assert_eq!(modified == old, item.name() != "boot.PARTITION", 
+                "{}", item.name())
+        }
+    }
+
+    #[test]
+    fn write_sparse_item_file() {
+        let block = SIZE_SPARSE_BLOCK;
+        // Leading, middle and trailing holes, the middle one not a whole block
+        let mut data = vec![0; 2 * block];
+        data.extend(vec![0x5a; block]);
+        data.extend(vec![0; block + 0x10]);
+        data.extend(vec![0xa5; 0x20]);
+        data.extend(vec![0; 3 * block]);
+        let path = temp_path("sparse.bin");
+        let payload = Payload::Owned(data.clone());
+        let bar = progress_bar_with_template_optional(false, payload.len(), 
+            "Writing").unwrap();
+        let mut hasher = Sha1sumHasher::new();
+        write_item_file(&path, &payload, "sparse", Some(&mut hasher), true, 
+            &bar, &AtomicBool::new(false)).unwrap();
+        let written = std::fs::read(&path).unwrap();
+        let len = std::fs::metadata(&path).unwrap().len();
+        // Without set_len the trailing hole would be missing
+        let mut file = File::create(&path).unwrap();
+        assert!(write_all_sparse(&mut file, &data).is_ok());
+        let len_unset = file.metadata().unwrap().len();
+        drop(file);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(len, data.len() as u64);
+        assert!(written == data);
+        assert!(hasher.finalize() == Sha1sum::from_data(&data));
+        // Holes are skipped by whole blocks, the block with 0xa5 is written
+        assert_eq!(len_unset, 5 * block as u64);
+    }
+
+    #[test]
+    fn verify_items_across_chunks() {
+        // Partitions spanning chunks, and a backup overlapping its original
+        let pattern = |size: usize, seed: u8| (0..size)
+            .map(|i|(i % 251) as u8 ^ seed).collect::<Vec<_>>();
+        let size = chunk_size() * 3 / 2;
+        let image = ImageBuilder::new()
+            .add_usb("DDR", vec![0xdd; 0x40])
+            .add_usb("UBOOT", vec![0xbb; 0x100])
+            .add_generic("aml_sdc_burn", "ini", b"[common]\n".to_vec())
+            .add_generic("meson1", "dtb", vec![0xd0; 0x40])
+            .add_generic("platform", "conf", b"Platform:0x0811\n".to_vec())
+            .add_partition("boot", pattern(size, 0xb0))
+            .add_partition("recovery", pattern(size, 0xb0))
+            .add_partition("system", pattern(size * 2, 0x5e))
+            .build().unwrap();
+        let mut bytes = image.try_write_vec().unwrap();
+        let path = temp_path("chunks.img");
+        let verify = |bytes: &[u8], fail_fast: bool| {
+            std::fs::write(&path, bytes).unwrap();
+            Image::try_read_file(&path, &ReadFileOptions::default()).unwrap()
+                .verify(&path, false, None, fail_fast)
+        };
+        let statuses = |report: VerifyReport| report.items.into_iter()
+            .map(|check|(check.name, check.status)).collect::<Vec<_>>();
+        let names: Vec<_> = image.items().iter().map(Item::name).collect();
+        // Only partitions have VERIFY items in the file
+        let expected = |mismatched: &str| names.iter().map(|name|
+            (name.clone(), if name == mismatched {
+                CheckStatus::Mismatch
+            } else if name.ends_with(".PARTITION") {
+                CheckStatus::Verified
+            } else {
+                CheckStatus::NoChecksum
+            })).collect::<Vec<_>>();
+        let report = verify(&bytes, false).unwrap();
+        assert!(report.error().is_none());
+        assert!(statuses(report) == expected(""));
+        // Corrupt system.PARTITION in its second chunk
+        let (_, infos, id) = fixture_table(&bytes, "system.PARTITION");
+        bytes[infos[id].offset_in_image as usize + size] ^= 0xff;
+        let report = verify(&bytes, false).unwrap();
+        assert!(report.error().is_some());
+        assert!(statuses(report) == expected("system.PARTITION"));
+        assert!(verify(&bytes, true).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn diff_against_manifest() {
+        let path = temp_path("expected.yaml");
+        fixture().to_manifest().unwrap().try_write_file(&path).unwrap();
+        let expected = Manifest::try_read_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(fixture().diff_manifest(&expected).unwrap().is_empty());
+        // boot.PARTITION changed, system.PARTITION replaced by logo.PARTITION
+        let image = ImageBuilder::new()
+            .align(8)
+            .add_usb("DDR", vec![0xdd; 0x40])
+            .add_usb("UBOOT", vec![0xbb; 0x100])
+            .add_generic("aml_sdc_burn", "ini", b"[common]\n".to_vec())
+            .add_generic("meson1", "dtb", vec![0xd0; 0x40])
+            .add_generic("platform", "conf", b"Platform:0x0811\n".to_vec())
+            .add_partition("boot", vec![0xb1; 0x210])
+            .add_partition("recovery", vec![0xb0; 0x200])
+            .add_partition("logo", vec![0x10; 0x100])
+            .build().unwrap();
+        let diff = image.diff_manifest(&expected).unwrap();
+        assert_eq!(diff.added, ["logo.PARTITION"]);
+        assert_eq!(diff.missing, ["system.PARTITION"]);
+        let found = image.to_manifest().unwrap();
+        let sha1sum = found.find_item("boot.PARTITION").unwrap()
+            .sha1sum.clone().unwrap();
+        let mismatched: Vec<_> = diff.mismatched.iter().map(|mismatch|
+            (mismatch.item.as_deref(), mismatch.field, 
+                mismatch.expected.as_str(), mismatch.found.as_str()))
+            .collect();
+        assert_eq!(mismatched, [
+            (None, "align", "4", "8"),
+            (Some("boot.PARTITION"), "size", "512", "528"),
+            (Some("boot.PARTITION"), "sha1sum", 
+                Sha1sum::from_data(&[0xb0; 0x200]).to_string().as_str(), 
+                sha1sum.as_str()),
+        ]);
+        // Fields not in the expected manifest are not checked
+        let mut partial = expected;
+        for item in partial.items.iter_mut() {
+            item.size = None;
+            item.sha1sum = None;
+        }
+        partial.align = 8;
+        let diff = image.diff_manifest(&partial).unwrap();
+        assert!(diff.mismatched.is_empty());
+        assert!(! diff.is_empty());
+    }
+
+    #[test]
+    fn original_ids_kept() {
+        let bytes = fixture().try_write_vec().unwrap();
+        let (_, infos, dtb) = fixture_table(&bytes, "meson1.dtb");
+        let (_, _, conf) = fixture_table(&bytes, "platform.conf");
+        let ids = |bytes: &[u8]| Image::try_read_table_from(
+            &mut Cursor::new(bytes)).unwrap().1.iter().map(|info|
+                (format!("{}.{}", info.item_sub_type, info.item_main_type), 
+                    info.item_id)).collect::<Vec<_>>();
+        let read = || {
+            let mut image = read_patched(bytes.clone(), 
+                &ReadFileOptions::default()).unwrap();
+            image.fill_verify().unwrap();
+            image
+        };
+        let image = read();
+        assert!(image.items().iter().all(|item|
+            infos[item.original_id().unwrap() as usize].item_sub_type == 
+                item.stem));
+        assert_eq!(ids(&image.try_write_vec().unwrap()), ids(&bytes));
+        // IDs from the manifest, swapped against the alphabetical order
+        let mut manifest = image.to_manifest().unwrap();
+        let swap = |manifest: &mut Manifest, name: &str, id: usize|
+            manifest.items.iter_mut().find(|item|item.name == name).unwrap()
+                .item_id = Some(id as u32);
+        swap(&mut manifest, "meson1.dtb", conf);
+        swap(&mut manifest, "platform.conf", dtb);
+        let mut image = read();
+        image.apply_manifest(&manifest).unwrap();
+        let mut expected = ids(&bytes);
+        expected.swap(dtb, conf);
+        expected[dtb].1 = dtb as u32;
+        expected[conf].1 = conf as u32;
+        assert_eq!(ids(&image.try_write_vec().unwrap()), expected);
+        // Duplicated IDs can't be kept, so they're numbered sequentially
+        swap(&mut manifest, "meson1.dtb", dtb);
+        let mut image = read();
+        image.apply_manifest(&manifest).unwrap();
+        assert_eq!(ids(&image.try_write_vec().unwrap()), ids(&bytes));
+    }
+
+    /// The fixture with backup directives of items set by its manifest as 
+    /// `(name, backup_of, backup)`
+    fn with_backups(directives: &[(&str, Option<&str>, BackupPolicy)]) 
+        -> Result<Image> 
+    {
+        let mut image = fixture();
+        let mut manifest = image.to_manifest()?;
+        for (name, of, backup) in directives {
+            let item = manifest.items.iter_mut()
+                .find(|item|item.name == *name).unwrap();
+            item.backup_of = of.map(Into::into);
+            item.backup = Some(*backup);
+        }
+        image.apply_manifest(&manifest)?;
+        Ok(image)
+    }
+
+    fn invalid_backup(result: Result<Image>) -> bool {
+        matches!(result, Err(Error::ImageError(ImageError::InvalidBackup { .. })))
+    }
+
+    #[test]
+    fn backup_directives_followed() {
+        // Whether boot and recovery are backups, and if they share a region
+        let backups = |image: Image| {
+            let bytes = image.try_write_vec().unwrap();
+            let (_, infos, boot) = fixture_table(&bytes, "boot.PARTITION");
+            let (_, _, recovery) = fixture_table(&bytes, "recovery.PARTITION");
+            (infos[boot].is_backup_item, infos[recovery].is_backup_item,
+                infos[boot].offset_in_image == infos[recovery].offset_in_image)
+        };
+        assert_eq!(backups(fixture()), (0, 1, true));
+        assert_eq!(backups(with_backups(&[("boot.PARTITION", 
+            Some("recovery.PARTITION"), BackupPolicy::Auto)]).unwrap()), 
+            (1, 0, true));
+        assert_eq!(backups(with_backups(&[("recovery.PARTITION", None, 
+            BackupPolicy::Never)]).unwrap()), (0, 0, false));
+        // Content is only compared when writing
+        let image = with_backups(&[("system.PARTITION", 
+            Some("boot.PARTITION"), BackupPolicy::Auto)]).unwrap();
+        assert!(matches!(image.try_write_vec(), 
+            Err(Error::ImageError(ImageError::BackupMismatch { .. }))));
+    }
+
+    #[test]
+    fn backup_directives_refused() {
+        use BackupPolicy::{Auto, Never};
+        for directives in [
+            &[("boot.PARTITION", Some("boot.PARTITION"), Auto)][..],
+            &[("boot.PARTITION", Some("logo.PARTITION"), Auto)],
+            &[("boot.PARTITION", Some("recovery.PARTITION"), Never)],
+            &[("boot.PARTITION", Some("recovery.PARTITION"), Auto), 
+                ("recovery.PARTITION", Some("system.PARTITION"), Auto)],
+            &[("boot.PARTITION", Some("recovery.PARTITION"), Auto), 
+                ("recovery.PARTITION", None, Never)],
+            &[("meson1.dtb", Some("boot.PARTITION"), Auto)],
+        ] {
+            assert!(invalid_backup(with_backups(directives)), 
+                "{:?}", directives.iter().map(|(name, of, _)|(name, of))
+                    .collect::<Vec<_>>())
+        }
+        assert!(with_backups(&[("recovery.PARTITION", 
+            Some("boot.PARTITION"), Auto)]).is_ok());
+    }
+
+    #[test]
+    fn verify_against_sidecars() {
+        use crate::checksum::{find_digest, find_sidecar, try_read_digest, 
+            try_write_sidecars};
+        let bytes = fixture().try_write_vec().unwrap();
+        let path = temp_path("sidecar.img");
+        std::fs::write(&path, &bytes).unwrap();
+        let mut hasher = DigestHasher::new(DigestAlgo::Sha256);
+        hasher.update(&bytes);
+        let digest = hasher.finalize();
+        let image = Image::try_read_file(&path, &ReadFileOptions::default())
+            .unwrap();
+        let report = image.verify(&path, false, Some(DigestAlgo::Sha256), 
+            false).unwrap();
+        assert_eq!(report.digest.as_ref(), Some(&digest));
+        assert_eq!(find_sidecar(&path), None);
+        let sidecars = try_write_sidecars(&path, 
+            std::slice::from_ref(&digest), &WriteFileOptions::default())
+            .unwrap();
+        assert_eq!(find_sidecar(&path).as_ref(), sidecars.first());
+        let name = path.file_name().unwrap().to_str().unwrap();
+        assert_eq!(try_read_digest(&sidecars[0], name).unwrap(), digest);
+        assert!(try_read_digest(&sidecars[0], "other.img").is_err());
+        for sidecar in sidecars.iter().chain([&path]) {
+            std::fs::remove_file(sidecar).unwrap()
+        }
+        // Bare digests, and coreutils lines matched on basenames
+        let md5 = "0123456789abcdef0123456789ABCDEF";
+        let expected = Some(FileDigest { algo: DigestAlgo::Md5, 
+            digest: md5.to_lowercase() });
+        assert_eq!(find_digest(&format!("{}\n", md5), name), expected);
+        let content = format!("# md5sums\n{}  other.img\n{} *out/{}\n", 
+            "f".repeat(32), md5, name);
+        assert_eq!(find_digest(&content, name), expected);
+        assert_eq!(find_digest(&content, "missing.img"), None);
+        assert_eq!(find_digest("0123  sidecar.img\n", "sidecar.img"), None);
+    }
+
+    #[test]
+    fn unpack_keeping_corrupted() {
+        let mut bytes = fixture().try_write_vec().unwrap();
+        let (_, infos, id) = fixture_table(&bytes, "system.PARTITION");
+        bytes[infos[id].offset_in_image as usize] ^= 0xff;
+        let image = read_patched(bytes, &ReadFileOptions::default()).unwrap();
+        let dir = temp_path("corrupted");
+        let options = WriteDirOptions { verify: true, ..Default::default() };
+        assert!(image.try_write_dir(&dir, &options).is_err());
+        assert!(! dir.join("system.PARTITION").exists());
+        let options = WriteDirOptions { keep_corrupted: true, ..options };
+        let report = image.try_write_dir(&dir, &options).unwrap();
+        let corrupted: Vec<_> = report.corrupted.iter()
+            .map(|(name, _)|name.as_str()).collect();
+        let written: Vec<_> = image.items().iter().map(|item|
+            std::fs::read(dir.join(item.name())).ok()).collect();
+        let kept = std::fs::read(dir.join("system.PARTITION.corrupt"));
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(corrupted, ["system.PARTITION"]);
+        // The others are still written
+        for (item, written) in fixture().items().iter().zip(written) {
+            if item.name() == "system.PARTITION" {
+                assert_eq!(written, None)
+            } else {
+                assert_eq!(written, item.data.try_to_vec(&item.name()).ok())
+            }
+        }
+        let mut expected = vec![0x5e; 0x400];
+        expected[0] ^= 0xff;
+        assert_eq!(kept.unwrap(), expected);
+    }
+}
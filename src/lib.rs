@@ -0,0 +1,92 @@
+/*
+ampack, to unpack and pack Aml burning images: library module
+Copyright (C) 2024-present Guoxin "7Ji" Pu
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as
+published by the Free Software Foundation, either version 3 of the
+License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Read, verify, unpack, convert and pack Amlogic burning images, the library
+//! behind the `ampack` command.
+//!
+//! What most users need is re-exported here at the crate root: [`Image`] and
+//! its [`Item`]s, read with [`Image::try_read_file`], 
+//! [`Image::try_read_from`] or [`Image::try_read_dir`], verified with 
+//! [`Image::verify`], and written with [`Image::try_write_file`],
+//! [`Image::try_write_to`] or [`Image::try_write_dir`], plus their options,
+//! results and [`Error`]. The public modules hold the rest the command is 
+//! built from, e.g. the [`manifest`] or the [`output`] settings, and are 
+//! covered by semver just the same.
+//!
+//! Nothing is printed on stdout or stderr: status lines, tables, warnings and
+//! errors are only logged through the `log` crate, and no progress bars are
+//! drawn, unless [`output::set_console`] is called as the command does. The
+//! progress of long operations could be followed with a 
+//! [`ProgressCallback`] either way.
+
+// Printing hides the progress bars for the moment, so lines printed while 
+// they're drawn, e.g. from worker threads, are not torn by them; and goes
+// through the output module, which moves them to stderr in JSON mode
+macro_rules! println {
+    ($($arg:tt)*) => {
+        $crate::progress::suspend(|| 
+            $crate::output::print_info(format_args!($($arg)*)))
+    };
+}
+
+macro_rules! eprintln {
+    ($($arg:tt)*) => {
+        $crate::progress::suspend(|| 
+            $crate::output::print_error(format_args!($($arg)*)))
+    };
+}
+
+macro_rules! warning {
+    ($class:ident, $($arg:tt)*) => {
+        $crate::progress::suspend(|| $crate::output::print_warning(
+            $crate::output::WarningClass::$class, format_args!($($arg)*)))
+    };
+}
+
+pub mod archive;
+#[cfg(feature = "async")]
+mod asynchronous;
+pub mod atomic;
+pub mod cache;
+#[cfg(feature = "capi")]
+mod capi;
+pub mod checksum;
+pub mod compress;
+pub mod crc32;
+pub mod error;
+pub mod http;
+pub mod identify;
+pub mod image;
+pub mod manifest;
+pub mod output;
+pub mod payload;
+pub mod progress;
+pub mod retry;
+pub mod sha1sum;
+pub mod stamp;
+mod storage;
+#[cfg(feature = "wasm")]
+mod wasm;
+
+pub use atomic::WriteFileOptions;
+pub use checksum::{ChecksumAlgo, DigestAlgo, FileDigest};
+pub use error::{Error, ErrorContext, Result};
+pub use image::{CheckStatus, Image, ImageBuilder, ImageError, ImageHeader, ImageSummary, ImageVersion, Item, ItemCheck, ReadDirOptions, ReadFileOptions, VerifyReport, WriteDirOptions, WriteDirReport};
+pub use progress::{set_progress_callback, ProgressCallback};
+pub use sha1sum::Sha1sum;
+pub use stamp::Stamp;
@@ -0,0 +1,217 @@
+/*
+ampack, to unpack and pack Aml burning images: manifest module
+Copyright (C) 2024-present Guoxin "7Ji" Pu
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as
+published by the Free Software Foundation, either version 3 of the
+License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! The manifest written along unpacked items, recording what would be
+//! lost in a dir, to be applied when packed again.
+
+use std::{fmt::Display, fs::File, path::Path};
+
+use serde::{Serialize, Deserialize};
+
+use crate::{atomic::{self, WriteFileOptions}, identify::PayloadKind, image::ImageVersion, Result};
+
+/// Name of the manifest file written by unpack and picked up by pack
+pub const MANIFEST_NAME: &str = "ampack.yaml";
+
+/// How a text item was normalized by pack before being packed
+#[derive(Default, Clone, Serialize, Deserialize)]
+pub struct TextNormalization {
+    /// Count of CRLF line endings converted to LF
+    pub crlf: usize,
+    /// Whether a leading UTF-8 BOM was stripped
+    pub bom: bool,
+}
+
+/// Whether pack could dedup an item against identical ones
+#[derive(Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BackupPolicy {
+    /// A backup of the first identical item before it, if any
+    #[default]
+    Auto,
+    /// Never a backup, nor an item others are backups of
+    Never,
+}
+
+/// Metadata of an item not recoverable from the unpacked file itself
+#[derive(Default, Serialize, Deserialize)]
+pub struct ManifestItem {
+    /// Name of the item, `[stem].[extension]`
+    pub name: String,
+    /// Name of the unpacked file, if it had to be escaped from the item name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_name: Option<String>,
+    /// Size of the payload, checked by `verify --expect`, ignored by pack
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    /// Hex of the sha1sum of the payload, checked by `verify --expect`, 
+    /// ignored by pack
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha1sum: Option<String>,
+    /// What the payload looks like, checked by `verify --expect`, ignored by
+    /// pack
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payload: Option<PayloadKind>,
+    /// Hex of the reserved bytes in the item info, if not all zero
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reserve: Option<String>,
+    /// Hex of the reserved bytes in the VERIFY item info, if not all zero
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub verify_reserve: Option<String>,
+    /// How pack normalized the item file, if it did, informational only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text_normalization: Option<TextNormalization>,
+    /// Command run by pack on the item file before packing it, instead of 
+    /// the one given with `pack --hook`, see there
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hook: Option<String>,
+    /// ID of the item in the item info table of the unpacked image, kept by
+    /// pack if all items have one and they're unique and dense, as burning
+    /// scripts could refer to partitions by them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub item_id: Option<u32>,
+    /// Name of the item file before `pack --sanitize-names` changed it, if 
+    /// it did, informational only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub original_name: Option<String>,
+    /// Name of the item this one must be written as a backup of, instead of
+    /// the one found by pack, both must be identical
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backup_of: Option<String>,
+    /// `never` to keep pack from deduping this item against identical ones
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backup: Option<BackupPolicy>,
+}
+
+/// Metadata of an unpacked image, to keep unpack and pack round trips faithful
+#[derive(Default, Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: ImageVersion,
+    pub align: u32,
+    /// Hex of the reserved bytes in the image header, if not all zero
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reserve: Option<String>,
+    #[serde(default)]
+    pub items: Vec<ManifestItem>,
+}
+
+impl Manifest {
+    pub fn try_read_file<P: AsRef<Path>>(file: P) -> Result<Self> {
+        Ok(serde_yaml::from_reader(File::open(file.as_ref())?)?)
+    }
+
+    pub fn try_write_file<P: AsRef<Path>>(&self, file: P) -> Result<()> {
+        atomic::write_file(file, &WriteFileOptions::default(), |file|
+            Ok(serde_yaml::to_writer(file, self)?))
+    }
+
+    /// The manifest as written into a file, e.g. for an archive entry
+    pub fn try_to_vec(&self) -> Result<Vec<u8>> {
+        Ok(serde_yaml::to_string(self)?.into_bytes())
+    }
+
+    pub fn find_item(&self, name: &str) -> Option<&ManifestItem> {
+        self.items.iter().find(|item|item.name == name)
+    }
+
+    /// Compare `found`, the manifest of an image, against this one as 
+    /// expected: items only in either one, and fields recorded in this one
+    /// that differ in `found`. Fields missing from this one are not checked,
+    /// so a hand-written manifest could list only what matters.
+    pub fn diff(&self, found: &Manifest) -> ManifestDiff {
+        let mut diff = ManifestDiff::default();
+        let mut mismatch = |item: Option<&str>, field, 
+                            expected: String, found: String| 
+            if expected != found {
+                diff.mismatched.push(FieldMismatch { item: item.map(Into::into),
+                    field, expected, found })
+            };
+        mismatch(None, "version", self.version.to_string(), 
+            found.version.to_string());
+        mismatch(None, "align", self.align.to_string(), found.align.to_string());
+        for expected in self.items.iter() {
+            let found = match found.find_item(&expected.name) {
+                Some(found) => found,
+                None => continue,
+            };
+            let name = Some(expected.name.as_str());
+            if let Some(size) = expected.size {
+                mismatch(name, "size", size.to_string(), 
+                    found.size.map(|size|size.to_string()).unwrap_or_default())
+            }
+            if let Some(sha1sum) = &expected.sha1sum {
+                mismatch(name, "sha1sum", sha1sum.to_lowercase(), 
+                    found.sha1sum.clone().unwrap_or_default())
+            }
+            if let Some(payload) = &expected.payload {
+                mismatch(name, "payload", payload.to_string(), 
+                    found.payload.map(|payload|payload.to_string())
+                        .unwrap_or_default())
+            }
+            if let Some(item_id) = expected.item_id {
+                mismatch(name, "item_id", item_id.to_string(), 
+                    found.item_id.map(|id|id.to_string()).unwrap_or_default())
+            }
+        }
+        diff.missing = self.items.iter()
+            .filter(|item|found.find_item(&item.name).is_none())
+            .map(|item|item.name.clone()).collect();
+        diff.added = found.items.iter()
+            .filter(|item|self.find_item(&item.name).is_none())
+            .map(|item|item.name.clone()).collect();
+        diff
+    }
+}
+
+/// A field of the image or an item differing from the expected manifest
+#[derive(Serialize)]
+pub struct FieldMismatch {
+    /// Name of the item, or none for the image header
+    pub item: Option<String>,
+    pub field: &'static str,
+    pub expected: String,
+    pub found: String,
+}
+
+impl Display for FieldMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.item {
+            Some(item) => write!(f, "item '{}'", item)?,
+            None => write!(f, "image")?,
+        }
+        write!(f, " {}: expected {}, found {}", self.field, self.expected, 
+            if self.found.is_empty() { "none" } else { &self.found })
+    }
+}
+
+/// How an image differs from an expected manifest, see `Manifest::diff`
+#[derive(Default, Serialize)]
+pub struct ManifestDiff {
+    /// Items in the image but not in the manifest
+    pub added: Vec<String>,
+    /// Items in the manifest but not in the image
+    pub missing: Vec<String>,
+    pub mismatched: Vec<FieldMismatch>,
+}
+
+impl ManifestDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.missing.is_empty() && 
+            self.mismatched.is_empty()
+    }
+}
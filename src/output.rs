@@ -0,0 +1,796 @@
+/*
+ampack, to unpack and pack Aml burning images: output module
+Copyright (C) 2024-present Guoxin "7Ji" Pu
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as
+published by the Free Software Foundation, either version 3 of the
+License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Where lines, tables, warnings and results end up. Nothing is printed
+//! on stdout or stderr unless `set_console` is called, as the command does.
+
+use std::{cell::Cell, fmt::{Arguments, Display}, io::IsTerminal, sync::{Mutex, OnceLock}, time::{Duration, Instant}};
+
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use crate::{checksum::FileDigest, Result};
+
+/// Version of the schema of the JSON document and the verification report,
+/// bumped whenever a field is removed or changes its meaning, new fields 
+/// could be added without bumping
+pub const JSON_SCHEMA_VERSION: u32 = 1;
+
+static JSON: OnceLock<bool> = OnceLock::new();
+
+/// Set whether a single JSON document is printed on stdout at the end, with
+/// everything for humans moved to stderr, only the first call takes effect
+pub fn set_json(json: bool) {
+    let _ = JSON.set(json);
+}
+
+pub fn json() -> bool {
+    JSON.get().copied().unwrap_or(false)
+}
+
+/// How the item tables of a command are printed
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum OutputFormat {
+    /// Tables for humans, sizes and offsets in hex
+    #[default]
+    Table,
+    /// RFC 4180 CSV with a header row on stdout, sizes and offsets as raw 
+    /// integers, everything else moved to stderr
+    Csv,
+}
+
+static FORMAT: OnceLock<OutputFormat> = OnceLock::new();
+
+/// Set how item tables are printed, only the first call takes effect
+pub fn set_format(format: OutputFormat) {
+    let _ = FORMAT.set(format);
+}
+
+pub fn csv() -> bool {
+    FORMAT.get() == Some(&OutputFormat::Csv)
+}
+
+static STDOUT_DATA: OnceLock<bool> = OnceLock::new();
+
+/// Set whether stdout carries the bytes of an image written to `-`, so 
+/// nothing else goes there, only the first call takes effect
+pub fn set_stdout_data(data: bool) {
+    let _ = STDOUT_DATA.set(data);
+}
+
+/// Whether stdout is reserved for the JSON document, CSV or image data, so 
+/// lines and tables for humans should go to stderr
+fn stdout_reserved() -> bool {
+    json() || csv() || STDOUT_DATA.get().copied().unwrap_or(false)
+}
+
+static COLLECT_MESSAGES: OnceLock<bool> = OnceLock::new();
+
+/// Collect warnings and errors printed, for a report, even if not in JSON 
+/// mode, only the first call takes effect
+pub fn set_collect_messages(collect: bool) {
+    let _ = COLLECT_MESSAGES.set(collect);
+}
+
+fn collecting_messages() -> bool {
+    json() || COLLECT_MESSAGES.get().copied().unwrap_or(false)
+}
+
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "lowercase")]
+enum Severity {
+    Warning,
+    Error,
+}
+
+#[derive(Serialize, Clone)]
+pub struct Message {
+    severity: Severity,
+    message: String,
+}
+
+/// What goes into the JSON document besides the command and its outcome
+#[derive(Default)]
+struct Collected {
+    messages: Vec<Message>,
+    result: Map<String, Value>,
+}
+
+static COLLECTED: Mutex<Option<Collected>> = Mutex::new(None);
+
+fn collect<F: FnOnce(&mut Collected)>(f: F) {
+    if let Ok(mut collected) = COLLECTED.lock() {
+        f(collected.get_or_insert_with(Default::default))
+    }
+}
+
+fn collect_message(severity: Severity, message: String) {
+    collect(|collected| collected.messages.push(Message { severity, message }))
+}
+
+/// What item tables are sorted by, only for display
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum SortKey {
+    Size,
+    /// `[stem].[extension]`, or `[sub type].[main type]` in item infos
+    Name,
+    /// Offset in the image
+    Offset,
+    /// Extension, or main type in item infos
+    Type,
+}
+
+static SORT: OnceLock<Option<(SortKey, bool)>> = OnceLock::new();
+
+/// Set what item tables are sorted by and whether in reverse, only the first
+/// call takes effect
+pub fn set_sort(sort: Option<(SortKey, bool)>) {
+    let _ = SORT.set(sort);
+}
+
+/// The fields of a row in an item table rows could be sorted by
+pub struct SortFields {
+    pub size: u64,
+    pub name: String,
+    pub offset: Option<u64>,
+    pub kind: String,
+}
+
+/// The order to show `count` rows of an item table in, with `fields` giving
+/// the fields of a row by its index. The sort is stable, rows with the same
+/// key keep their order in the image, also when reversed.
+pub fn display_order<F>(count: usize, fields: F) -> Vec<usize> 
+where
+    F: Fn(usize) -> SortFields
+{
+    match SORT.get().copied().flatten() {
+        Some((key, reverse)) => sort_order(
+            &(0..count).map(fields).collect::<Vec<_>>(), key, reverse),
+        None => (0..count).collect(),
+    }
+}
+
+/// The order of rows with `fields` sorted by `key`, stable as described in
+/// `display_order`
+pub(crate) fn sort_order(fields: &[SortFields], key: SortKey, reverse: bool) 
+    -> Vec<usize> 
+{
+    let mut order: Vec<usize> = (0..fields.len()).collect();
+    order.sort_by(|a, b| {
+        let (a, b) = (&fields[*a], &fields[*b]);
+        let ordering = match key {
+            SortKey::Size => a.size.cmp(&b.size),
+            SortKey::Name => a.name.cmp(&b.name),
+            // Those not in the image last
+            SortKey::Offset => a.offset.unwrap_or(u64::MAX)
+                .cmp(&b.offset.unwrap_or(u64::MAX)),
+            SortKey::Type => a.kind.cmp(&b.kind),
+        };
+        if reverse {
+            ordering.reverse()
+        } else {
+            ordering
+        }
+    });
+    order
+}
+
+static HUMAN: OnceLock<bool> = OnceLock::new();
+
+/// Set whether sizes in tables and summaries are shown in binary units 
+/// instead of exact hex bytes, only the first call takes effect
+pub fn set_human(human: bool) {
+    let _ = HUMAN.set(human);
+}
+
+/// Whether sizes are shown in binary units
+fn human() -> bool {
+    HUMAN.get().copied().unwrap_or(false)
+}
+
+/// Format `bytes` in binary units, e.g. `5.72 MiB`, or `512 B` if less than
+/// a KiB
+pub fn binary_bytes(bytes: u64) -> String {
+    const PREFIXES: [&str; 6] = ["Ki", "Mi", "Gi", "Ti", "Pi", "Ei"];
+    if bytes < 1024 {
+        return format!("{} B", bytes)
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut prefix = 0;
+    // Values rounded up to 1024.00 are shown in the next unit instead
+    while value >= 1023.995 && prefix < PREFIXES.len() - 1 {
+        value /= 1024.0;
+        prefix += 1
+    }
+    format!("{:.2} {}B", value, PREFIXES[prefix])
+}
+
+/// Format `size` for tables, exact in hex (e.g. `0x5b8d80`), or in binary 
+/// units (e.g. `5.72 MiB`) if asked for, JSON and CSV always take the exact 
+/// integers
+pub fn size(size: u64) -> String {
+    if human() {
+        binary_bytes(size)
+    } else {
+        format!("0x{:x}", size)
+    }
+}
+
+/// Format `size` for summaries, e.g. `0x5b8d80 bytes` or `5.72 MiB`
+pub fn size_text(size: u64) -> String {
+    if human() {
+        binary_bytes(size)
+    } else {
+        format!("0x{:x} bytes", size)
+    }
+}
+
+/// Format `size` for summaries with the exact decimal bytes, e.g. 
+/// `0x5b8d80 (6000000) bytes` or `5.72 MiB (6000000 bytes)`
+pub fn size_text_exact(size: u64) -> String {
+    if human() {
+        format!("{} ({} bytes)", binary_bytes(size), size)
+    } else {
+        format!("0x{:x} ({}) bytes", size, size)
+    }
+}
+
+/// Whether to color warnings, errors and tables
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum ColorMode {
+    /// Only on terminals, and not if NO_COLOR is set
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+static COLOR: OnceLock<ColorMode> = OnceLock::new();
+
+/// Set whether to color output for humans, only the first call takes effect
+pub fn set_color(color: ColorMode) {
+    let _ = COLOR.set(color);
+}
+
+/// Whether output for humans to a stream that's a `terminal` or not should be
+/// colored, NO_COLOR set to anything non-empty turns that off unless colors
+/// are forced
+fn colored(terminal: bool) -> bool {
+    match COLOR.get().copied().unwrap_or_default() {
+        ColorMode::Auto => terminal && 
+            std::env::var_os("NO_COLOR").is_none_or(|value|value.is_empty()),
+        ColorMode::Always => true,
+        ColorMode::Never => false,
+    }
+}
+
+/// Whether lines and tables printed on stderr should be colored
+pub fn stderr_colored() -> bool {
+    colored(std::io::stderr().is_terminal())
+}
+
+fn stdout_colored() -> bool {
+    colored(std::io::stdout().is_terminal())
+}
+
+/// Print `line` on stderr, in yellow if it's a warning or red otherwise, if
+/// colored
+fn eprint_colored(line: &str, warning: bool) {
+    if stderr_colored() {
+        std::eprintln!("\x1b[{}m{}\x1b[0m", if warning { 33 } else { 31 }, line)
+    } else {
+        std::eprintln!("{}", line)
+    }
+}
+
+static CONSOLE: OnceLock<bool> = OnceLock::new();
+
+/// Set whether lines, tables and warnings are printed on stdout and stderr 
+/// as the command does, instead of only being logged through `log`, only the
+/// first call takes effect
+pub fn set_console(console: bool) {
+    let _ = CONSOLE.set(console);
+}
+
+/// Whether anything is printed on stdout and stderr
+pub fn console() -> bool {
+    CONSOLE.get().copied().unwrap_or(false)
+}
+
+static QUIET: OnceLock<u8> = OnceLock::new();
+
+/// Set how quiet it should be: 1 to only print warnings, errors and results,
+/// 2 to also drop warnings, only the first call takes effect
+pub fn set_quiet(quiet: u8) {
+    let _ = QUIET.set(quiet);
+}
+
+fn quiet() -> u8 {
+    QUIET.get().copied().unwrap_or(0)
+}
+
+thread_local! {
+    static PRINTING_RESULTS: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Run `f`, with everything it prints on this thread being what the user 
+/// asked for, which is printed even when quiet
+pub fn print_results<F: FnOnce() -> R, R>(f: F) -> R {
+    PRINTING_RESULTS.with(|printing| printing.set(true));
+    let result = f();
+    PRINTING_RESULTS.with(|printing| printing.set(false));
+    result
+}
+
+fn printing_results() -> bool {
+    PRINTING_RESULTS.with(|printing| printing.get())
+}
+
+/// Whether a line that's a warning or not should be printed when quiet
+fn shown_when_quiet(warning: bool) -> bool {
+    printing_results() || quiet() <= u8::from(warning)
+}
+
+/// Print a line for humans, on stdout, or stderr if stdout is reserved. 
+/// Dropped when quiet unless it's a result, and only logged without console.
+pub fn print_info(args: Arguments) {
+    log::info!("{}", args);
+    if ! console() || ! shown_when_quiet(false) {
+        return
+    }
+    if stdout_reserved() {
+        std::eprintln!("{}", args)
+    } else {
+        std::println!("{}", args)
+    }
+}
+
+/// Print an error line on stderr, also collected if messages are collected.
+/// Never dropped, but only logged without console.
+pub fn print_error(args: Arguments) {
+    print_line(Severity::Error, args.to_string())
+}
+
+/// Print a line of `severity` on stderr, also collected if messages are 
+/// collected. Errors are never dropped, warnings only when very quiet, both
+/// are only logged without console.
+fn print_line(severity: Severity, line: String) {
+    let warning = matches!(severity, Severity::Warning);
+    log::log!(if warning {
+        log::Level::Warn
+    } else {
+        log::Level::Error
+    }, "{}", line);
+    if console() && ! collecting_messages() && quiet() < 2 {
+        return eprint_colored(&line, warning)
+    }
+    if collecting_messages() {
+        collect_message(severity, line.clone())
+    }
+    if console() && (! warning || shown_when_quiet(warning)) {
+        eprint_colored(&line, warning)
+    }
+}
+
+/// Classes of warnings, for `--allow` to keep them from being promoted to 
+/// errors by `--strict`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum WarningClass {
+    /// The checksum cache could not be read or written
+    Cache,
+    /// Entries whose names only differ in case
+    CaseCollision,
+    /// Files not normalized as text as they look binary
+    BinaryText,
+    /// Items not spilled out of memory, or spill dirs not removed
+    Spill,
+    /// Items recorded in the manifest but not existing
+    Manifest,
+    /// Hooks recorded in the manifest ignored without --manifest-hooks
+    ManifestHooks,
+    /// Entries not packed as they're not named as `[stem].[extension]`
+    SkippedEntries,
+    /// Items written with escaped names
+    EscapedNames,
+    /// The planned layout could not be fully checked
+    Layout,
+    /// The alignment guessed from items differs from the one set
+    Alignment,
+    /// Files could not be mapped into memory
+    Mmap,
+    /// Transient I/O errors retried
+    Retry,
+    /// Images extracted from archives to temporary files
+    Extracted,
+    /// No item left to unpack by --only and --exclude
+    Filter,
+    /// An invalid log level in AMPACK_LOG ignored
+    LogLevel,
+}
+
+/// Allowed warning classes if strict
+static STRICT: OnceLock<Option<Vec<WarningClass>>> = OnceLock::new();
+
+/// Warnings promoted to errors
+static PROMOTED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Set whether warnings not of the `allowed` classes are promoted to errors,
+/// only the first call takes effect
+pub fn set_strict(strict: bool, allowed: Vec<WarningClass>) {
+    let _ = STRICT.set(strict.then_some(allowed));
+}
+
+/// Print a warning of `class` prefixed with "Warning: " as `print_error` 
+/// does, but as a warning, and remember it if it's promoted to an error
+pub fn print_warning(class: WarningClass, args: Arguments) {
+    let line = format!("Warning: {}", args);
+    if let Some(Some(allowed)) = STRICT.get() {
+        if ! allowed.contains(&class) {
+            if let Ok(mut promoted) = PROMOTED.lock() {
+                promoted.push(line.clone())
+            }
+        }
+    }
+    print_line(Severity::Warning, line)
+}
+
+/// The warnings promoted to errors so far
+pub fn promoted_warnings() -> Vec<String> {
+    PROMOTED.lock().map(|promoted| promoted.clone()).unwrap_or_default()
+}
+
+/// The warnings and errors collected so far
+pub fn messages() -> Vec<Message> {
+    COLLECTED.lock().ok()
+        .and_then(|collected| collected.as_ref()
+            .map(|collected| collected.messages.clone()))
+        .unwrap_or_default()
+}
+
+static LOG_TABLES: OnceLock<bool> = OnceLock::new();
+
+/// Set whether tables are also logged, plain, at info, e.g. for a log file,
+/// only the first call takes effect
+pub fn set_log_tables(log: bool) {
+    let _ = LOG_TABLES.set(log);
+}
+
+/// Print a table for humans, on stdout, or stderr if stdout is reserved, 
+/// dropped when quiet unless it's a result, only logged without console
+pub fn print_table<T: cli_table::Table>(table: T) -> Result<()> {
+    let mut table = table.table();
+    if LOG_TABLES.get().copied().unwrap_or(false) {
+        // Plain, for the log file
+        table = table.color_choice(cli_table::ColorChoice::Never);
+        log::info!("{}", table.display()?);
+    }
+    if ! console() || ! shown_when_quiet(false) {
+        return Ok(())
+    }
+    if stdout_reserved() {
+        print_table_stderr(table)
+    } else {
+        table = table.color_choice(color_choice(stdout_colored()));
+        crate::progress::suspend(||cli_table::print_stdout(table))?;
+        Ok(())
+    }
+}
+
+/// Print a table for humans on stderr, e.g. along with a warning
+pub fn print_table_stderr<T: cli_table::Table>(table: T) -> Result<()> {
+    if ! console() {
+        return Ok(())
+    }
+    let table = table.table().color_choice(color_choice(stderr_colored()));
+    crate::progress::suspend(||cli_table::print_stderr(table))?;
+    Ok(())
+}
+
+fn color_choice(colored: bool) -> cli_table::ColorChoice {
+    if colored {
+        cli_table::ColorChoice::Always
+    } else {
+        cli_table::ColorChoice::Never
+    }
+}
+
+/// Print `rows` under `header` as RFC 4180 CSV on stdout, if with console
+pub fn print_csv<R, F>(header: &[&str], rows: R) -> Result<()>
+where
+    R: IntoIterator<Item = Vec<F>>,
+    F: AsRef<[u8]>
+{
+    if ! console() {
+        return Ok(())
+    }
+    crate::progress::suspend(|| {
+        let mut writer = csv::Writer::from_writer(std::io::stdout().lock());
+        writer.write_record(header)?;
+        for row in rows {
+            writer.write_record(row)?
+        }
+        writer.flush()?;
+        Ok(())
+    })
+}
+
+/// Record `value` as field `key` of the result in the JSON document, does
+/// nothing if not in JSON mode
+pub fn record<T: Serialize + ?Sized>(key: &str, value: &T) {
+    if ! json() {
+        return
+    }
+    match serde_json::to_value(value) {
+        Ok(value) => collect(|collected| {
+            collected.result.insert(key.into(), value);
+        }),
+        Err(e) => collect_message(Severity::Error,
+            format!("Failed to record '{}' for JSON output: {}", key, e)),
+    }
+}
+
+static START: OnceLock<Instant> = OnceLock::new();
+
+/// Set when the run started, for the summary at its end, only the first call
+/// takes effect
+pub fn set_start(start: Instant) {
+    let _ = START.set(start);
+}
+
+/// A stage of the run in its summary, e.g. hashing items
+#[derive(Serialize)]
+struct SummaryStage {
+    stage: String,
+    /// Bytes gone through, if counted
+    bytes: Option<u64>,
+    seconds: f64,
+}
+
+/// What a command did, as a line printed at its end, e.g. `Summary: 11 items
+/// written, 0x61600c bytes, CRC32 0x4a1bc622, in 1.20s (hashed 0.50s, wrote 
+/// 0.60s)`, also recorded as `summary` in the JSON document. The byte-counted
+/// stages (hashing, writing, ...) are taken from those finished in the run.
+#[derive(Default)]
+pub struct RunSummary {
+    counts: Vec<(&'static str, u64)>,
+    bytes: Option<(&'static str, u64)>,
+    crc32: Option<u32>,
+    digests: Vec<FileDigest>,
+    stages: Vec<SummaryStage>,
+    seconds: f64,
+}
+
+impl RunSummary {
+    /// Add a count, e.g. `items checked` or `failed`
+    pub fn count(mut self, label: &'static str, count: usize) -> Self {
+        self.counts.push((label, count as u64));
+        self
+    }
+
+    /// Set how many bytes were processed and how, e.g. `hashed`
+    pub fn bytes(mut self, label: &'static str, bytes: u64) -> Self {
+        self.bytes = Some((label, bytes));
+        self
+    }
+
+    pub fn crc32(mut self, crc32: u32) -> Self {
+        self.crc32 = Some(crc32);
+        self
+    }
+
+    /// Add digests of the output file, e.g. `sha256`
+    pub fn digests(mut self, digests: &[FileDigest]) -> Self {
+        self.digests.extend_from_slice(digests);
+        self
+    }
+
+    /// Add a stage without a progress bar counting its bytes, e.g. scanning
+    /// the input dir
+    pub fn stage(mut self, stage: &str, elapsed: Duration) -> Self {
+        self.stages.push(SummaryStage { stage: stage.into(), bytes: None, 
+            seconds: elapsed.as_secs_f64() });
+        self
+    }
+
+    /// Print the summary and record it in the JSON document
+    pub fn emit(mut self) {
+        for finished in crate::progress::take_stages() {
+            let stage = finished.verb.to_lowercase();
+            let seconds = finished.elapsed.as_secs_f64();
+            match self.stages.iter_mut().find(|known| known.stage == stage) {
+                Some(known) => {
+                    known.bytes = Some(known.bytes.unwrap_or_default() + 
+                        finished.bytes);
+                    known.seconds += seconds
+                },
+                None => self.stages.push(SummaryStage { stage, 
+                    bytes: Some(finished.bytes), seconds }),
+            }
+        }
+        self.seconds = START.get()
+            .map(|start|start.elapsed().as_secs_f64()).unwrap_or_default();
+        println!("{}", self);
+        if json() {
+            let mut summary = Map::new();
+            for (label, count) in self.counts.iter() {
+                summary.insert(label.replace(' ', "_"), (*count).into());
+            }
+            if let Some((label, bytes)) = self.bytes {
+                summary.insert(format!("bytes_{}", label), bytes.into());
+            }
+            if let Some(crc32) = self.crc32 {
+                summary.insert("crc32".into(), crc32.into());
+            }
+            for digest in self.digests.iter() {
+                summary.insert(digest.algo.to_string(), 
+                    digest.digest.clone().into());
+            }
+            summary.insert("seconds".into(), self.seconds.into());
+            if let Ok(stages) = serde_json::to_value(&self.stages) {
+                summary.insert("stages".into(), stages);
+            }
+            record("summary", &summary);
+        }
+    }
+}
+
+impl Display for RunSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Summary: ")?;
+        for (label, count) in self.counts.iter() {
+            write!(f, "{} {}, ", count, label)?
+        }
+        if let Some((label, bytes)) = self.bytes {
+            write!(f, "{} {}, ", size_text(bytes), label)?
+        }
+        if let Some(crc32) = self.crc32 {
+            write!(f, "CRC32 0x{:08x}, ", crc32)?
+        }
+        for digest in self.digests.iter() {
+            write!(f, "{}, ", digest)?
+        }
+        write!(f, "in {:.2}s", self.seconds)?;
+        for (id, stage) in self.stages.iter().enumerate() {
+            write!(f, "{}{} {:.2}s", if id == 0 { " (" } else { ", " }, 
+                stage.stage, stage.seconds)?
+        }
+        if ! self.stages.is_empty() {
+            write!(f, ")")?
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+struct Document {
+    schema_version: u32,
+    ampack_version: &'static str,
+    command: String,
+    ok: bool,
+    error: Option<String>,
+    result: Map<String, Value>,
+    messages: Vec<Message>,
+}
+
+/// Print the JSON document of `command` that ended with `outcome` on stdout,
+/// does nothing if not in JSON mode
+pub fn emit(command: &str, outcome: &Result<()>) -> Result<()> {
+    if ! json() {
+        return Ok(())
+    }
+    let collected = COLLECTED.lock().ok()
+        .and_then(|mut collected| collected.take())
+        .unwrap_or_default();
+    let document = Document {
+        schema_version: JSON_SCHEMA_VERSION,
+        ampack_version: env!("CARGO_PKG_VERSION"),
+        command: command.into(),
+        ok: outcome.is_ok(),
+        error: outcome.as_ref().err().map(|e| e.to_string()),
+        result: collected.result,
+        messages: collected.messages,
+    };
+    std::println!("{}", serde_json::to_string_pretty(&document)?);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(size: u64, name: &str, offset: Option<u64>, kind: &str) 
+        -> SortFields 
+    {
+        SortFields { size, name: name.into(), offset, kind: kind.into() }
+    }
+
+    #[test]
+    fn sort_order_stable_with_ties() {
+        // Rows as in an image: USB items, two partitions of the same size, 
+        // two items of the same type, and one not in the image
+        let rows = [
+            fields(0x11170, "DDR.USB", Some(0x1900), "USB"),
+            fields(0x493e0, "UBOOT.USB", Some(0x12a70), "USB"),
+            fields(0x1e8480, "boot.PARTITION", Some(0x5be64), "PARTITION"),
+            fields(0x10, "platform.conf", None, "conf"),
+            fields(0x1e8480, "recovery.PARTITION", Some(0x5be64), "PARTITION"),
+            fields(0x3d0900, "system.PARTITION", Some(0x2456dc), "PARTITION"),
+        ];
+        assert_eq!(sort_order(&rows, SortKey::Size, false), [3, 0, 1, 2, 4, 5]);
+        // Ties keep their order in the image also when reversed
+        assert_eq!(sort_order(&rows, SortKey::Size, true), [5, 2, 4, 1, 0, 3]);
+        assert_eq!(sort_order(&rows, SortKey::Name, false), [0, 1, 2, 3, 4, 5]);
+        assert_eq!(sort_order(&rows, SortKey::Name, true), [5, 4, 3, 2, 1, 0]);
+        // Those not in the image last, shared offsets in the image order
+        assert_eq!(sort_order(&rows, SortKey::Offset, false), [0, 1, 2, 4, 5, 3]);
+        assert_eq!(sort_order(&rows, SortKey::Type, false), [2, 4, 5, 0, 1, 3]);
+        assert_eq!(sort_order(&rows, SortKey::Type, true), [3, 0, 1, 2, 4, 5]);
+        assert!(sort_order(&[], SortKey::Size, false).is_empty());
+    }
+
+    #[test]
+    fn binary_bytes_boundaries() {
+        for (bytes, text) in [
+            (0, "0 B"),
+            (1023, "1023 B"),
+            (1024, "1.00 KiB"),
+            (1025, "1.00 KiB"),
+            (1536, "1.50 KiB"),
+            (0x100000 - 1, "1.00 MiB"),
+            (0x100000, "1.00 MiB"),
+            (0x40000000, "1.00 GiB"),
+            (1 << 40, "1.00 TiB"),
+            (1 << 50, "1.00 PiB"),
+            (1 << 60, "1.00 EiB"),
+            (6000000, "5.72 MiB"),
+            (u64::MAX, "16.00 EiB"),
+        ] {
+            assert_eq!(binary_bytes(bytes), text, "{} bytes", bytes)
+        }
+    }
+
+    #[test]
+    fn size_human() {
+        set_human(true);
+        assert_eq!(size(1023), "1023 B");
+        assert_eq!(size(1024), "1.00 KiB");
+        assert_eq!(size(0x1d4c00000), "7.32 GiB");
+        assert_eq!(size_text(1 << 30), "1.00 GiB");
+        assert_eq!(size_text_exact(1024), "1.00 KiB (1024 bytes)");
+    }
+
+    #[test]
+    fn severity_of_messages() {
+        set_collect_messages(true);
+        print_warning(WarningClass::Cache, format_args!("severity test {}", 1));
+        // Errors are errors whatever they start with
+        print_error(format_args!("Warning-like severity test {}", 2));
+        let collected: Vec<_> = messages().into_iter()
+            .filter(|message|message.message.contains("severity test"))
+            .map(|message|(matches!(message.severity, Severity::Warning), 
+                message.message))
+            .collect();
+        assert_eq!(collected, [
+            (true, "Warning: severity test 1".to_owned()),
+            (false, "Warning-like severity test 2".to_owned())]);
+    }
+}
@@ -0,0 +1,698 @@
+/*
+ampack, to unpack and pack Aml burning images: item payload module
+Copyright (C) 2024-present Guoxin "7Ji" Pu
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as
+published by the Free Software Foundation, either version 3 of the
+License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Where the bytes of items live, in memory, in files or in the image
+//! they're read from, and how they're streamed in chunks.
+
+use std::{fs::{create_dir_all, remove_dir_all, File}, io::{Read, Seek, SeekFrom}, path::{Path, PathBuf}, sync::{mpsc::{channel, sync_channel}, Arc, Condvar, Mutex, OnceLock}};
+
+use serde::{Serialize, Deserialize};
+
+use rayon::prelude::*;
+
+use crate::{atomic::{self, WriteFileOptions}, error::{Context, ErrorContext}, image::ImageError, retry, storage, Result};
+
+/// Default granularity of streaming payloads, 1 MiB
+pub const SIZE_CHUNK_DEFAULT: usize = 0x100000;
+
+static SIZE_CHUNK: OnceLock<usize> = OnceLock::new();
+
+/// Chunks read ahead of the consumer of a payload
+const PIPELINE_DEPTH: usize = 4;
+
+static READ_AHEAD: OnceLock<bool> = OnceLock::new();
+
+/// Set whether payloads are read ahead on another thread while their chunks 
+/// are consumed, only the first call takes effect
+pub fn set_read_ahead(read_ahead: bool) {
+    let _ = READ_AHEAD.set(read_ahead);
+}
+
+/// Set the global granularity of streaming payloads, which is also the step of
+/// progress bars, only the first call takes effect
+pub fn set_chunk_size(size: usize) {
+    let _ = SIZE_CHUNK.set(size);
+}
+
+pub fn chunk_size() -> usize {
+    SIZE_CHUNK.get().copied().unwrap_or(SIZE_CHUNK_DEFAULT)
+}
+
+static READS_SEQUENTIAL: OnceLock<bool> = OnceLock::new();
+
+/// Force payloads to be read one after another even if they are on 
+/// non-rotational storage, only the first call takes effect
+pub fn set_reads_sequential(sequential: bool) {
+    let _ = READS_SEQUENTIAL.set(sequential);
+}
+
+/// Whether `payloads` could be read concurrently: not if sequential reads were
+/// forced, or if any of the files backing them looks like it's on a 
+/// rotational disk, where concurrent reads only make the head seek around
+pub fn reads_in_parallel<'a, I>(payloads: I) -> bool
+where
+    I: IntoIterator<Item = &'a Payload>
+{
+    paths_read_in_parallel(payloads.into_iter().filter_map(|payload|
+        match payload {
+            Payload::Region { source, .. } => Some(source.path()),
+            _ => None,
+        }))
+}
+
+/// Whether files at `paths` could be read concurrently, see `reads_in_parallel`
+pub fn paths_read_in_parallel<'a, I>(paths: I) -> bool
+where
+    I: IntoIterator<Item = &'a Path>
+{
+    if READS_SEQUENTIAL.get().copied().unwrap_or_default() {
+        return false
+    }
+    let mut checked: Vec<&Path> = Vec::new();
+    for path in paths {
+        // Files from the same dir are almost always on the same disk
+        let dir = path.parent().unwrap_or(path);
+        if checked.contains(&dir) {
+            continue
+        }
+        if storage::is_rotational(path) {
+            println!("Reading items one after another as '{}' is on a \
+                rotational disk", path.display());
+            return false
+        }
+        checked.push(dir)
+    }
+    true
+}
+
+/// Physical memory of the system, None if unknown on this platform
+#[cfg(unix)]
+fn physical_memory() -> Option<u64> {
+    let (pages, size_page) = unsafe { 
+        (libc::sysconf(libc::_SC_PHYS_PAGES), libc::sysconf(libc::_SC_PAGESIZE))
+    };
+    if pages <= 0 || size_page <= 0 {
+        return None
+    }
+    Some(pages as u64 * size_page as u64)
+}
+
+#[cfg(not(unix))]
+fn physical_memory() -> Option<u64> {
+    None
+}
+
+/// A limit on the sum of buffers held by the payloads being read in parallel
+struct BufferBudget {
+    limit: u64,
+    used: Mutex<u64>,
+    released: Condvar,
+}
+
+static BUFFER_BUDGET: OnceLock<BufferBudget> = OnceLock::new();
+
+/// Set the limit of buffers held by payloads read in parallel, 0 for a 
+/// quarter of the physical memory, only the first call takes effect
+pub fn set_buffer_memory(limit: u64) {
+    let _ = BUFFER_BUDGET.set(BufferBudget::new(limit));
+}
+
+impl BufferBudget {
+    fn new(limit: u64) -> Self {
+        let limit = match limit {
+            0 => physical_memory().map(|size| size / 4).unwrap_or(u64::MAX),
+            limit => limit,
+        };
+        Self { limit, used: Mutex::new(0), released: Condvar::new() }
+    }
+}
+
+/// Buffer memory reserved for reading a payload, returned to the budget when
+/// dropped
+pub struct BufferReservation {
+    size: u64,
+}
+
+impl Drop for BufferReservation {
+    fn drop(&mut self) {
+        if self.size == 0 {
+            return
+        }
+        if let Some(budget) = BUFFER_BUDGET.get() {
+            let mut used = budget.used.lock().unwrap_or_else(|e| e.into_inner());
+            *used -= self.size;
+            budget.released.notify_all()
+        }
+    }
+}
+
+/// Map `jobs` with `map`, on the rayon pool if `parallel`, or one after another
+/// on the current thread otherwise, results are always in the order of `jobs`
+pub fn map_jobs<T, R, F>(jobs: Vec<T>, parallel: bool, map: F) -> Vec<R>
+where
+    T: Send,
+    R: Send,
+    F: Fn(T) -> R + Sync + Send
+{
+    if parallel {
+        jobs.into_par_iter().map(map).collect()
+    } else {
+        jobs.into_iter().map(map).collect()
+    }
+}
+
+/// Anything an image could be read from
+pub trait ReadSeek: Read + Seek + Send {}
+
+impl<T: Read + Seek + Send> ReadSeek for T {}
+
+/// A reader shared by the payloads read from it, each read seeks to where the
+/// reading payload is at first, so they do not step on each other
+pub struct SharedReader {
+    reader: Mutex<Box<dyn ReadSeek>>,
+}
+
+impl SharedReader {
+    pub fn new<R: ReadSeek + 'static>(reader: R) -> Self {
+        Self { reader: Mutex::new(Box::new(reader)) }
+    }
+
+    /// A cursor into the reader with its own position, starting at 0
+    pub fn cursor(self: &Arc<Self>) -> SharedCursor {
+        SharedCursor { shared: self.clone(), position: 0 }
+    }
+}
+
+/// A cursor into a `SharedReader`, see `SharedReader::cursor`
+pub struct SharedCursor {
+    shared: Arc<SharedReader>,
+    position: u64,
+}
+
+impl Read for SharedCursor {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut reader = self.shared.reader.lock()
+            .unwrap_or_else(|e| e.into_inner());
+        reader.seek(SeekFrom::Start(self.position))?;
+        let size = reader.read(buf)?;
+        self.position += size as u64;
+        Ok(size)
+    }
+}
+
+impl Seek for SharedCursor {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.position = match pos {
+            SeekFrom::Start(offset) => offset,
+            SeekFrom::End(_) => self.shared.reader.lock()
+                .unwrap_or_else(|e| e.into_inner()).seek(pos)?,
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset)
+                .ok_or_else(|| std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput, 
+                    "seek to a negative position"))?,
+        };
+        Ok(self.position)
+    }
+}
+
+/// A file opened once and shared by all payloads that are regions of it, so
+/// they keep reading the same file even if its path is removed or replaced
+/// before they are consumed, e.g. an image unpacked into the dir it's in.
+/// Reads are positional and do not step on each other.
+pub struct SourceFile {
+    path: PathBuf,
+    file: File,
+    /// Platforms without positional reads have to seek first
+    #[cfg(not(any(unix, windows)))]
+    lock: Mutex<()>,
+}
+
+impl SourceFile {
+    pub fn open<P: AsRef<Path>>(path: P) -> std::io::Result<Arc<Self>> {
+        let path = path.as_ref();
+        Ok(Arc::new(Self { 
+            path: path.into(), 
+            file: File::open(path)?,
+            #[cfg(not(any(unix, windows)))]
+            lock: Mutex::new(()),
+        }))
+    }
+
+    /// The path the file was opened from, which might not point to it anymore
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    #[cfg(unix)]
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+        std::os::unix::fs::FileExt::read_at(&self.file, buf, offset)
+    }
+
+    #[cfg(windows)]
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+        std::os::windows::fs::FileExt::seek_read(&self.file, buf, offset)
+    }
+
+    #[cfg(not(any(unix, windows)))]
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(offset))?;
+        file.read(buf)
+    }
+
+    /// A cursor into the file with its own position, starting at 0
+    pub fn cursor(self: &Arc<Self>) -> SourceCursor {
+        SourceCursor { source: self.clone(), position: 0 }
+    }
+}
+
+/// (De)serialize a `SourceFile` as its path, opening it again when read
+mod serde_source {
+    use super::*;
+
+    pub fn serialize<S: serde::Serializer>(source: &Arc<SourceFile>, 
+                                           serializer: S) 
+        -> std::result::Result<S::Ok, S::Error> 
+    {
+        source.path.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: serde::Deserializer<'de>>(deserializer: D) 
+        -> std::result::Result<Arc<SourceFile>, D::Error> 
+    {
+        SourceFile::open(PathBuf::deserialize(deserializer)?)
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// A cursor into a `SourceFile`, see `SourceFile::cursor`
+pub struct SourceCursor {
+    source: Arc<SourceFile>,
+    position: u64,
+}
+
+impl Read for SourceCursor {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let size = self.source.read_at(buf, self.position)?;
+        self.position += size as u64;
+        Ok(size)
+    }
+}
+
+impl Seek for SourceCursor {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.source.file.metadata()?.len()
+                .checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        }.ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, 
+            "seek to a negative position"))?;
+        Ok(self.position)
+    }
+}
+
+/// Payload of an item, either held in memory, or a region of a file or of a
+/// reader that is only read, in chunks, when the payload is consumed
+#[derive(Serialize, Deserialize)]
+pub enum Payload {
+    Owned(Vec<u8>),
+    /// A region of a file, opened when the payload was created
+    Region {
+        #[serde(with = "serde_source")]
+        source: Arc<SourceFile>,
+        offset: u64,
+        size: u64,
+    },
+    /// A region of a reader the image was read from
+    #[serde(skip)]
+    Shared {
+        reader: Arc<SharedReader>,
+        offset: u64,
+        size: u64,
+    },
+    /// A region of a file mapped into memory
+    #[cfg(feature = "mmap")]
+    #[serde(skip)]
+    Mapped {
+        map: Arc<memmap2::Mmap>,
+        offset: usize,
+        size: usize,
+    },
+}
+
+/// Map the whole file at `path` into memory, None with a warning if that is
+/// not possible (e.g. not enough address space on 32-bit hosts, or special 
+/// filesystems), for the caller to fall back to reading regions.
+/// 
+/// The mapping is only safe as long as the file is not modified underneath,
+/// which ampack never does to its inputs, but other processes could.
+#[cfg(feature = "mmap")]
+pub fn try_map_file(path: &Path) -> Option<Arc<memmap2::Mmap>> {
+    let result = File::open(path).and_then(|file| 
+        unsafe { memmap2::Mmap::map(&file) });
+    match result {
+        Ok(map) => Some(Arc::new(map)),
+        Err(e) => {
+            warning!(Mmap, "failed to map '{}' into memory, falling back \
+                to reading it: {}", path.display(), e);
+            None
+        },
+    }
+}
+
+impl Default for Payload {
+    fn default() -> Self {
+        Self::Owned(Vec::new())
+    }
+}
+
+impl Payload {
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn len(&self) -> u64 {
+        match self {
+            Payload::Owned(data) => data.len() as u64,
+            Payload::Region { size, .. } | Payload::Shared { size, .. } => 
+                *size,
+            #[cfg(feature = "mmap")]
+            Payload::Mapped { size, .. } => *size as u64,
+        }
+    }
+
+    /// Memory taken by buffers while the payload is read
+    fn size_buffers(&self) -> u64 {
+        match self {
+            Payload::Shared { size, .. } => (chunk_size() as u64).min(*size),
+            Payload::Region { size, .. } => {
+                let depth = if READ_AHEAD.get().copied().unwrap_or(true) { 
+                    PIPELINE_DEPTH as u64 + 2 
+                } else { 
+                    1 
+                };
+                (chunk_size() as u64 * depth).min(*size)
+            },
+            // Already in memory, or mapped from the page cache
+            _ => 0,
+        }
+    }
+
+    /// Wait until the buffers needed to read the payload fit in the budget of
+    /// buffer memory, and reserve them, so parallel readers of many large
+    /// payloads could not use up the memory. A payload needing more than the
+    /// whole budget is read once nothing else is.
+    pub fn reserve_buffers(&self) -> BufferReservation {
+        let budget = BUFFER_BUDGET.get_or_init(|| BufferBudget::new(0));
+        let size = self.size_buffers().min(budget.limit);
+        if size == 0 {
+            return BufferReservation { size }
+        }
+        let mut used = budget.used.lock().unwrap_or_else(|e| e.into_inner());
+        while *used + size > budget.limit {
+            used = budget.released.wait(used).unwrap_or_else(|e| e.into_inner());
+        }
+        *used += size;
+        BufferReservation { size }
+    }
+
+    /// The `len` bytes of the payload starting at `start`, in the same form
+    pub fn slice(&self, start: u64, len: u64) -> Payload {
+        let start = start.min(self.len());
+        let len = len.min(self.len() - start);
+        match self {
+            Payload::Owned(data) => Payload::Owned(
+                data[start as usize..(start + len) as usize].to_vec()),
+            Payload::Region { source, offset, .. } => Payload::Region { 
+                source: source.clone(), offset: offset + start, size: len },
+            Payload::Shared { reader, offset, .. } => Payload::Shared { 
+                reader: reader.clone(), offset: offset + start, size: len },
+            #[cfg(feature = "mmap")]
+            Payload::Mapped { map, offset, .. } => Payload::Mapped { 
+                map: map.clone(), offset: offset + start as usize, 
+                size: len as usize },
+        }
+    }
+
+    /// Offset of the payload in the file or reader it's read or mapped from,
+    /// None if it's held in memory
+    pub fn offset_in_file(&self) -> Option<u64> {
+        match self {
+            Payload::Owned(_) => None,
+            Payload::Region { offset, .. } | Payload::Shared { offset, .. } => 
+                Some(*offset),
+            #[cfg(feature = "mmap")]
+            Payload::Mapped { offset, .. } => Some(*offset as u64),
+        }
+    }
+
+    /// The file the payload is read from as a whole, and the name of it, None
+    /// if it's not from a file, or only a region in it
+    pub fn source_file(&self) -> Option<(&Path, String)> {
+        match self {
+            Payload::Region { source, offset: 0, .. } => Some((source.path(), 
+                source.path().file_name()?.to_string_lossy().into_owned())),
+            _ => None,
+        }
+    }
+
+    /// Feed the payload of item `name` into `consume` chunk by chunk, for a
+    /// region only a single chunk is held in memory at any time
+    pub fn try_for_each_chunk<F>(&self, name: &str, mut consume: F)
+        -> Result<()>
+    where
+        F: FnMut(&[u8]) -> Result<()>
+    {
+        let (source, offset, size) = match self {
+            Payload::Owned(data) => {
+                for chunk in data.chunks(chunk_size()) {
+                    consume(chunk)?
+                }
+                return Ok(())
+            },
+            Payload::Region { source, offset, size } => (source, *offset, *size),
+            // Reads from a shared reader are serialized anyway, so there is
+            // nothing to gain from reading ahead
+            Payload::Shared { reader, offset, size } => 
+                return try_read_chunks(&mut reader.cursor(), None, name, 
+                    *offset, *size, consume),
+            #[cfg(feature = "mmap")]
+            Payload::Mapped { map, offset, size } => {
+                for chunk in map[*offset..*offset + *size].chunks(chunk_size()) {
+                    consume(chunk)?
+                }
+                return Ok(())
+            },
+        };
+        if size <= chunk_size() as u64 || ! READ_AHEAD.get().copied().unwrap_or(true) {
+            return try_read_chunks(&mut source.cursor(), Some(source.path()), 
+                name, offset, size, consume)
+        }
+        // Read ahead on another thread, so the disk is kept busy while the 
+        // consumer is hashing or writing the previous chunks
+        std::thread::scope(|scope| {
+            let (sender, receiver) = sync_channel::<Result<Vec<u8>>>(
+                PIPELINE_DEPTH);
+            let (sender_free, receiver_free) = channel::<Vec<u8>>();
+            scope.spawn(move || {
+                let size_chunk = chunk_size();
+                let mut cursor = source.cursor();
+                cursor.position = offset;
+                let mut done = 0;
+                while done < size {
+                    // Reuse the buffers the consumer is done with
+                    let mut buffer = receiver_free.try_recv()
+                        .unwrap_or_default();
+                    buffer.resize((size - done).min(size_chunk as u64) as usize, 0);
+                    log::trace!("Reading 0x{:x} bytes of '{}' at 0x{:x} of '{}'", 
+                        buffer.len(), name, offset + done, 
+                        source.path().display());
+                    let result = retry::read_exact(&mut cursor, &mut buffer)
+                        .map_err(|e| ImageError::ReadFailed { 
+                            name: name.into(), offset, size,
+                            transferred: done + e.transferred as u64, 
+                            error: e.error })
+                        .context(||reading_context(Some(source.path()), 
+                            offset, size));
+                    done += buffer.len() as u64;
+                    let failed = result.is_err();
+                    // The consumer has given up if it has hung up
+                    if sender.send(result.map(|_|buffer)).is_err() || failed {
+                        return
+                    }
+                }
+            });
+            for buffer in receiver {
+                let buffer = buffer?;
+                consume(&buffer)?;
+                let _ = sender_free.send(buffer);
+            }
+            Ok(())
+        })
+    }
+
+    /// Read at most the first `len` bytes of the payload
+    pub fn try_head(&self, name: &str, len: usize) -> Result<Vec<u8>> {
+        let len = len.min(self.len() as usize);
+        let mut head = Vec::with_capacity(len);
+        match self {
+            Payload::Owned(data) => head.extend_from_slice(&data[0..len]),
+            Payload::Region { source, offset, size } => {
+                let mut cursor = source.cursor();
+                cursor.seek(SeekFrom::Start(*offset))?;
+                head.resize(len, 0);
+                retry::read_exact(&mut cursor, &mut head).map_err(|e|
+                    ImageError::ReadFailed { name: name.into(), offset: *offset,
+                        size: *size, transferred: e.transferred as u64,
+                        error: e.error })?;
+            },
+            Payload::Shared { reader, offset, size } => {
+                let mut cursor = reader.cursor();
+                cursor.seek(SeekFrom::Start(*offset))?;
+                head.resize(len, 0);
+                retry::read_exact(&mut cursor, &mut head).map_err(|e|
+                    ImageError::ReadFailed { name: name.into(), offset: *offset,
+                        size: *size, transferred: e.transferred as u64,
+                        error: e.error })?;
+            },
+            #[cfg(feature = "mmap")]
+            Payload::Mapped { map, offset, .. } => 
+                head.extend_from_slice(&map[*offset..*offset + len]),
+        }
+        Ok(head)
+    }
+
+    /// Read the whole payload into memory, for operations mutating it
+    pub fn try_to_vec(&self, name: &str) -> Result<Vec<u8>> {
+        if let Payload::Owned(data) = self {
+            return Ok(data.clone())
+        }
+        let mut data = Vec::with_capacity(self.len() as usize);
+        self.try_for_each_chunk(name, |chunk| {
+            data.extend_from_slice(chunk);
+            Ok(())
+        })?;
+        Ok(data)
+    }
+}
+
+/// Context of errors reading `size` bytes at `offset` of the file at `path`,
+/// or of a reader if None, so they're not taken for errors of the consumer,
+/// e.g. writing the item somewhere else
+fn reading_context(path: Option<&Path>, offset: u64, size: u64) 
+    -> ErrorContext 
+{
+    let context = ErrorContext::reading().region(offset, size);
+    match path {
+        Some(path) => context.path(path),
+        None => context,
+    }
+}
+
+/// Feed `size` bytes at `offset` of `reader`, the file at `path` (None if 
+/// it's not a file) of item `name`, into `consume` chunk by chunk, with only
+/// a single chunk in memory
+fn try_read_chunks<R, F>(
+    reader: &mut R, path: Option<&Path>, name: &str, offset: u64, size: u64, 
+    mut consume: F
+) 
+    -> Result<()>
+where
+    R: Read + Seek,
+    F: FnMut(&[u8]) -> Result<()>
+{
+    reader.seek(SeekFrom::Start(offset))
+        .context(||reading_context(path, offset, size))?;
+    let size_chunk = chunk_size();
+    let mut buffer = vec![0; size_chunk.min(size as usize)];
+    let mut done = 0;
+    while done < size {
+        let buffer = &mut buffer[0..(size - done).min(size_chunk as u64) as usize];
+        log::trace!("Reading 0x{:x} bytes of '{}' at 0x{:x} of '{}'", 
+            buffer.len(), name, offset + done, 
+            path.map_or("reader".into(), Path::to_string_lossy));
+        retry::read_exact(reader, buffer).map_err(|e|
+            ImageError::ReadFailed { name: name.into(), offset, size,
+                transferred: done + e.transferred as u64, error: e.error })
+            .context(||reading_context(path, offset, size))?;
+        consume(buffer)?;
+        done += buffer.len() as u64;
+    }
+    Ok(())
+}
+
+/// A private dir under a parent dir, holding payloads spilled out of memory
+/// and files written by pack hooks, only created on the first file, and 
+/// removed with all its content when dropped
+pub struct SpillDir {
+    path: PathBuf,
+    count: usize,
+}
+
+impl SpillDir {
+    pub fn new(parent: &Path) -> Self {
+        Self {
+            path: parent.join(format!("ampack-spill.{}", std::process::id())),
+            count: 0,
+        }
+    }
+
+    /// Write an in-memory payload into a file in the dir and turn it into a 
+    /// region of that file, returning the path of it
+    pub fn try_spill(&mut self, payload: &mut Payload) -> Result<PathBuf> {
+        let data = match payload {
+            Payload::Owned(data) => data,
+            _ => return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput,
+                "only in-memory payloads could be spilled").into()),
+        };
+        let path = self.try_new_path("")?;
+        atomic::write_file(&path, &WriteFileOptions::default(), |file| 
+            retry::write_all(file, data).map_err(|e| e.error.into()))?;
+        *payload = Payload::Region { source: SourceFile::open(&path)?, 
+            offset: 0, size: data.len() as u64 };
+        Ok(path)
+    }
+
+    /// A path for a new file in the dir, ending with `suffix`, creating the
+    /// dir if it's the first
+    pub fn try_new_path(&mut self, suffix: &str) -> Result<PathBuf> {
+        if self.count == 0 {
+            if let Err(e) = create_dir_all(&self.path) {
+                eprintln!("Failed to create spill dir '{}': {}", 
+                    self.path.display(), e);
+                return Err(e.into())
+            }
+        }
+        let path = self.path.join(format!("{}{}", self.count, suffix));
+        self.count += 1;
+        Ok(path)
+    }
+}
+
+impl Drop for SpillDir {
+    fn drop(&mut self) {
+        if self.count > 0 {
+            if let Err(e) = remove_dir_all(&self.path) {
+                warning!(Spill, "failed to remove spill dir '{}': {}", 
+                    self.path.display(), e)
+            }
+        }
+    }
+}
@@ -16,15 +16,468 @@ You should have received a copy of the GNU Affero General Public License
 along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+//! Progress of long operations, drawn as bars with the `progress-bars`
+//! feature and reported to a `ProgressCallback` either way.
 
-use crate::Result;
+use std::{fmt::Display, io::IsTerminal, sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex, OnceLock}, time::{Duration, Instant}};
 
+#[cfg(feature = "progress-bars")]
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle, TermLike};
+
+use crate::{output::binary_bytes, Result};
+
+/// How progress is shown
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum ProgressMode {
+    /// A bar for each item being processed in parallel
+    #[default]
+    PerItem,
+    /// A single bar for the bytes of all items, with the current item
+    Aggregate,
+    /// No progress bars nor summaries of stages
+    None,
+}
+
+static PROGRESS_MODE: OnceLock<ProgressMode> = OnceLock::new();
+
+/// Set how progress is shown, only the first call takes effect
+pub fn set_progress_mode(mode: ProgressMode) {
+    let _ = PROGRESS_MODE.set(mode);
+}
+
+/// How progress is shown, never drawn without console
+fn progress_mode() -> ProgressMode {
+    if ! crate::output::console() {
+        return ProgressMode::None
+    }
+    PROGRESS_MODE.get().copied().unwrap_or_default()
+}
+
+/// Least interval between status lines printed instead of bars
+#[cfg(feature = "progress-bars")]
+const INTERVAL_PLAIN: Duration = Duration::from_secs(5);
+
+static PLAIN: OnceLock<bool> = OnceLock::new();
+
+/// Set whether progress is printed as plain status lines instead of bars, 
+/// which is always the case if stderr is not a terminal (e.g. cron, CI, or
+/// piped through tee), only the first call takes effect
+pub fn set_plain(plain: bool) {
+    let _ = PLAIN.set(plain || ! std::io::stderr().is_terminal());
+}
+
+#[cfg(feature = "progress-bars")]
+fn plain() -> bool {
+    PLAIN.get().copied().unwrap_or(false)
+}
+
+/// A fake terminal that prints the frames drawn by indicatif as plain lines 
+/// to stderr, at most once every `INTERVAL_PLAIN`, without any control 
+/// characters
+#[cfg(feature = "progress-bars")]
+#[derive(Debug)]
+struct PlainLines {
+    /// The frame being drawn, and when the last one was printed
+    state: Mutex<(String, Instant)>,
+}
+
+#[cfg(feature = "progress-bars")]
+impl PlainLines {
+    fn new() -> Self {
+        Self { state: Mutex::new((String::new(), Instant::now())) }
+    }
+
+    fn push(&self, s: &str) {
+        if let Ok(mut state) = self.state.lock() {
+            state.0.push_str(s)
+        }
+    }
+}
+
+#[cfg(feature = "progress-bars")]
+impl TermLike for PlainLines {
+    fn width(&self) -> u16 {
+        120
+    }
+
+    fn height(&self) -> u16 {
+        u16::MAX
+    }
+
+    fn move_cursor_up(&self, _: usize) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn move_cursor_down(&self, _: usize) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn move_cursor_right(&self, _: usize) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn move_cursor_left(&self, _: usize) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn write_line(&self, s: &str) -> std::io::Result<()> {
+        self.push(s);
+        self.push("\n");
+        Ok(())
+    }
+
+    fn write_str(&self, s: &str) -> std::io::Result<()> {
+        self.push(s);
+        Ok(())
+    }
+
+    fn clear_line(&self) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn flush(&self) -> std::io::Result<()> {
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            Err(_) => return Ok(()),
+        };
+        let frame = std::mem::take(&mut state.0);
+        if state.1.elapsed() < INTERVAL_PLAIN {
+            return Ok(())
+        }
+        let mut printed = false;
+        for line in frame.lines().map(str::trim_end).filter(|line|! line.is_empty()) {
+            // Already drawing the bars, suspending them would deadlock
+            std::eprintln!("{}", line);
+            printed = true
+        }
+        if printed {
+            state.1 = Instant::now()
+        }
+        Ok(())
+    }
+}
+
+/// Where bars are drawn: the terminal, or plain lines
+#[cfg(feature = "progress-bars")]
+fn draw_target() -> ProgressDrawTarget {
+    if plain() {
+        ProgressDrawTarget::term_like_with_hz(Box::new(PlainLines::new()), 1)
+    } else {
+        ProgressDrawTarget::stderr()
+    }
+}
+
+#[cfg(feature = "progress-bars")]
+static BARS: OnceLock<MultiProgress> = OnceLock::new();
+
+/// All bars are drawn together, so they could all be hidden when printing
+#[cfg(feature = "progress-bars")]
+fn bars() -> &'static MultiProgress {
+    BARS.get_or_init(|| MultiProgress::with_draw_target(draw_target()))
+}
+
+/// Run `f` with all progress bars hidden and redraw them after it, so what
+/// `f` prints is neither torn by nor tearing the bars
+pub fn suspend<F: FnOnce() -> R, R>(f: F) -> R {
+    #[cfg(feature = "progress-bars")]
+    return bars().suspend(f);
+    #[cfg(not(feature = "progress-bars"))]
+    f()
+}
+
+/// Receives the progress of long operations, to show it in other ways than
+/// the bars drawn on stderr, e.g. in a GUI, set with `set_progress_callback`
+pub trait ProgressCallback: Send + Sync {
+    /// `position` of `length` bytes (or entries, when reading the item infos
+    /// of an image or the entries of a dir) of `stage`, e.g. `Verifying 
+    /// image`, are done, called for every chunk
+    fn progress(&self, stage: &str, position: u64, length: u64);
+
+    /// A stage finished, e.g. `Hashed`, going through `bytes` in `elapsed`
+    fn finished(&self, stage: &str, bytes: u64, elapsed: Duration) {
+        let _ = (stage, bytes, elapsed);
+    }
+}
+
+static CALLBACK: OnceLock<Box<dyn ProgressCallback>> = OnceLock::new();
+
+/// Set the callback receiving the progress of long operations, in addition
+/// to the bars if drawn, only the first call takes effect
+pub fn set_progress_callback(callback: Box<dyn ProgressCallback>) {
+    let _ = CALLBACK.set(callback);
+}
+
+/// What clones of a bar share
+struct BarState {
+    stage: String,
+    position: AtomicU64,
+    length: u64,
+    start: Instant,
+}
+
+/// A progress bar, drawn on stderr with the `progress-bars` feature unless 
+/// progress is not shown, and reported to the progress callback if any. 
+/// Clones advance the same bar.
+#[derive(Clone)]
+pub struct Bar {
+    #[cfg(feature = "progress-bars")]
+    bar: ProgressBar,
+    state: Arc<BarState>,
+}
+
+impl Bar {
+    /// A bar of `length` for the stage in front of ` => ` in `template`, not 
+    /// drawn
+    fn hidden(length: u64, template: &str) -> Self {
+        let stage = match template.split_once(" => ") {
+            Some((stage, _)) => stage,
+            None => template,
+        };
+        Self {
+            #[cfg(feature = "progress-bars")]
+            bar: ProgressBar::hidden(),
+            state: Arc::new(BarState { stage: stage.into(), 
+                position: AtomicU64::new(0), length, start: Instant::now() }),
+        }
+    }
+
+    fn report(&self, position: u64) {
+        if let Some(callback) = CALLBACK.get() {
+            callback.progress(&self.state.stage, position, self.state.length)
+        }
+    }
+
+    pub fn inc(&self, delta: u64) {
+        let position = self.state.position.fetch_add(delta, Ordering::Relaxed)
+            + delta;
+        #[cfg(feature = "progress-bars")]
+        self.bar.inc(delta);
+        self.report(position)
+    }
+
+    pub fn set_position(&self, position: u64) {
+        self.state.position.store(position, Ordering::Relaxed);
+        #[cfg(feature = "progress-bars")]
+        self.bar.set_position(position);
+        self.report(position)
+    }
+
+    pub fn position(&self) -> u64 {
+        self.state.position.load(Ordering::Relaxed)
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.state.start.elapsed()
+    }
+
+    /// Set the message shown after the bar, e.g. the current item
+    pub fn set_message(&self, message: String) {
+        #[cfg(feature = "progress-bars")]
+        self.bar.set_message(message);
+        #[cfg(not(feature = "progress-bars"))]
+        let _ = message;
+    }
+
+    /// Redraw the bar every `interval` even if it's not advanced
+    pub fn enable_steady_tick(&self, interval: Duration) {
+        #[cfg(feature = "progress-bars")]
+        self.bar.enable_steady_tick(interval);
+        #[cfg(not(feature = "progress-bars"))]
+        let _ = interval;
+    }
+}
+
+/// Template of a bar counting bytes, with throughput and ETA, for a stage 
+/// named `prefix` and an optional `suffix` (e.g. the item name)
+pub fn template_bytes(prefix: &str, suffix: &str) -> String {
+    format!("{} => [{{elapsed_precise}}] {{bar:40.cyan/blue}} \
+        {{binary_bytes:>10}}/{{binary_total_bytes:>10}} \
+        {{binary_bytes_per_sec:>12}} ETA {{eta:>3}} {}", prefix, suffix)
+}
+
+/// How many bytes a stage went through in how long, printed once its bar is 
+/// gone, e.g. "Read 7.20 GiB in 41.03s, 179.71 MiB/s"
+#[derive(Clone)]
+pub struct StageSummary {
+    pub verb: &'static str,
+    pub bytes: u64,
+    pub elapsed: Duration,
+}
+
+impl StageSummary {
+    pub fn new(verb: &'static str, bytes: u64, elapsed: Duration) 
+        -> Self 
+    {
+        Self { verb, bytes, elapsed }
+    }
+
+    pub fn from_bar(verb: &'static str, bar: &Bar) -> Self {
+        Self::new(verb, bar.position(), bar.elapsed())
+    }
+}
+
+impl Display for StageSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let secs = self.elapsed.as_secs_f64();
+        write!(f, "{} {} in {:.2}s", self.verb, binary_bytes(self.bytes), secs)?;
+        if secs > 0.0 {
+            write!(f, ", {}/s", binary_bytes((self.bytes as f64 / secs) as u64))?
+        }
+        Ok(())
+    }
+}
+
+/// Stages finished so far, for the summary at the end of the run
+static STAGES: Mutex<Vec<StageSummary>> = Mutex::new(Vec::new());
+
+/// Print the summary of a finished stage unless progress is not shown, and
+/// keep it for the summary of the run
+fn record_stage(stage: StageSummary) {
+    if progress_mode() != ProgressMode::None {
+        println!("{}", stage)
+    }
+    if let Some(callback) = CALLBACK.get() {
+        callback.finished(stage.verb, stage.bytes, stage.elapsed)
+    }
+    if let Ok(mut stages) = STAGES.lock() {
+        stages.push(stage)
+    }
+}
+
+/// Take the summaries of stages finished so far
+pub fn take_stages() -> Vec<StageSummary> {
+    STAGES.lock().map(|mut stages|std::mem::take(&mut *stages))
+        .unwrap_or_default()
+}
+
+/// Clear a bar and remove it from those drawn
+pub fn finish_bar(bar: &Bar) {
+    #[cfg(feature = "progress-bars")]
+    {
+        bar.bar.finish_and_clear();
+        bars().remove(&bar.bar)
+    }
+    #[cfg(not(feature = "progress-bars"))]
+    let _ = bar;
+}
+
+/// Clear a bar counting bytes and print the summary of its stage
+pub fn finish_stage(bar: &Bar, verb: &'static str) {
+    finish_bar(bar);
+    record_stage(StageSummary::from_bar(verb, bar))
+}
+
+/// Bars of a stage processing items in parallel, either one for each item
+/// or a single one shared by all of them, as set by the progress mode
+pub struct ItemBars {
+    aggregate: Option<Bar>,
+    prefix: &'static str,
+    /// Bytes of items done with, as they could have stopped halfway
+    processed: AtomicU64,
+    start: Instant,
+}
+
+impl ItemBars {
+    /// Bars of a stage named `prefix` going through `total` bytes of items
+    pub fn new(prefix: &'static str, total: u64) -> Result<Self> {
+        let aggregate = match progress_mode() {
+            ProgressMode::Aggregate => {
+                let bar = progress_bar_with_template(
+                    total, template_bytes(prefix, "{msg}"))?;
+                bar.enable_steady_tick(Duration::from_secs(1));
+                Some(bar)
+            },
+            _ => None,
+        };
+        Ok(Self { aggregate, prefix, processed: AtomicU64::new(0), 
+            start: Instant::now() })
+    }
+
+    /// The bar to advance by the bytes of item `name` of `length` bytes as
+    /// they're processed
+    pub fn start(&self, name: &str, length: u64) -> Result<Bar> {
+        match &self.aggregate {
+            Some(bar) => {
+                bar.set_message(name.to_owned());
+                Ok(bar.clone())
+            },
+            None => progress_bar_with_template(
+                length, template_bytes(self.prefix, name)),
+        }
+    }
+
+    /// Account for `length` bytes of an item passed without processing
+    pub fn skip(&self, length: u64) {
+        if let Some(bar) = &self.aggregate {
+            bar.inc(length)
+        }
+        self.processed.fetch_add(length, Ordering::Relaxed);
+    }
+
+    /// Remove the bar of an item done with, unless it's the shared one
+    pub fn done(&self, bar: &Bar) {
+        if self.aggregate.is_none() {
+            self.processed.fetch_add(bar.position(), Ordering::Relaxed);
+            finish_bar(bar)
+        }
+    }
+
+    /// Clear the shared bar if any and print the summary of the stage as 
+    /// `verb`, the bars of items are already gone when they're done
+    pub fn finish(self, verb: &'static str) {
+        let processed = match &self.aggregate {
+            Some(bar) => {
+                finish_bar(bar);
+                bar.position()
+            },
+            None => self.processed.into_inner(),
+        };
+        record_stage(StageSummary::new(verb, processed, self.start.elapsed()))
+    }
+}
+
+/// Drop the styles (e.g. `.cyan/blue` in `{bar:40.cyan/blue}`) from the 
+/// placeholders in `template`
+#[cfg(feature = "progress-bars")]
+fn strip_template_styles(template: &str) -> String {
+    let mut stripped = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let Some(len) = rest[start..].find('}') else {
+            break
+        };
+        let placeholder = &rest[start..start + len];
+        match placeholder.find(':').and_then(|colon| 
+            placeholder[colon..].find('.').map(|dot|colon + dot)) 
+        {
+            Some(dot) => stripped.push_str(&rest[..start + dot]),
+            None => stripped.push_str(&rest[..start + len]),
+        }
+        stripped.push('}');
+        rest = &rest[start + len + 1..]
+    }
+    stripped.push_str(rest);
+    stripped
+}
+
+#[cfg(feature = "progress-bars")]
 fn progress_style_with_templace<S: AsRef<str>>(template: S) 
     -> Result<ProgressStyle> 
 {
-    let template = template.as_ref();
-    match ProgressStyle::with_template(template) {
+    let mut template = template.as_ref().to_owned();
+    if ! crate::output::stderr_colored() {
+        template = strip_template_styles(&template)
+    }
+    // A percentage instead of the bar in plain lines
+    if plain() {
+        if let Some(start) = template.find("{bar") {
+            if let Some(len) = template[start..].find('}') {
+                template.replace_range(start..start + len + 1, "{percent:>3}%")
+            }
+        }
+    }
+    match ProgressStyle::with_template(&template) {
         Ok(style) => Ok(style),
         Err(e) => {
             eprintln!(
@@ -36,23 +489,33 @@ fn progress_style_with_templace<S: AsRef<str>>(template: S)
     }
 }
 
-pub(crate) fn progress_bar_with_template<S>(length: u64, template: S) 
-    -> Result<ProgressBar>
+pub fn progress_bar_with_template<S>(length: u64, template: S) 
+    -> Result<Bar>
 where
     S: AsRef<str>,
 {
-    let style = progress_style_with_templace(template)?;
-    let bar = ProgressBar::new(length);
-    bar.set_style(style);
+    #[allow(unused_mut)]
+    let mut bar = Bar::hidden(length, template.as_ref());
+    #[cfg(feature = "progress-bars")]
+    if progress_mode() != ProgressMode::None {
+        let style = progress_style_with_templace(template)?;
+        let drawn = ProgressBar::new(length);
+        drawn.set_style(style);
+        bar.bar = bars().add(drawn)
+    }
     Ok(bar)
 }
 
-pub(crate) fn progress_bar_with_template_multi<S>(
-    multi_progress: &MultiProgress, length: u64, template: S
+pub fn progress_bar_with_template_optional<S>(
+    show: bool, length: u64, template: S
 ) 
-    -> Result<ProgressBar>
+    -> Result<Bar>
 where
     S: AsRef<str>,
 {
-    Ok(multi_progress.add(progress_bar_with_template(length, template)?))
-}
\ No newline at end of file
+    if show {
+        progress_bar_with_template(length, template)
+    } else {
+        Ok(Bar::hidden(length, template.as_ref()))
+    }
+}
@@ -0,0 +1,202 @@
+/*
+ampack, to unpack and pack Aml burning images: I/O retry module
+Copyright (C) 2024-present Guoxin "7Ji" Pu
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as
+published by the Free Software Foundation, either version 3 of the
+License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Reads and writes retried on transient I/O errors, as set by the policy.
+
+use std::{io::{ErrorKind, Read, Write}, sync::OnceLock, thread::sleep, time::Duration};
+
+/// How transient I/O errors (EIO, timeouts) are retried, interrupted calls
+/// are always retried at once and not counted
+#[derive(Clone, Copy)]
+pub struct RetryPolicy {
+    pub attempts: u32,
+    pub backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 3,
+            backoff: Duration::from_millis(500),
+        }
+    }
+}
+
+static POLICY: OnceLock<RetryPolicy> = OnceLock::new();
+
+/// Set the global retry policy, only the first call takes effect
+pub fn set_policy(policy: RetryPolicy) {
+    let _ = POLICY.set(policy);
+}
+
+fn policy() -> RetryPolicy {
+    POLICY.get().copied().unwrap_or_default()
+}
+
+#[cfg(unix)]
+fn is_eio(error: &std::io::Error) -> bool {
+    error.raw_os_error() == Some(libc::EIO)
+}
+
+#[cfg(not(unix))]
+fn is_eio(_error: &std::io::Error) -> bool {
+    false
+}
+
+fn is_retryable(error: &std::io::Error) -> bool {
+    match error.kind() {
+        ErrorKind::TimedOut | ErrorKind::WouldBlock => true,
+        _ => is_eio(error)
+    }
+}
+
+/// An I/O error that could not be retried away, with how many bytes were 
+/// successfully transferred before giving up
+pub struct TransferError {
+    pub error: std::io::Error,
+    pub transferred: usize,
+}
+
+fn wait_or_give_up(error: std::io::Error, failures: &mut u32, transferred: usize) 
+    -> std::result::Result<(), TransferError> 
+{
+    let policy = policy();
+    if ! is_retryable(&error) || *failures >= policy.attempts {
+        return Err(TransferError { error, transferred })
+    }
+    *failures += 1;
+    warning!(Retry, "transient I/O error after {} bytes: {}, retrying \
+        ({}/{})", transferred, error, failures, policy.attempts);
+    sleep(policy.backoff * *failures);
+    Ok(())
+}
+
+/// Like `Read::read_exact`, but retries transient errors
+pub fn read_exact<R: Read>(reader: &mut R, buffer: &mut [u8]) 
+    -> std::result::Result<(), TransferError> 
+{
+    let mut transferred = 0;
+    let mut failures = 0;
+    while transferred < buffer.len() {
+        match reader.read(&mut buffer[transferred..]) {
+            Ok(0) => return Err(TransferError { 
+                error: ErrorKind::UnexpectedEof.into(), transferred }),
+            Ok(size) => transferred += size,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => wait_or_give_up(e, &mut failures, transferred)?,
+        }
+    }
+    Ok(())
+}
+
+/// Like `Write::write_all`, but retries transient errors
+pub fn write_all<W: Write>(writer: &mut W, buffer: &[u8]) 
+    -> std::result::Result<(), TransferError> 
+{
+    let mut transferred = 0;
+    let mut failures = 0;
+    while transferred < buffer.len() {
+        match writer.write(&buffer[transferred..]) {
+            Ok(0) => return Err(TransferError { 
+                error: ErrorKind::WriteZero.into(), transferred }),
+            Ok(size) => transferred += size,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => wait_or_give_up(e, &mut failures, transferred)?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Fails each call with `kind` until `failures` calls are made, then
+    /// reads/writes one byte at a time
+    struct Flaky {
+        kind: ErrorKind,
+        failures: u32,
+    }
+
+    impl Flaky {
+        fn call(&mut self) -> std::io::Result<usize> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err(self.kind.into())
+            }
+            Ok(1)
+        }
+    }
+
+    impl Read for Flaky {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let size = self.call()?;
+            buf[0] = 0xaa;
+            Ok(size)
+        }
+    }
+
+    impl Write for Flaky {
+        fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+            self.call()
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn interrupted_not_counted() {
+        // Far more than the attempts, and without waiting for the backoff
+        let failures = policy().attempts * 100;
+        let mut buffer = [0; 4];
+        read_exact(&mut Flaky { kind: ErrorKind::Interrupted, failures }, 
+            &mut buffer).unwrap_or_else(|e|panic!("{}", e.error));
+        assert_eq!(buffer, [0xaa; 4]);
+        write_all(&mut Flaky { kind: ErrorKind::Interrupted, failures }, 
+            &buffer).unwrap_or_else(|e|panic!("{}", e.error));
+    }
+
+    #[test]
+    fn permanent_not_retried() {
+        let mut buffer = [0; 4];
+        let e = match read_exact(&mut Flaky { 
+            kind: ErrorKind::PermissionDenied, failures: 1 }, &mut buffer) 
+        {
+            Ok(_) => panic!("permanent error retried away"),
+            Err(e) => e,
+        };
+        assert_eq!(e.error.kind(), ErrorKind::PermissionDenied);
+        assert_eq!(e.transferred, 0);
+    }
+
+    #[test]
+    fn retryable() {
+        assert!(is_retryable(&ErrorKind::TimedOut.into()));
+        assert!(is_retryable(&ErrorKind::WouldBlock.into()));
+        assert!(! is_retryable(&ErrorKind::Interrupted.into()));
+        assert!(! is_retryable(&ErrorKind::NotFound.into()));
+        #[cfg(unix)]
+        {
+            assert!(is_retryable(&std::io::Error::from_raw_os_error(libc::EIO)));
+            assert!(! is_retryable(
+                &std::io::Error::from_raw_os_error(libc::ENOENT)));
+        }
+    }
+}
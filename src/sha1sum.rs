@@ -16,40 +16,64 @@ You should have received a copy of the GNU Affero General Public License
 along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
+//! The sha1sums recorded in VERIFY items.
+
 use std::fmt::Display;
 
 use hex::FromHex;
 
-use indicatif::ProgressBar;
+use crate::progress::Bar;
 use serde::{Serialize, Deserialize};
 use sha1::{Digest, Sha1};
 
-use crate::Result;
+use crate::{payload::Payload, Result};
 
 type Sha1sumByteArray = [u8; 20];
 
 #[derive(Default, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Clone)]
-pub(crate) struct Sha1sum(Sha1sumByteArray);
+pub struct Sha1sum(Sha1sumByteArray);
 
 impl Sha1sum {
-    pub(crate) fn from_hex(slice: &[u8]) -> Result<Self> {
+    pub fn from_hex(slice: &[u8]) -> Result<Self> {
         Ok(Self(Sha1sumByteArray::from_hex(slice)?))
     }
 
-    pub(crate) fn from_data(data: &[u8]) -> Self {
+    pub fn from_data(data: &[u8]) -> Self {
         Self(Sha1::digest(data).into())
     }
 
-    pub(crate) fn from_data_with_bar(data: &[u8], bar: &ProgressBar) -> Self {
-        const STEP: usize = 0x100000;
+    /// Hash the payload of item `name` chunk by chunk, advancing `bar` by the
+    /// bytes hashed
+    pub fn try_from_payload_with_bar(
+        payload: &Payload, name: &str, bar: &Bar
+    ) 
+        -> Result<Self> 
+    {
         let mut hasher = Sha1::new();
-        for chunk in data.chunks(STEP) {
-            // bar.set_message(format!("{}/{}", id, suffix));
+        payload.try_for_each_chunk(name, |chunk| {
             hasher.update(chunk);
-            bar.inc(1);
-        }
-        bar.finish_and_clear();
-        Self(hasher.finalize().into())
+            bar.inc(chunk.len() as u64);
+            Ok(())
+        })?;
+        Ok(Self(hasher.finalize().into()))
+    }
+}
+
+/// Incremental hasher, for payloads hashed as they're streamed elsewhere
+#[derive(Clone, Default)]
+pub struct Sha1sumHasher(Sha1);
+
+impl Sha1sumHasher {
+    pub fn new() -> Self {
+        Self(Sha1::new())
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.0.update(data)
+    }
+
+    pub fn finalize(self) -> Sha1sum {
+        Sha1sum(self.0.finalize().into())
     }
 }
 
@@ -60,4 +84,58 @@ impl Display for Sha1sum {
         }
         Ok(())
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::progress::progress_bar_with_template_optional;
+
+    /// Test vectors from FIPS 180-2, these hold for both the accelerated and
+    /// the portable backends, see the `sha1-asm` and `sha1-soft` features
+    const VECTORS: [(&[u8], &str); 3] = [
+        (b"abc", "a9993e364706816aba3e25717850c26c9cd0d89d"),
+        (b"", "da39a3ee5e6b4b0d3255bfef95601890afd80709"),
+        (b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq", 
+            "84983e441c3bd26ebaae4aa1f95129e5e54670f1"),
+    ];
+
+    #[test]
+    fn from_data_fips_vectors() {
+        for (data, hex) in VECTORS {
+            assert_eq!(Sha1sum::from_data(data).to_string(), hex)
+        }
+    }
+
+    #[test]
+    fn from_data_million_a() {
+        assert_eq!(Sha1sum::from_data(&vec![b'a'; 1_000_000]).to_string(),
+            "34aa973cd4c4daa4f61eeb2bdbad27316534016f")
+    }
+
+    #[test]
+    fn from_hex_round_trip() {
+        for (_, hex) in VECTORS {
+            assert_eq!(Sha1sum::from_hex(hex.as_bytes()).unwrap().to_string(),
+                hex)
+        }
+        assert!(Sha1sum::from_hex(b"a9993e36").is_err())
+    }
+
+    #[test]
+    fn incremental_and_payload_match() {
+        let data: Vec<u8> = (0..100_000_u32).map(|id|(id * 131) as u8)
+            .collect();
+        let expected = Sha1sum::from_data(&data);
+        let mut hasher = Sha1sumHasher::new();
+        for chunk in data.chunks(4099) {
+            hasher.update(chunk)
+        }
+        assert_eq!(hasher.finalize().to_string(), expected.to_string());
+        let bar = progress_bar_with_template_optional(
+            false, data.len() as u64, "Hashing => {bar}").unwrap();
+        let payload = Payload::Owned(data);
+        assert_eq!(Sha1sum::try_from_payload_with_bar(&payload, "test", &bar)
+            .unwrap().to_string(), expected.to_string());
+        assert_eq!(bar.position(), payload.len());
+    }
+}
@@ -0,0 +1,120 @@
+/*
+ampack, to unpack and pack Aml burning images: provenance stamp module
+Copyright (C) 2024-present Guoxin "7Ji" Pu
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as
+published by the Free Software Foundation, either version 3 of the
+License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Stamps of the version of ampack and the time an image was packed, kept in
+//! the reserved bytes of its header.
+
+use std::{fmt::Display, time::{SystemTime, UNIX_EPOCH}};
+
+use serde::Serialize;
+
+/// Magic at the start of the reserved header bytes stamped by ampack
+const STAMP_MAGIC: &[u8; 4] = b"AMPK";
+/// Offset of the unix time, little-endian u64, after the magic
+const OFFSET_TIME: usize = 4;
+/// Offset of the version of ampack, ASCII padded with NULs to the end
+const OFFSET_VERSION: usize = 12;
+
+/// `Made by ampack vX.Y at [time]`, stamped into the reserved bytes of the
+/// image header by `pack --stamp`, to tell images packed by ampack apart from
+/// those by vendor tools
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Stamp {
+    pub version: String,
+    /// Seconds since the unix epoch
+    pub time: u64,
+}
+
+impl Stamp {
+    /// A stamp of this version of ampack at `time`, or now if None
+    pub fn new(time: Option<u64>) -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION").into(),
+            time: time.unwrap_or_else(|| SystemTime::now()
+                .duration_since(UNIX_EPOCH).map(|time|time.as_secs())
+                .unwrap_or_default()),
+        }
+    }
+
+    /// The stamp as reserved bytes of `size`, the version is cut if it does
+    /// not fit
+    pub fn to_bytes(&self, size: usize) -> Vec<u8> {
+        let mut bytes = vec![0; size];
+        bytes[..OFFSET_TIME].copy_from_slice(STAMP_MAGIC);
+        bytes[OFFSET_TIME..OFFSET_VERSION].copy_from_slice(
+            &self.time.to_le_bytes());
+        let version = self.version.as_bytes();
+        let len = version.len().min(size - OFFSET_VERSION - 1);
+        bytes[OFFSET_VERSION..OFFSET_VERSION + len].copy_from_slice(
+            &version[..len]);
+        bytes
+    }
+
+    /// The stamp in `reserve`, None if it's not one, i.e. all zeros or bytes
+    /// of vendor tools: no magic, or a version not of printable ASCII
+    /// followed only by NULs
+    pub fn from_bytes(reserve: &[u8]) -> Option<Self> {
+        if ! reserve.starts_with(STAMP_MAGIC) {
+            return None
+        }
+        let time = u64::from_le_bytes(
+            reserve.get(OFFSET_TIME..OFFSET_VERSION)?.try_into().ok()?);
+        let version = reserve.get(OFFSET_VERSION..)?;
+        let len = version.iter().position(|byte| *byte == 0)?;
+        if len == 0 || ! version[..len].iter().all(u8::is_ascii_graphic) ||
+            version[len..].iter().any(|byte| *byte != 0)
+        {
+            return None
+        }
+        Some(Self {
+            version: String::from_utf8_lossy(&version[..len]).into_owned(),
+            time,
+        })
+    }
+}
+
+/// Year, month and day of `days` since the unix epoch, in the proleptic
+/// Gregorian calendar
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524
+        - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4
+        - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_from_march + 2) / 5 + 1) as u32;
+    let month = if month_from_march < 10 {
+        month_from_march + 3
+    } else {
+        month_from_march - 9
+    } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+impl Display for Stamp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let seconds = (self.time % 86400) as u32;
+        let (year, month, day) = civil_from_days((self.time / 86400) as i64);
+        write!(f, "made by ampack v{} at {} ({:04}-{:02}-{:02} {:02}:{:02}:{:02} \
+            UTC)", self.version, self.time, year, month, day, seconds / 3600,
+            seconds / 60 % 60, seconds % 60)
+    }
+}
@@ -0,0 +1,60 @@
+/*
+ampack, to unpack and pack Aml burning images: storage detection module
+Copyright (C) 2024-present Guoxin "7Ji" Pu
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as
+published by the Free Software Foundation, either version 3 of the
+License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::path::Path;
+
+/// Best-effort guess whether `path` (or, if it does not exist yet, its
+/// closest existing ancestor) lives on a rotational disk, on which concurrent
+/// I/O causes seek storms. Only Linux is detected, via sysfs, anything unknown
+/// is assumed to be non-rotational.
+#[cfg(target_os = "linux")]
+pub fn is_rotational(path: &Path) -> bool {
+    use std::{fs::{canonicalize, read_to_string}, os::unix::fs::MetadataExt};
+
+    let metadata = match path.ancestors().find_map(|path|
+        if path.as_os_str().is_empty() {
+            Path::new(".").metadata().ok()
+        } else {
+            path.metadata().ok()
+        }
+    ) {
+        Some(metadata) => metadata,
+        None => return false,
+    };
+    let dev = metadata.dev();
+    let major = ((dev >> 32) & 0xfffff000) | ((dev >> 8) & 0xfff);
+    let minor = ((dev >> 12) & 0xffffff00) | (dev & 0xff);
+    let block = match canonicalize(
+        format!("/sys/dev/block/{}:{}", major, minor))
+    {
+        Ok(block) => block,
+        Err(_) => return false, // e.g. tmpfs, overlayfs, network filesystems
+    };
+    // Partitions have no queue of their own, the whole disk is their parent
+    for dir in [Some(block.as_path()), block.parent()].into_iter().flatten() {
+        if let Ok(rotational) = read_to_string(dir.join("queue/rotational")) {
+            return rotational.trim() == "1"
+        }
+    }
+    false
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn is_rotational(_path: &Path) -> bool {
+    false
+}
@@ -0,0 +1,125 @@
+/*
+ampack, to unpack and pack Aml burning images: WebAssembly bindings module
+Copyright (C) 2024-present Guoxin "7Ji" Pu
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as
+published by the Free Software Foundation, either version 3 of the
+License, or (at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// Only the table is parsed, through the generic readers, nothing here 
+// touches files, threads or the clock, which are not in the browser
+
+use std::io::{Read, Seek, SeekFrom};
+
+use js_sys::{Function, Uint8Array, JSON};
+use serde::Serialize;
+use wasm_bindgen::prelude::*;
+
+use crate::{image::{identify_item_infos_from, Image, ImageHeader, RawItemInfo}, identify::PayloadKind, retry, sha1sum::Sha1sumHasher};
+
+/// An image read through a JS function `(offset, length) => Uint8Array`,
+/// returning `length` bytes at `offset`, or fewer only at the end, e.g. 
+/// slices of an `ArrayBuffer`, or of a `File` with `FileReaderSync` in a 
+/// worker
+struct JsReader {
+    read: Function,
+    size: u64,
+    position: u64,
+}
+
+impl Read for JsReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = (buf.len() as u64).min(self.size.saturating_sub(self.position));
+        if len == 0 {
+            return Ok(0)
+        }
+        let chunk = self.read.call2(&JsValue::NULL, 
+            &JsValue::from_f64(self.position as f64), 
+            &JsValue::from_f64(len as f64))
+            .map_err(|e| std::io::Error::other(format!("{:?}", e)))?;
+        let chunk = Uint8Array::new(&chunk);
+        let len = (chunk.length() as usize).min(len as usize);
+        chunk.subarray(0, len as u32).copy_to(&mut buf[..len]);
+        self.position += len as u64;
+        Ok(len)
+    }
+}
+
+impl Seek for JsReader {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.size.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        self.position = position.ok_or_else(|| std::io::Error::new(
+            std::io::ErrorKind::InvalidInput, "seek to a negative position"))?;
+        Ok(self.position)
+    }
+}
+
+/// What `inspectImage` returns, in the same shape as `info --json`
+#[derive(Serialize)]
+struct Inspection {
+    header: ImageHeader,
+    infos: Vec<RawItemInfo>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    payloads: Vec<PayloadKind>,
+}
+
+fn js_error<E: std::fmt::Display>(e: E) -> JsError {
+    JsError::new(&e.to_string())
+}
+
+/// Read the header and the item info table of the image of `size` bytes 
+/// read with `read`, see `JsReader`, and with `identify`, what the payloads
+/// of items look like from their first bytes. Only the table, and the heads
+/// of payloads with `identify`, are read.
+#[wasm_bindgen(js_name = inspectImage)]
+pub fn inspect_image(read: Function, size: f64, identify: bool) 
+    -> Result<JsValue, JsError> 
+{
+    let mut reader = JsReader { read, size: size as u64, position: 0 };
+    let (header, infos) = Image::try_read_table_from(&mut reader)
+        .map_err(js_error)?;
+    let payloads = if identify {
+        identify_item_infos_from(&mut reader, &infos).map_err(js_error)?
+    } else {
+        Vec::new()
+    };
+    let inspection = Inspection { header, infos, payloads };
+    let json = serde_json::to_string(&inspection).map_err(js_error)?;
+    JSON::parse(&json).map_err(|e| JsError::new(&format!("{:?}", e)))
+}
+
+/// SHA1 of `size` bytes at `offset` of the image of `size_image` bytes read
+/// with `read`, in hex, for comparing a partition against the sha1sum in its
+/// VERIFY item. Reads the whole region, so it's slow for large partitions.
+#[wasm_bindgen(js_name = sha1sumRegion)]
+pub fn sha1sum_region(read: Function, size_image: f64, offset: f64, size: f64)
+    -> Result<String, JsError> 
+{
+    let mut reader = JsReader { read, size: size_image as u64, position: 0 };
+    reader.seek(SeekFrom::Start(offset as u64)).map_err(js_error)?;
+    let mut hasher = Sha1sumHasher::new();
+    let mut buffer = vec![0; crate::payload::SIZE_CHUNK_DEFAULT];
+    let mut left = size as u64;
+    while left > 0 {
+        let buffer = &mut buffer[..left.min(
+            crate::payload::SIZE_CHUNK_DEFAULT as u64) as usize];
+        retry::read_exact(&mut reader, buffer).map_err(|e| js_error(e.error))?;
+        hasher.update(buffer);
+        left -= buffer.len() as u64
+    }
+    Ok(hasher.finalize().to_string())
+}